@@ -0,0 +1,344 @@
+use crate::models::openai::{Message, OpenAIChatCompletionRequest, OpenAIChatCompletionResponse};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CACHE_CAPACITY: usize = 1000;
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Response cache
+pub trait ResponseCache: Send + Sync {
+    fn get(&self, key: &str) -> Option<OpenAIChatCompletionResponse>;
+    fn put(&self, key: String, model: &str, response: OpenAIChatCompletionResponse);
+    // Evicts all entries for `model`, returning how many were removed
+    fn invalidate_model(&self, model: &str) -> usize;
+    fn invalidate_all(&self) -> usize;
+}
+
+// Whether a response came from the cache, reported to clients in a header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    // The request isn't cacheable or no cache is configured
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Bypass => "bypass",
+        }
+    }
+}
+
+// Only deterministic, non-streaming requests are worth caching
+pub fn is_cacheable(request: &OpenAIChatCompletionRequest) -> bool {
+    request.stream != Some(true) && request.temperature == Some(0.0)
+}
+
+// Hashes the request as canonical JSON, so the order of extra fields doesn't
+// change the key. End user identifiers are left out, the same request from
+// another user gets the same answer. A caller's own upstream key is hashed in,
+// its answers aren't served to callers using another key.
+pub fn cache_key(request: &OpenAIChatCompletionRequest) -> String {
+    let mut value = serde_json::to_value(request).expect("Request serializes to JSON");
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("user");
+        fields.remove("safety_identifier");
+        if let Some(key) = &request.provider_key {
+            fields.insert("provider_key".to_string(), hex_digest(key.expose()).into());
+        }
+    }
+    hex_digest(&value.to_string())
+}
+
+// Hashes the model and the leading system and developer messages, the prefix
+// that stays the same across the turns of a conversation. Used as the upstream
+// `prompt_cache_key` so requests sharing that prefix land on the same cache.
+pub fn prompt_cache_key(request: &OpenAIChatCompletionRequest) -> String {
+    let prefix: Vec<&Message> = request
+        .messages
+        .iter()
+        .take_while(|message| matches!(message, Message::System { .. } | Message::Developer { .. }))
+        .collect();
+    let value = serde_json::json!({"model": request.model, "messages": prefix});
+    hex_digest(&value.to_string())
+}
+
+pub(crate) fn hex_digest(input: &str) -> String {
+    let digest = Sha256::digest(input.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+struct Entry {
+    model: String,
+    response: OpenAIChatCompletionResponse,
+    expires: Instant,
+    // Tick of the last get or put, the least recently used entry is evicted
+    // first
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    tick: u64,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+// Keeps at most `capacity` responses, each for the TTL of its model or else
+// `ttl`. Expired entries are dropped when they are looked up.
+pub struct InMemoryCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    ttl: Duration,
+    model_ttls: HashMap<String, Duration>,
+}
+
+impl Default for InMemoryCache {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::default(),
+            capacity: DEFAULT_CACHE_CAPACITY,
+            ttl: DEFAULT_CACHE_TTL,
+            model_ttls: HashMap::new(),
+        }
+    }
+}
+
+impl InMemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_model_ttls(mut self, model_ttls: HashMap<String, Duration>) -> Self {
+        self.model_ttls = model_ttls;
+        self
+    }
+
+    fn ttl(&self, model: &str) -> Duration {
+        self.model_ttls.get(model).copied().unwrap_or(self.ttl)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ResponseCache for InMemoryCache {
+    fn get(&self, key: &str) -> Option<OpenAIChatCompletionResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let tick = entries.tick();
+        let entry = entries.entries.get_mut(key)?;
+        if entry.expires <= Instant::now() {
+            entries.entries.remove(key);
+            return None;
+        }
+        entry.used = tick;
+        Some(entry.response.clone())
+    }
+
+    fn put(&self, key: String, model: &str, response: OpenAIChatCompletionResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = Entry {
+            model: model.to_string(),
+            response,
+            expires: Instant::now() + self.ttl(model),
+            used: entries.tick(),
+        };
+        entries.entries.insert(key, entry);
+        // A linear scan, the cache is small enough that it doesn't pay to
+        // keep the entries ordered by use
+        while entries.entries.len() > self.capacity {
+            let Some(oldest) = entries
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            entries.entries.remove(&oldest);
+        }
+    }
+
+    fn invalidate_model(&self, model: &str) -> usize {
+        let entries = &mut self.entries.lock().unwrap().entries;
+        let before = entries.len();
+        entries.retain(|_, entry| entry.model != model);
+        before - entries.len()
+    }
+
+    fn invalidate_all(&self) -> usize {
+        let entries = &mut self.entries.lock().unwrap().entries;
+        let evicted = entries.len();
+        entries.clear();
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::completion;
+    use crate::models::openai::ProviderKey;
+    use serde_json::json;
+
+    #[test]
+    fn test_invalidate_model_keeps_other_models() {
+        let cache = InMemoryCache::new();
+        cache.put("a".to_string(), "gpt-4o", completion("gpt-4o", "Hi!"));
+        cache.put("b".to_string(), "gpt-4o", completion("gpt-4o", "Hi!"));
+        cache.put(
+            "c".to_string(),
+            "gpt-4o-mini",
+            completion("gpt-4o-mini", "Hi!"),
+        );
+
+        assert_eq!(cache.invalidate_model("gpt-4o"), 2);
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.invalidate_all(), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_least_recently_used_entry_is_evicted() {
+        let cache = InMemoryCache::new().with_capacity(2);
+        cache.put("a".to_string(), "gpt-4o", completion("gpt-4o", "A"));
+        cache.put("b".to_string(), "gpt-4o", completion("gpt-4o", "B"));
+        assert!(cache.get("a").is_some());
+
+        cache.put("c".to_string(), "gpt-4o", completion("gpt-4o", "C"));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn test_expired_entry_is_a_miss() {
+        let cache = InMemoryCache::new().with_ttl(Duration::ZERO);
+        cache.put("a".to_string(), "gpt-4o", completion("gpt-4o", "A"));
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_entry_past_model_ttl_is_a_miss() {
+        let cache = InMemoryCache::new()
+            .with_model_ttls(HashMap::from([("gpt-4o".to_string(), Duration::ZERO)]));
+        cache.put("a".to_string(), "gpt-4o", completion("gpt-4o", "A"));
+        cache.put(
+            "b".to_string(),
+            "gpt-4o-mini",
+            completion("gpt-4o-mini", "B"),
+        );
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+    }
+
+    #[test]
+    fn test_cache_key_ignores_user() {
+        let request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        let from_user = OpenAIChatCompletionRequest {
+            user: Some("user-1234".to_string()),
+            ..request.clone()
+        };
+
+        assert_eq!(cache_key(&request), cache_key(&from_user));
+    }
+
+    #[test]
+    fn test_cache_key_includes_provider_key() {
+        let request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        let with_key = |key: &str| OpenAIChatCompletionRequest {
+            provider_key: Some(ProviderKey::new(key)),
+            ..request.clone()
+        };
+
+        assert_ne!(cache_key(&request), cache_key(&with_key("sk-a")));
+        assert_ne!(cache_key(&with_key("sk-a")), cache_key(&with_key("sk-b")));
+        assert_eq!(cache_key(&with_key("sk-a")), cache_key(&with_key("sk-a")));
+    }
+
+    #[test]
+    fn test_cache_key_ignores_extra_field_order() {
+        let first: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "seed": 1,
+            "top_p": 0.5
+        }))
+        .unwrap();
+        let second: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "top_p": 0.5,
+            "seed": 1,
+            "model": "gpt-4o",
+            "messages": []
+        }))
+        .unwrap();
+
+        assert_eq!(cache_key(&first), cache_key(&second));
+    }
+
+    #[test]
+    fn test_prompt_cache_key_ignores_conversation() {
+        let first = OpenAIChatCompletionRequest::new("gpt-4o")
+            .with_message("system", "You are a support bot.")
+            .with_message("user", "Hello!");
+        let second = OpenAIChatCompletionRequest::new("gpt-4o")
+            .with_message("system", "You are a support bot.")
+            .with_message("user", "Where is my order?");
+        let other_prompt = OpenAIChatCompletionRequest::new("gpt-4o")
+            .with_message("system", "You are a poet.")
+            .with_message("user", "Hello!");
+
+        assert_eq!(prompt_cache_key(&first), prompt_cache_key(&second));
+        assert_ne!(prompt_cache_key(&first), prompt_cache_key(&other_prompt));
+    }
+
+    #[test]
+    fn test_only_deterministic_requests_are_cacheable() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+        assert!(!is_cacheable(&request));
+
+        request.temperature = Some(0.0);
+        assert!(is_cacheable(&request));
+
+        request.stream = Some(true);
+        assert!(!is_cacheable(&request));
+    }
+}
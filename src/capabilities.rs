@@ -0,0 +1,368 @@
+use crate::models::openai::{Content, Message, OpenAIChatCompletionRequest};
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+// What a model accepts, used to adapt requests before they are sent upstream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub logit_bias: bool,
+    pub developer_role: bool,
+    pub streaming: bool,
+    // Whether `n` can ask for more than one choice in a single request
+    pub multiple_choices: bool,
+    pub tools: bool,
+    // Whether messages can contain `image_url` parts
+    pub vision: bool,
+    // Prompt and completion tokens together, `None` when unknown
+    pub context_window: Option<u32>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            logit_bias: true,
+            developer_role: true,
+            streaming: true,
+            multiple_choices: true,
+            tools: true,
+            vision: true,
+            context_window: None,
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn satisfies(&self, needs: &Requirements) -> bool {
+        (self.tools || !needs.tools)
+            && (self.vision || !needs.vision)
+            && self
+                .context_window
+                .is_none_or(|window| needs.context_tokens <= window)
+    }
+}
+
+// What a request needs from the model that serves it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Requirements {
+    pub tools: bool,
+    pub vision: bool,
+    // Estimated prompt tokens, at four bytes per token, plus the completion
+    // tokens the request asks for
+    pub context_tokens: u32,
+}
+
+impl Requirements {
+    pub fn of(request: &OpenAIChatCompletionRequest) -> Self {
+        let tools = request.tools.is_some()
+            || request
+                .extra
+                .as_ref()
+                .is_some_and(|extra| extra.contains_key("functions"));
+        let vision = request.messages.iter().any(|message| {
+            matches!(message.content(), Some(Content::Array(parts)) if parts
+                .iter()
+                .any(|part| part.get("type").and_then(Value::as_str) == Some("image_url")))
+        });
+        let prompt_bytes: usize = request.messages.iter().map(Message::content_bytes).sum();
+        let completion = request
+            .max_completion_tokens
+            .or(request.max_tokens)
+            .unwrap_or_default()
+            .max(0) as u32;
+        Self {
+            tools,
+            vision,
+            context_tokens: (prompt_bytes / 4) as u32 + completion,
+        }
+    }
+}
+
+// What to do when a client asks to stream from a model that can't
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnsupportedStream {
+    // Send the whole response as a stream of chunks once it's complete
+    #[default]
+    Bridge,
+    Reject,
+}
+
+impl FromStr for UnsupportedStream {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bridge" => Ok(UnsupportedStream::Bridge),
+            "reject" => Ok(UnsupportedStream::Reject),
+            _ => Err(anyhow!("expected bridge or reject")),
+        }
+    }
+}
+
+// Capabilities keyed by model prefix, the longest matching prefix wins
+#[derive(Debug, Clone)]
+pub struct CapabilityTable {
+    entries: Vec<(String, Capabilities)>,
+}
+
+impl Default for CapabilityTable {
+    fn default() -> Self {
+        let reasoning = Capabilities {
+            logit_bias: false,
+            ..Default::default()
+        };
+        let legacy = Capabilities {
+            developer_role: false,
+            vision: false,
+            ..Default::default()
+        };
+        let minimal = Capabilities {
+            logit_bias: false,
+            developer_role: false,
+            ..Default::default()
+        };
+        // The first o1 releases don't stream, call tools or see images
+        let early_reasoning = Capabilities {
+            streaming: false,
+            tools: false,
+            vision: false,
+            context_window: Some(128_000),
+            ..minimal
+        };
+        let single_choice = Capabilities {
+            multiple_choices: false,
+            context_window: Some(200_000),
+            ..minimal
+        };
+        let context = |capabilities: Capabilities, tokens: u32| Capabilities {
+            context_window: Some(tokens),
+            ..capabilities
+        };
+        Self::new()
+            .with("gpt-3.5", context(legacy, 16_385))
+            .with("gpt-4", context(legacy, 8_192))
+            .with(
+                "gpt-4-turbo",
+                Capabilities {
+                    vision: true,
+                    ..context(legacy, 128_000)
+                },
+            )
+            .with("gpt-4o", context(Capabilities::default(), 128_000))
+            .with("gpt-4.1", context(Capabilities::default(), 1_047_576))
+            .with("o1", context(reasoning, 200_000))
+            .with("o1-mini", early_reasoning)
+            .with("o1-preview", early_reasoning)
+            .with("o3", context(reasoning, 200_000))
+            .with("o4", context(reasoning, 200_000))
+            .with("claude-", single_choice)
+            .with("gemini-", context(minimal, 1_048_576))
+    }
+}
+
+impl CapabilityTable {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, prefix: impl Into<String>, capabilities: Capabilities) -> Self {
+        self.entries.push((prefix.into(), capabilities));
+        self
+    }
+
+    // Marks models starting with `prefix` as unable to stream, keeping their
+    // other capabilities.
+    pub fn without_streaming(self, prefix: &str) -> Self {
+        let capabilities = Capabilities {
+            streaming: false,
+            ..self.lookup(prefix)
+        };
+        self.with(prefix, capabilities)
+    }
+
+    // Marks models starting with `prefix` as answering with a single choice
+    pub fn without_multiple_choices(self, prefix: &str) -> Self {
+        let capabilities = Capabilities {
+            multiple_choices: false,
+            ..self.lookup(prefix)
+        };
+        self.with(prefix, capabilities)
+    }
+
+    pub fn lookup(&self, model: &str) -> Capabilities {
+        self.entries
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, capabilities)| *capabilities)
+            .unwrap_or_default()
+    }
+
+    // Drops parameters the target model doesn't support instead of letting the
+    // upstream reject the whole request. Returns the names of dropped parameters.
+    pub fn filter(&self, request: &mut OpenAIChatCompletionRequest) -> Vec<&'static str> {
+        let capabilities = self.lookup(&request.model);
+        let mut dropped = Vec::new();
+
+        if !capabilities.logit_bias {
+            if let Some(extra) = request.extra.as_mut() {
+                if extra.remove("logit_bias").is_some() {
+                    dropped.push("logit_bias");
+                }
+            }
+        }
+
+        dropped
+    }
+
+    // How many separate requests to send for a request with `n > 1` to a model
+    // that answers with a single choice, `None` when it can be sent as is.
+    pub fn choice_split(&self, request: &OpenAIChatCompletionRequest) -> Option<u64> {
+        let n = request.n.filter(|n| *n > 1)? as u64;
+        (!self.lookup(&request.model).multiple_choices).then_some(n)
+    }
+
+    // Rewrites `developer` messages as `system` messages for models that
+    // predate the developer role. Returns how many messages were rewritten.
+    pub fn collapse_developer_role(&self, request: &mut OpenAIChatCompletionRequest) -> usize {
+        if self.lookup(&request.model).developer_role {
+            return 0;
+        }
+        let mut collapsed = 0;
+        for message in &mut request.messages {
+            if let Message::Developer { content, name } = message {
+                *message = Message::System {
+                    content: std::mem::replace(content, Content::Text(String::new())),
+                    name: name.take(),
+                };
+                collapsed += 1;
+            }
+        }
+        collapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request_with_logit_bias(model: &str) -> OpenAIChatCompletionRequest {
+        serde_json::from_value(json!({
+            "model": model,
+            "messages": [{"role": "user", "content": "Hello!"}],
+            "logit_bias": {"50256": -100}
+        }))
+        .expect("Failed to parse request")
+    }
+
+    #[test]
+    fn test_lookup_longest_prefix() {
+        let table = CapabilityTable::new()
+            .with(
+                "gpt-",
+                Capabilities {
+                    logit_bias: false,
+                    ..Default::default()
+                },
+            )
+            .with("gpt-4o", Capabilities::default());
+
+        assert!(table.lookup("gpt-4o-mini").logit_bias);
+        assert!(!table.lookup("gpt-3.5-turbo").logit_bias);
+        assert!(table.lookup("unknown-model").logit_bias);
+    }
+
+    #[test]
+    fn test_logit_bias_stripped_for_unsupported_model() {
+        let mut request = request_with_logit_bias("o1-mini");
+
+        let dropped = CapabilityTable::default().filter(&mut request);
+
+        assert_eq!(dropped, vec!["logit_bias"]);
+        assert!(!request.extra.unwrap().contains_key("logit_bias"));
+    }
+
+    #[test]
+    fn test_logit_bias_preserved_for_supported_model() {
+        let mut request = request_with_logit_bias("gpt-4o");
+
+        let dropped = CapabilityTable::default().filter(&mut request);
+
+        assert!(dropped.is_empty());
+        assert_eq!(request.extra.unwrap()["logit_bias"], json!({"50256": -100}));
+    }
+
+    fn developer_request(model: &str) -> OpenAIChatCompletionRequest {
+        OpenAIChatCompletionRequest::new(model)
+            .with_message("developer", "Be brief.")
+            .with_message("user", "Hello!")
+    }
+
+    #[test]
+    fn test_developer_role_collapsed_for_older_model() {
+        let mut request = developer_request("gpt-3.5-turbo");
+
+        assert_eq!(
+            CapabilityTable::default().collapse_developer_role(&mut request),
+            1
+        );
+
+        match &request.messages[0] {
+            Message::System { content, .. } => {
+                assert_eq!(content, &Content::Text("Be brief.".to_string()));
+            }
+            _ => panic!("Expected System message"),
+        }
+        assert!(matches!(request.messages[1], Message::User { .. }));
+    }
+
+    #[test]
+    fn test_developer_role_kept_for_newer_model() {
+        let mut request = developer_request("gpt-4o-mini");
+
+        assert_eq!(
+            CapabilityTable::default().collapse_developer_role(&mut request),
+            0
+        );
+        assert!(matches!(request.messages[0], Message::Developer { .. }));
+    }
+
+    #[test]
+    fn test_streaming_capability() {
+        let table = CapabilityTable::default().without_streaming("my-batch-model");
+
+        assert!(table.lookup("gpt-4o").streaming);
+        assert!(table.lookup("o1").streaming);
+        assert!(!table.lookup("o1-mini").streaming);
+        assert!(!table.lookup("my-batch-model-v2").streaming);
+
+        let gpt4 = CapabilityTable::default().without_streaming("gpt-4");
+        assert!(!gpt4.lookup("gpt-4").streaming);
+        assert!(!gpt4.lookup("gpt-4").developer_role);
+    }
+
+    #[test]
+    fn test_choice_split() {
+        let table = CapabilityTable::default().without_multiple_choices("my-model");
+        let request = |model: &str, n: u64| -> OpenAIChatCompletionRequest {
+            serde_json::from_value(json!({"model": model, "messages": [], "n": n})).unwrap()
+        };
+
+        assert_eq!(
+            table.choice_split(&request("claude-3-5-sonnet", 3)),
+            Some(3)
+        );
+        assert_eq!(table.choice_split(&request("my-model-v2", 2)), Some(2));
+        assert_eq!(table.choice_split(&request("claude-3-5-sonnet", 1)), None);
+        assert_eq!(table.choice_split(&request("gpt-4o", 3)), None);
+        assert_eq!(
+            table.choice_split(&OpenAIChatCompletionRequest::new("claude-3-5-sonnet")),
+            None
+        );
+    }
+}
@@ -0,0 +1,378 @@
+// Settings for the shared upstream HTTP client
+//
+// Providers and load balancers close idle connections without telling the
+// client, so the next request on such a socket fails. Evicting idle
+// connections before that happens avoids these stale-connection errors.
+use anyhow::{anyhow, Result};
+use reqwest::{redirect, tls};
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Well below the 60s idle timeout common on cloud load balancers
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+pub const DEFAULT_MAX_REDIRECTS: usize = 3;
+// A hung upstream is given up on after the request timeout, which covers
+// reading the whole response, streams included
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Which upstream redirects are followed. Proxies in front of a provider may
+// redirect, and following blindly could hand the API key to another origin.
+// When a redirect leaves the origin the `Authorization` header is dropped,
+// so the key only ever goes where it was configured to go. Providers with the
+// key in a header of their own don't follow such redirects at all, see
+// `ClientConfig::keyed_client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectPolicy {
+    None,
+    // Up to this many redirects on the same scheme, host and port
+    SameOrigin(usize),
+    // Up to this many redirects anywhere
+    Limited(usize),
+}
+
+impl RedirectPolicy {
+    // The same limit, on the origin of the request only
+    fn same_origin(self) -> Self {
+        match self {
+            RedirectPolicy::Limited(0) => RedirectPolicy::None,
+            RedirectPolicy::Limited(max) => RedirectPolicy::SameOrigin(max),
+            policy => policy,
+        }
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::Limited(DEFAULT_MAX_REDIRECTS)
+    }
+}
+
+impl FromStr for RedirectPolicy {
+    type Err = anyhow::Error;
+
+    // `none`, `same-origin` or the most redirects to follow
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(RedirectPolicy::None),
+            "same-origin" => Ok(RedirectPolicy::SameOrigin(DEFAULT_MAX_REDIRECTS)),
+            _ => value
+                .parse()
+                .map(RedirectPolicy::Limited)
+                .map_err(|_| anyhow!("expected none, same-origin or a number")),
+        }
+    }
+}
+
+impl From<RedirectPolicy> for redirect::Policy {
+    fn from(policy: RedirectPolicy) -> Self {
+        match policy {
+            RedirectPolicy::None | RedirectPolicy::Limited(0) => redirect::Policy::none(),
+            // reqwest removes `Authorization` itself when a redirect changes origin
+            RedirectPolicy::Limited(max) => redirect::Policy::limited(max),
+            RedirectPolicy::SameOrigin(max) => redirect::Policy::custom(move |attempt| {
+                let same_origin = attempt
+                    .previous()
+                    .first()
+                    .is_some_and(|first| first.origin() == attempt.url().origin());
+                if !same_origin {
+                    attempt.stop()
+                } else if attempt.previous().len() > max {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            }),
+        }
+    }
+}
+
+// Oldest TLS version upstream connections may negotiate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    #[default]
+    Tls12,
+    // Needs a TLS backend with TLS 1.3 support, the client fails to build otherwise
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(anyhow!("expected 1.2 or 1.3")),
+        }
+    }
+}
+
+impl From<TlsVersion> for tls::Version {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls12 => tls::Version::TLS_1_2,
+            TlsVersion::Tls13 => tls::Version::TLS_1_3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub idle_timeout: Duration,
+    pub max_idle_per_host: usize,
+    pub min_tls_version: TlsVersion,
+    pub redirects: RedirectPolicy,
+    pub request_timeout: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            min_tls_version: TlsVersion::default(),
+            redirects: RedirectPolicy::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn builder(&self) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .pool_idle_timeout(self.idle_timeout)
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .min_tls_version(self.min_tls_version.into())
+            .redirect(self.redirects.into())
+            .timeout(self.request_timeout)
+            .connect_timeout(self.connect_timeout)
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client> {
+        Ok(self.builder().build()?)
+    }
+
+    // Client for providers that send their key in a header such as `x-api-key`.
+    // reqwest drops only `Authorization` and cookies when a redirect leaves the
+    // origin, so redirects to another origin aren't followed.
+    pub fn keyed_client(&self) -> Result<reqwest::Client> {
+        let config = ClientConfig {
+            redirects: self.redirects.same_origin(),
+            ..*self
+        };
+        config.client()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    // Serves `GET /` and records the client address of every request, one
+    // address per connection.
+    async fn peers() -> (String, Arc<Mutex<HashSet<SocketAddr>>>) {
+        let peers = Arc::new(Mutex::new(HashSet::new()));
+        let seen = peers.clone();
+        let app = Router::new().route(
+            "/",
+            get(move |ConnectInfo(addr): ConnectInfo<SocketAddr>| {
+                let seen = seen.clone();
+                async move {
+                    seen.lock().unwrap().insert(addr);
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        (format!("http://{}/", addr), peers)
+    }
+
+    async fn connections_for_two_requests(pool: ClientConfig) -> usize {
+        let (url, peers) = peers().await;
+        let client = pool.client().unwrap();
+        client.get(&url).send().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.get(&url).send().await.unwrap();
+        let count = peers.lock().unwrap().len();
+        count
+    }
+
+    // Answers `POST /` by redirecting to `target`
+    async fn redirect_to(target: String) -> String {
+        let app = Router::new().route(
+            "/",
+            axum::routing::post(move || {
+                let target = target.clone();
+                async move { axum::response::Redirect::temporary(&target) }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/", addr)
+    }
+
+    // Serves `POST /` and records the `Authorization` header of every request
+    async fn authorizations() -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        recorded_header("authorization").await
+    }
+
+    // Serves `POST /` and records the `header` of every request
+    async fn recorded_header(header: &'static str) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = Router::new().route(
+            "/",
+            axum::routing::post(move |headers: axum::http::HeaderMap| {
+                let recorded = recorded.clone();
+                async move {
+                    let value = headers
+                        .get(header)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    recorded.lock().unwrap().push(value);
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/", addr), seen)
+    }
+
+    async fn post_through_redirect(redirects: RedirectPolicy) -> (u16, Vec<Option<String>>) {
+        let (target, seen) = authorizations().await;
+        let url = redirect_to(target).await;
+        let config = ClientConfig {
+            redirects,
+            ..ClientConfig::default()
+        };
+
+        let response = config
+            .client()
+            .unwrap()
+            .post(&url)
+            .bearer_auth("sk-secret")
+            .send()
+            .await
+            .unwrap();
+
+        let seen = seen.lock().unwrap().clone();
+        (response.status().as_u16(), seen)
+    }
+
+    #[tokio::test]
+    async fn test_cross_origin_redirect_drops_bearer_token() {
+        let (status, seen) = post_through_redirect(RedirectPolicy::default()).await;
+
+        assert_eq!(status, 200);
+        assert_eq!(seen, vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_cross_origin_redirect_never_gets_key_header() {
+        let (target, seen) = recorded_header("x-api-key").await;
+        let url = redirect_to(target).await;
+
+        let response = ClientConfig::default()
+            .keyed_client()
+            .unwrap()
+            .post(&url)
+            .header("x-api-key", "sk-ant-secret")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 307);
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_redirects_can_be_disabled() {
+        let (status, seen) = post_through_redirect(RedirectPolicy::None).await;
+        assert_eq!(status, 307);
+        assert!(seen.is_empty());
+
+        let (status, seen) = post_through_redirect(RedirectPolicy::SameOrigin(3)).await;
+        assert_eq!(status, 307);
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn test_parse_redirect_policy() {
+        assert_eq!(
+            "none".parse::<RedirectPolicy>().unwrap(),
+            RedirectPolicy::None
+        );
+        assert_eq!(
+            "same-origin".parse::<RedirectPolicy>().unwrap(),
+            RedirectPolicy::SameOrigin(DEFAULT_MAX_REDIRECTS)
+        );
+        assert_eq!(
+            "5".parse::<RedirectPolicy>().unwrap(),
+            RedirectPolicy::Limited(5)
+        );
+        assert!("always".parse::<RedirectPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_min_tls_version_is_applied() {
+        let config = ClientConfig {
+            min_tls_version: TlsVersion::Tls12,
+            ..ClientConfig::default()
+        };
+
+        assert!(config.client().is_ok());
+    }
+
+    #[test]
+    fn test_parse_tls_version() {
+        assert_eq!("1.2".parse::<TlsVersion>().unwrap(), TlsVersion::Tls12);
+        assert_eq!("1.3".parse::<TlsVersion>().unwrap(), TlsVersion::Tls13);
+        assert!("1.1".parse::<TlsVersion>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_reused_within_timeout() {
+        let pool = ClientConfig::default();
+        assert_eq!(connections_for_two_requests(pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_is_applied() {
+        let pool = ClientConfig {
+            idle_timeout: Duration::from_millis(50),
+            ..ClientConfig::default()
+        };
+        assert_eq!(connections_for_two_requests(pool).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_idle_per_host_is_applied() {
+        let pool = ClientConfig {
+            max_idle_per_host: 0,
+            ..ClientConfig::default()
+        };
+        assert_eq!(connections_for_two_requests(pool).await, 2);
+    }
+}
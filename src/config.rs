@@ -0,0 +1,1543 @@
+use crate::cache::{DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL};
+use crate::capabilities::{CapabilityTable, UnsupportedStream};
+use crate::client::{
+    ClientConfig, RedirectPolicy, TlsVersion, DEFAULT_CONNECT_TIMEOUT, DEFAULT_IDLE_TIMEOUT,
+    DEFAULT_MAX_IDLE_PER_HOST, DEFAULT_REQUEST_TIMEOUT,
+};
+use crate::logging::LogLevel;
+use crate::models::anthropic::ANTHROPIC_PROVIDER;
+use crate::models::api_version::{ApiVersions, VersionLocation, DEFAULT_VERSION_NAME};
+use crate::models::bedrock::BEDROCK_SERVICE;
+use crate::models::body_transform::BodyTransform;
+use crate::models::deadline::DeadlineHint;
+use crate::models::echo::{ALL_MODELS, ECHO_PROVIDER};
+use crate::models::gemini::GEMINI_PROVIDER;
+use crate::models::openai::{Message, DEFAULT_MAX_RESPONSE_BYTES, OPENAI_BASE_URL};
+use crate::models::provider::OPENAI_PROVIDER;
+use crate::pool::{DEFAULT_SESSION_TTL, POOL_PROVIDER};
+use crate::rate_limit::AdaptiveBounds;
+use crate::retry::{RetryPolicy, DEFAULT_BASE_DELAY, DEFAULT_RETRYABLE_STATUSES};
+use crate::server::{DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_FANOUT};
+use crate::transform::{ContentFilter, FilterRule};
+use crate::validation::{MaxTokensConflict, ToolLimits};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const SHADOW_PROVIDER: &str = "shadow";
+pub const DEFAULT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000);
+pub const CONFIG_ENV: &str = "KUBELLM_CONFIG";
+pub const CONFIG_ARG: &str = "--config";
+// Providers chat completions can be routed to
+pub const PROVIDERS: [&str; 6] = [
+    OPENAI_PROVIDER,
+    ANTHROPIC_PROVIDER,
+    GEMINI_PROVIDER,
+    BEDROCK_SERVICE,
+    ECHO_PROVIDER,
+    POOL_PROVIDER,
+];
+
+// Provider configuration
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderConfig {
+    pub name: String,
+    pub api_key_env: String,
+}
+
+impl ProviderConfig {
+    pub fn new(name: impl Into<String>, api_key_env: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            api_key_env: api_key_env.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    // Address the server listens on
+    pub addr: SocketAddr,
+    pub providers: Vec<ProviderConfig>,
+    // Requests per minute per model above which a warning is logged
+    pub soft_limits: HashMap<String, u32>,
+    // Requests per minute per model above which requests are rejected
+    pub rate_limits: HashMap<String, u32>,
+    // Per-model bounds of rate limits that adapt to upstream throttling
+    pub adaptive_rate_limits: HashMap<String, AdaptiveBounds>,
+    // Largest upstream response body that is buffered before giving up
+    pub max_response_bytes: usize,
+    // Retired model names and the model that replaces them
+    pub deprecated_models: HashMap<String, String>,
+    pub warn_deprecated_models: bool,
+    // Named prompt templates requests can expand, off when empty
+    pub prompt_templates: HashMap<String, Vec<Message>>,
+    // Provider of models no route matches
+    pub default_provider: Option<String>,
+    // Model of requests no explicit route or configured name covers, unless
+    // their model is remapped. Without routes that is every request.
+    pub default_model: Option<String>,
+    // Provider per model name prefix, e.g. `claude-` to `anthropic`. Models no
+    // prefix matches are sent to the default provider, or OpenAI without one,
+    // unless routes are configured without a default provider.
+    pub routes: HashMap<String, String>,
+    // Model for requests that omit it, by API key fingerprint, see `fingerprint`
+    pub key_default_models: HashMap<String, String>,
+    // Model of load balancer health checks, answered without an upstream
+    pub healthcheck_model: Option<String>,
+    // Log level of requests by API key fingerprint, to debug one client
+    pub key_log_levels: HashMap<String, LogLevel>,
+    // Models requests for `cheapest` choose from, see `pricing::CostRouting`
+    pub cheapest_candidates: Vec<String>,
+    // Models answered by the echo provider without an upstream, `*` for all
+    pub echo_models: Vec<String>,
+    pub echo_reply: Option<String>,
+    // Bedrock serves `anthropic.*` and `amazon.titan-text*` models in this
+    // region, signed with the `AWS_*` credentials
+    pub bedrock_region: Option<String>,
+    // Gemini serves `gemini-*` models with the key in `GEMINI_API_KEY`
+    pub gemini: bool,
+    // Anthropic serves `claude-*` models with the key in `ANTHROPIC_API_KEY`
+    pub anthropic: bool,
+    // Base URLs of OpenAI compatible deployments the pool provider balances
+    // over, and how long an idle session stays on its deployment
+    pub pool_deployments: Vec<String>,
+    pub session_ttl_secs: u64,
+    // API version per model, sent as `api_version_name` in the query or a header
+    pub api_versions: HashMap<String, String>,
+    pub api_version_name: String,
+    pub api_version_location: VersionLocation,
+    // Milliseconds a request has to complete unless `x-kubellm-timeout-ms` says
+    // otherwise, and how the upstream is told what is left of it
+    pub timeout_ms: Option<u64>,
+    pub deadline_hint: Option<DeadlineHint>,
+    // Request body edits per OpenAI compatible provider, `openai` or `shadow`
+    pub body_transforms: HashMap<String, BodyTransform>,
+    // Model to retry with once when the upstream fails for a model
+    pub fallback_models: HashMap<String, String>,
+    // Model to ask once more when a model refuses on content policy grounds.
+    // Off unless configured, since it routes around a provider's safety policy.
+    pub refusal_models: HashMap<String, String>,
+    // Largest accepted text content of a single message
+    pub max_message_bytes: Option<usize>,
+    pub tool_limits: ToolLimits,
+    // Requests with different `max_tokens` and `max_completion_tokens`
+    pub max_tokens_conflict: MaxTokensConflict,
+    // Largest accepted request body
+    pub max_body_bytes: usize,
+    // Most upstream calls one compare request or `n` split may make
+    pub max_fanout: usize,
+    // Model prefixes that can't stream and what to do when asked to
+    pub non_streaming_models: Vec<String>,
+    pub unsupported_stream: UnsupportedStream,
+    // Seconds after which a stream is cut off, however much it still sends
+    pub max_stream_duration_secs: Option<u64>,
+    // Streams one API key may have open at once
+    pub max_streams_per_key: Option<usize>,
+    // Model prefixes that answer `n > 1` with one choice, so `n` requests are sent
+    pub single_choice_models: Vec<String>,
+    // Concurrent upstream requests per provider
+    pub max_concurrency: Option<usize>,
+    // Report the queue depth of the provider in a response header
+    pub queue_depth_header: bool,
+    // Fields removed from forwarded stream chunks, e.g. `obfuscation`
+    pub strip_stream_fields: Vec<String>,
+    // Request annotations returned in `kubellm_extra`, off when empty
+    pub response_metadata: Vec<String>,
+    // Patterns replaced in the assistant's text of JSON responses
+    pub content_filters: Vec<FilterRule>,
+    // Upstream failures that are retried and how often
+    pub max_retries: u32,
+    pub retryable_statuses: Vec<u16>,
+    pub retryable_codes: Vec<String>,
+    pub retry_base_delay_ms: u64,
+    // Idle upstream connections are closed after this many seconds
+    pub pool_idle_timeout_secs: u64,
+    pub pool_max_idle_per_host: usize,
+    // Upstream connections that can't negotiate this version are refused
+    pub min_tls_version: TlsVersion,
+    // Which upstream redirects are followed, see `RedirectPolicy`
+    pub redirects: RedirectPolicy,
+    // Upstream requests are given up on after these many seconds, with a 504
+    pub request_timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    // Mirror a fraction of requests to an OpenAI compatible provider under
+    // evaluation, whose API key is read from `shadow_api_key_env`
+    pub shadow_base_url: Option<String>,
+    // OpenAI or a compatible API such as Azure OpenAI, vLLM or Ollama
+    pub openai_base_url: String,
+    pub shadow_api_key_env: String,
+    pub shadow_sample_rate: f64,
+    pub shadow_model: Option<String>,
+    // Proxies retrieval of completions created with `store: true`
+    pub completion_retrieval: bool,
+    // Call every provider once after startup to open connections
+    pub warmup: bool,
+    // `/readyz` checks that the OpenAI upstream can be reached
+    pub readiness_check: bool,
+    // Cache deterministic responses in memory, at most `cache_capacity` of
+    // them for `cache_ttl_secs` each
+    pub cache: bool,
+    pub cache_capacity: usize,
+    pub cache_ttl_secs: u64,
+    // Seconds per model that override `cache_ttl_secs`
+    pub cache_model_ttls: HashMap<String, u64>,
+    // Share one upstream stream between identical deterministic streaming requests
+    pub stream_dedupe: bool,
+    // Retry without streaming when the upstream answers a stream with JSON
+    pub stream_fallback: bool,
+    // Derive `prompt_cache_key` from the system prompt when a request has none
+    pub auto_prompt_cache_key: bool,
+    // Enables developer conveniences that must never be on in production
+    pub dev_mode: bool,
+    // Hosts a request may point the upstream at in dev mode
+    pub base_url_allowlist: Vec<String>,
+    // Serve requests without a bearer token, for deployments behind another
+    // auth layer
+    pub allow_anonymous: bool,
+    // Accept the caller's upstream key in `x-kubellm-provider-key`
+    pub trusted_provider_keys: bool,
+    // Bearer token for the /admin endpoints, which are disabled without it
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: DEFAULT_ADDR,
+            providers: vec![ProviderConfig::new("openai", "OPENAI_API_KEY")],
+            soft_limits: HashMap::new(),
+            rate_limits: HashMap::new(),
+            adaptive_rate_limits: HashMap::new(),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            deprecated_models: HashMap::new(),
+            prompt_templates: HashMap::new(),
+            warn_deprecated_models: true,
+            default_provider: None,
+            default_model: None,
+            routes: HashMap::new(),
+            key_default_models: HashMap::new(),
+            healthcheck_model: None,
+            key_log_levels: HashMap::new(),
+            cheapest_candidates: Vec::new(),
+            echo_models: Vec::new(),
+            echo_reply: None,
+            bedrock_region: None,
+            gemini: false,
+            anthropic: false,
+            pool_deployments: Vec::new(),
+            session_ttl_secs: DEFAULT_SESSION_TTL.as_secs(),
+            api_versions: HashMap::new(),
+            api_version_name: DEFAULT_VERSION_NAME.to_string(),
+            api_version_location: VersionLocation::default(),
+            timeout_ms: None,
+            deadline_hint: None,
+            body_transforms: HashMap::new(),
+            fallback_models: HashMap::new(),
+            refusal_models: HashMap::new(),
+            max_message_bytes: None,
+            tool_limits: ToolLimits::default(),
+            max_tokens_conflict: MaxTokensConflict::default(),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_fanout: DEFAULT_MAX_FANOUT,
+            non_streaming_models: Vec::new(),
+            single_choice_models: Vec::new(),
+            max_stream_duration_secs: None,
+            max_streams_per_key: None,
+            unsupported_stream: UnsupportedStream::default(),
+            max_concurrency: None,
+            queue_depth_header: false,
+            strip_stream_fields: Vec::new(),
+            response_metadata: Vec::new(),
+            content_filters: Vec::new(),
+            max_retries: RetryPolicy::default().max_retries,
+            retryable_statuses: DEFAULT_RETRYABLE_STATUSES.to_vec(),
+            retryable_codes: Vec::new(),
+            retry_base_delay_ms: DEFAULT_BASE_DELAY.as_millis() as u64,
+            pool_idle_timeout_secs: DEFAULT_IDLE_TIMEOUT.as_secs(),
+            pool_max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            min_tls_version: TlsVersion::default(),
+            redirects: RedirectPolicy::default(),
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT.as_secs(),
+            connect_timeout_secs: DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            shadow_base_url: None,
+            openai_base_url: OPENAI_BASE_URL.to_string(),
+            shadow_api_key_env: "SHADOW_API_KEY".to_string(),
+            shadow_sample_rate: 0.01,
+            shadow_model: None,
+            completion_retrieval: false,
+            warmup: false,
+            readiness_check: false,
+            cache: false,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            cache_ttl_secs: DEFAULT_CACHE_TTL.as_secs(),
+            cache_model_ttls: HashMap::new(),
+            stream_dedupe: false,
+            stream_fallback: true,
+            auto_prompt_cache_key: false,
+            dev_mode: false,
+            base_url_allowlist: Vec::new(),
+            allow_anonymous: false,
+            trusted_provider_keys: false,
+            admin_token: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    // Applies `KUBELLM_*` overrides on top of the defaults
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        Self::default().with_lookup(lookup)
+    }
+
+    // The config file's settings, with the environment's on top
+    pub fn from_file(file: &ConfigFile, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config = Self::default();
+        file.apply(&mut config);
+        config.with_lookup(|name| lookup(name).or_else(|| file.setting(name)))
+    }
+
+    // Applies `KUBELLM_*` overrides on top of this configuration
+    fn with_lookup(mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let config = &mut self;
+        if let Some(value) = lookup("KUBELLM_HOST") {
+            let host: IpAddr = value.trim().parse().map_err(|_| {
+                anyhow!(
+                    "KUBELLM_HOST: invalid value '{}', expected an IP address such as 0.0.0.0",
+                    value
+                )
+            })?;
+            config.addr.set_ip(host);
+        }
+        if let Some(value) = lookup("KUBELLM_PORT") {
+            let port: u16 = value.trim().parse().map_err(|_| {
+                anyhow!(
+                    "KUBELLM_PORT: invalid value '{}', expected a port from 0 to 65535",
+                    value
+                )
+            })?;
+            config.addr.set_port(port);
+        }
+        if let Some(value) = lookup("KUBELLM_SOFT_LIMITS") {
+            config.soft_limits = parse_model_map("KUBELLM_SOFT_LIMITS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_RATE_LIMITS") {
+            config.rate_limits = parse_model_map("KUBELLM_RATE_LIMITS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_ADAPTIVE_RATE_LIMITS") {
+            config.adaptive_rate_limits = parse_model_map("KUBELLM_ADAPTIVE_RATE_LIMITS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_RESPONSE_BYTES") {
+            config.max_response_bytes = parse_value("KUBELLM_MAX_RESPONSE_BYTES", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_DEPRECATED_MODELS") {
+            config.deprecated_models = parse_model_map("KUBELLM_DEPRECATED_MODELS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_WARN_DEPRECATED_MODELS") {
+            config.warn_deprecated_models = parse_value("KUBELLM_WARN_DEPRECATED_MODELS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_PROMPT_TEMPLATES") {
+            config.prompt_templates = serde_json::from_str(&value)
+                .map_err(|err| anyhow!("KUBELLM_PROMPT_TEMPLATES: {}", err))?;
+        }
+        if let Some(value) = lookup("KUBELLM_DEFAULT_PROVIDER") {
+            check_provider("KUBELLM_DEFAULT_PROVIDER", &value)?;
+            config.default_provider = Some(value);
+        }
+        if let Some(value) = lookup("KUBELLM_ROUTES") {
+            config.routes = parse_model_map("KUBELLM_ROUTES", &value)?;
+            for provider in config.routes.values() {
+                check_provider("KUBELLM_ROUTES", provider)?;
+            }
+        }
+        if let Some(value) = lookup("KUBELLM_DEFAULT_MODEL") {
+            if value.trim().is_empty() {
+                return Err(anyhow!("KUBELLM_DEFAULT_MODEL: must not be empty"));
+            }
+            config.default_model = Some(value);
+        }
+        if let Some(value) = lookup("KUBELLM_KEY_DEFAULT_MODELS") {
+            config.key_default_models = parse_model_map("KUBELLM_KEY_DEFAULT_MODELS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_KEY_LOG_LEVELS") {
+            config.key_log_levels = parse_model_map("KUBELLM_KEY_LOG_LEVELS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_HEALTHCHECK_MODEL") {
+            config.healthcheck_model = Some(value).filter(|model| !model.is_empty());
+        }
+        if let Some(value) = lookup("KUBELLM_CHEAPEST_CANDIDATES") {
+            config.cheapest_candidates = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_ECHO_MODELS") {
+            config.echo_models = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_ECHO_REPLY") {
+            config.echo_reply = Some(value);
+        }
+        if let Some(value) = lookup("KUBELLM_BEDROCK_REGION") {
+            config.bedrock_region = Some(value);
+        }
+        if let Some(value) = lookup("KUBELLM_GEMINI") {
+            config.gemini = parse_value("KUBELLM_GEMINI", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_POOL_DEPLOYMENTS") {
+            config.pool_deployments = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_SESSION_TTL") {
+            config.session_ttl_secs = parse_value("KUBELLM_SESSION_TTL", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_ANTHROPIC") {
+            config.anthropic = parse_value("KUBELLM_ANTHROPIC", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_API_VERSIONS") {
+            config.api_versions = parse_model_map("KUBELLM_API_VERSIONS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_API_VERSION_NAME") {
+            config.api_version_name = value;
+        }
+        if let Some(value) = lookup("KUBELLM_API_VERSION_LOCATION") {
+            config.api_version_location = parse_value("KUBELLM_API_VERSION_LOCATION", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_TIMEOUT_MS") {
+            config.timeout_ms = Some(parse_value("KUBELLM_TIMEOUT_MS", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_DEADLINE_HINT") {
+            config.deadline_hint = Some(parse_value("KUBELLM_DEADLINE_HINT", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_BODY_TRANSFORMS") {
+            config.body_transforms = parse_model_map("KUBELLM_BODY_TRANSFORMS", &value)?;
+            for provider in config.body_transforms.keys() {
+                if provider != OPENAI_PROVIDER && provider != SHADOW_PROVIDER {
+                    return Err(anyhow!(
+                        "KUBELLM_BODY_TRANSFORMS: '{}' is not an OpenAI compatible provider",
+                        provider
+                    ));
+                }
+            }
+        }
+        if let Some(value) = lookup("KUBELLM_FALLBACK_MODELS") {
+            config.fallback_models = parse_model_map("KUBELLM_FALLBACK_MODELS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_REFUSAL_MODELS") {
+            config.refusal_models = parse_model_map("KUBELLM_REFUSAL_MODELS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_MESSAGE_BYTES") {
+            config.max_message_bytes = Some(parse_value("KUBELLM_MAX_MESSAGE_BYTES", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_TOOLS") {
+            config.tool_limits.max_tools = Some(parse_value("KUBELLM_MAX_TOOLS", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_TOOL_BYTES") {
+            config.tool_limits.max_bytes = Some(parse_value("KUBELLM_MAX_TOOL_BYTES", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_TOKENS_CONFLICT") {
+            config.max_tokens_conflict = parse_value("KUBELLM_MAX_TOKENS_CONFLICT", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_BODY_BYTES") {
+            config.max_body_bytes = parse_value("KUBELLM_MAX_BODY_BYTES", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_FANOUT") {
+            config.max_fanout = parse_value("KUBELLM_MAX_FANOUT", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_NON_STREAMING_MODELS") {
+            config.non_streaming_models = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_SINGLE_CHOICE_MODELS") {
+            config.single_choice_models = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_STREAM_DURATION") {
+            config.max_stream_duration_secs =
+                Some(parse_value("KUBELLM_MAX_STREAM_DURATION", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_STREAMS_PER_KEY") {
+            config.max_streams_per_key = Some(parse_value("KUBELLM_MAX_STREAMS_PER_KEY", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_UNSUPPORTED_STREAM") {
+            config.unsupported_stream = parse_value("KUBELLM_UNSUPPORTED_STREAM", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_CONCURRENCY") {
+            config.max_concurrency = Some(parse_value("KUBELLM_MAX_CONCURRENCY", &value)?);
+        }
+        if let Some(value) = lookup("KUBELLM_QUEUE_DEPTH_HEADER") {
+            config.queue_depth_header = parse_value("KUBELLM_QUEUE_DEPTH_HEADER", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_STRIP_STREAM_FIELDS") {
+            config.strip_stream_fields = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_RESPONSE_METADATA") {
+            config.response_metadata = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_CONTENT_FILTERS") {
+            config.content_filters = serde_json::from_str(&value)
+                .map_err(|err| anyhow!("KUBELLM_CONTENT_FILTERS: {}", err))?;
+            ContentFilter::new(&config.content_filters)
+                .map_err(|err| anyhow!("KUBELLM_CONTENT_FILTERS: {}", err))?;
+        }
+        if let Some(value) = lookup("KUBELLM_MAX_RETRIES") {
+            config.max_retries = parse_value("KUBELLM_MAX_RETRIES", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_RETRY_STATUSES") {
+            config.retryable_statuses = parse_list(&value)
+                .iter()
+                .map(|status| parse_value("KUBELLM_RETRY_STATUSES", status))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(value) = lookup("KUBELLM_RETRY_ERROR_CODES") {
+            config.retryable_codes = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_RETRY_BASE_DELAY_MS") {
+            config.retry_base_delay_ms = parse_value("KUBELLM_RETRY_BASE_DELAY_MS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_POOL_IDLE_TIMEOUT") {
+            config.pool_idle_timeout_secs = parse_value("KUBELLM_POOL_IDLE_TIMEOUT", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_POOL_MAX_IDLE_PER_HOST") {
+            config.pool_max_idle_per_host = parse_value("KUBELLM_POOL_MAX_IDLE_PER_HOST", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_MIN_TLS_VERSION") {
+            config.min_tls_version = parse_value("KUBELLM_MIN_TLS_VERSION", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_REDIRECTS") {
+            config.redirects = parse_value("KUBELLM_REDIRECTS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_REQUEST_TIMEOUT") {
+            config.request_timeout_secs = parse_value("KUBELLM_REQUEST_TIMEOUT", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_CONNECT_TIMEOUT") {
+            config.connect_timeout_secs = parse_value("KUBELLM_CONNECT_TIMEOUT", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_SHADOW_API_KEY_ENV") {
+            config.shadow_api_key_env = value;
+        }
+        if let Some(value) = lookup("KUBELLM_SHADOW_SAMPLE_RATE") {
+            config.shadow_sample_rate = parse_value("KUBELLM_SHADOW_SAMPLE_RATE", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_SHADOW_MODEL") {
+            config.shadow_model = Some(value);
+        }
+        if let Some(value) = lookup("KUBELLM_SHADOW_BASE_URL") {
+            config.shadow_base_url = Some(value);
+        }
+        if let Some(value) = lookup("KUBELLM_OPENAI_BASE_URL") {
+            config.openai_base_url = value;
+        }
+        if let Some(value) = lookup("KUBELLM_COMPLETION_RETRIEVAL") {
+            config.completion_retrieval = parse_value("KUBELLM_COMPLETION_RETRIEVAL", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_WARMUP") {
+            config.warmup = parse_value("KUBELLM_WARMUP", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_READINESS_CHECK") {
+            config.readiness_check = parse_value("KUBELLM_READINESS_CHECK", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_CACHE") {
+            config.cache = parse_value("KUBELLM_CACHE", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_CACHE_CAPACITY") {
+            config.cache_capacity = parse_value("KUBELLM_CACHE_CAPACITY", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_CACHE_TTL") {
+            config.cache_ttl_secs = parse_value("KUBELLM_CACHE_TTL", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_CACHE_MODEL_TTLS") {
+            config.cache_model_ttls = parse_model_map("KUBELLM_CACHE_MODEL_TTLS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_STREAM_DEDUPE") {
+            config.stream_dedupe = parse_value("KUBELLM_STREAM_DEDUPE", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_STREAM_FALLBACK") {
+            config.stream_fallback = parse_value("KUBELLM_STREAM_FALLBACK", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_AUTO_PROMPT_CACHE_KEY") {
+            config.auto_prompt_cache_key = parse_value("KUBELLM_AUTO_PROMPT_CACHE_KEY", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_DEV_MODE") {
+            config.dev_mode = parse_value("KUBELLM_DEV_MODE", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_BASE_URL_ALLOWLIST") {
+            config.base_url_allowlist = parse_list(&value);
+        }
+        if let Some(value) = lookup("KUBELLM_ALLOW_ANONYMOUS") {
+            config.allow_anonymous = parse_value("KUBELLM_ALLOW_ANONYMOUS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_TRUSTED_PROVIDER_KEYS") {
+            config.trusted_provider_keys = parse_value("KUBELLM_TRUSTED_PROVIDER_KEYS", &value)?;
+        }
+        if let Some(value) = lookup("KUBELLM_ADMIN_TOKEN") {
+            config.admin_token = Some(value).filter(|token| !token.is_empty());
+        }
+        config.providers = config.keyed_providers();
+        Ok(self)
+    }
+
+    // Providers whose API key must be set, the shadow provider's is validated
+    // along with the others
+    fn keyed_providers(&self) -> Vec<ProviderConfig> {
+        let mut providers = Vec::new();
+        // Offline, nothing is sent to OpenAI so no key is needed
+        if !self.echo_models.iter().any(|model| model == ALL_MODELS) {
+            providers.push(ProviderConfig::new(OPENAI_PROVIDER, "OPENAI_API_KEY"));
+        }
+        if self.gemini {
+            providers.push(ProviderConfig::new(GEMINI_PROVIDER, "GEMINI_API_KEY"));
+        }
+        if self.anthropic {
+            providers.push(ProviderConfig::new(ANTHROPIC_PROVIDER, "ANTHROPIC_API_KEY"));
+        }
+        if self.shadow_base_url.is_some() {
+            providers.push(ProviderConfig::new(
+                SHADOW_PROVIDER,
+                self.shadow_api_key_env.clone(),
+            ));
+        }
+        providers
+    }
+
+    // The effective configuration with each provider's API key replaced by its
+    // fingerprint, safe to print when debugging precedence issues.
+    pub fn redacted(&self, lookup: impl Fn(&str) -> Option<String>) -> Value {
+        let mut value = serde_json::to_value(self).expect("Config serializes to JSON");
+        if let Some(providers) = value["providers"].as_array_mut() {
+            for (entry, provider) in providers.iter_mut().zip(&self.providers) {
+                entry["api_key"] = match lookup(&provider.api_key_env) {
+                    Some(key) if !key.trim().is_empty() => Value::String(fingerprint(&key)),
+                    _ => Value::String("<missing>".to_string()),
+                };
+            }
+        }
+        if let Some(token) = &self.admin_token {
+            value["admin_token"] = Value::String(fingerprint(token));
+        }
+        value
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            retryable_statuses: self.retryable_statuses.iter().copied().collect(),
+            retryable_codes: self.retryable_codes.iter().cloned().collect(),
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+        }
+    }
+
+    pub fn capabilities(&self) -> CapabilityTable {
+        let table = self
+            .non_streaming_models
+            .iter()
+            .fold(CapabilityTable::default(), |table, prefix| {
+                table.without_streaming(prefix)
+            });
+        self.single_choice_models
+            .iter()
+            .fold(table, |table, prefix| {
+                table.without_multiple_choices(prefix)
+            })
+    }
+
+    pub fn api_versions(&self) -> ApiVersions {
+        ApiVersions::new(self.api_versions.clone())
+            .with_name(self.api_version_name.clone())
+            .with_location(self.api_version_location)
+    }
+
+    pub fn client(&self) -> ClientConfig {
+        ClientConfig {
+            idle_timeout: Duration::from_secs(self.pool_idle_timeout_secs),
+            max_idle_per_host: self.pool_max_idle_per_host,
+            min_tls_version: self.min_tls_version,
+            redirects: self.redirects,
+            request_timeout: Duration::from_secs(self.request_timeout_secs),
+            connect_timeout: Duration::from_secs(self.connect_timeout_secs),
+        }
+    }
+
+    // Resolves the API key of every configured provider from the environment.
+    pub fn credentials(&self) -> Result<HashMap<String, String>, MissingCredentials> {
+        self.credentials_from(|name| std::env::var(name).ok())
+    }
+
+    // Collects all missing secrets instead of stopping at the first one, so an
+    // operator can fix the deployment in one go.
+    pub fn credentials_from(
+        &self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<HashMap<String, String>, MissingCredentials> {
+        let mut credentials = HashMap::new();
+        let mut missing = Vec::new();
+        for provider in &self.providers {
+            match lookup(&provider.api_key_env).filter(|key| !key.trim().is_empty()) {
+                Some(key) => {
+                    credentials.insert(provider.name.clone(), key);
+                }
+                None => missing.push(provider.clone()),
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(credentials)
+        } else {
+            Err(MissingCredentials { missing })
+        }
+    }
+}
+
+// Short, stable identifier for a secret that can be logged or printed
+pub fn fingerprint(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    let hex: String = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+// Config file
+//
+// A TOML, YAML or JSON document, read by the file's extension and as JSON
+// without one, with sections for the settings a deployment always needs:
+//
+//   [listen]     `host` and `port`
+//   [providers]  the `default` provider, and `openai`, `anthropic`, `gemini`
+//                and `bedrock` tables. A table enables its provider, with the
+//                `api_key`, OpenAI's `base_url` and Bedrock's `region`.
+//   [routes]     provider per model name prefix
+//   [timeouts]   `request_ms`, `upstream_secs`, `connect_secs` and `stream_secs`
+//
+// Any other setting goes at the top level under the name of its environment
+// variable, lists as arrays and model maps as tables. Environment variables
+// take precedence over the file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub listen: ListenSection,
+    pub providers: ProvidersSection,
+    pub routes: HashMap<String, String>,
+    pub timeouts: TimeoutsSection,
+    #[serde(flatten)]
+    settings: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenSection {
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvidersSection {
+    pub default: Option<String>,
+    pub openai: Option<OpenAISection>,
+    pub anthropic: Option<KeySection>,
+    pub gemini: Option<KeySection>,
+    pub bedrock: Option<BedrockSection>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAISection {
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeySection {
+    pub api_key: Option<String>,
+}
+
+// Bedrock signs with the `AWS_*` credentials from the environment
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BedrockSection {
+    pub region: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsSection {
+    // `KUBELLM_TIMEOUT_MS`
+    pub request_ms: Option<u64>,
+    // `KUBELLM_REQUEST_TIMEOUT`
+    pub upstream_secs: Option<u64>,
+    // `KUBELLM_CONNECT_TIMEOUT`
+    pub connect_secs: Option<u64>,
+    // `KUBELLM_MAX_STREAM_DURATION`
+    pub stream_secs: Option<u64>,
+}
+
+// Settings whose environment variable holds JSON, passed on as JSON
+const JSON_SETTINGS: [&str; 2] = ["KUBELLM_PROMPT_TEMPLATES", "KUBELLM_CONTENT_FILTERS"];
+
+impl ConfigFile {
+    // The file named by `--config <path>` or `KUBELLM_CONFIG`, empty when
+    // neither is given
+    pub fn load(args: &[String], lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let path = match args.iter().position(|arg| arg == CONFIG_ARG) {
+            Some(index) => Some(
+                args.get(index + 1)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{}: missing path", CONFIG_ARG))?,
+            ),
+            None => lookup(CONFIG_ENV),
+        };
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| anyhow!("Failed to read config file {}: {}", path, err))?;
+        let file = if path.ends_with(".toml") {
+            Self::parse_toml(&contents)
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            Self::parse_yaml(&contents)
+        } else {
+            Self::parse(&contents)
+        };
+        file.map_err(|err| anyhow!("Config file {}: {}", path, err))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        serde_json::from_str::<Self>(contents)?.checked()
+    }
+
+    pub fn parse_toml(contents: &str) -> Result<Self> {
+        toml::from_str::<Self>(contents)?.checked()
+    }
+
+    pub fn parse_yaml(contents: &str) -> Result<Self> {
+        serde_yaml::from_str::<Self>(contents)?.checked()
+    }
+
+    // Top level names other than the sections are settings, and must be
+    // environment variable names with values that can be one
+    fn checked(self) -> Result<Self> {
+        for (name, value) in &self.settings {
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            {
+                return Err(anyhow!("unknown section '{}'", name));
+            }
+            if setting(name, value).is_none() {
+                return Err(anyhow!("{}: invalid value", name));
+            }
+        }
+        if let Some(provider) = &self.providers.default {
+            check_provider("providers.default", provider)?;
+        }
+        for provider in self.routes.values() {
+            check_provider("routes", provider)?;
+        }
+        Ok(self)
+    }
+
+    // Sets what the sections configure
+    fn apply(&self, config: &mut Config) {
+        if let Some(host) = self.listen.host {
+            config.addr.set_ip(host);
+        }
+        if let Some(port) = self.listen.port {
+            config.addr.set_port(port);
+        }
+        let providers = &self.providers;
+        if let Some(provider) = &providers.default {
+            config.default_provider = Some(provider.clone());
+        }
+        if let Some(base_url) = providers
+            .openai
+            .as_ref()
+            .and_then(|openai| openai.base_url.clone())
+        {
+            config.openai_base_url = base_url;
+        }
+        config.anthropic = providers.anthropic.is_some();
+        config.gemini = providers.gemini.is_some();
+        config.bedrock_region = providers
+            .bedrock
+            .as_ref()
+            .map(|bedrock| bedrock.region.clone());
+        config.routes = self.routes.clone();
+        let timeouts = &self.timeouts;
+        config.timeout_ms = timeouts.request_ms;
+        if let Some(secs) = timeouts.upstream_secs {
+            config.request_timeout_secs = secs;
+        }
+        if let Some(secs) = timeouts.connect_secs {
+            config.connect_timeout_secs = secs;
+        }
+        config.max_stream_duration_secs = timeouts.stream_secs;
+    }
+
+    // The file's value of an environment variable, API keys of the provider
+    // sections included
+    pub fn setting(&self, name: &str) -> Option<String> {
+        let providers = &self.providers;
+        let api_key = match name {
+            "OPENAI_API_KEY" => providers
+                .openai
+                .as_ref()
+                .and_then(|openai| openai.api_key.as_ref()),
+            "ANTHROPIC_API_KEY" => providers
+                .anthropic
+                .as_ref()
+                .and_then(|section| section.api_key.as_ref()),
+            "GEMINI_API_KEY" => providers
+                .gemini
+                .as_ref()
+                .and_then(|section| section.api_key.as_ref()),
+            _ => None,
+        };
+        api_key
+            .cloned()
+            .or_else(|| setting(name, self.settings.get(name)?))
+    }
+}
+
+// A file value in the format of the environment variable
+fn setting(name: &str, value: &Value) -> Option<String> {
+    if JSON_SETTINGS.contains(&name) {
+        return match value {
+            Value::String(value) => Some(value.clone()),
+            value => Some(value.to_string()),
+        };
+    }
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .map(scalar)
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        Value::Object(pairs) => pairs
+            .iter()
+            .map(|(key, value)| Some(format!("{}={}", key, scalar(value)?)))
+            .collect::<Option<Vec<_>>>()
+            .map(|pairs| pairs.join(",")),
+        value => scalar(value),
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn check_provider(name: &str, provider: &str) -> Result<()> {
+    if !PROVIDERS.contains(&provider) {
+        return Err(anyhow!("{}: unknown provider '{}'", name, provider));
+    }
+    Ok(())
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("{}: invalid value '{}'", name, value))
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Parses `model=value` pairs separated by commas, e.g. `gpt-4o=60,gpt-4o-mini=600`
+fn parse_model_map<T: FromStr>(name: &str, value: &str) -> Result<HashMap<String, T>> {
+    let mut map = HashMap::new();
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (model, value) = pair
+            .split_once('=')
+            .ok_or_else(|| anyhow!("{}: expected model=value, got '{}'", name, pair))?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("{}: invalid value for model '{}'", name, model.trim()))?;
+        map.insert(model.trim().to_string(), value);
+    }
+    Ok(map)
+}
+
+#[derive(Debug)]
+pub struct MissingCredentials {
+    pub missing: Vec<ProviderConfig>,
+}
+
+impl fmt::Display for MissingCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing credentials for configured providers:")?;
+        for provider in &self.missing {
+            write!(
+                f,
+                "\n  - {}: environment variable {} is not set",
+                provider.name, provider.api_key_env
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MissingCredentials {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_providers() -> Config {
+        Config {
+            providers: vec![
+                ProviderConfig::new("openai", "OPENAI_API_KEY"),
+                ProviderConfig::new("anthropic", "ANTHROPIC_API_KEY"),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_soft_limits_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_SOFT_LIMITS" => Some("gpt-4o=60, gpt-4o-mini=600".to_string()),
+            _ => None,
+        })
+        .expect("Valid soft limits");
+
+        assert_eq!(config.soft_limits["gpt-4o"], 60);
+        assert_eq!(config.soft_limits["gpt-4o-mini"], 600);
+    }
+
+    #[test]
+    fn test_invalid_soft_limits_from_env() {
+        let error = Config::from_lookup(|name| match name {
+            "KUBELLM_SOFT_LIMITS" => Some("gpt-4o=lots".to_string()),
+            _ => None,
+        })
+        .expect_err("Invalid soft limit");
+
+        assert_eq!(
+            error.to_string(),
+            "KUBELLM_SOFT_LIMITS: invalid value for model 'gpt-4o'"
+        );
+    }
+
+    #[test]
+    fn test_deprecated_models_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_DEPRECATED_MODELS" => Some("gpt-4=gpt-4o".to_string()),
+            _ => None,
+        })
+        .expect("Valid deprecated models");
+
+        assert_eq!(config.deprecated_models["gpt-4"], "gpt-4o");
+        assert!(config.warn_deprecated_models);
+    }
+
+    #[test]
+    fn test_prompt_templates_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_PROMPT_TEMPLATES" => Some(
+                r#"{"support": [{"role": "system", "content": "You help with {{product}}."}]}"#
+                    .to_string(),
+            ),
+            _ => None,
+        })
+        .expect("Valid prompt templates");
+
+        assert_eq!(config.prompt_templates["support"].len(), 1);
+    }
+
+    #[test]
+    fn test_retry_policy_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_MAX_RETRIES" => Some("2".to_string()),
+            "KUBELLM_RETRY_STATUSES" => Some("500, 529".to_string()),
+            "KUBELLM_RETRY_ERROR_CODES" => Some("server_error".to_string()),
+            "KUBELLM_RETRY_BASE_DELAY_MS" => Some("250".to_string()),
+            _ => None,
+        })
+        .expect("Valid retry policy");
+
+        let policy = config.retry_policy();
+        assert_eq!(policy.max_retries, 2);
+        assert!(policy.retryable_statuses.contains(&529));
+        assert!(!policy.retryable_statuses.contains(&503));
+        assert!(policy.retryable_codes.contains("server_error"));
+        assert_eq!(policy.base_delay, Duration::from_millis(250));
+        assert_eq!(Config::default().retry_policy().max_retries, 3);
+    }
+
+    #[test]
+    fn test_max_tokens_conflict_from_env() {
+        let config = Config::from_lookup(|name| {
+            (name == "KUBELLM_MAX_TOKENS_CONFLICT").then(|| "max_completion_tokens".to_string())
+        })
+        .expect("Valid max tokens conflict");
+        assert_eq!(
+            config.max_tokens_conflict,
+            MaxTokensConflict::MaxCompletionTokens
+        );
+
+        let error = Config::from_lookup(|name| {
+            (name == "KUBELLM_MAX_TOKENS_CONFLICT").then(|| "max_tokens".to_string())
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "KUBELLM_MAX_TOKENS_CONFLICT: invalid value 'max_tokens'"
+        );
+    }
+
+    #[test]
+    fn test_streaming_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_NON_STREAMING_MODELS" => Some("my-batch-model".to_string()),
+            "KUBELLM_UNSUPPORTED_STREAM" => Some("reject".to_string()),
+            _ => None,
+        })
+        .expect("Valid streaming settings");
+
+        assert_eq!(config.unsupported_stream, UnsupportedStream::Reject);
+        assert!(!config.capabilities().lookup("my-batch-model").streaming);
+        assert!(config.capabilities().lookup("gpt-4o").streaming);
+    }
+
+    #[test]
+    fn test_default_provider_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_DEFAULT_PROVIDER" => Some("openai".to_string()),
+            "KUBELLM_DEFAULT_MODEL" => Some("gpt-4o-mini".to_string()),
+            "KUBELLM_KEY_DEFAULT_MODELS" => Some("sha256:1a2b3c4d=gpt-4o".to_string()),
+            "KUBELLM_ROUTES" => Some("gpt-=openai,claude-=anthropic".to_string()),
+            _ => None,
+        })
+        .expect("Valid default route");
+        assert_eq!(config.key_default_models["sha256:1a2b3c4d"], "gpt-4o");
+        assert_eq!(config.routes["claude-"], "anthropic");
+        assert_eq!(config.default_provider.as_deref(), Some("openai"));
+        assert_eq!(config.default_model.as_deref(), Some("gpt-4o-mini"));
+
+        let error = Config::from_lookup(|name| {
+            (name == "KUBELLM_DEFAULT_PROVIDER").then(|| "acme".to_string())
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "KUBELLM_DEFAULT_PROVIDER: unknown provider 'acme'"
+        );
+        let error =
+            Config::from_lookup(|name| (name == "KUBELLM_ROUTES").then(|| "gpt-=acme".to_string()))
+                .unwrap_err();
+        assert_eq!(error.to_string(), "KUBELLM_ROUTES: unknown provider 'acme'");
+        let error =
+            Config::from_lookup(|name| (name == "KUBELLM_DEFAULT_MODEL").then(|| " ".to_string()))
+                .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "KUBELLM_DEFAULT_MODEL: must not be empty"
+        );
+    }
+
+    #[test]
+    fn test_config_file() {
+        let file = ConfigFile::parse(
+            r#"{
+                "listen": {"host": "0.0.0.0", "port": 8080},
+                "providers": {
+                    "openai": {"api_key": "sk-from-file", "base_url": "http://localhost:8000/v1"},
+                    "anthropic": {"api_key": "sk-ant-from-file"}
+                },
+                "routes": {"claude-": "anthropic", "gpt-": "openai"},
+                "timeouts": {"request_ms": 30000, "connect_secs": 5},
+                "KUBELLM_ECHO_MODELS": ["echo-1", "echo-2"]
+            }"#,
+        )
+        .expect("Valid config file");
+
+        let config = Config::from_file(&file, |_| None).expect("Valid settings");
+
+        assert_eq!(config.addr, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.openai_base_url, "http://localhost:8000/v1");
+        assert!(config.anthropic);
+        assert_eq!(config.routes["claude-"], "anthropic");
+        assert_eq!(config.echo_models, vec!["echo-1", "echo-2"]);
+        assert_eq!(config.timeout_ms, Some(30000));
+        assert_eq!(config.connect_timeout_secs, 5);
+        let credentials = config
+            .credentials_from(|name| file.setting(name))
+            .expect("Keys in the file");
+        assert_eq!(credentials["openai"], "sk-from-file");
+        assert_eq!(credentials["anthropic"], "sk-ant-from-file");
+        assert!(ConfigFile::parse("[]").is_err());
+        assert!(ConfigFile::parse(r#"{"KUBELLM_ECHO_MODELS": [null]}"#).is_err());
+        assert!(ConfigFile::parse(r#"{"listen": {"port": 70000}}"#).is_err());
+        assert!(ConfigFile::parse(r#"{"timeouts": {"request": 1}}"#).is_err());
+        assert!(ConfigFile::parse(r#"{"routes": {"gpt-": "acme"}}"#).is_err());
+        let error = ConfigFile::parse(r#"{"lisen": {"port": 8080}}"#).unwrap_err();
+        assert_eq!(error.to_string(), "unknown section 'lisen'");
+    }
+
+    #[test]
+    fn test_toml_config_file() {
+        let file = ConfigFile::parse_toml(
+            r#"
+                KUBELLM_ECHO_MODELS = ["echo-1", "echo-2"]
+
+                [listen]
+                port = 8080
+
+                [providers.bedrock]
+                region = "eu-west-1"
+
+                [routes]
+                "claude-" = "anthropic"
+
+                [timeouts]
+                stream_secs = 600
+
+                [KUBELLM_RATE_LIMITS]
+                "gpt-4o" = 100
+            "#,
+        )
+        .expect("Valid config file");
+
+        let config = Config::from_file(&file, |_| None).expect("Valid settings");
+
+        assert_eq!(config.addr.port(), 8080);
+        assert_eq!(config.bedrock_region.as_deref(), Some("eu-west-1"));
+        assert_eq!(config.routes["claude-"], "anthropic");
+        assert_eq!(config.echo_models, vec!["echo-1", "echo-2"]);
+        assert_eq!(config.max_stream_duration_secs, Some(600));
+        assert_eq!(config.rate_limits["gpt-4o"], 100);
+        assert!(ConfigFile::parse_toml("KUBELLM_ROUTES = ").is_err());
+    }
+
+    #[test]
+    fn test_yaml_config_file() {
+        let file = ConfigFile::parse_yaml(
+            r#"
+listen:
+  host: 0.0.0.0
+providers:
+  gemini:
+    api_key: gm-from-file
+routes:
+  gemini-: gemini
+KUBELLM_CACHE: true
+"#,
+        )
+        .expect("Valid config file");
+
+        let config = Config::from_file(&file, |_| None).expect("Valid settings");
+
+        assert_eq!(config.addr.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert!(config.gemini);
+        assert_eq!(config.routes["gemini-"], "gemini");
+        assert!(config.cache);
+        assert_eq!(file.setting("GEMINI_API_KEY").unwrap(), "gm-from-file");
+    }
+
+    #[test]
+    fn test_json_settings_in_config_file() {
+        let file = ConfigFile::parse_toml(
+            r#"
+                [[KUBELLM_CONTENT_FILTERS]]
+                pattern = "(?i)project falcon"
+
+                [[KUBELLM_CONTENT_FILTERS]]
+                pattern = '\d{3}-\d{2}-\d{4}'
+                replacement = "***"
+
+                [KUBELLM_PROMPT_TEMPLATES]
+                summarize = [{role = "system", content = "Summarize {{text}}"}]
+            "#,
+        )
+        .expect("Valid config file");
+
+        let config = Config::from_file(&file, |_| None).expect("Valid settings");
+
+        assert_eq!(config.content_filters.len(), 2);
+        assert_eq!(config.content_filters[0].pattern, "(?i)project falcon");
+        assert_eq!(config.content_filters[1].replacement, "***");
+        assert_eq!(config.prompt_templates["summarize"].len(), 1);
+    }
+
+    #[test]
+    fn test_environment_overrides_config_file() {
+        let file = ConfigFile::parse(
+            r#"{
+                "listen": {"port": 8080},
+                "providers": {"openai": {"api_key": "sk-from-file"}},
+                "KUBELLM_CACHE": true,
+                "KUBELLM_DEFAULT_MODEL": "gpt-4o"
+            }"#,
+        )
+        .unwrap();
+        let env = |name: &str| match name {
+            "KUBELLM_PORT" => Some("9090".to_string()),
+            "KUBELLM_CACHE" => Some("false".to_string()),
+            "OPENAI_API_KEY" => Some("sk-from-env".to_string()),
+            _ => None,
+        };
+
+        let config = Config::from_file(&file, env).unwrap();
+
+        assert_eq!(config.addr.port(), 9090);
+        assert!(!config.cache);
+        assert_eq!(config.default_model.as_deref(), Some("gpt-4o"));
+        let credentials = config
+            .credentials_from(|name| env(name).or_else(|| file.setting(name)))
+            .unwrap();
+        assert_eq!(credentials["openai"], "sk-from-env");
+    }
+
+    #[test]
+    fn test_config_file_format_follows_extension() {
+        let dir = std::env::temp_dir().join(format!("kubellm-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let toml = dir.join("kubellm.toml");
+        let yaml = dir.join("kubellm.yaml");
+        let json = dir.join("kubellm.json");
+        std::fs::write(&toml, "[timeouts]\nrequest_ms = 1000").unwrap();
+        std::fs::write(&yaml, "timeouts:\n  request_ms: 2000").unwrap();
+        std::fs::write(&json, r#"{"timeouts": {"request_ms": 3000}}"#).unwrap();
+        let load = |path: &std::path::Path| {
+            let args = ["kubellm", "--config", path.to_str().unwrap()].map(String::from);
+            ConfigFile::load(&args, |_| None).unwrap()
+        };
+
+        assert_eq!(load(&toml).timeouts.request_ms, Some(1000));
+        assert_eq!(load(&yaml).timeouts.request_ms, Some(2000));
+        assert_eq!(load(&json).timeouts.request_ms, Some(3000));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_addr_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_HOST" => Some("0.0.0.0".to_string()),
+            "KUBELLM_PORT" => Some("8080".to_string()),
+            _ => None,
+        })
+        .expect("Valid address");
+        assert_eq!(config.addr, "0.0.0.0:8080".parse().unwrap());
+
+        let config =
+            Config::from_lookup(|name| (name == "KUBELLM_HOST").then(|| "::".to_string())).unwrap();
+        assert_eq!(config.addr, "[::]:3000".parse().unwrap());
+
+        let error =
+            Config::from_lookup(|name| (name == "KUBELLM_HOST").then(|| "localhost".to_string()))
+                .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "KUBELLM_HOST: invalid value 'localhost', expected an IP address such as 0.0.0.0"
+        );
+        let error =
+            Config::from_lookup(|name| (name == "KUBELLM_PORT").then(|| "70000".to_string()))
+                .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("KUBELLM_PORT: invalid value '70000'"));
+    }
+
+    #[test]
+    fn test_config_file_path() {
+        let args = ["kubellm", "--config"].map(String::from);
+
+        let error = ConfigFile::load(&args, |_| None).unwrap_err();
+        assert_eq!(error.to_string(), "--config: missing path");
+        let file = ConfigFile::load(&args[..1], |_| None).unwrap();
+        assert!(file.settings.is_empty());
+        let error = ConfigFile::load(&args[..1], |name| {
+            (name == CONFIG_ENV).then(|| "/nonexistent/kubellm.json".to_string())
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("/nonexistent/kubellm.json"));
+    }
+
+    #[test]
+    fn test_body_transforms_from_env() {
+        let config = Config::from_lookup(|name| {
+            (name == "KUBELLM_BODY_TRANSFORMS")
+                .then(|| "openai=drop:user;rename:max_tokens:max_completion_tokens".to_string())
+        })
+        .expect("Valid body transforms");
+        assert!(config.body_transforms.contains_key("openai"));
+
+        let error = Config::from_lookup(|name| {
+            (name == "KUBELLM_BODY_TRANSFORMS").then(|| "anthropic=drop:user".to_string())
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "KUBELLM_BODY_TRANSFORMS: 'anthropic' is not an OpenAI compatible provider"
+        );
+    }
+
+    #[test]
+    fn test_offline_needs_no_openai_key() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_ECHO_MODELS" => Some("*".to_string()),
+            "KUBELLM_ECHO_REPLY" => Some("Canned".to_string()),
+            _ => None,
+        })
+        .expect("Valid echo settings");
+
+        assert_eq!(config.echo_models, vec!["*"]);
+        assert_eq!(config.echo_reply.as_deref(), Some("Canned"));
+        assert!(config.credentials_from(|_| None).is_ok());
+    }
+
+    #[test]
+    fn test_providers_need_api_keys() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_GEMINI" => Some("true".to_string()),
+            "KUBELLM_ANTHROPIC" => Some("true".to_string()),
+            "KUBELLM_BEDROCK_REGION" => Some("eu-west-1".to_string()),
+            "KUBELLM_POOL_DEPLOYMENTS" => {
+                Some("https://eu.example.com/v1, https://us.example.com/v1".to_string())
+            }
+            "KUBELLM_SESSION_TTL" => Some("60".to_string()),
+            _ => None,
+        })
+        .expect("Valid provider settings");
+
+        assert!(config.gemini);
+        assert_eq!(
+            config.pool_deployments,
+            vec!["https://eu.example.com/v1", "https://us.example.com/v1"]
+        );
+        assert_eq!(config.session_ttl_secs, 60);
+        assert_eq!(config.bedrock_region.as_deref(), Some("eu-west-1"));
+        let error = config
+            .credentials_from(|name| (name == "OPENAI_API_KEY").then(|| "sk-test".to_string()))
+            .unwrap_err();
+        assert!(error.to_string().contains("GEMINI_API_KEY"));
+        assert!(error.to_string().contains("ANTHROPIC_API_KEY"));
+    }
+
+    #[test]
+    fn test_shadow_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_SHADOW_BASE_URL" => Some("http://localhost:8000/v1".to_string()),
+            "KUBELLM_SHADOW_SAMPLE_RATE" => Some("0.5".to_string()),
+            _ => None,
+        })
+        .expect("Valid shadow settings");
+
+        assert_eq!(config.shadow_sample_rate, 0.5);
+        let shadow = config.providers.last().unwrap();
+        assert_eq!(shadow.name, "shadow");
+        assert_eq!(shadow.api_key_env, "SHADOW_API_KEY");
+    }
+
+    #[test]
+    fn test_client_from_env() {
+        let config = Config::from_lookup(|name| match name {
+            "KUBELLM_POOL_IDLE_TIMEOUT" => Some("10".to_string()),
+            "KUBELLM_MIN_TLS_VERSION" => Some("1.3".to_string()),
+            "KUBELLM_REDIRECTS" => Some("none".to_string()),
+            "KUBELLM_REQUEST_TIMEOUT" => Some("120".to_string()),
+            _ => None,
+        })
+        .expect("Valid client settings");
+
+        let client = config.client();
+        assert_eq!(client.idle_timeout, Duration::from_secs(10));
+        assert_eq!(client.max_idle_per_host, DEFAULT_MAX_IDLE_PER_HOST);
+        assert_eq!(client.min_tls_version, TlsVersion::Tls13);
+        assert_eq!(client.redirects, RedirectPolicy::None);
+        assert_eq!(client.request_timeout, Duration::from_secs(120));
+        assert_eq!(client.connect_timeout, DEFAULT_CONNECT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_redacted_config() {
+        let lookup = |name: &str| match name {
+            "KUBELLM_SOFT_LIMITS" => Some("gpt-4o=60".to_string()),
+            "OPENAI_API_KEY" => Some("sk-secret".to_string()),
+            "KUBELLM_ADMIN_TOKEN" => Some("admin-secret".to_string()),
+            _ => None,
+        };
+        let config = Config::from_lookup(lookup).expect("Valid config");
+
+        let redacted = config.redacted(lookup);
+
+        assert_eq!(redacted["soft_limits"]["gpt-4o"], 60);
+        assert_eq!(redacted["providers"][0]["name"], "openai");
+        assert_eq!(
+            redacted["providers"][0]["api_key"],
+            fingerprint("sk-secret").as_str()
+        );
+        assert_eq!(
+            redacted["admin_token"],
+            fingerprint("admin-secret").as_str()
+        );
+        assert!(!redacted.to_string().contains("sk-secret"));
+        assert!(!redacted.to_string().contains("admin-secret"));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint("sk-secret"), fingerprint("sk-secret"));
+        assert_ne!(fingerprint("sk-secret"), fingerprint("sk-other"));
+        assert!(fingerprint("sk-secret").starts_with("sha256:"));
+        assert_eq!(fingerprint("sk-secret").len(), "sha256:".len() + 8);
+    }
+
+    #[test]
+    fn test_credentials_resolved() {
+        let credentials = two_providers()
+            .credentials_from(|name| Some(format!("key-for-{}", name)))
+            .expect("All keys are present");
+
+        assert_eq!(credentials["openai"], "key-for-OPENAI_API_KEY");
+        assert_eq!(credentials["anthropic"], "key-for-ANTHROPIC_API_KEY");
+    }
+
+    #[test]
+    fn test_missing_credentials_are_listed() {
+        let error = two_providers()
+            .credentials_from(|name| match name {
+                "OPENAI_API_KEY" => Some("sk-test".to_string()),
+                "ANTHROPIC_API_KEY" => Some("  ".to_string()),
+                _ => None,
+            })
+            .expect_err("Anthropic key is blank");
+
+        assert_eq!(error.missing.len(), 1);
+        assert_eq!(
+            error.to_string(),
+            "Missing credentials for configured providers:\n  \
+             - anthropic: environment variable ANTHROPIC_API_KEY is not set"
+        );
+    }
+
+    #[test]
+    fn test_all_missing_credentials_are_aggregated() {
+        let error = two_providers()
+            .credentials_from(|_| None)
+            .expect_err("No keys are present");
+
+        let message = error.to_string();
+        assert!(message.contains("OPENAI_API_KEY"));
+        assert!(message.contains("ANTHROPIC_API_KEY"));
+    }
+}
@@ -0,0 +1,196 @@
+use futures_util::stream::{self, Stream, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Stream deduplication
+//
+// Identical deterministic streaming requests that arrive while one of them is
+// still waiting for its first chunk share that request's upstream stream. The
+// first subscriber starts the upstream, later ones join through a broadcast
+// channel. Once the first chunk is sent the stream is closed to new
+// subscribers, since they would have missed it, and a new request starts its
+// own upstream.
+
+pub const DEFAULT_DEDUPE_CAPACITY: usize = 256;
+
+// A subscriber fell more than the channel capacity behind and lost chunks.
+// Its stream ends with this error instead of holding up the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl std::fmt::Display for Lagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Subscriber lagged behind by {} chunks", self.0)
+    }
+}
+
+impl std::error::Error for Lagged {}
+
+pub struct StreamDedupe<T> {
+    capacity: usize,
+    pending: Arc<Mutex<HashMap<String, broadcast::Sender<T>>>>,
+}
+
+impl<T: Clone + Send + 'static> Default for StreamDedupe<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEDUPE_CAPACITY)
+    }
+}
+
+impl<T: Clone + Send + 'static> StreamDedupe<T> {
+    // `capacity` is how many chunks a subscriber may fall behind
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Joins the pending stream for `key`, or starts one with `upstream`
+    pub fn subscribe<F, S>(&self, key: String, upstream: F) -> impl Stream<Item = Result<T, Lagged>>
+    where
+        F: Future<Output = S> + Send + 'static,
+        S: Stream<Item = T> + Send + 'static,
+    {
+        let mut pending = self.pending.lock().unwrap();
+        let receiver = match pending.get(&key) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(self.capacity);
+                pending.insert(key.clone(), sender.clone());
+                tokio::spawn(forward(key, upstream, sender, self.pending.clone()));
+                receiver
+            }
+        };
+        stream::unfold(Some(receiver), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.recv().await {
+                Ok(item) => Some((Ok(item), Some(receiver))),
+                Err(RecvError::Closed) => None,
+                // Ends the stream after the error
+                Err(RecvError::Lagged(missed)) => Some((Err(Lagged(missed)), None)),
+            }
+        })
+    }
+
+    // Streams still open to new subscribers
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+async fn forward<T, F, S>(
+    key: String,
+    upstream: F,
+    sender: broadcast::Sender<T>,
+    pending: Arc<Mutex<HashMap<String, broadcast::Sender<T>>>>,
+) where
+    T: Clone,
+    F: Future<Output = S>,
+    S: Stream<Item = T>,
+{
+    let upstream = upstream.await;
+    futures_util::pin_mut!(upstream);
+    let mut open = true;
+    while let Some(item) = upstream.next().await {
+        if open {
+            close(&key, &sender, &pending);
+            open = false;
+        }
+        // Fails only when every subscriber is gone
+        if sender.send(item).is_err() {
+            break;
+        }
+    }
+    if open {
+        close(&key, &sender, &pending);
+    }
+}
+
+// Stops new subscribers from joining the stream of `sender`
+fn close<T>(
+    key: &str,
+    sender: &broadcast::Sender<T>,
+    pending: &Mutex<HashMap<String, broadcast::Sender<T>>>,
+) {
+    let mut pending = pending.lock().unwrap();
+    if pending
+        .get(key)
+        .is_some_and(|current| current.same_channel(sender))
+    {
+        pending.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    // An upstream that waits for `go` before streaming `items`
+    async fn upstream(
+        calls: Arc<AtomicUsize>,
+        go: oneshot::Receiver<()>,
+        items: Vec<u32>,
+    ) -> impl Stream<Item = u32> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        go.await.unwrap();
+        stream::iter(items)
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_share_one_upstream() {
+        let dedupe = StreamDedupe::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (go, wait) = oneshot::channel();
+        let (_, unused) = oneshot::channel();
+
+        let first = dedupe.subscribe(
+            "a".to_string(),
+            upstream(calls.clone(), wait, vec![1, 2, 3]),
+        );
+        let second = dedupe.subscribe("a".to_string(), upstream(calls.clone(), unused, vec![9]));
+        go.send(()).unwrap();
+
+        let (first, second) = tokio::join!(first.collect::<Vec<_>>(), second.collect::<Vec<_>>());
+        assert_eq!(first, vec![Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(second, vec![Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(dedupe.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_different_keys_do_not_share() {
+        let dedupe = StreamDedupe::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (go_a, wait_a) = oneshot::channel();
+        let (go_b, wait_b) = oneshot::channel();
+
+        let a = dedupe.subscribe("a".to_string(), upstream(calls.clone(), wait_a, vec![1]));
+        let b = dedupe.subscribe("b".to_string(), upstream(calls.clone(), wait_b, vec![2]));
+        go_a.send(()).unwrap();
+        go_b.send(()).unwrap();
+
+        assert_eq!(a.collect::<Vec<_>>().await, vec![Ok(1)]);
+        assert_eq!(b.collect::<Vec<_>>().await, vec![Ok(2)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_cut_off() {
+        let dedupe = StreamDedupe::new(2);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (go, wait) = oneshot::channel();
+
+        let slow = dedupe.subscribe("a".to_string(), upstream(calls, wait, (1..=5).collect()));
+        go.send(()).unwrap();
+        // Lets the upstream send everything before anything is read
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(slow.collect::<Vec<_>>().await, vec![Err(Lagged(3))]);
+    }
+}
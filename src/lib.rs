@@ -1 +1,24 @@
+pub mod cache;
+pub mod capabilities;
+pub mod client;
+pub mod config;
+pub mod dedupe;
+pub mod logging;
+pub mod metrics;
 pub mod models;
+pub mod pool;
+pub mod preprocess;
+pub mod pricing;
+pub mod rate_limit;
+pub mod retry;
+pub mod router;
+pub mod server;
+pub mod shadow;
+pub mod sigv4;
+pub mod status;
+pub mod streaming;
+pub mod transform;
+pub mod validation;
+
+#[cfg(test)]
+mod mock;
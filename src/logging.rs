@@ -0,0 +1,173 @@
+// Per-request log levels
+//
+// Logging is filtered by `RUST_LOG` as a whole. To debug a single client
+// without raising the level for everyone, the chat completion span of its
+// requests records a `log_level`, and events inside a span with a log level
+// are logged when they are at that level or above, whatever `RUST_LOG` says.
+use anyhow::anyhow;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::filter::{DynFilterFn, EnvFilter, FilterExt};
+use tracing_subscriber::layer::{Context, Filter, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+// Span field holding the level of the request's logs
+pub const LOG_LEVEL_FIELD: &str = "log_level";
+
+// Levels a request can be raised to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(anyhow!("expected debug or trace")),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// The level recorded on a span
+struct SpanLevel(LevelFilter);
+
+// Keeps the `log_level` of spans, for `filter` to find
+pub struct SpanLevels;
+
+impl<S> Layer<S> for SpanLevels
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = LevelVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(level), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(SpanLevel(level));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = LevelVisitor(None);
+        values.record(&mut visitor);
+        if let (Some(level), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().replace(SpanLevel(level));
+        }
+    }
+}
+
+struct LevelVisitor(Option<LevelFilter>);
+
+impl Visit for LevelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == LOG_LEVEL_FIELD {
+            self.0 = value.parse().ok();
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+// Lets through what `base` does, and events inside spans whose log level
+// they meet. Needs `SpanLevels` on the same subscriber.
+pub fn filter<S>(base: EnvFilter) -> impl Filter<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    base.or(DynFilterFn::new(|metadata, cx: &Context<'_, S>| {
+        cx.lookup_current().is_some_and(|span| {
+            span.scope().any(|span| {
+                span.extensions()
+                    .get::<SpanLevel>()
+                    .is_some_and(|SpanLevel(level)| metadata.level() <= level)
+            })
+        })
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    pub(crate) struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Logs {
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Subscriber logging at info, and per-request levels, into `Logs`
+    pub(crate) fn capture() -> (impl Subscriber + Send + Sync, Logs) {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let fmt = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .with_filter(filter(EnvFilter::new("info")));
+        let subscriber = tracing_subscriber::registry().with(SpanLevels).with(fmt);
+        (subscriber, logs)
+    }
+
+    #[test]
+    fn test_span_log_level_raises_verbosity() {
+        let (subscriber, logs) = capture();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("chat_completion", log_level = tracing::field::Empty);
+            span.record(LOG_LEVEL_FIELD, LogLevel::Debug.as_str());
+            span.in_scope(|| {
+                tracing::debug!("debug inside");
+                tracing::trace!("trace inside");
+            });
+            tracing::debug!("debug outside");
+            tracing::info!("info outside");
+        });
+
+        let logs = logs.contents();
+        assert!(logs.contains("debug inside"));
+        assert!(!logs.contains("trace inside"));
+        assert!(!logs.contains("debug outside"));
+        assert!(logs.contains("info outside"));
+    }
+}
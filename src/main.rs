@@ -1,47 +1,281 @@
-use anyhow::{Error, Result};
-use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
-use kubellm::models::openai::{self, OpenAIChatCompletionRequest, OpenAIClient};
-use reqwest::StatusCode;
-use std::net::SocketAddr;
+use anyhow::{anyhow, Error, Result};
+use kubellm::cache::{InMemoryCache, ResponseCache};
+use kubellm::config::{Config, ConfigFile, SHADOW_PROVIDER};
+use kubellm::dedupe::StreamDedupe;
+use kubellm::logging::{self, SpanLevels};
+use kubellm::models::anthropic::{AnthropicClient, ANTHROPIC_PROVIDER};
+use kubellm::models::bedrock::{BedrockClient, BEDROCK_SERVICE};
+use kubellm::models::echo::{EchoProvider, ECHO_PROVIDER};
+use kubellm::models::gemini::{GeminiClient, GEMINI_PROVIDER};
+use kubellm::models::openai::OpenAIClient;
+use kubellm::models::provider::{Provider, OPENAI_PROVIDER};
+use kubellm::pool::{DeploymentPool, POOL_PROVIDER, SESSION_PRUNE_INTERVAL};
+use kubellm::preprocess::{DeprecatedModels, PromptTemplates};
+use kubellm::pricing::{CostRouting, PricingTable};
+use kubellm::rate_limit::{ProviderConcurrency, RateLimiter, SoftLimiter, StreamLimiter};
+use kubellm::router::ModelRouter;
+use kubellm::server::{self, AppState, Readiness};
+use kubellm::shadow::Shadow;
+use kubellm::streaming::ChunkNormalizer;
+use kubellm::transform::{ContentFilter, MetadataEnricher, ResponseTransform};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-
-#[derive(Clone)]
-pub struct AppState {
-    client: OpenAIClient,
-}
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    // Get API key from environment variable
-    let api_key =
-        std::env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set in environment");
+    // Log level and filters come from `RUST_LOG`, e.g. `kubellm=debug`, and
+    // may be raised per request, see `logging`
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(SpanLevels)
+        .with(tracing_subscriber::fmt::layer().with_filter(logging::filter(env_filter)))
+        .init();
+    let args: Vec<String> = std::env::args().collect();
+    let env = |name: &str| std::env::var(name).ok();
+    let file = ConfigFile::load(&args, env)?;
+    let config = Config::from_file(&file, env)?;
+    // Environment variables take precedence over the file
+    let lookup = |name: &str| env(name).or_else(|| file.setting(name));
+    if args.iter().any(|arg| arg == "--print-config") {
+        let redacted = config.redacted(lookup);
+        println!("{}", serde_json::to_string_pretty(&redacted)?);
+        return Ok(());
+    }
+
+    // Check that every configured provider has an API key before binding
+    let mut credentials = match config.credentials_from(lookup) {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let rate_limiter = Arc::new(
+        RateLimiter::new(config.rate_limits.clone())
+            .with_adaptive(config.adaptive_rate_limits.clone()),
+    );
+    let mut client = OpenAIClient::with_base_url(String::new(), &config.openai_base_url);
+    // Several OpenAI keys are separated by commas and used in turn
+    if let Some(api_keys) = credentials.remove("openai") {
+        let api_keys = api_keys
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(String::from)
+            .collect();
+        client = client
+            .with_api_keys(api_keys)
+            .map_err(|err| anyhow!("OPENAI_API_KEY: {}", err))?;
+    }
+    let mut client = client
+        .with_rate_limiter(rate_limiter.clone())
+        .with_max_response_bytes(config.max_response_bytes)
+        .with_retry_policy(config.retry_policy())
+        .with_api_versions(config.api_versions())
+        .with_client_config(config.client())?;
+    if let Some(hint) = &config.deadline_hint {
+        client = client.with_deadline_hint(hint.clone());
+    }
+    if let Some(transform) = config.body_transforms.get(OPENAI_PROVIDER) {
+        client = client.with_body_transform(transform.clone());
+    }
+    let shadow = match &config.shadow_base_url {
+        Some(base_url) => {
+            let mut client =
+                OpenAIClient::new(credentials.remove(SHADOW_PROVIDER).unwrap_or_default())
+                    .with_client_config(config.client())?;
+            if let Some(transform) = config.body_transforms.get(SHADOW_PROVIDER) {
+                client = client.with_body_transform(transform.clone());
+            }
+            let mut shadow = Shadow::new(client, base_url, config.shadow_sample_rate);
+            if let Some(model) = &config.shadow_model {
+                shadow = shadow.with_model(model);
+            }
+            Some(Arc::new(shadow))
+        }
+        None => None,
+    };
+    // Enabled providers by name, each with the prefixes it serves by default
+    let mut providers: HashMap<&str, (Arc<dyn Provider>, Vec<&str>)> = HashMap::new();
+    providers.insert(OPENAI_PROVIDER, (Arc::new(client.clone()), Vec::new()));
+    if !config.echo_models.is_empty() {
+        let mut echo = EchoProvider::new(config.echo_models.clone());
+        if let Some(reply) = &config.echo_reply {
+            echo = echo.with_reply(reply);
+        }
+        // Serves only the configured models, ahead of the catch-all
+        providers.insert(ECHO_PROVIDER, (Arc::new(echo), vec![""]));
+    }
+    if let Some(region) = &config.bedrock_region {
+        let bedrock = BedrockClient::from_env(region)?
+            .with_max_response_bytes(config.max_response_bytes)
+            .with_client_config(config.client())?;
+        let prefixes = vec!["anthropic.", "amazon.titan-text"];
+        providers.insert(BEDROCK_SERVICE, (Arc::new(bedrock), prefixes));
+    }
+    if config.anthropic {
+        let anthropic =
+            AnthropicClient::new(credentials.remove(ANTHROPIC_PROVIDER).unwrap_or_default())
+                .with_max_response_bytes(config.max_response_bytes)
+                .with_client_config(config.client())?;
+        providers.insert(ANTHROPIC_PROVIDER, (Arc::new(anthropic), vec!["claude-"]));
+    }
+    if config.gemini {
+        let gemini = GeminiClient::new(credentials.remove(GEMINI_PROVIDER).unwrap_or_default())
+            .with_max_response_bytes(config.max_response_bytes)
+            .with_client_config(config.client())?;
+        providers.insert(GEMINI_PROVIDER, (Arc::new(gemini), vec!["gemini-"]));
+    }
+    if !config.pool_deployments.is_empty() {
+        let mut deployments: Vec<Arc<dyn Provider>> = Vec::new();
+        for base_url in &config.pool_deployments {
+            deployments.push(Arc::new(client.deployment(base_url)));
+        }
+        let pool = Arc::new(
+            DeploymentPool::new(deployments)
+                .with_session_ttl(Duration::from_secs(config.session_ttl_secs)),
+        );
+        tokio::spawn(pool.clone().prune_sessions_every(SESSION_PRUNE_INTERVAL));
+        providers.insert(POOL_PROVIDER, (pool, Vec::new()));
+    }
+    let mut router = ModelRouter::default();
+    for (provider, prefixes) in providers.values() {
+        for prefix in prefixes {
+            router.register(*prefix, provider.clone());
+        }
+    }
+    let enabled = |name: &str| {
+        providers
+            .get(name)
+            .map(|(provider, _)| provider.clone())
+            .ok_or_else(|| anyhow!("Provider {} is routed to but not enabled", name))
+    };
+    for (prefix, name) in &config.routes {
+        router.register(prefix, enabled(name)?);
+    }
+    if config.routes.is_empty() || config.default_provider.is_some() {
+        let name = config
+            .default_provider
+            .as_deref()
+            .unwrap_or(OPENAI_PROVIDER);
+        router.register("", enabled(name)?);
+    }
+    let mut response_transforms: Vec<Box<dyn ResponseTransform>> = Vec::new();
+    if !config.response_metadata.is_empty() {
+        response_transforms.push(Box::new(MetadataEnricher::new(
+            config.response_metadata.clone(),
+        )));
+    }
+    let content_filter = if config.content_filters.is_empty() {
+        None
+    } else {
+        Some(Arc::new(ContentFilter::new(&config.content_filters)?))
+    };
+    let cache: Option<Arc<dyn ResponseCache>> = if config.cache {
+        let cache = InMemoryCache::new()
+            .with_capacity(config.cache_capacity)
+            .with_ttl(Duration::from_secs(config.cache_ttl_secs))
+            .with_model_ttls(
+                config
+                    .cache_model_ttls
+                    .iter()
+                    .map(|(model, secs)| (model.clone(), Duration::from_secs(*secs)))
+                    .collect(),
+            );
+        Some(Arc::new(cache))
+    } else {
+        None
+    };
+    if config.dev_mode {
+        tracing::warn!("Dev mode is enabled, do not use this in production");
+    }
+    let warmup = config.warmup.then(|| {
+        let mut warmup: Vec<(String, Arc<dyn Provider>)> = providers
+            .iter()
+            .map(|(name, (provider, _))| (name.to_string(), provider.clone()))
+            .collect();
+        if let Some(shadow) = &shadow {
+            warmup.push((SHADOW_PROVIDER.to_string(), Arc::new(shadow.upstream())));
+        }
+        warmup.sort_by(|a, b| a.0.cmp(&b.0));
+        warmup
+    });
     let state = AppState {
-        client: openai::OpenAIClient::new(api_key),
+        soft_limiter: Arc::new(SoftLimiter::new(config.soft_limits.clone())),
+        rate_limiter,
+        deprecated_models: Arc::new(
+            DeprecatedModels::new(config.deprecated_models.clone())
+                .with_warnings(config.warn_deprecated_models),
+        ),
+        prompt_templates: Arc::new(PromptTemplates::new(config.prompt_templates.clone())),
+        default_model: config.default_model.clone(),
+        key_default_models: Arc::new(config.key_default_models.clone()),
+        healthcheck_model: config.healthcheck_model.clone(),
+        key_log_levels: Arc::new(config.key_log_levels.clone()),
+        cost_routing: (!config.cheapest_candidates.is_empty()).then(|| {
+            Arc::new(CostRouting::new(
+                config.cheapest_candidates.clone(),
+                PricingTable::default(),
+            ))
+        }),
+        fallback_models: Arc::new(config.fallback_models.clone()),
+        refusal_models: Arc::new(config.refusal_models.clone()),
+        capabilities: Arc::new(config.capabilities()),
+        unsupported_stream: config.unsupported_stream,
+        max_stream_duration: config.max_stream_duration_secs.map(Duration::from_secs),
+        stream_limiter: config
+            .max_streams_per_key
+            .map(|max| Arc::new(StreamLimiter::new(max))),
+        timeout: config.timeout_ms.map(Duration::from_millis),
+        max_message_bytes: config.max_message_bytes,
+        tool_limits: config.tool_limits,
+        max_tokens_conflict: config.max_tokens_conflict,
+        max_body_bytes: config.max_body_bytes,
+        max_fanout: config.max_fanout,
+        auto_prompt_cache_key: config.auto_prompt_cache_key,
+        concurrency: config
+            .max_concurrency
+            .map(|permits| Arc::new(ProviderConcurrency::new(permits))),
+        queue_depth_header: config.queue_depth_header,
+        chunk_normalizer: Arc::new(ChunkNormalizer::new(config.strip_stream_fields.clone())),
+        response_transforms: Arc::new(response_transforms),
+        content_filter,
+        cache,
+        router: Arc::new(router),
+        stream_dedupe: config
+            .stream_dedupe
+            .then(|| Arc::new(StreamDedupe::default())),
+        stream_fallback: config.stream_fallback,
+        shadow,
+        completion_retrieval: config.completion_retrieval,
+        admin_token: config.admin_token.clone(),
+        allow_anonymous: config.allow_anonymous,
+        trusted_provider_keys: config.trusted_provider_keys,
+        readiness: config
+            .readiness_check
+            .then(|| Arc::new(Readiness::new(client.clone()))),
+        dev_mode: config.dev_mode,
+        base_url_allowlist: Arc::new(config.base_url_allowlist.clone()),
+        ..AppState::new(client)
     };
 
     // Build router
-    let app = Router::new()
-        .route("/v1/chat/completions", post(chat_handler))
-        .with_state(state);
+    let app = server::router(state);
 
     // Run server
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let addr = config.addr;
     let listener = TcpListener::bind(addr).await?;
 
-    println!("Listening on {}", addr);
+    tracing::info!(%addr, "Listening");
+    if let Some(providers) = warmup {
+        tokio::spawn(server::warmup(providers));
+    }
     axum::serve(listener, app).await?;
 
     Ok(())
 }
-
-async fn chat_handler(
-    State(state): State<AppState>,
-    Json(request): Json<OpenAIChatCompletionRequest>,
-) -> impl IntoResponse {
-    println!("Received request");
-    let response = state.client.chat(request).await.unwrap();
-    println!("Prompt tokens:     {}", response.usage.prompt_tokens);
-    println!("Completion tokens: {}", response.usage.completion_tokens);
-    println!("Total tokens:      {}", response.usage.total_tokens);
-    (StatusCode::OK, Json(response))
-}
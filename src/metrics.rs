@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Prometheus metrics, rendered in the text exposition format
+
+pub const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let _ = writeln!(out, "{}_sum {}", name, self.sum().as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+pub fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Renders one sample per label value
+pub fn render_gauge(out: &mut String, name: &str, help: &str, label: &str, values: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (value_label, value) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value_label, value);
+    }
+}
+
+// Counter with one sample per label value, e.g. per model
+#[derive(Debug, Default)]
+pub struct LabeledCounter {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    pub fn inc(&self, label_value: &str) {
+        let mut values = self.values.lock().unwrap();
+        *values.entry(label_value.to_string()).or_default() += 1;
+    }
+
+    pub fn get(&self, label_value: &str) -> u64 {
+        let values = self.values.lock().unwrap();
+        values.get(label_value).copied().unwrap_or(0)
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (value_label, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value_label, value);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub requests: AtomicU64,
+    pub model_requests: LabeledCounter,
+    // Error responses by HTTP status
+    pub errors: LabeledCounter,
+    // Time until the upstream answered, or sent its first chunk for streams
+    pub upstream_latency: Histogram,
+    pub stream_first_token: Histogram,
+    pub stream_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            model_requests: LabeledCounter::default(),
+            errors: LabeledCounter::default(),
+            upstream_latency: Histogram::new(LATENCY_BUCKETS),
+            stream_first_token: Histogram::new(LATENCY_BUCKETS),
+            stream_duration: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    // Counts a chat completion request by the status it was answered with
+    pub fn response(&self, status: u16) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status >= 400 {
+            self.errors.inc(&status.to_string());
+        }
+    }
+
+    pub fn render(&self, out: &mut String) {
+        render_counter(
+            out,
+            "kubellm_requests_total",
+            "Chat completion requests",
+            self.requests.load(Ordering::Relaxed),
+        );
+        self.model_requests.render(
+            out,
+            "kubellm_model_requests_total",
+            "Chat completion requests per model, after aliases and defaults",
+            "model",
+        );
+        self.errors.render(
+            out,
+            "kubellm_errors_total",
+            "Chat completion requests answered with an error, per HTTP status",
+            "status",
+        );
+        self.upstream_latency.render(
+            out,
+            "kubellm_upstream_latency_seconds",
+            "Time until the upstream answered, or sent the first chunk of a stream",
+        );
+        self.stream_first_token.render(
+            out,
+            "kubellm_stream_first_token_seconds",
+            "Time from request receipt to the first non-empty streamed delta",
+        );
+        self.stream_duration.render(
+            out,
+            "kubellm_stream_duration_seconds",
+            "Total duration of streamed responses",
+        );
+    }
+}
+
+// Tracks time to first token and total duration of a single streamed response.
+// The first token is observed as soon as it's seen, the duration when the
+// stream finishes or, for clients that disconnect, when it's dropped.
+pub struct StreamTimer {
+    started: Instant,
+    first_token: Option<Duration>,
+    model: String,
+    metrics: Arc<Metrics>,
+    finished: bool,
+}
+
+impl StreamTimer {
+    // `started` is when the request was received, not when the upstream replied
+    pub fn new(started: Instant, model: impl Into<String>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            started,
+            first_token: None,
+            model: model.into(),
+            metrics,
+            finished: false,
+        }
+    }
+
+    // Call for every forwarded chunk, only the first non-empty one counts
+    pub fn chunk(&mut self, non_empty: bool) {
+        if non_empty && self.first_token.is_none() {
+            let first_token = self.started.elapsed();
+            self.first_token = Some(first_token);
+            self.metrics.stream_first_token.observe(first_token);
+            tracing::info!(
+                model = %self.model,
+                first_token_ms = first_token.as_millis() as u64,
+                "First token"
+            );
+        }
+    }
+
+    pub fn first_token(&self) -> Option<Duration> {
+        self.first_token
+    }
+
+    pub fn finish(mut self) -> Duration {
+        self.finished = true;
+        self.observe("Stream finished")
+    }
+
+    fn observe(&self, message: &str) -> Duration {
+        let duration = self.started.elapsed();
+        self.metrics.stream_duration.observe(duration);
+        tracing::info!(
+            model = %self.model,
+            first_token_ms = self.first_token.map(|ttft| ttft.as_millis() as u64),
+            duration_ms = duration.as_millis() as u64,
+            "{}",
+            message
+        );
+        duration
+    }
+}
+
+impl Drop for StreamTimer {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.observe("Stream closed before it finished");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_render() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "Test histogram");
+
+        assert!(out.contains("# TYPE test_seconds histogram"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.1\"} 1"));
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 2"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(out.contains("test_seconds_sum 0.55"));
+        assert!(out.contains("test_seconds_count 2"));
+    }
+
+    #[test]
+    fn test_request_counters_render() {
+        let metrics = Metrics::default();
+        metrics.model_requests.inc("gpt-4o");
+        metrics.model_requests.inc("gpt-4o");
+        metrics.response(200);
+        metrics.response(502);
+
+        let mut out = String::new();
+        metrics.render(&mut out);
+
+        assert!(out.contains("kubellm_requests_total 2"));
+        assert!(out.contains("kubellm_model_requests_total{model=\"gpt-4o\"} 2"));
+        assert!(out.contains("# TYPE kubellm_errors_total counter"));
+        assert!(out.contains("kubellm_errors_total{status=\"502\"} 1"));
+        assert!(!out.contains("kubellm_errors_total{status=\"200\"}"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_timer_records_first_token_separately() {
+        let metrics = Arc::new(Metrics::default());
+        let mut timer = StreamTimer::new(Instant::now(), "gpt-4o", metrics.clone());
+
+        // Role-only first chunk, then a delayed first token and a slow tail
+        timer.chunk(false);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        timer.chunk(true);
+        let first_token = timer.first_token().expect("First token recorded");
+        assert_eq!(metrics.stream_first_token.count(), 1);
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        timer.chunk(true);
+        let duration = timer.finish();
+
+        assert!(first_token >= Duration::from_millis(20));
+        assert!(duration >= first_token + Duration::from_millis(40));
+        assert_eq!(metrics.stream_first_token.count(), 1);
+        assert_eq!(metrics.stream_duration.count(), 1);
+        assert_eq!(
+            metrics.stream_first_token.sum().as_millis(),
+            first_token.as_millis()
+        );
+    }
+
+    #[test]
+    fn test_stream_timer_without_tokens() {
+        let metrics = Arc::new(Metrics::default());
+        let mut timer = StreamTimer::new(Instant::now(), "gpt-4o", metrics.clone());
+        timer.chunk(false);
+
+        timer.finish();
+
+        assert_eq!(metrics.stream_first_token.count(), 0);
+        assert_eq!(metrics.stream_duration.count(), 1);
+    }
+
+    #[test]
+    fn test_dropped_stream_is_observed() {
+        let (subscriber, logs) = crate::logging::tests::capture();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let metrics = Arc::new(Metrics::default());
+        let mut timer = StreamTimer::new(Instant::now(), "gpt-4o", metrics.clone());
+        timer.chunk(true);
+
+        drop(timer);
+
+        assert_eq!(metrics.stream_first_token.count(), 1);
+        assert_eq!(metrics.stream_duration.count(), 1);
+        let logs = logs.contents();
+        assert!(logs.contains("INFO"));
+        assert!(logs.contains("First token"));
+        assert!(logs.contains("Stream closed before it finished"));
+    }
+}
@@ -0,0 +1,217 @@
+// Mock upstreams for tests
+use crate::models::openai::OpenAIChatCompletionResponse;
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::sse::{Event, Sse},
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::stream::{self, StreamExt};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+pub(crate) fn completion_json(model: &str, content: &str) -> Value {
+    json!({
+        "id": "chatcmpl-123",
+        "object": "chat.completion",
+        "created": 1728933352,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": content},
+            "logprobs": null,
+            "finish_reason": "stop"
+        }],
+        "usage": {
+            "prompt_tokens": 1,
+            "completion_tokens": 1,
+            "total_tokens": 2,
+            "prompt_tokens_details": {},
+            "completion_tokens_details": {}
+        },
+        "system_fingerprint": "fp_123"
+    })
+}
+
+pub(crate) fn completion(model: &str, content: &str) -> OpenAIChatCompletionResponse {
+    serde_json::from_value(completion_json(model, content)).expect("Failed to parse response")
+}
+
+// Serves `app` on a random local port and returns its base URL
+pub(crate) async fn spawn(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}/v1", addr)
+}
+
+// An OpenAI compatible upstream answering chat completions with `respond`,
+// counting the calls it receives.
+pub(crate) async fn upstream<F>(respond: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(Value) -> (StatusCode, Value) + Clone + Send + Sync + 'static,
+{
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let app = Router::new().route(
+        "/v1/chat/completions",
+        post(move |Json(request): Json<Value>| {
+            let counter = counter.clone();
+            let respond = respond.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = respond(request);
+                (status, Json(body))
+            }
+        }),
+    );
+    (spawn(app).await, calls)
+}
+
+// Answers every chat completion with `content`
+pub(crate) async fn openai(content: &'static str) -> (String, Arc<AtomicUsize>) {
+    upstream(move |request| {
+        let model = request["model"].as_str().unwrap_or_default();
+        (StatusCode::OK, completion_json(model, content))
+    })
+    .await
+}
+
+// Answers every POST, whatever the provider's path, with a chat completion
+// with `content` after `delay`
+pub(crate) async fn slow(content: &'static str, delay: Duration) -> String {
+    let app = Router::new().route(
+        "/{*path}",
+        post(move |Json(request): Json<Value>| async move {
+            tokio::time::sleep(delay).await;
+            let model = request["model"].as_str().unwrap_or_default();
+            Json(completion_json(model, content))
+        }),
+    );
+    spawn(app).await
+}
+
+pub(crate) fn chunk_json(content: &str, finish_reason: Option<&str>) -> Value {
+    json!({
+        "id": "chatcmpl-123",
+        "object": "chat.completion.chunk",
+        "created": 1728933352,
+        "model": "gpt-4o",
+        "choices": [{
+            "index": 0,
+            "delta": {"content": content},
+            "finish_reason": finish_reason
+        }]
+    })
+}
+
+// Streams `chunks` as server-sent events followed by `[DONE]`, waiting `delay`
+// before each event, counting the calls it receives
+pub(crate) async fn sse(chunks: Vec<Value>, delay: Duration) -> (String, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let app = Router::new().route(
+        "/v1/chat/completions",
+        post(move || {
+            let counter = counter.clone();
+            let chunks = chunks.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let data = chunks
+                    .into_iter()
+                    .map(|chunk| chunk.to_string())
+                    .chain(std::iter::once("[DONE]".to_string()));
+                let events = stream::iter(data).then(move |data| async move {
+                    tokio::time::sleep(delay).await;
+                    Ok::<_, Infallible>(Event::default().data(data))
+                });
+                Sse::new(events)
+            }
+        }),
+    );
+    (spawn(app).await, calls)
+}
+
+// Stores every chat completion under its id and serves it back on
+// `GET /v1/chat/completions/{id}`, like OpenAI does for `store: true`
+pub(crate) async fn stored(content: &'static str) -> String {
+    let completion = completion_json("gpt-4o", content);
+    let stored = completion.clone();
+    let app = Router::new()
+        .route(
+            "/v1/chat/completions",
+            post(move || {
+                let completion = completion.clone();
+                async move { Json(completion) }
+            }),
+        )
+        .route(
+            "/v1/chat/completions/{id}",
+            get(move |Path(id): Path<String>| {
+                let stored = stored.clone();
+                async move {
+                    if id == stored["id"] {
+                        (StatusCode::OK, Json(stored))
+                    } else {
+                        let error = json!({"error": {
+                            "message": format!("No completion found with id '{}'", id),
+                            "type": "invalid_request_error"
+                        }});
+                        (StatusCode::NOT_FOUND, Json(error))
+                    }
+                }
+            }),
+        );
+    spawn(app).await
+}
+
+// Answers embeddings requests with a vector of the input's length for each
+// input
+pub(crate) async fn embeddings() -> String {
+    let app = Router::new().route(
+        "/v1/embeddings",
+        post(|Json(request): Json<Value>| async move {
+            let inputs = match &request["input"] {
+                Value::Array(inputs) => inputs.clone(),
+                input => vec![input.clone()],
+            };
+            let data: Vec<_> = inputs
+                .iter()
+                .enumerate()
+                .map(|(index, input)| {
+                    let length = input.as_str().unwrap_or_default().len();
+                    json!({"object": "embedding", "embedding": [length as f64], "index": index})
+                })
+                .collect();
+            Json(json!({
+                "object": "list",
+                "data": data,
+                "model": request["model"],
+                "usage": {"prompt_tokens": inputs.len(), "total_tokens": inputs.len()}
+            }))
+        }),
+    );
+    spawn(app).await
+}
+
+// Answers `GET /v1/models` with `status`, counting the calls it receives
+pub(crate) async fn models(status: StatusCode) -> (String, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let app = Router::new().route(
+        "/v1/models",
+        get(move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (status, Json(json!({"object": "list", "data": []})))
+            }
+        }),
+    );
+    (spawn(app).await, calls)
+}
@@ -0,0 +1,615 @@
+// Anthropic
+//
+// The Messages API takes the system prompt apart from the conversation, which
+// must alternate between the user and the assistant, and always needs
+// `max_tokens`. Bedrock hosts the same API, so its Anthropic models share the
+// translation here.
+use crate::client::ClientConfig;
+use crate::models::content::{ContentPart, ImageSource};
+use crate::models::finish_reason;
+use crate::models::openai::{
+    api_key, completion, read_body_capped, tool_arguments, with_tool_calls, Content, FunctionCall,
+    Message, OpenAIChatCompletionRequest, OpenAIChatCompletionResponse, ToolCall,
+    DEFAULT_MAX_RESPONSE_BYTES,
+};
+use crate::models::tool_choice::ToolChoice;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub const ANTHROPIC_PROVIDER: &str = "anthropic";
+pub const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+pub(crate) const DEFAULT_MAX_TOKENS: i32 = 1024;
+
+// Anthropic Messages request
+#[derive(Debug, Serialize)]
+pub(crate) struct AnthropicRequest {
+    // Bedrock takes the model from the URL and the version from the body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) anthropic_version: Option<&'static str>,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicMessage {
+    role: &'static str,
+    content: MessageContent,
+}
+
+// Plain text, or content blocks once a message has an image or tool calls
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: AnthropicImage,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicImage {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+impl MessageContent {
+    fn of(message: &Message) -> Result<Self> {
+        match message {
+            Message::Assistant {
+                tool_calls: Some(calls),
+                ..
+            } if !calls.is_empty() => {
+                let text = message.content_text();
+                let mut blocks = Vec::new();
+                if !text.is_empty() {
+                    blocks.push(ContentBlock::Text { text });
+                }
+                for call in calls {
+                    blocks.push(ContentBlock::ToolUse {
+                        id: call.id.clone(),
+                        name: call.function.name.clone(),
+                        input: tool_arguments(call)?,
+                    });
+                }
+                return Ok(MessageContent::Blocks(blocks));
+            }
+            Message::Tool { tool_call_id, .. } => {
+                return Ok(MessageContent::Blocks(vec![ContentBlock::ToolResult {
+                    tool_use_id: tool_call_id.clone(),
+                    content: message.content_text(),
+                }]));
+            }
+            _ => {}
+        }
+        let parts = match message.content() {
+            Some(content @ Content::Array(_)) => ContentPart::parts(content)?,
+            _ => return Ok(MessageContent::Text(message.content_text())),
+        };
+        let blocks = parts
+            .into_iter()
+            .map(|part| match part {
+                ContentPart::Text(text) => ContentBlock::Text { text },
+                ContentPart::Image(ImageSource::Base64 { media_type, data }) => {
+                    ContentBlock::Image {
+                        source: AnthropicImage::Base64 { media_type, data },
+                    }
+                }
+                ContentPart::Image(ImageSource::Url(url)) => ContentBlock::Image {
+                    source: AnthropicImage::Url { url },
+                },
+            })
+            .collect();
+        Ok(MessageContent::Blocks(blocks))
+    }
+
+    fn into_blocks(self) -> Vec<ContentBlock> {
+        match self {
+            MessageContent::Text(text) => vec![ContentBlock::Text { text }],
+            MessageContent::Blocks(blocks) => blocks,
+        }
+    }
+
+    fn append(&mut self, other: MessageContent) {
+        match (self, other) {
+            (MessageContent::Text(text), MessageContent::Text(other)) => {
+                text.push_str("\n\n");
+                text.push_str(&other);
+            }
+            (content, other) => {
+                let mut blocks =
+                    std::mem::replace(content, MessageContent::Blocks(Vec::new())).into_blocks();
+                blocks.extend(other.into_blocks());
+                *content = MessageContent::Blocks(blocks);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AnthropicResponse {
+    id: String,
+    content: Vec<AnthropicContent>,
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicContent {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    // Thinking and other blocks aren't passed on
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: i32,
+    output_tokens: i32,
+}
+
+#[derive(Clone)]
+pub struct AnthropicClient {
+    client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+    max_response_bytes: usize,
+}
+
+impl AnthropicClient {
+    pub fn new(api_key: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_key,
+            base_url: ANTHROPIC_BASE_URL.to_string(),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    // Replaces the HTTP client with one using the given settings
+    pub fn with_client_config(mut self, config: ClientConfig) -> Result<Self> {
+        self.client = config.keyed_client()?;
+        Ok(self)
+    }
+
+    // Lists the models, a cheap authenticated call that opens a connection
+    pub async fn warmup(&self) -> Result<()> {
+        let response = self
+            .client
+            .get(format!("{}/models", self.base_url.trim_end_matches('/')))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .send()
+            .await?;
+        let status = response.status();
+        // Drain the body so the connection goes back to the pool
+        response.bytes().await?;
+        if !status.is_success() {
+            return Err(anyhow!("Anthropic API returned {}", status));
+        }
+        Ok(())
+    }
+
+    pub async fn chat(
+        &self,
+        request: OpenAIChatCompletionRequest,
+    ) -> Result<OpenAIChatCompletionResponse> {
+        let mut body = anthropic_request(&request)?;
+        body.model = Some(request.model.clone());
+        let response = self
+            .client
+            .post(format!("{}/messages", self.base_url.trim_end_matches('/')))
+            .header("x-api-key", api_key(&request, &self.api_key))
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = read_body_capped(response, self.max_response_bytes).await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "Anthropic API error: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        let response: AnthropicResponse = serde_json::from_slice(&body)?;
+        Ok(from_anthropic(&request.model, response))
+    }
+}
+
+pub(crate) fn max_tokens(request: &OpenAIChatCompletionRequest) -> i32 {
+    request
+        .max_completion_tokens
+        .or(request.max_tokens)
+        .unwrap_or(DEFAULT_MAX_TOKENS)
+}
+
+// OpenAI function tools as Anthropic tools, which have the JSON schema of
+// their arguments in `input_schema`
+fn anthropic_tools(request: &OpenAIChatCompletionRequest) -> Option<Vec<Value>> {
+    let tools = request
+        .tools
+        .as_ref()?
+        .iter()
+        .map(|tool| {
+            let function = &tool.function;
+            let input_schema = match &function.parameters {
+                Value::Null => json!({"type": "object"}),
+                parameters => parameters.clone(),
+            };
+            let mut tool = json!({"name": function.name, "input_schema": input_schema});
+            if let Some(description) = &function.description {
+                tool["description"] = json!(description);
+            }
+            tool
+        })
+        .collect();
+    Some(tools)
+}
+
+// System and developer messages go into the separate `system` field, wherever
+// they are in the conversation. Tool calls are `tool_use` blocks of the
+// assistant and tool results `tool_result` blocks of the user, and consecutive
+// turns of the same role are merged so the roles alternate. Images are sent
+// as image blocks, inline or by URL.
+pub(crate) fn anthropic_request(request: &OpenAIChatCompletionRequest) -> Result<AnthropicRequest> {
+    let mut system = Vec::new();
+    let mut messages: Vec<AnthropicMessage> = Vec::new();
+    for message in &request.messages {
+        let role = match message {
+            Message::System { .. } | Message::Developer { .. } => {
+                system.push(message.content_text());
+                continue;
+            }
+            Message::Assistant { .. } => "assistant",
+            _ => "user",
+        };
+        let content = MessageContent::of(message)?;
+        match messages.last_mut() {
+            Some(last) if last.role == role => last.content.append(content),
+            _ => messages.push(AnthropicMessage { role, content }),
+        }
+    }
+    Ok(AnthropicRequest {
+        model: None,
+        anthropic_version: None,
+        max_tokens: max_tokens(request),
+        system: (!system.is_empty()).then(|| system.join("\n\n")),
+        messages,
+        temperature: request.temperature,
+        tools: anthropic_tools(request),
+        tool_choice: request.tool_choice.as_ref().map(ToolChoice::to_anthropic),
+    })
+}
+
+pub(crate) fn from_anthropic(
+    model: &str,
+    response: AnthropicResponse,
+) -> OpenAIChatCompletionResponse {
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    for block in response.content {
+        match block {
+            AnthropicContent::Text { text } => content.push_str(&text),
+            AnthropicContent::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                id,
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name,
+                    arguments: input.to_string(),
+                },
+            }),
+            AnthropicContent::Other => {}
+        }
+    }
+    let finish_reason = finish_reason::ANTHROPIC.map(response.stop_reason.as_deref().unwrap_or(""));
+    let response = completion(
+        response.id,
+        model,
+        content,
+        finish_reason,
+        response.usage.input_tokens,
+        response.usage.output_tokens,
+    );
+    with_tool_calls(response, tool_calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use axum::{http::HeaderMap, routing::post, Json, Router};
+    use std::sync::{Arc, Mutex};
+
+    fn body(request: &OpenAIChatCompletionRequest) -> Value {
+        serde_json::to_value(anthropic_request(request).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_roles_are_mapped_and_merged() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": "Let me check.", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+                {"role": "user", "content": "And tomorrow?"}
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            body(&request)["messages"],
+            json!([
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Let me check."},
+                    {"type": "tool_use", "id": "call_1", "name": "get_weather", "input": {"city": "Paris"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "call_1", "content": "Sunny"},
+                    {"type": "text", "text": "And tomorrow?"}
+                ]}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_image_parts_are_translated() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Compare these."},
+                    {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,/9j/4AAQ"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "image_url", "image_url": {"url": "https://example.com/cat.png", "detail": "low"}}
+                ]}
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            body(&request)["messages"],
+            json!([{"role": "user", "content": [
+                {"type": "text", "text": "Compare these."},
+                {"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "/9j/4AAQ"}},
+                {"type": "image", "source": {"type": "url", "url": "https://example.com/cat.png"}}
+            ]}])
+        );
+    }
+
+    #[test]
+    fn test_system_messages_are_extracted_anywhere() {
+        let request = OpenAIChatCompletionRequest::new("claude-3-5-haiku-latest")
+            .with_message("system", "Be brief.")
+            .with_message("user", "Hello!")
+            .with_message("developer", "Answer in French.")
+            .with_message("user", "How are you?");
+
+        let body = body(&request);
+
+        assert_eq!(body["system"], "Be brief.\n\nAnswer in French.");
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": "Hello!\n\nHow are you?"}])
+        );
+        assert_eq!(body["max_tokens"], DEFAULT_MAX_TOKENS);
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_response_is_mapped() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = Router::new().route(
+            "/messages",
+            post(move |headers: HeaderMap, Json(body): Json<Value>| {
+                let recorded = recorded.clone();
+                async move {
+                    recorded.lock().unwrap().push((headers, body));
+                    Json(json!({
+                        "id": "msg_123",
+                        "type": "message",
+                        "role": "assistant",
+                        "content": [{"type": "text", "text": "Hi there"}],
+                        "stop_reason": "end_turn",
+                        "usage": {"input_tokens": 10, "output_tokens": 3}
+                    }))
+                }
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        let base_url = base_url.trim_end_matches("/v1");
+        let request = OpenAIChatCompletionRequest::new("claude-3-5-haiku-latest")
+            .with_message("user", "Hello!");
+
+        let response = AnthropicClient::new("sk-ant-test".to_string())
+            .with_base_url(base_url)
+            .chat(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.id, "msg_123");
+        assert_eq!(response.choices[0].message.content_text(), "Hi there");
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(response.usage.prompt_tokens, 10);
+        assert_eq!(response.usage.total_tokens, 13);
+        let seen = seen.lock().unwrap();
+        let (headers, body) = &seen[0];
+        assert_eq!(headers["x-api-key"], "sk-ant-test");
+        assert_eq!(headers["anthropic-version"], ANTHROPIC_VERSION);
+        assert_eq!(body["model"], "claude-3-5-haiku-latest");
+    }
+
+    #[tokio::test]
+    async fn test_forced_tool_call_round_trip() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = Router::new().route(
+            "/messages",
+            post(move |Json(body): Json<Value>| {
+                let recorded = recorded.clone();
+                async move {
+                    recorded.lock().unwrap().push(body);
+                    Json(json!({
+                        "id": "msg_123",
+                        "type": "message",
+                        "role": "assistant",
+                        "content": [{
+                            "type": "tool_use",
+                            "id": "toolu_1",
+                            "name": "get_weather",
+                            "input": {"city": "Paris"}
+                        }],
+                        "stop_reason": "tool_use",
+                        "usage": {"input_tokens": 10, "output_tokens": 3}
+                    }))
+                }
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        let client = AnthropicClient::new("sk-ant-test".to_string())
+            .with_base_url(base_url.trim_end_matches("/v1"));
+        let mut request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-3-5-haiku-latest",
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{"type": "function", "function": {"name": "get_weather"}}],
+            "tool_choice": "required"
+        }))
+        .unwrap();
+
+        let response = client.chat(request.clone()).await.unwrap();
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason, "tool_calls");
+        let Message::Assistant {
+            content,
+            tool_calls: Some(tool_calls),
+            ..
+        } = &choice.message
+        else {
+            panic!("No tool calls in {:?}", choice.message);
+        };
+        assert_eq!(*content, None);
+        assert_eq!(tool_calls[0].id, "toolu_1");
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(
+            serde_json::from_str::<Value>(&tool_calls[0].function.arguments).unwrap(),
+            json!({"city": "Paris"})
+        );
+
+        request.messages.push(choice.message.clone());
+        request.messages.push(
+            serde_json::from_value(
+                json!({"role": "tool", "tool_call_id": "toolu_1", "content": "Sunny"}),
+            )
+            .unwrap(),
+        );
+        client.chat(request).await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0]["tool_choice"], json!({"type": "any"}));
+        assert_eq!(
+            seen[1]["messages"].as_array().unwrap()[1..],
+            [
+                json!({"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+                ]}),
+                json!({"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny"}
+                ]})
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_key_is_not_redirected_to_another_origin() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let target = Router::new().route(
+            "/messages",
+            post(move |headers: HeaderMap| {
+                let recorded = recorded.clone();
+                async move {
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push(headers.get("x-api-key").cloned());
+                }
+            }),
+        );
+        let target = mock::spawn(target).await;
+        let target = format!("{}/messages", target.trim_end_matches("/v1"));
+        let redirect = Router::new().route(
+            "/messages",
+            post(move || async move { axum::response::Redirect::temporary(&target) }),
+        );
+        let base_url = mock::spawn(redirect).await;
+        let request = OpenAIChatCompletionRequest::new("claude-3-5-haiku-latest")
+            .with_message("user", "Hello!");
+
+        let result = AnthropicClient::new("sk-ant-test".to_string())
+            .with_base_url(base_url.trim_end_matches("/v1"))
+            .with_client_config(ClientConfig::default())
+            .unwrap()
+            .chat(request)
+            .await;
+
+        assert!(result.is_err());
+        assert!(seen.lock().unwrap().is_empty());
+    }
+}
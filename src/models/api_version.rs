@@ -0,0 +1,83 @@
+// API version pins
+//
+// Providers that version their API, such as Azure OpenAI with its
+// `api-version` query parameter, move to the current version when none is
+// given. Pinning a model to a version keeps it on a known request and response
+// schema until the pin is changed on purpose.
+use anyhow::anyhow;
+use reqwest::RequestBuilder;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+pub const DEFAULT_VERSION_NAME: &str = "api-version";
+
+// Where the version goes on the upstream request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionLocation {
+    #[default]
+    Query,
+    Header,
+}
+
+impl FromStr for VersionLocation {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "query" => Ok(VersionLocation::Query),
+            "header" => Ok(VersionLocation::Header),
+            _ => Err(anyhow!("expected query or header")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiVersions {
+    name: String,
+    location: VersionLocation,
+    // Version per model, models without one get the provider's current version
+    pins: HashMap<String, String>,
+}
+
+impl Default for ApiVersions {
+    fn default() -> Self {
+        Self::new(HashMap::new())
+    }
+}
+
+impl ApiVersions {
+    pub fn new(pins: HashMap<String, String>) -> Self {
+        Self {
+            name: DEFAULT_VERSION_NAME.to_string(),
+            location: VersionLocation::default(),
+            pins,
+        }
+    }
+
+    // Name of the query parameter or header carrying the version
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_location(mut self, location: VersionLocation) -> Self {
+        self.location = location;
+        self
+    }
+
+    pub fn version(&self, model: &str) -> Option<&str> {
+        self.pins.get(model).map(String::as_str)
+    }
+
+    pub fn apply(&self, model: &str, request: RequestBuilder) -> RequestBuilder {
+        let Some(version) = self.version(model) else {
+            return request;
+        };
+        match self.location {
+            VersionLocation::Query => request.query(&[(self.name.as_str(), version)]),
+            VersionLocation::Header => request.header(self.name.as_str(), version),
+        }
+    }
+}
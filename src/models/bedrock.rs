@@ -0,0 +1,360 @@
+// AWS Bedrock
+//
+// Bedrock hosts models of several vendors behind one `InvokeModel` API, with a
+// request and response body in the vendor's own format. Requests are signed
+// with SigV4 instead of carrying a bearer token.
+use crate::client::ClientConfig;
+use crate::models::anthropic::{
+    anthropic_request, from_anthropic, max_tokens, AnthropicRequest, AnthropicResponse,
+};
+use crate::models::finish_reason;
+use crate::models::openai::{
+    completion, read_body_capped, Message, OpenAIChatCompletionRequest,
+    OpenAIChatCompletionResponse, DEFAULT_MAX_RESPONSE_BYTES,
+};
+use crate::sigv4::{uri_encode, AwsCredentials, RequestSigner, SigV4Signer};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub const BEDROCK_SERVICE: &str = "bedrock";
+const ANTHROPIC_VERSION: &str = "bedrock-2023-05-31";
+
+// Amazon Titan Text request, which takes a single prompt
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanRequest {
+    input_text: String,
+    text_generation_config: TitanConfig,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanConfig {
+    max_token_count: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanResponse {
+    input_text_token_count: i32,
+    results: Vec<TitanResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanResult {
+    token_count: i32,
+    output_text: String,
+    completion_reason: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct BedrockClient {
+    client: reqwest::Client,
+    signer: Arc<dyn RequestSigner>,
+    base_url: String,
+    max_response_bytes: usize,
+}
+
+impl BedrockClient {
+    pub fn new(region: &str, signer: Arc<dyn RequestSigner>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            signer,
+            base_url: format!("https://bedrock-runtime.{}.amazonaws.com", region),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    // Signs with the `AWS_*` credentials from the environment
+    pub fn from_env(region: &str) -> Result<Self> {
+        let signer = SigV4Signer::new(AwsCredentials::from_env()?, region, BEDROCK_SERVICE);
+        Ok(Self::new(region, Arc::new(signer)))
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    // Replaces the HTTP client with one using the given settings
+    pub fn with_client_config(mut self, config: ClientConfig) -> Result<Self> {
+        self.client = config.keyed_client()?;
+        Ok(self)
+    }
+
+    // Vendors whose request format is translated
+    pub fn serves(model: &str) -> bool {
+        model.starts_with("anthropic.") || model.starts_with("amazon.titan-text")
+    }
+
+    // The runtime API has no cheap read-only call, so this sends an unsigned
+    // request to the endpoint. Any answer, an auth error included, means the
+    // connection is open.
+    pub async fn warmup(&self) -> Result<()> {
+        let response = self.client.get(&self.base_url).send().await?;
+        // Drain the body so the connection goes back to the pool
+        response.bytes().await?;
+        Ok(())
+    }
+
+    pub async fn chat(
+        &self,
+        request: OpenAIChatCompletionRequest,
+    ) -> Result<OpenAIChatCompletionResponse> {
+        let body = if request.model.starts_with("anthropic.") {
+            serde_json::to_vec(&bedrock_anthropic_request(&request)?)?
+        } else if request.model.starts_with("amazon.titan-text") {
+            serde_json::to_vec(&titan_request(&request))?
+        } else {
+            return Err(anyhow!("Unsupported Bedrock model: {}", request.model));
+        };
+
+        let url = format!(
+            "{}/model/{}/invoke",
+            self.base_url.trim_end_matches('/'),
+            uri_encode(&request.model)
+        );
+        let mut http_request = self
+            .client
+            .post(url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .body(body)
+            .build()?;
+        self.signer.sign(&mut http_request)?;
+
+        let response = self.client.execute(http_request).await?;
+        let status = response.status();
+        let body = read_body_capped(response, self.max_response_bytes).await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "Bedrock API error: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        if request.model.starts_with("anthropic.") {
+            let response: AnthropicResponse = serde_json::from_slice(&body)?;
+            Ok(from_anthropic(&request.model, response))
+        } else {
+            let response: TitanResponse = serde_json::from_slice(&body)?;
+            Ok(from_titan(&request.model, response))
+        }
+    }
+}
+
+// The Anthropic Messages request with the Bedrock version of the API
+fn bedrock_anthropic_request(request: &OpenAIChatCompletionRequest) -> Result<AnthropicRequest> {
+    let mut body = anthropic_request(request)?;
+    body.anthropic_version = Some(ANTHROPIC_VERSION);
+    Ok(body)
+}
+
+// Titan continues a transcript, so the conversation is flattened into one
+fn titan_request(request: &OpenAIChatCompletionRequest) -> TitanRequest {
+    let mut prompt = String::new();
+    for message in &request.messages {
+        let speaker = match message {
+            Message::Assistant { .. } => "Bot",
+            Message::System { .. } | Message::Developer { .. } => "System",
+            _ => "User",
+        };
+        prompt.push_str(&format!("{}: {}\n", speaker, message.content_text()));
+    }
+    prompt.push_str("Bot:");
+    TitanRequest {
+        input_text: prompt,
+        text_generation_config: TitanConfig {
+            max_token_count: max_tokens(request),
+            temperature: request.temperature,
+        },
+    }
+}
+
+fn from_titan(model: &str, response: TitanResponse) -> OpenAIChatCompletionResponse {
+    let result = response.results.into_iter().next();
+    let (content, completion_tokens, reason) = match result {
+        Some(result) => (
+            result.output_text.trim_start().to_string(),
+            result.token_count,
+            result.completion_reason.unwrap_or_default(),
+        ),
+        None => (String::new(), 0, String::new()),
+    };
+    completion(
+        "bedrock-titan".to_string(),
+        model,
+        content,
+        finish_reason::TITAN.map(&reason),
+        response.input_text_token_count,
+        completion_tokens,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::models::openai::Content;
+    use axum::{extract::Path, http::HeaderMap, routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    // Answers every InvokeModel call with `response`, recording the model and
+    // the Authorization header of the request.
+    async fn bedrock(response: Value) -> (String, Arc<Mutex<Vec<(String, String)>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let app = Router::new().route(
+            "/model/{model}/invoke",
+            post(move |Path(model): Path<String>, headers: HeaderMap| {
+                let recorded = recorded.clone();
+                let response = response.clone();
+                async move {
+                    let authorization = headers["authorization"].to_str().unwrap().to_string();
+                    recorded.lock().unwrap().push((model, authorization));
+                    Json(response)
+                }
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        (base_url.trim_end_matches("/v1").to_string(), calls)
+    }
+
+    fn client(base_url: &str) -> BedrockClient {
+        let credentials = AwsCredentials::new("AKIDEXAMPLE", "secret");
+        let signer = SigV4Signer::new(credentials, "us-east-1", BEDROCK_SERVICE);
+        BedrockClient::new("us-east-1", Arc::new(signer)).with_base_url(base_url)
+    }
+
+    #[test]
+    fn test_anthropic_request_moves_system_prompt() {
+        let request = OpenAIChatCompletionRequest::new("anthropic.claude-3-haiku-20240307-v1:0")
+            .with_message("system", "Be brief.")
+            .with_message("user", "Hello!");
+
+        let body = serde_json::to_value(bedrock_anthropic_request(&request).unwrap()).unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "anthropic_version": "bedrock-2023-05-31",
+                "max_tokens": 1024,
+                "system": "Be brief.",
+                "messages": [{"role": "user", "content": "Hello!"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_translates_tools() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "anthropic.claude-3-haiku-20240307-v1:0",
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "description": "Current weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }}],
+            "tool_choice": "required"
+        }))
+        .unwrap();
+
+        let body = serde_json::to_value(bedrock_anthropic_request(&request).unwrap()).unwrap();
+
+        assert_eq!(
+            body["tools"],
+            json!([{
+                "name": "get_weather",
+                "description": "Current weather",
+                "input_schema": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }])
+        );
+        assert_eq!(body["tool_choice"], json!({"type": "any"}));
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_response_is_signed_and_mapped() {
+        let (base_url, calls) = bedrock(json!({
+            "id": "msg_123",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-haiku-20240307",
+            "content": [{"type": "text", "text": "Hi there"}],
+            "stop_reason": "max_tokens",
+            "usage": {"input_tokens": 10, "output_tokens": 3}
+        }))
+        .await;
+        let request = OpenAIChatCompletionRequest::new("anthropic.claude-3-haiku-20240307-v1:0")
+            .with_message("user", "Hello!");
+
+        let response = client(&base_url).chat(request).await.unwrap();
+
+        assert_eq!(response.id, "msg_123");
+        assert_eq!(response.model, "anthropic.claude-3-haiku-20240307-v1:0");
+        assert_eq!(
+            response.choices[0].message.content(),
+            Some(&Content::Text("Hi there".to_string()))
+        );
+        assert_eq!(response.choices[0].finish_reason, "length");
+        assert_eq!(response.usage.total_tokens, 13);
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[0].0, "anthropic.claude-3-haiku-20240307-v1:0");
+        assert!(calls[0]
+            .1
+            .starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(calls[0]
+            .1
+            .contains("/us-east-1/bedrock/aws4_request, SignedHeaders="));
+    }
+
+    #[tokio::test]
+    async fn test_titan_response_is_mapped() {
+        let (base_url, _) = bedrock(json!({
+            "inputTextTokenCount": 5,
+            "results": [{
+                "tokenCount": 2,
+                "outputText": " Hi there",
+                "completionReason": "FINISH"
+            }]
+        }))
+        .await;
+        let request = OpenAIChatCompletionRequest::new("amazon.titan-text-express-v1")
+            .with_message("user", "Hello!");
+
+        let response = client(&base_url).chat(request).await.unwrap();
+
+        assert_eq!(
+            response.choices[0].message.content(),
+            Some(&Content::Text("Hi there".to_string()))
+        );
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(response.usage.prompt_tokens, 5);
+        assert_eq!(response.usage.total_tokens, 7);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_model_is_rejected() {
+        let request = OpenAIChatCompletionRequest::new("meta.llama3-8b-instruct-v1:0");
+
+        let error = client("http://127.0.0.1:1")
+            .chat(request)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Unsupported Bedrock model: meta.llama3-8b-instruct-v1:0"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// Request body transforms
+//
+// OpenAI compatible APIs differ in small ways from OpenAI's: one rejects
+// `user`, another wants `max_completion_tokens` instead of `max_tokens` or
+// `stream` sent even when false. A body transform edits the top-level fields
+// of the outbound request body to suit the provider, without code changes.
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldOp {
+    Drop(String),
+    Rename { from: String, to: String },
+    // Sets the field when the request doesn't
+    Default { field: String, value: Value },
+}
+
+impl FromStr for FieldOp {
+    type Err = anyhow::Error;
+
+    // `drop:<field>`, `rename:<from>:<to>` or `default:<field>:<value>`, where
+    // the value is JSON or else a string
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.trim().splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("drop"), Some(field), None) if !field.is_empty() => {
+                Ok(FieldOp::Drop(field.to_string()))
+            }
+            (Some("rename"), Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => {
+                Ok(FieldOp::Rename {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                })
+            }
+            (Some("default"), Some(field), Some(value)) if !field.is_empty() => {
+                Ok(FieldOp::Default {
+                    field: field.to_string(),
+                    value: serde_json::from_str(value)
+                        .unwrap_or_else(|_| Value::String(value.to_string())),
+                })
+            }
+            _ => Err(anyhow!(
+                "expected drop:<field>, rename:<from>:<to> or default:<field>:<value>"
+            )),
+        }
+    }
+}
+
+// Field operations applied in order
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BodyTransform {
+    ops: Vec<FieldOp>,
+}
+
+impl FromStr for BodyTransform {
+    type Err = anyhow::Error;
+
+    // Operations separated by semicolons, e.g. `drop:user;rename:max_tokens:max_completion_tokens`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let ops = value
+            .split(';')
+            .filter(|op| !op.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { ops })
+    }
+}
+
+impl BodyTransform {
+    pub fn new(ops: Vec<FieldOp>) -> Self {
+        Self { ops }
+    }
+
+    pub fn apply(&self, body: &mut Value) {
+        let Some(fields) = body.as_object_mut() else {
+            return;
+        };
+        for op in &self.ops {
+            match op {
+                FieldOp::Drop(field) => {
+                    fields.remove(field);
+                }
+                FieldOp::Rename { from, to } => {
+                    if let Some(value) = fields.remove(from) {
+                        fields.insert(to.clone(), value);
+                    }
+                }
+                FieldOp::Default { field, value } => {
+                    fields.entry(field.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_body_transform() {
+        let transform: BodyTransform =
+            "drop:user; rename:max_tokens:max_completion_tokens;default:stream:false"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            transform,
+            BodyTransform::new(vec![
+                FieldOp::Drop("user".to_string()),
+                FieldOp::Rename {
+                    from: "max_tokens".to_string(),
+                    to: "max_completion_tokens".to_string(),
+                },
+                FieldOp::Default {
+                    field: "stream".to_string(),
+                    value: json!(false),
+                },
+            ])
+        );
+        assert!("drop:".parse::<BodyTransform>().is_err());
+        assert!("rename:max_tokens".parse::<BodyTransform>().is_err());
+    }
+
+    #[test]
+    fn test_default_keeps_request_value() {
+        let transform: BodyTransform = "default:stream:false;default:service:fast".parse().unwrap();
+        let mut body = json!({"model": "gpt-4o", "stream": true});
+
+        transform.apply(&mut body);
+
+        assert_eq!(
+            body,
+            json!({"model": "gpt-4o", "stream": true, "service": "fast"})
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// Content parts
+//
+// OpenAI array content is a list of typed parts. Translation layers read it
+// through `ContentPart` and render each part in their provider's format. Image
+// parts hold a web URL or a data URI with the base64 encoded image inline,
+// which is split into its media type and data here.
+use crate::models::openai::Content;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentPart {
+    Text(String),
+    Image(ImageSource),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url(String),
+}
+
+impl ContentPart {
+    // Accepts `text` and `image_url` parts
+    pub fn from_openai(part: &Value) -> Result<Self> {
+        match part.get("type").and_then(Value::as_str) {
+            Some("text") => {
+                let text = part.get("text").and_then(Value::as_str).unwrap_or_default();
+                Ok(ContentPart::Text(text.to_string()))
+            }
+            Some("image_url") => {
+                let url = part
+                    .get("image_url")
+                    .and_then(|image| image.get("url"))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("image_url part without a url"))?;
+                Ok(ContentPart::Image(ImageSource::parse(url)?))
+            }
+            Some(kind) => Err(anyhow!("Unsupported content part: {}", kind)),
+            None => Err(anyhow!("Content part without a type")),
+        }
+    }
+
+    // Text content is a single text part
+    pub fn parts(content: &Content) -> Result<Vec<Self>> {
+        match content {
+            Content::Text(text) => Ok(vec![ContentPart::Text(text.clone())]),
+            Content::Array(parts) => parts.iter().map(Self::from_openai).collect(),
+        }
+    }
+}
+
+impl ImageSource {
+    // `data:<media type>;base64,<data>` or a web URL
+    pub fn parse(url: &str) -> Result<Self> {
+        let Some(uri) = url.strip_prefix("data:") else {
+            return Ok(ImageSource::Url(url.to_string()));
+        };
+        let (media_type, data) = uri
+            .split_once(";base64,")
+            .ok_or_else(|| anyhow!("Only base64 encoded data URIs are supported"))?;
+        if media_type.is_empty() {
+            return Err(anyhow!("Data URI without a media type"));
+        }
+        Ok(ImageSource::Base64 {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        })
+    }
+
+    // The media type of a URL is guessed from its extension
+    pub fn media_type(&self) -> &str {
+        match self {
+            ImageSource::Base64 { media_type, .. } => media_type,
+            ImageSource::Url(url) => {
+                let path = url.split(['?', '#']).next().unwrap_or_default();
+                match path.rsplit_once('.').map(|(_, extension)| extension) {
+                    Some("png") => "image/png",
+                    Some("gif") => "image/gif",
+                    Some("webp") => "image/webp",
+                    _ => "image/jpeg",
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_data_uri_is_split() {
+        assert_eq!(
+            ImageSource::parse("data:image/png;base64,iVBORw0KGgo=").unwrap(),
+            ImageSource::Base64 {
+                media_type: "image/png".to_string(),
+                data: "iVBORw0KGgo=".to_string(),
+            }
+        );
+        assert!(ImageSource::parse("data:text/plain,hello").is_err());
+        assert!(ImageSource::parse("data:;base64,aGk=").is_err());
+    }
+
+    #[test]
+    fn test_url_media_type() {
+        let media_type = |url: &str| ImageSource::parse(url).unwrap().media_type().to_string();
+
+        assert_eq!(
+            media_type("https://example.com/cat.png?size=large"),
+            "image/png"
+        );
+        assert_eq!(media_type("https://example.com/cat"), "image/jpeg");
+    }
+
+    #[test]
+    fn test_unsupported_part() {
+        let part = json!({"type": "input_audio", "input_audio": {"data": "", "format": "wav"}});
+
+        assert!(ContentPart::from_openai(&part).is_err());
+    }
+}
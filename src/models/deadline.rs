@@ -0,0 +1,106 @@
+// Deadline propagation
+//
+// A request may have to complete within `x-kubellm-timeout-ms` or
+// `KUBELLM_TIMEOUT_MS`. Past its deadline the gateway stops waiting, but the
+// upstream would go on generating tokens nobody reads. Providers that accept a
+// timeout hint are told how long is left, so they can stop early as well.
+use anyhow::anyhow;
+use reqwest::RequestBuilder;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// How a provider takes the milliseconds left until the deadline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadlineHint {
+    Header(String),
+    // Top-level field of the request body
+    Field(String),
+}
+
+impl FromStr for DeadlineHint {
+    type Err = anyhow::Error;
+
+    // `header:<name>` or `field:<name>`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => Ok(DeadlineHint::Header(name.into())),
+            Some(("field", name)) if !name.is_empty() => Ok(DeadlineHint::Field(name.into())),
+            _ => Err(anyhow!("expected header:<name> or field:<name>")),
+        }
+    }
+}
+
+impl DeadlineHint {
+    // Sets `body` as the body along with the hint
+    pub fn apply(
+        &self,
+        remaining: Duration,
+        body: &Value,
+        upstream_request: RequestBuilder,
+    ) -> RequestBuilder {
+        let millis = remaining.as_millis() as u64;
+        match self {
+            DeadlineHint::Header(name) => upstream_request
+                .header(name.as_str(), millis.to_string())
+                .json(body),
+            DeadlineHint::Field(name) => {
+                let mut body = body.clone();
+                if let Some(fields) = body.as_object_mut() {
+                    fields.insert(name.clone(), Value::from(millis));
+                }
+                upstream_request.json(&body)
+            }
+        }
+    }
+}
+
+// Time left until `deadline`, an error once it has passed
+pub fn remaining(deadline: Instant) -> Result<Duration, DeadlineExceeded> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or(DeadlineExceeded)
+}
+
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request did not complete within its deadline")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_deadline_hint() {
+        assert_eq!(
+            "header:x-request-timeout-ms"
+                .parse::<DeadlineHint>()
+                .unwrap(),
+            DeadlineHint::Header("x-request-timeout-ms".to_string())
+        );
+        assert_eq!(
+            "field:timeout".parse::<DeadlineHint>().unwrap(),
+            DeadlineHint::Field("timeout".to_string())
+        );
+        assert!("timeout".parse::<DeadlineHint>().is_err());
+        assert!("field:".parse::<DeadlineHint>().is_err());
+    }
+
+    #[test]
+    fn test_remaining_fails_after_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert!(remaining(deadline).unwrap() > Duration::from_secs(59));
+        assert!(remaining(Instant::now() - Duration::from_millis(1)).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+// Echo provider
+//
+// Answers without an upstream, for CI and demos without API access. The reply
+// is the last user message, or a configured canned reply, and the usage counts
+// words instead of tokens. Responses only depend on the request, so tests can
+// assert on them.
+use crate::models::finish_reason;
+use crate::models::openai::{
+    Choice, Content, Message, OpenAIChatCompletionRequest, OpenAIChatCompletionResponse, Usage,
+};
+use serde_json::json;
+use std::collections::HashMap;
+
+pub const ECHO_PROVIDER: &str = "echo";
+// Routes every model to the echo provider
+pub const ALL_MODELS: &str = "*";
+
+#[derive(Debug, Clone, Default)]
+pub struct EchoProvider {
+    models: Vec<String>,
+    reply: Option<String>,
+}
+
+impl EchoProvider {
+    pub fn new(models: Vec<String>) -> Self {
+        Self {
+            models,
+            reply: None,
+        }
+    }
+
+    pub fn with_reply(mut self, reply: impl Into<String>) -> Self {
+        self.reply = Some(reply.into());
+        self
+    }
+
+    pub fn serves(&self, model: &str) -> bool {
+        self.models
+            .iter()
+            .any(|served| served == ALL_MODELS || served == model)
+    }
+
+    // Served models, without the wildcard
+    pub fn models(&self) -> Vec<String> {
+        self.models
+            .iter()
+            .filter(|model| *model != ALL_MODELS)
+            .cloned()
+            .collect()
+    }
+
+    pub fn chat(&self, request: &OpenAIChatCompletionRequest) -> OpenAIChatCompletionResponse {
+        let reply = match &self.reply {
+            Some(reply) => reply.clone(),
+            None => request
+                .messages
+                .iter()
+                .rev()
+                .find(|message| matches!(message, Message::User { .. }))
+                .map(text)
+                .unwrap_or_default(),
+        };
+        let prompt_tokens = request
+            .messages
+            .iter()
+            .map(|message| words(&text(message)))
+            .sum();
+        let completion_tokens = words(&reply);
+        OpenAIChatCompletionResponse {
+            id: "chatcmpl-echo".to_string(),
+            choices: vec![Choice {
+                index: 0,
+                message: Message::Assistant {
+                    content: Some(Content::Text(reply)),
+                    name: None,
+                    audio: None,
+                    tool_calls: None,
+                    extra: HashMap::new(),
+                },
+                finish_reason: finish_reason::STOP.to_string(),
+                logprobs: None,
+            }],
+            created: 0,
+            model: request.model.clone(),
+            service_tier: None,
+            system_fingerprint: "fp_echo".to_string(),
+            object: "chat.completion".to_string(),
+            usage: Usage {
+                completion_tokens,
+                prompt_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+                completion_tokens_details: json!({}),
+                prompt_tokens_details: json!({}),
+            },
+            prompt_filter_results: None,
+            kubellm_extra: None,
+        }
+    }
+}
+
+// Text of a message, the text parts joined for array content
+fn text(message: &Message) -> String {
+    match message.content() {
+        Some(Content::Text(text)) => text.clone(),
+        Some(Content::Array(parts)) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(|text| text.as_str()))
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => String::new(),
+    }
+}
+
+fn words(text: &str) -> i32 {
+    text.split_whitespace().count() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> OpenAIChatCompletionRequest {
+        OpenAIChatCompletionRequest::new("gpt-4o")
+            .with_message("system", "Be brief.")
+            .with_message("user", "Hello there")
+    }
+
+    #[test]
+    fn test_echoes_last_user_message() {
+        let response = EchoProvider::new(vec!["gpt-4o".to_string()]).chat(&request());
+
+        assert_eq!(response.choices[0].message.content_text(), "Hello there");
+        assert_eq!(response.usage.prompt_tokens, 4);
+        assert_eq!(response.usage.completion_tokens, 2);
+        assert_eq!(response.usage.total_tokens, 6);
+    }
+
+    #[test]
+    fn test_canned_reply() {
+        let echo = EchoProvider::new(vec![ALL_MODELS.to_string()]).with_reply("All systems go");
+
+        assert!(echo.serves("any-model"));
+        assert_eq!(
+            echo.chat(&request()).choices[0].message.content_text(),
+            "All systems go"
+        );
+    }
+}
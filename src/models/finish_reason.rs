@@ -0,0 +1,104 @@
+// Finish reason mapping
+//
+// Every provider has its own vocabulary for why generation stopped. Translation
+// layers look the provider value up in one of these tables instead of carrying
+// their own match arms, so clients always see OpenAI `finish_reason` values.
+
+pub const STOP: &str = "stop";
+pub const LENGTH: &str = "length";
+pub const TOOL_CALLS: &str = "tool_calls";
+pub const CONTENT_FILTER: &str = "content_filter";
+
+#[derive(Debug, Clone, Copy)]
+pub struct FinishReasonTable {
+    entries: &'static [(&'static str, &'static str)],
+}
+
+pub const ANTHROPIC: FinishReasonTable = FinishReasonTable {
+    entries: &[
+        ("end_turn", STOP),
+        ("stop_sequence", STOP),
+        ("pause_turn", STOP),
+        ("max_tokens", LENGTH),
+        ("tool_use", TOOL_CALLS),
+        ("refusal", CONTENT_FILTER),
+    ],
+};
+
+pub const GEMINI: FinishReasonTable = FinishReasonTable {
+    entries: &[
+        ("STOP", STOP),
+        ("MAX_TOKENS", LENGTH),
+        ("SAFETY", CONTENT_FILTER),
+        ("RECITATION", CONTENT_FILTER),
+        ("BLOCKLIST", CONTENT_FILTER),
+        ("PROHIBITED_CONTENT", CONTENT_FILTER),
+        ("SPII", CONTENT_FILTER),
+        ("IMAGE_SAFETY", CONTENT_FILTER),
+        ("MALFORMED_FUNCTION_CALL", STOP),
+        ("LANGUAGE", STOP),
+        ("OTHER", STOP),
+        ("FINISH_REASON_UNSPECIFIED", STOP),
+    ],
+};
+
+pub const TITAN: FinishReasonTable = FinishReasonTable {
+    entries: &[
+        ("FINISH", STOP),
+        ("LENGTH", LENGTH),
+        ("STOP_CRITERIA_MET", STOP),
+        ("CONTENT_FILTERED", CONTENT_FILTER),
+    ],
+};
+
+impl FinishReasonTable {
+    pub fn lookup(&self, reason: &str) -> Option<&'static str> {
+        self.entries
+            .iter()
+            .find(|(provider, _)| *provider == reason)
+            .map(|(_, openai)| *openai)
+    }
+
+    // Unknown values fall back to "stop" so clients never see a value outside
+    // the OpenAI vocabulary.
+    pub fn map(&self, reason: &str) -> &'static str {
+        self.lookup(reason).unwrap_or(STOP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anthropic_mapping() {
+        assert_eq!(ANTHROPIC.map("end_turn"), "stop");
+        assert_eq!(ANTHROPIC.map("stop_sequence"), "stop");
+        assert_eq!(ANTHROPIC.map("max_tokens"), "length");
+        assert_eq!(ANTHROPIC.map("tool_use"), "tool_calls");
+        assert_eq!(ANTHROPIC.map("refusal"), "content_filter");
+    }
+
+    #[test]
+    fn test_gemini_mapping() {
+        assert_eq!(GEMINI.map("STOP"), "stop");
+        assert_eq!(GEMINI.map("MAX_TOKENS"), "length");
+        assert_eq!(GEMINI.map("SAFETY"), "content_filter");
+        assert_eq!(GEMINI.map("RECITATION"), "content_filter");
+    }
+
+    #[test]
+    fn test_titan_mapping() {
+        assert_eq!(TITAN.map("FINISH"), "stop");
+        assert_eq!(TITAN.map("LENGTH"), "length");
+        assert_eq!(TITAN.map("CONTENT_FILTERED"), "content_filter");
+    }
+
+    #[test]
+    fn test_unknown_reason_falls_back_to_stop() {
+        assert_eq!(ANTHROPIC.lookup("something_new"), None);
+        assert_eq!(ANTHROPIC.map("something_new"), "stop");
+        // Gemini values are upper case, lower case input is not a match
+        assert_eq!(GEMINI.lookup("stop"), None);
+    }
+}
@@ -0,0 +1,607 @@
+// Google Gemini
+//
+// The Gemini API's `generateContent` takes the conversation as `contents`,
+// with the assistant in the `model` role, and the system prompt apart from it
+// in `system_instruction`. Requests carry the API key in `x-goog-api-key`.
+use crate::client::ClientConfig;
+use crate::models::content::{ContentPart, ImageSource};
+use crate::models::finish_reason;
+use crate::models::openai::{
+    api_key, completion, read_body_capped, tool_arguments, with_tool_calls, Content, FunctionCall,
+    Message, OpenAIChatCompletionRequest, OpenAIChatCompletionResponse, ToolCall,
+    DEFAULT_MAX_RESPONSE_BYTES,
+};
+use crate::models::tool_choice::ToolChoice;
+use crate::sigv4::uri_encode;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub const GEMINI_PROVIDER: &str = "gemini";
+pub const GEMINI_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+#[derive(Debug, Serialize)]
+struct GeminiRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GeminiContent>,
+    contents: Vec<GeminiContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiContent {
+    // Absent on the system instruction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    parts: Vec<RequestPart>,
+}
+
+// Text, an inline image, an image by URL, or a tool call or its result
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RequestPart {
+    Text(String),
+    InlineData { mime_type: String, data: String },
+    FileData { mime_type: String, file_uri: String },
+    FunctionCall { name: String, args: Value },
+    FunctionResponse { name: String, response: Value },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPart {
+    #[serde(default)]
+    text: String,
+    function_call: Option<GeminiFunctionCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiFunctionCall {
+    name: String,
+    args: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    #[serde(default)]
+    usage_metadata: UsageMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<CandidateContent>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CandidateContent {
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: i32,
+    #[serde(default)]
+    candidates_token_count: i32,
+}
+
+#[derive(Clone)]
+pub struct GeminiClient {
+    client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+    max_response_bytes: usize,
+}
+
+impl GeminiClient {
+    pub fn new(api_key: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_key,
+            base_url: GEMINI_BASE_URL.to_string(),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    // Replaces the HTTP client with one using the given settings
+    pub fn with_client_config(mut self, config: ClientConfig) -> Result<Self> {
+        self.client = config.keyed_client()?;
+        Ok(self)
+    }
+
+    // Lists the models, a cheap authenticated call that opens a connection
+    pub async fn warmup(&self) -> Result<()> {
+        let response = self
+            .client
+            .get(format!("{}/models", self.base_url.trim_end_matches('/')))
+            .header("x-goog-api-key", &self.api_key)
+            .send()
+            .await?;
+        let status = response.status();
+        // Drain the body so the connection goes back to the pool
+        response.bytes().await?;
+        if !status.is_success() {
+            return Err(anyhow!("Gemini API returned {}", status));
+        }
+        Ok(())
+    }
+
+    pub async fn chat(
+        &self,
+        request: OpenAIChatCompletionRequest,
+    ) -> Result<OpenAIChatCompletionResponse> {
+        let url = format!(
+            "{}/models/{}:generateContent",
+            self.base_url.trim_end_matches('/'),
+            uri_encode(&request.model)
+        );
+        let response = self
+            .client
+            .post(url)
+            .header("x-goog-api-key", api_key(&request, &self.api_key))
+            .json(&gemini_request(&request)?)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = read_body_capped(response, self.max_response_bytes).await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "Gemini API error: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        let response: GeminiResponse = serde_json::from_slice(&body)?;
+        Ok(from_gemini(&request.model, response))
+    }
+}
+
+fn text(content: String) -> Vec<RequestPart> {
+    vec![RequestPart::Text(content)]
+}
+
+fn parts(message: &Message) -> Result<Vec<RequestPart>> {
+    let parts = match message.content() {
+        Some(content @ Content::Array(_)) => ContentPart::parts(content)?,
+        _ => return Ok(text(message.content_text())),
+    };
+    let parts = parts
+        .into_iter()
+        .map(|part| match part {
+            ContentPart::Text(text) => RequestPart::Text(text),
+            ContentPart::Image(image) => {
+                let mime_type = image.media_type().to_string();
+                match image {
+                    ImageSource::Base64 { data, .. } => RequestPart::InlineData { mime_type, data },
+                    ImageSource::Url(file_uri) => RequestPart::FileData {
+                        mime_type,
+                        file_uri,
+                    },
+                }
+            }
+        })
+        .collect();
+    Ok(parts)
+}
+
+// An assistant turn with tool calls has a function call part for each
+fn function_calls(message: &Message) -> Result<Vec<RequestPart>> {
+    let mut parts = Vec::new();
+    let text = message.content_text();
+    if !text.is_empty() {
+        parts.push(RequestPart::Text(text));
+    }
+    if let Message::Assistant {
+        tool_calls: Some(calls),
+        ..
+    } = message
+    {
+        for call in calls {
+            parts.push(RequestPart::FunctionCall {
+                name: call.function.name.clone(),
+                args: tool_arguments(call)?,
+            });
+        }
+    }
+    Ok(parts)
+}
+
+// A tool result answers the function call it names by id. Gemini knows
+// functions by name only, so the name is looked up in the conversation.
+// Results that aren't a JSON object are wrapped in one.
+fn function_response(
+    request: &OpenAIChatCompletionRequest,
+    message: &Message,
+    tool_call_id: &str,
+) -> RequestPart {
+    let name = request
+        .messages
+        .iter()
+        .filter_map(|message| match message {
+            Message::Assistant {
+                tool_calls: Some(calls),
+                ..
+            } => Some(calls),
+            _ => None,
+        })
+        .flatten()
+        .find(|call| call.id == tool_call_id)
+        .map_or(tool_call_id, |call| call.function.name.as_str());
+    let content = message.content_text();
+    let response = match serde_json::from_str::<Value>(&content) {
+        Ok(object @ Value::Object(_)) => object,
+        _ => json!({"content": content}),
+    };
+    RequestPart::FunctionResponse {
+        name: name.to_string(),
+        response,
+    }
+}
+
+// OpenAI function tools as Gemini function declarations, which take the
+// OpenAI function object as is
+fn gemini_tools(request: &OpenAIChatCompletionRequest) -> Option<Vec<Value>> {
+    let declarations: Vec<Value> = request
+        .tools
+        .as_ref()?
+        .iter()
+        .map(|tool| json!(tool.function))
+        .collect();
+    Some(vec![json!({"function_declarations": declarations})])
+}
+
+// System and developer messages are joined in order into the one
+// `system_instruction`, wherever they appear in the conversation. The results
+// of consecutive tool messages go into one user turn.
+fn gemini_request(request: &OpenAIChatCompletionRequest) -> Result<GeminiRequest> {
+    let mut system = Vec::new();
+    let mut contents: Vec<GeminiContent> = Vec::new();
+    let mut previous_tool = false;
+    for message in &request.messages {
+        let tool = matches!(message, Message::Tool { .. });
+        match message {
+            Message::System { .. } | Message::Developer { .. } => {
+                system.push(message.content_text())
+            }
+            Message::Assistant {
+                tool_calls: Some(calls),
+                ..
+            } if !calls.is_empty() => contents.push(GeminiContent {
+                role: Some("model"),
+                parts: function_calls(message)?,
+            }),
+            Message::Assistant { .. } => contents.push(GeminiContent {
+                role: Some("model"),
+                parts: parts(message)?,
+            }),
+            Message::Tool { tool_call_id, .. } => {
+                let part = function_response(request, message, tool_call_id);
+                match contents.last_mut() {
+                    Some(last) if previous_tool => last.parts.push(part),
+                    _ => contents.push(GeminiContent {
+                        role: Some("user"),
+                        parts: vec![part],
+                    }),
+                }
+            }
+            _ => contents.push(GeminiContent {
+                role: Some("user"),
+                parts: parts(message)?,
+            }),
+        }
+        previous_tool = tool;
+    }
+    let max_output_tokens = request.max_completion_tokens.or(request.max_tokens);
+    let generation_config = (request.temperature.is_some() || max_output_tokens.is_some())
+        .then_some(GenerationConfig {
+            temperature: request.temperature,
+            max_output_tokens,
+        });
+    Ok(GeminiRequest {
+        system_instruction: (!system.is_empty()).then(|| GeminiContent {
+            role: None,
+            parts: text(system.join("\n\n")),
+        }),
+        contents,
+        generation_config,
+        tools: gemini_tools(request),
+        tool_config: request.tool_choice.as_ref().map(ToolChoice::to_gemini),
+    })
+}
+
+fn from_gemini(model: &str, response: GeminiResponse) -> OpenAIChatCompletionResponse {
+    let candidate = response.candidates.into_iter().next();
+    let (parts, reason) = match candidate {
+        Some(candidate) => (
+            candidate
+                .content
+                .map(|content| content.parts)
+                .unwrap_or_default(),
+            candidate.finish_reason.unwrap_or_default(),
+        ),
+        None => (Vec::new(), String::new()),
+    };
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    for part in parts {
+        content.push_str(&part.text);
+        if let Some(call) = part.function_call {
+            // Gemini calls have no id, results are matched to them by name
+            tool_calls.push(ToolCall {
+                id: format!("call_{}", tool_calls.len()),
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name: call.name,
+                    arguments: call.args.unwrap_or_else(|| json!({})).to_string(),
+                },
+            });
+        }
+    }
+    // Gemini finishes with `STOP` after function calls too
+    let finish_reason = match finish_reason::GEMINI.map(&reason) {
+        finish_reason::STOP if !tool_calls.is_empty() => finish_reason::TOOL_CALLS,
+        finish_reason => finish_reason,
+    };
+    let response = completion(
+        "gemini".to_string(),
+        model,
+        content,
+        finish_reason,
+        response.usage_metadata.prompt_token_count,
+        response.usage_metadata.candidates_token_count,
+    );
+    with_tool_calls(response, tool_calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use axum::{
+        extract::Path,
+        http::{HeaderMap, Uri},
+        routing::post,
+        Json, Router,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_system_messages_are_merged_into_system_instruction() {
+        let request = OpenAIChatCompletionRequest::new("gemini-1.5-flash")
+            .with_message("system", "Be brief.")
+            .with_message("developer", "Answer in French.")
+            .with_message("user", "Hello!");
+
+        let body = serde_json::to_value(gemini_request(&request).unwrap()).unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "system_instruction": {"parts": [{"text": "Be brief.\n\nAnswer in French."}]},
+                "contents": [{"role": "user", "parts": [{"text": "Hello!"}]}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_image_parts_are_translated() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gemini-1.5-flash",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "Compare these."},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.webp"}}
+            ]}]
+        }))
+        .unwrap();
+
+        let body = serde_json::to_value(gemini_request(&request).unwrap()).unwrap();
+
+        assert_eq!(
+            body["contents"][0]["parts"],
+            json!([
+                {"text": "Compare these."},
+                {"inline_data": {"mime_type": "image/png", "data": "iVBORw0KGgo="}},
+                {"file_data": {"mime_type": "image/webp", "file_uri": "https://example.com/cat.webp"}}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_gemini_request_translates_tools() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gemini-1.5-flash",
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "max_tokens": 100,
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }}],
+            "tool_choice": {"type": "function", "function": {"name": "get_weather"}}
+        }))
+        .unwrap();
+
+        let body = serde_json::to_value(gemini_request(&request).unwrap()).unwrap();
+
+        assert_eq!(body["generation_config"], json!({"max_output_tokens": 100}));
+        assert_eq!(
+            body["tools"][0]["function_declarations"][0]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            body["tool_config"]["function_calling_config"]["allowed_function_names"],
+            json!(["get_weather"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forced_tool_call_round_trip() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = Router::new().route(
+            "/models/{method}",
+            post(move |Json(body): Json<Value>| {
+                let recorded = recorded.clone();
+                async move {
+                    recorded.lock().unwrap().push(body);
+                    Json(json!({
+                        "candidates": [{
+                            "content": {"role": "model", "parts": [{
+                                "functionCall": {"name": "get_weather", "args": {"city": "Paris"}}
+                            }]},
+                            "finishReason": "STOP"
+                        }],
+                        "usageMetadata": {"promptTokenCount": 6, "candidatesTokenCount": 5}
+                    }))
+                }
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        let client = GeminiClient::new("gm-test".to_string())
+            .with_base_url(base_url.trim_end_matches("/v1"));
+        let mut request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gemini-1.5-flash",
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{"type": "function", "function": {"name": "get_weather"}}],
+            "tool_choice": "required"
+        }))
+        .unwrap();
+
+        let response = client.chat(request.clone()).await.unwrap();
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason, "tool_calls");
+        let Message::Assistant {
+            content,
+            tool_calls: Some(tool_calls),
+            ..
+        } = &choice.message
+        else {
+            panic!("No tool calls in {:?}", choice.message);
+        };
+        assert_eq!(*content, None);
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(
+            serde_json::from_str::<Value>(&tool_calls[0].function.arguments).unwrap(),
+            json!({"city": "Paris"})
+        );
+
+        let id = tool_calls[0].id.clone();
+        request.messages.push(choice.message.clone());
+        request.messages.push(
+            serde_json::from_value(json!({"role": "tool", "tool_call_id": id, "content": "Sunny"}))
+                .unwrap(),
+        );
+        client.chat(request).await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            seen[0]["tool_config"],
+            json!({"function_calling_config": {"mode": "ANY"}})
+        );
+        assert_eq!(
+            seen[1]["contents"].as_array().unwrap()[1..],
+            [
+                json!({"role": "model", "parts": [
+                    {"function_call": {"name": "get_weather", "args": {"city": "Paris"}}}
+                ]}),
+                json!({"role": "user", "parts": [
+                    {"function_response": {"name": "get_weather", "response": {"content": "Sunny"}}}
+                ]})
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gemini_response_is_mapped() {
+        let app = Router::new().route(
+            "/models/{method}",
+            post(
+                |Path(method): Path<String>, headers: HeaderMap| async move {
+                    assert_eq!(method, "gemini-1.5-flash:generateContent");
+                    assert_eq!(headers["x-goog-api-key"], "gm-test");
+                    Json(json!({
+                        "candidates": [{
+                            "content": {"role": "model", "parts": [{"text": "Bonjour"}]},
+                            "finishReason": "MAX_TOKENS"
+                        }],
+                        "usageMetadata": {"promptTokenCount": 6, "candidatesTokenCount": 1}
+                    }))
+                },
+            ),
+        );
+        let base_url = mock::spawn(app).await;
+        let base_url = base_url.trim_end_matches("/v1");
+        let request =
+            OpenAIChatCompletionRequest::new("gemini-1.5-flash").with_message("user", "Hello!");
+
+        let response = GeminiClient::new("gm-test".to_string())
+            .with_base_url(base_url)
+            .chat(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.choices[0].message.content_text(), "Bonjour");
+        assert_eq!(response.choices[0].finish_reason, "length");
+        assert_eq!(response.usage.total_tokens, 7);
+    }
+
+    #[tokio::test]
+    async fn test_model_is_encoded_in_url() {
+        let app = Router::new().route(
+            "/models/{method}",
+            post(|Path(method): Path<String>, uri: Uri| async move {
+                assert_eq!(method, "gemini/1.5?alt=sse#x:generateContent");
+                assert_eq!(uri.query(), None);
+                Json(json!({
+                    "candidates": [{
+                        "content": {"role": "model", "parts": [{"text": "Hi"}]},
+                        "finishReason": "STOP"
+                    }]
+                }))
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        let base_url = base_url.trim_end_matches("/v1");
+        let request =
+            OpenAIChatCompletionRequest::new("gemini/1.5?alt=sse#x").with_message("user", "Hi");
+
+        let response = GeminiClient::new("gm-test".to_string())
+            .with_base_url(base_url)
+            .chat(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.choices[0].message.content_text(), "Hi");
+    }
+}
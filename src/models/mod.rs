@@ -1 +1,12 @@
+pub mod anthropic;
+pub mod api_version;
+pub mod bedrock;
+pub mod body_transform;
+pub mod content;
+pub mod deadline;
+pub mod echo;
+pub mod finish_reason;
+pub mod gemini;
 pub mod openai;
+pub mod provider;
+pub mod tool_choice;
@@ -1,13 +1,31 @@
+use crate::client::ClientConfig;
+use crate::models::api_version::ApiVersions;
+use crate::models::body_transform::BodyTransform;
+use crate::models::deadline::{self, DeadlineExceeded, DeadlineHint};
+use crate::models::tool_choice::ToolChoice;
+use crate::rate_limit::RateLimiter;
+use crate::retry::{self, RetryPolicy};
+use crate::streaming::SseDecoder;
+use crate::validation::ValidationError;
 use anyhow::Result;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use futures_util::stream::{self, Stream};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 // Chat Completion Request
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenAIChatCompletionRequest {
     pub messages: Vec<Message>,
+    // Empty when omitted, the gateway may pick a default for the caller
+    #[serde(default)]
     pub model: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -22,15 +40,71 @@ pub struct OpenAIChatCompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+
+    // Number of choices to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<StopSequence>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
 
+    // Keeps the completion upstream so it can be retrieved by id later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<bool>,
+
+    // Stable identifier of the end user, replaces `user` for abuse detection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety_identifier: Option<String>,
+
+    // Groups requests sharing a prompt prefix to improve upstream cache hits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_cache_key: Option<String>,
+
+    // Dropped for providers that don't take the tier, see `Provider`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<ServiceTier>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+
     #[serde(flatten)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra: Option<HashMap<String, Value>>,
+
+    // When the gateway stops waiting for the upstream, never sent as is
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+
+    // Conversation the request belongs to, pins it to a pool deployment
+    #[serde(skip)]
+    pub session: Option<String>,
+
+    // API key the caller sent for the upstream, used instead of the
+    // provider's own
+    #[serde(skip)]
+    pub provider_key: Option<ProviderKey>,
+
+    // Upstream calls made for the request, shared with its copies such as
+    // the request to a fallback model
+    #[serde(skip)]
+    pub attempts: Arc<AtomicU32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "role", rename_all = "snake_case")]
 pub enum Message {
     Developer {
@@ -53,12 +127,17 @@ pub enum Message {
         content: Option<Content>,
         #[serde(skip_serializing_if = "Option::is_none")]
         name: Option<String>,
+        // Audio of an audio response, or a reference to it by `id` in later turns
+        #[serde(skip_serializing_if = "Option::is_none")]
+        audio: Option<AudioRef>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_calls: Option<Vec<ToolCall>>,
         #[serde(flatten)]
         extra: HashMap<String, Value>,
     },
     Tool {
         content: Content,
-        tool_call: String,
+        tool_call_id: String,
     },
     Function {
         content: Content,
@@ -77,206 +156,1826 @@ impl Message {
             Message::Function { content, .. } => Some(content),
         }
     }
+    // Size of the text content, for array content the text parts are summed
+    pub fn content_bytes(&self) -> usize {
+        match self.content() {
+            Some(Content::Text(text)) => text.len(),
+            Some(Content::Array(parts)) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .map(str::len)
+                .sum(),
+            None => 0,
+        }
+    }
+
+    // For array content the text parts joined by newlines, other parts such
+    // as images are left out. Empty for an assistant message with only tool
+    // calls.
     pub fn content_text(&self) -> String {
-        let content = self.content().unwrap();
+        let Some(content) = self.content() else {
+            return String::new();
+        };
         match content {
             Content::Text(text) => text.clone(),
-            Content::Array(_) => "<Array>".to_string(),
+            Content::Array(parts) => parts
+                .iter()
+                .filter(|part| part.get("type").and_then(Value::as_str) == Some("text"))
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+// An upstream API key, kept out of logs by its `Debug`
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProviderKey(String);
+
+impl ProviderKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ProviderKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProviderKey(<redacted>)")
+    }
+}
+
+// Where the model stops generating, one sequence or several
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequence {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+// Processing tier of a request, and the tier that served it in responses.
+// Tiers added after these are kept as `Other` so they don't break parsing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ServiceTier {
+    Auto,
+    Default,
+    Flex,
+    Scale,
+    Priority,
+    Other(String),
+}
+
+impl ServiceTier {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ServiceTier::Auto => "auto",
+            ServiceTier::Default => "default",
+            ServiceTier::Flex => "flex",
+            ServiceTier::Scale => "scale",
+            ServiceTier::Priority => "priority",
+            ServiceTier::Other(tier) => tier,
+        }
+    }
+}
+
+impl From<String> for ServiceTier {
+    fn from(tier: String) -> Self {
+        match tier.as_str() {
+            "auto" => ServiceTier::Auto,
+            "default" => ServiceTier::Default,
+            "flex" => ServiceTier::Flex,
+            "scale" => ServiceTier::Scale,
+            "priority" => ServiceTier::Priority,
+            _ => ServiceTier::Other(tier),
+        }
+    }
+}
+
+impl From<ServiceTier> for String {
+    fn from(tier: ServiceTier) -> Self {
+        match tier {
+            ServiceTier::Other(tier) => tier,
+            tier => tier.as_str().to_string(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+impl fmt::Display for ServiceTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// A function the model may call, `parameters` is the JSON schema of its
+// arguments
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDef,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub parameters: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+// A function the assistant asks to call. `arguments` is the JSON encoded
+// arguments as the model wrote them, which may not be valid JSON.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+// Responses carry `data`, `transcript` and `expires_at` as well, which are
+// passed through untouched
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AudioRef {
+    pub id: String,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum Content {
     Text(String),
     Array(Vec<Value>),
 }
 // Chat Completion Response
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenAIChatCompletionResponse {
     pub id: String,
     pub choices: Vec<Choice>,
     pub created: i64,
     pub model: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub service_tier: Option<String>,
+    pub service_tier: Option<ServiceTier>,
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    // Azure OpenAI content filtering results for the prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_filter_results: Option<Value>,
+    // Added by response transforms, see `transform`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kubellm_extra: Option<Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
     pub index: i32,
     pub message: Message,
     pub finish_reason: String,
-    pub logprobs: Option<Value>,
+    pub logprobs: Option<Logprobs>,
+}
+
+// Log probabilities of the generated tokens, of the content or of a refusal.
+// Streams carry the ones of each delta's tokens on the chunk choice.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Logprobs {
+    pub content: Option<Vec<TokenLogprob>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<Vec<TokenLogprob>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    pub bytes: Option<Vec<u8>>,
+    // The most likely tokens at this position, when `top_logprobs` was requested
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl Logprobs {
+    // Appends the tokens of a later chunk
+    pub fn extend(&mut self, other: Logprobs) {
+        fn append(tokens: &mut Option<Vec<TokenLogprob>>, other: Option<Vec<TokenLogprob>>) {
+            if let Some(other) = other {
+                tokens.get_or_insert_with(Vec::new).extend(other);
+            }
+        }
+        append(&mut self.content, other.content);
+        append(&mut self.refusal, other.refusal);
+    }
+}
+
+// Some OpenAI compatible providers leave out counts, missing ones are 0 until
+// `complete` derives them from the others
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
+    #[serde(default)]
     pub completion_tokens: i32,
+    #[serde(default)]
     pub prompt_tokens: i32,
+    #[serde(default)]
     pub total_tokens: i32,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
     pub completion_tokens_details: Value,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
     pub prompt_tokens_details: Value,
 }
 
-#[derive(Clone)]
-pub struct OpenAIClient {
-    client: reqwest::Client,
-    api_key: String,
-}
-
-impl OpenAIClient {
-    pub fn new(api_key: String) -> Self {
-        Self {
-            client: reqwest::Client::new(),
-            api_key,
+impl Usage {
+    // Fills in a missing count from the other two, so `total_tokens` is
+    // always `prompt_tokens + completion_tokens`
+    pub fn complete(&mut self) {
+        if self.total_tokens == 0 {
+            self.total_tokens = self.prompt_tokens + self.completion_tokens;
+        } else if self.completion_tokens == 0 && self.total_tokens > self.prompt_tokens {
+            self.completion_tokens = self.total_tokens - self.prompt_tokens;
+        } else if self.prompt_tokens == 0 && self.total_tokens > self.completion_tokens {
+            self.prompt_tokens = self.total_tokens - self.completion_tokens;
         }
     }
+}
 
-    pub async fn chat(
-        &self,
-        request: OpenAIChatCompletionRequest,
-    ) -> Result<OpenAIChatCompletionResponse> {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
-        );
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-        let response = self
-            .client
-            .post("https://api.openai.com/v1/chat/completions")
-            .headers(headers)
-            .json(&request)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            return Err(anyhow::anyhow!("OpenAI API error: {}", error_text));
-        }
+// Model list of `GET /v1/models`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelList {
+    pub object: String,
+    pub data: Vec<ModelObject>,
+}
 
-        let response_body = response.json::<OpenAIChatCompletionResponse>().await?;
-        Ok(response_body)
-    }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelObject {
+    pub id: String,
+    pub object: String,
+    // Unix timestamp of when the model was created, 0 when unknown
+    pub created: i64,
+    pub owned_by: String,
 }
 
-impl Default for OpenAIChatCompletionRequest {
-    fn default() -> Self {
+impl ModelList {
+    pub fn new(data: Vec<ModelObject>) -> Self {
         Self {
-            model: "gpt-4o-mini".to_string(), // Default model
-            messages: Vec::new(),             // Empty messages vector
-            temperature: None,
-            max_tokens: None,
-            max_completion_tokens: None,
-            stream: None,
-            user: None,
-            extra: None,
+            object: "list".to_string(),
+            data,
         }
     }
 }
 
-impl OpenAIChatCompletionRequest {
-    pub fn new(model: impl Into<String>) -> Self {
+impl ModelObject {
+    pub fn new(id: impl Into<String>, owned_by: impl Into<String>) -> Self {
         Self {
-            model: model.into(),
-            ..Default::default()
+            id: id.into(),
+            object: "model".to_string(),
+            created: 0,
+            owned_by: owned_by.into(),
         }
     }
+}
 
-    pub fn with_message(mut self, role: impl Into<String>, content: impl Into<String>) -> Self {
-        let role = role.into();
-        let content = content.into();
-        self.messages.push(Message::new(role, content));
-        self
-    }
+// Embeddings Request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingsRequest {
+    pub model: String,
+    pub input: StringOrArray,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    // Upstream key of a trusted caller, see `api_key`
+    #[serde(skip)]
+    pub provider_key: Option<ProviderKey>,
 }
 
-impl Message {
-    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
-        let role = role.into();
-        match role.as_str() {
-            "user" => Message::User {
-                content: Content::Text(content.into()),
-                name: None,
-            },
-            "system" => Message::System {
-                content: Content::Text(content.into()),
-                name: None,
-            },
-            "assistant" => Message::Assistant {
-                content: Some(Content::Text(content.into())),
-                name: None,
-                extra: HashMap::new(),
-            },
-            "developer" => Message::Developer {
-                content: Content::Text(content.into()),
-                name: None,
-            },
-            _ => panic!("Invalid role: {}", role),
-        }
-    }
+// A single input or a batch of them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StringOrArray {
+    String(String),
+    Array(Vec<String>),
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
-    #[test]
-    fn test_parse_chat_completion_request() {
-        let request_json = json!({
-            "model": "gpt-4o",
-            "messages": [
-                {
-                    "role": "developer",
-                    "content": "You are a helpful assistant."
-                },
-                {
-                    "role": "user",
-                    "content": "Hello!"
-                }
-            ]
-        });
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingsResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingsUsage,
+}
 
-        let request: OpenAIChatCompletionRequest = serde_json::from_value(request_json.clone())
-            .expect("Failed to parse ChatCompletionRequest");
+// The embedding of the input at `index`, a list of floats, or a base64
+// string with `encoding_format: base64`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Embedding {
+    pub object: String,
+    pub embedding: Value,
+    pub index: u32,
+}
 
-        assert_eq!(request.model, "gpt-4o");
-        assert_eq!(request.messages.len(), 2);
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingsUsage {
+    #[serde(default)]
+    pub prompt_tokens: i32,
+    #[serde(default)]
+    pub total_tokens: i32,
+}
 
-        match &request.messages[0] {
-            Message::Developer { content, .. } => {
-                assert_eq!(
-                    content,
-                    &Content::Text("You are a helpful assistant.".to_string())
-                );
-            }
-            _ => panic!("Expected Developer message"),
+// Chat Completion Chunk, streamed as server-sent events
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub choices: Vec<ChunkChoice>,
+    pub created: i64,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<ServiceTier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    pub object: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    // Fields we don't model, such as `obfuscation`, are forwarded as is
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkChoice {
+    pub index: i32,
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Logprobs>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl OpenAIChatCompletionResponse {
+    // Whether the model declined to answer, either through the content filter
+    // or with a `refusal` message
+    pub fn is_refusal(&self) -> bool {
+        self.choices.iter().any(|choice| {
+            let refused = match &choice.message {
+                Message::Assistant { extra, .. } => extra
+                    .get("refusal")
+                    .is_some_and(|refusal| !refusal.is_null()),
+                _ => false,
+            };
+            refused || choice.finish_reason == crate::models::finish_reason::CONTENT_FILTER
+        })
+    }
+
+    // Combines responses to separate requests for the same prompt into one
+    // response with all their choices, numbered in order, and their summed usage.
+    pub fn merge_choices(responses: Vec<Self>) -> Option<Self> {
+        let mut responses = responses.into_iter();
+        let mut merged = responses.next()?;
+        for response in responses {
+            merged.choices.extend(response.choices);
+            let usage = &mut merged.usage;
+            usage.prompt_tokens += response.usage.prompt_tokens;
+            usage.completion_tokens += response.usage.completion_tokens;
+            usage.total_tokens += response.usage.total_tokens;
+            add_token_details(
+                &mut usage.prompt_tokens_details,
+                &response.usage.prompt_tokens_details,
+            );
+            add_token_details(
+                &mut usage.completion_tokens_details,
+                &response.usage.completion_tokens_details,
+            );
+        }
+        for (index, choice) in merged.choices.iter_mut().enumerate() {
+            choice.index = index as i32;
         }
+        Some(merged)
+    }
 
-        match &request.messages[1] {
-            Message::User { content, .. } => {
-                assert_eq!(content, &Content::Text("Hello!".to_string()));
+    // Puts a streamed response back together: the content and logprobs of each
+    // choice are concatenated in order and the usage is taken from the usage
+    // chunk. Delta fields other than the content, such as tool calls, are left
+    // out.
+    pub fn from_chunks(chunks: Vec<ChatCompletionChunk>) -> Option<Self> {
+        let first = chunks.first()?;
+        let mut response = OpenAIChatCompletionResponse {
+            id: first.id.clone(),
+            choices: Vec::new(),
+            created: first.created,
+            model: first.model.clone(),
+            service_tier: first.service_tier.clone(),
+            system_fingerprint: first.system_fingerprint.clone().unwrap_or_default(),
+            object: "chat.completion".to_string(),
+            usage: Usage {
+                completion_tokens: 0,
+                prompt_tokens: 0,
+                total_tokens: 0,
+                completion_tokens_details: Value::Null,
+                prompt_tokens_details: Value::Null,
+            },
+            prompt_filter_results: None,
+            kubellm_extra: None,
+        };
+        let mut contents: BTreeMap<i32, (String, Option<String>, Option<Logprobs>)> =
+            BTreeMap::new();
+        for chunk in chunks {
+            if let Some(usage) = chunk.usage {
+                response.usage = usage;
+            }
+            for choice in chunk.choices {
+                let (content, finish_reason, logprobs) = contents.entry(choice.index).or_default();
+                if let Some(delta) = choice.delta.content {
+                    content.push_str(&delta);
+                }
+                if choice.finish_reason.is_some() {
+                    *finish_reason = choice.finish_reason;
+                }
+                if let Some(delta) = choice.logprobs {
+                    logprobs.get_or_insert_with(Logprobs::default).extend(delta);
+                }
             }
-            _ => panic!("Expected User message"),
         }
+        response.choices = contents
+            .into_iter()
+            .map(|(index, (content, finish_reason, logprobs))| Choice {
+                index,
+                message: Message::Assistant {
+                    content: Some(Content::Text(content)),
+                    name: None,
+                    audio: None,
+                    tool_calls: None,
+                    extra: HashMap::new(),
+                },
+                finish_reason: finish_reason.unwrap_or_default(),
+                logprobs,
+            })
+            .collect();
+        Some(response)
+    }
 
-        // Serialize back to JSON and compare
-        let serialized =
-            serde_json::to_value(&request).expect("Failed to serialize ChatCompletionResponse");
-        assert_eq!(request_json, serialized);
+    // Replays a complete response as chunks, for clients that asked for a
+    // stream when the upstream could only answer in one piece. Each choice
+    // gets a chunk with its full content followed by one with its finish reason.
+    pub fn into_chunks(self) -> Vec<ChatCompletionChunk> {
+        let chunk = |choices| ChatCompletionChunk {
+            id: self.id.clone(),
+            choices,
+            created: self.created,
+            model: self.model.clone(),
+            service_tier: self.service_tier.clone(),
+            system_fingerprint: Some(self.system_fingerprint.clone()),
+            object: "chat.completion.chunk".to_string(),
+            usage: None,
+            extra: HashMap::new(),
+        };
+
+        let mut content_chunks = Vec::new();
+        let mut finish_chunks = Vec::new();
+        for choice in &self.choices {
+            let (content, extra) = match &choice.message {
+                Message::Assistant {
+                    content,
+                    audio,
+                    tool_calls,
+                    extra,
+                    ..
+                } => {
+                    let mut extra = extra.clone();
+                    if let Some(audio) = audio {
+                        extra.insert("audio".to_string(), serde_json::json!(audio));
+                    }
+                    // Tool call deltas are told apart by their index
+                    if let Some(tool_calls) = tool_calls {
+                        let deltas = tool_calls
+                            .iter()
+                            .enumerate()
+                            .map(|(index, call)| {
+                                let mut delta = serde_json::json!(call);
+                                delta["index"] = serde_json::json!(index);
+                                delta
+                            })
+                            .collect();
+                        extra.insert("tool_calls".to_string(), Value::Array(deltas));
+                    }
+                    (content.as_ref(), extra)
+                }
+                message => (message.content(), HashMap::new()),
+            };
+            let content = match content {
+                Some(Content::Text(text)) => Some(text.clone()),
+                _ => None,
+            };
+            content_chunks.push(chunk(vec![ChunkChoice {
+                index: choice.index,
+                delta: Delta {
+                    role: Some("assistant".to_string()),
+                    content,
+                    extra,
+                },
+                finish_reason: None,
+                logprobs: choice.logprobs.clone(),
+            }]));
+            finish_chunks.push(chunk(vec![ChunkChoice {
+                index: choice.index,
+                delta: Delta::default(),
+                finish_reason: Some(choice.finish_reason.clone()),
+                logprobs: None,
+            }]));
+        }
+        content_chunks.extend(finish_chunks);
+        content_chunks
     }
+}
 
-    #[test]
-    fn test_parse_chat_completion_response() {
-        let response_json = json!({
-            "id": "chatcmpl-123456",
-            "object": "chat.completion",
+// Adds up the token counts in two `*_tokens_details` objects
+fn add_token_details(details: &mut Value, other: &Value) {
+    let (Some(details), Some(other)) = (details.as_object_mut(), other.as_object()) else {
+        return;
+    };
+    for (key, value) in other {
+        let Some(count) = value.as_i64() else {
+            continue;
+        };
+        let total = details.get(key).and_then(Value::as_i64).unwrap_or(0) + count;
+        details.insert(key.clone(), Value::from(total));
+    }
+}
+
+// Whether the upstream is throttling: a 429, or no requests left in its window
+pub fn is_throttled(status: reqwest::StatusCode, headers: &HeaderMap) -> bool {
+    let remaining = ["x-ratelimit-remaining-requests", "x-ratelimit-remaining"]
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || remaining == Some(0)
+}
+
+// Whether the upstream actually answered with server-sent events
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"))
+}
+
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 10 * 1024 * 1024;
+
+// Joins `path` to the base URL, with or without a trailing slash
+fn endpoint(base_url: &str, path: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), path)
+}
+
+#[derive(Clone)]
+pub struct OpenAIClient {
+    client: reqwest::Client,
+    // Used in turn, one per upstream call, to spread load over their rate
+    // limits. Clones share the turn.
+    api_keys: Arc<Vec<String>>,
+    next_key: Arc<AtomicUsize>,
+    // OpenAI or any compatible API, e.g. Azure OpenAI, vLLM or Ollama
+    base_url: String,
+    max_response_bytes: usize,
+    retry_policy: RetryPolicy,
+    // Adaptive limits learn from every upstream response
+    rate_limiter: Option<Arc<RateLimiter>>,
+    api_versions: ApiVersions,
+    // Tells the upstream how long a request with a deadline has left
+    deadline_hint: Option<DeadlineHint>,
+    // Adapts the request body to the upstream's schema quirks
+    body_transform: Option<BodyTransform>,
+}
+
+impl OpenAIClient {
+    pub fn new(api_key: String) -> Self {
+        Self::with_base_url(api_key, OPENAI_BASE_URL)
+    }
+
+    // Client for another OpenAI compatible API, e.g. `http://localhost:8000/v1`
+    pub fn with_base_url(api_key: String, base_url: impl Into<String>) -> Self {
+        Self {
+            // Like `reqwest::Client::new`, only fails when TLS can't be initialized
+            client: ClientConfig::default()
+                .client()
+                .expect("Failed to build HTTP client"),
+            // An empty key is no key, requests go out unauthenticated
+            api_keys: Arc::new(
+                Some(api_key)
+                    .filter(|key| !key.is_empty())
+                    .into_iter()
+                    .collect(),
+            ),
+            next_key: Arc::default(),
+            base_url: base_url.into(),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            api_versions: ApiVersions::default(),
+            deadline_hint: None,
+            body_transform: None,
+        }
+    }
+
+    // Replaces the API key with several used round-robin
+    pub fn with_api_keys(mut self, api_keys: Vec<String>) -> Result<Self> {
+        if api_keys.is_empty() || api_keys.iter().any(|key| key.trim().is_empty()) {
+            return Err(anyhow::anyhow!("expected one or more non-empty API keys"));
+        }
+        self.api_keys = Arc::new(api_keys);
+        Ok(self)
+    }
+
+    fn next_key(&self) -> Option<&str> {
+        if self.api_keys.is_empty() {
+            return None;
+        }
+        let turn = self.next_key.fetch_add(1, Ordering::Relaxed);
+        Some(&self.api_keys[turn % self.api_keys.len()])
+    }
+
+    // Authenticates with the caller's key or else the next of the client's
+    // own, never with an empty bearer token
+    fn authorize(
+        &self,
+        builder: reqwest::RequestBuilder,
+        provider_key: Option<&ProviderKey>,
+    ) -> reqwest::RequestBuilder {
+        match provider_key
+            .map(ProviderKey::expose)
+            .or_else(|| self.next_key())
+        {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // Replaces the HTTP client with one using the given settings
+    pub fn with_client_config(mut self, config: ClientConfig) -> Result<Self> {
+        self.client = config.client()?;
+        Ok(self)
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn with_api_versions(mut self, api_versions: ApiVersions) -> Self {
+        self.api_versions = api_versions;
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // The same client for another deployment of the API, sharing connections
+    // and rate limits
+    pub fn deployment(&self, base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_deadline_hint(mut self, deadline_hint: DeadlineHint) -> Self {
+        self.deadline_hint = Some(deadline_hint);
+        self
+    }
+
+    pub fn with_body_transform(mut self, body_transform: BodyTransform) -> Self {
+        self.body_transform = Some(body_transform);
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    // Cheap authenticated call that establishes a pooled connection
+    pub async fn warmup(&self, base_url: &str) -> Result<()> {
+        let response = self
+            .authorize(self.client.get(endpoint(base_url, "models")), None)
+            .send()
+            .await?;
+        let status = response.status();
+        // Drain the body so the connection goes back to the pool
+        response.bytes().await?;
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("OpenAI API returned {}", status));
+        }
+        Ok(())
+    }
+
+    pub async fn chat(
+        &self,
+        request: OpenAIChatCompletionRequest,
+    ) -> Result<OpenAIChatCompletionResponse> {
+        self.chat_with_base_url(request, &self.base_url).await
+    }
+
+    // Sends the request to another OpenAI compatible API, e.g. `http://localhost:8000/v1`
+    pub async fn chat_with_base_url(
+        &self,
+        request: OpenAIChatCompletionRequest,
+        base_url: &str,
+    ) -> Result<OpenAIChatCompletionResponse> {
+        let response = self.send(&request, base_url).await?;
+        let body = read_body_capped(response, self.max_response_bytes).await?;
+        let mut response_body = serde_json::from_slice::<OpenAIChatCompletionResponse>(&body)?;
+        response_body.usage.complete();
+        Ok(response_body)
+    }
+
+    pub async fn chat_stream(&self, request: OpenAIChatCompletionRequest) -> Result<ChunkStream> {
+        self.chat_stream_with_base_url(request, &self.base_url)
+            .await
+    }
+
+    // Streams the completion as chunks parsed from the upstream's server-sent
+    // events, ending at `data: [DONE]`. Fails with `NotEventStream` when the
+    // upstream answers with anything else, and the stream ends with
+    // `ResponseTooLarge` once it has sent more than `max_response_bytes`.
+    pub async fn chat_stream_with_base_url(
+        &self,
+        mut request: OpenAIChatCompletionRequest,
+        base_url: &str,
+    ) -> Result<ChunkStream> {
+        request.stream = Some(true);
+        let response = self.send(&request, base_url).await?;
+        if !is_event_stream(response.headers()) {
+            return Err(NotEventStream.into());
+        }
+        let limit = self.max_response_bytes;
+        let reader = SseReader {
+            response,
+            decoder: SseDecoder::default(),
+            pending: VecDeque::new(),
+            read: 0,
+        };
+        let chunks = stream::unfold(Some(reader), move |reader| async move {
+            let mut reader = reader?;
+            loop {
+                if let Some(data) = reader.pending.pop_front() {
+                    if data == "[DONE]" {
+                        return None;
+                    }
+                    let chunk = serde_json::from_str::<ChatCompletionChunk>(&data)
+                        .map_err(|err| anyhow::anyhow!("Invalid stream chunk: {}: {}", err, data));
+                    return Some((chunk, Some(reader)));
+                }
+                match reader.response.chunk().await {
+                    Ok(Some(bytes)) => {
+                        reader.read += bytes.len();
+                        if reader.read > limit {
+                            return Some((Err(ResponseTooLarge { limit }.into()), None));
+                        }
+                        reader.pending.extend(reader.decoder.push(&bytes));
+                    }
+                    // The upstream hung up without `[DONE]`
+                    Ok(None) => return None,
+                    Err(err) => return Some((Err(timed_out(err, false)), None)),
+                }
+            }
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    // Sends the request, retrying per the retry policy, and returns the
+    // successful upstream response
+    async fn send(
+        &self,
+        request: &OpenAIChatCompletionRequest,
+        base_url: &str,
+    ) -> Result<reqwest::Response> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let url = endpoint(base_url, "chat/completions");
+        let mut body = serde_json::to_value(request)?;
+        if let Some(transform) = &self.body_transform {
+            transform.apply(&mut body);
+        }
+
+        let mut retries = 0;
+        loop {
+            let remaining = request.deadline.map(deadline::remaining).transpose()?;
+            // A retry goes out with the next key, which may not be throttled
+            let mut upstream_request = self.authorize(
+                self.client.post(&url).headers(headers.clone()),
+                request.provider_key.as_ref(),
+            );
+            upstream_request = match (remaining, &self.deadline_hint) {
+                (Some(remaining), Some(hint)) => hint.apply(remaining, &body, upstream_request),
+                _ => upstream_request.json(&body),
+            };
+            // Without a hint only the local timeout applies
+            if let Some(remaining) = remaining {
+                upstream_request = upstream_request.timeout(remaining);
+            }
+            request.attempts.fetch_add(1, Ordering::Relaxed);
+            let response = self
+                .api_versions
+                .apply(&request.model, upstream_request)
+                .send()
+                .await
+                .map_err(|err| timed_out(err, remaining.is_some()))?;
+
+            let status = response.status();
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.observe(&request.model, is_throttled(status, response.headers()));
+            }
+            if !status.is_success() {
+                let retry_after = retry::retry_after(response.headers());
+                let error_body = read_body_capped(response, self.max_response_bytes).await?;
+                let delay = self.retry_policy.delay(retries + 1, retry_after);
+                // No point waiting for a retry the deadline cuts off anyway
+                let in_time = request
+                    .deadline
+                    .is_none_or(|deadline| Instant::now() + delay < deadline);
+                if retries < self.retry_policy.max_retries
+                    && self.retry_policy.is_retryable(status, &error_body)
+                    && in_time
+                {
+                    retries += 1;
+                    tracing::warn!(
+                        status = status.as_u16(),
+                        delay_ms = delay.as_millis() as u64,
+                        retry = retries,
+                        max_retries = self.retry_policy.max_retries,
+                        "OpenAI API failed, retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                return Err(OpenAIError {
+                    status,
+                    body: String::from_utf8_lossy(&error_body).into_owned(),
+                }
+                .into());
+            }
+            return Ok(response);
+        }
+    }
+
+    // Embeds the input with the upstream's embeddings endpoint, failing with
+    // `OpenAIError` when it answers with an error status
+    pub async fn embeddings(
+        &self,
+        request: &EmbeddingsRequest,
+        base_url: &str,
+    ) -> Result<EmbeddingsResponse> {
+        let response = self
+            .authorize(
+                self.client.post(endpoint(base_url, "embeddings")),
+                request.provider_key.as_ref(),
+            )
+            .json(request)
+            .send()
+            .await
+            .map_err(|err| timed_out(err, false))?;
+        let status = response.status();
+        let body = read_body_capped(response, self.max_response_bytes).await?;
+        if !status.is_success() {
+            return Err(OpenAIError {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            }
+            .into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    // Fetches a completion created with `store: true`, returning the upstream
+    // status and body as is.
+    pub async fn retrieve_completion(
+        &self,
+        id: &str,
+        base_url: &str,
+    ) -> Result<(reqwest::StatusCode, Vec<u8>)> {
+        let mut url = reqwest::Url::parse(&endpoint(base_url, "chat/completions"))?;
+        // Pushed as a segment so the id can't escape the path
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid base URL: {}", base_url))?
+            .push(id);
+        let response = self.authorize(self.client.get(url), None).send().await?;
+        let status = response.status();
+        let body = read_body_capped(response, self.max_response_bytes).await?;
+        Ok((status, body))
+    }
+}
+
+pub type ChunkStream = Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk>> + Send>>;
+
+struct SseReader {
+    response: reqwest::Response,
+    decoder: SseDecoder,
+    // Event data decoded but not yet parsed
+    pending: VecDeque<String>,
+    read: usize,
+}
+
+// The upstream answered a streaming request with something other than
+// server-sent events, usually a complete JSON response
+#[derive(Debug)]
+pub struct NotEventStream;
+
+impl fmt::Display for NotEventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream did not answer with an event stream")
+    }
+}
+
+impl std::error::Error for NotEventStream {}
+
+// The upstream didn't answer within the client's request or connect timeout
+#[derive(Debug)]
+pub struct UpstreamTimeout;
+
+impl fmt::Display for UpstreamTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream did not answer in time")
+    }
+}
+
+impl std::error::Error for UpstreamTimeout {}
+
+// Tells timeouts apart from other transport errors. A request with a deadline
+// times out when the deadline passes.
+fn timed_out(err: reqwest::Error, deadline: bool) -> anyhow::Error {
+    match (err.is_timeout(), deadline) {
+        (true, true) => DeadlineExceeded.into(),
+        (true, false) => UpstreamTimeout.into(),
+        (false, _) => err.into(),
+    }
+}
+
+// The upstream answered with an error status, kept so it can be relayed as is
+#[derive(Debug)]
+pub struct OpenAIError {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+}
+
+impl fmt::Display for OpenAIError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OpenAI API error: {}", self.body)
+    }
+}
+
+impl std::error::Error for OpenAIError {}
+
+#[derive(Debug)]
+pub struct ResponseTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for ResponseTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Upstream response exceeded the maximum size of {} bytes",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+// Buffers the response body, giving up as soon as it grows past `limit` so a
+// misbehaving upstream can't exhaust memory.
+pub(crate) async fn read_body_capped(
+    mut response: reqwest::Response,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Err(ResponseTooLarge { limit }.into());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|err| timed_out(err, false))?
+    {
+        if body.len() + chunk.len() > limit {
+            return Err(ResponseTooLarge { limit }.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+// The key the caller sent for the upstream, or else the provider's own
+pub(crate) fn api_key<'a>(request: &'a OpenAIChatCompletionRequest, own: &'a str) -> &'a str {
+    request
+        .provider_key
+        .as_ref()
+        .map_or(own, ProviderKey::expose)
+}
+
+// Single choice response of a provider translated from another API
+pub(crate) fn completion(
+    id: String,
+    model: &str,
+    content: String,
+    finish_reason: &str,
+    prompt_tokens: i32,
+    completion_tokens: i32,
+) -> OpenAIChatCompletionResponse {
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default();
+    OpenAIChatCompletionResponse {
+        id,
+        choices: vec![Choice {
+            index: 0,
+            message: Message::Assistant {
+                content: Some(Content::Text(content)),
+                name: None,
+                audio: None,
+                tool_calls: None,
+                extra: HashMap::new(),
+            },
+            finish_reason: finish_reason.to_string(),
+            logprobs: None,
+        }],
+        created,
+        model: model.to_string(),
+        service_tier: None,
+        system_fingerprint: String::new(),
+        object: "chat.completion".to_string(),
+        usage: Usage {
+            completion_tokens,
+            prompt_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            completion_tokens_details: json!({}),
+            prompt_tokens_details: json!({}),
+        },
+        prompt_filter_results: None,
+        kubellm_extra: None,
+    }
+}
+
+// Adds the tool calls of a translated response to its message, which has no
+// content when the provider answered with tool calls only
+pub(crate) fn with_tool_calls(
+    mut response: OpenAIChatCompletionResponse,
+    calls: Vec<ToolCall>,
+) -> OpenAIChatCompletionResponse {
+    if calls.is_empty() {
+        return response;
+    }
+    if let Some(Choice {
+        message:
+            Message::Assistant {
+                content,
+                tool_calls,
+                ..
+            },
+        ..
+    }) = response.choices.first_mut()
+    {
+        if content
+            .as_ref()
+            .is_some_and(|content| matches!(content, Content::Text(text) if text.is_empty()))
+        {
+            *content = None;
+        }
+        *tool_calls = Some(calls);
+    }
+    response
+}
+
+// The arguments of a tool call, which are a JSON object in a string
+pub(crate) fn tool_arguments(call: &ToolCall) -> Result<Value> {
+    serde_json::from_str(&call.function.arguments).map_err(|err| {
+        anyhow::anyhow!(
+            "Arguments of tool call {} are not valid JSON: {}",
+            call.id,
+            err
+        )
+    })
+}
+
+impl Default for OpenAIChatCompletionRequest {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4o-mini".to_string(), // Default model
+            messages: Vec::new(),             // Empty messages vector
+            temperature: None,
+            top_p: None,
+            n: None,
+            stop: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            max_tokens: None,
+            max_completion_tokens: None,
+            stream: None,
+            user: None,
+            store: None,
+            safety_identifier: None,
+            prompt_cache_key: None,
+            service_tier: None,
+            tools: None,
+            tool_choice: None,
+            extra: None,
+            deadline: None,
+            session: None,
+            provider_key: None,
+            attempts: Arc::default(),
+        }
+    }
+}
+
+impl OpenAIChatCompletionRequest {
+    pub fn new(model: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            ..Default::default()
+        }
+    }
+
+    // For building requests from known roles, panics on any other role
+    pub fn with_message(mut self, role: impl Into<String>, content: impl Into<String>) -> Self {
+        let message = Message::try_new(role, content).unwrap_or_else(|err| panic!("{}", err));
+        self.messages.push(message);
+        self
+    }
+
+    // Checks what OpenAI would reject anyway, so such requests fail without a
+    // round trip. Returns every problem, not just the first.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        if self.messages.is_empty() {
+            errors.push(ValidationError::new(
+                "messages",
+                "'messages' must contain at least one message",
+            ));
+        }
+        let ranges = [
+            ("temperature", self.temperature, 2.0),
+            ("top_p", self.top_p, 1.0),
+        ];
+        for (param, value, max) in ranges {
+            if let Some(value) = value.filter(|value| !(0.0..=max).contains(value)) {
+                errors.push(ValidationError::new(
+                    param,
+                    format!("'{}' must be between 0 and {}, got {}", param, max, value),
+                ));
+            }
+        }
+        let limits = [
+            ("max_tokens", self.max_tokens),
+            ("max_completion_tokens", self.max_completion_tokens),
+        ];
+        for (param, value) in limits {
+            if let Some(value) = value.filter(|value| *value < 0) {
+                errors.push(ValidationError::new(
+                    param,
+                    format!("'{}' must not be negative, got {}", param, value),
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Message {
+    // A text message of a role that takes nothing else, `system`,
+    // `developer`, `user` or `assistant`
+    pub fn try_new(
+        role: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Result<Self, InvalidRole> {
+        let role = role.into();
+        let message = match role.as_str() {
+            "user" => Message::User {
+                content: Content::Text(content.into()),
+                name: None,
+            },
+            "system" => Message::System {
+                content: Content::Text(content.into()),
+                name: None,
+            },
+            "assistant" => Message::Assistant {
+                content: Some(Content::Text(content.into())),
+                name: None,
+                audio: None,
+                tool_calls: None,
+                extra: HashMap::new(),
+            },
+            "developer" => Message::Developer {
+                content: Content::Text(content.into()),
+                name: None,
+            },
+            _ => return Err(InvalidRole(role)),
+        };
+        Ok(message)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidRole(pub String);
+
+impl fmt::Display for InvalidRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid role: {}, expected system, developer, user or assistant",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidRole {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use axum::response::IntoResponse;
+    use futures_util::StreamExt;
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    #[test]
+    fn test_parse_chat_completion_request() {
+        let request_json = json!({
+            "model": "gpt-4o",
+            "messages": [
+                {
+                    "role": "developer",
+                    "content": "You are a helpful assistant."
+                },
+                {
+                    "role": "user",
+                    "content": "Hello!"
+                }
+            ]
+        });
+
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(request_json.clone())
+            .expect("Failed to parse ChatCompletionRequest");
+
+        assert_eq!(request.model, "gpt-4o");
+        assert_eq!(request.messages.len(), 2);
+
+        match &request.messages[0] {
+            Message::Developer { content, .. } => {
+                assert_eq!(
+                    content,
+                    &Content::Text("You are a helpful assistant.".to_string())
+                );
+            }
+            _ => panic!("Expected Developer message"),
+        }
+
+        match &request.messages[1] {
+            Message::User { content, .. } => {
+                assert_eq!(content, &Content::Text("Hello!".to_string()));
+            }
+            _ => panic!("Expected User message"),
+        }
+
+        // Serialize back to JSON and compare
+        let serialized =
+            serde_json::to_value(&request).expect("Failed to serialize ChatCompletionResponse");
+        assert_eq!(request_json, serialized);
+    }
+
+    #[test]
+    fn test_model_list_round_trip() {
+        let body = json!({
+            "object": "list",
+            "data": [{
+                "id": "gpt-4o",
+                "object": "model",
+                "created": 1715367049,
+                "owned_by": "system"
+            }]
+        });
+
+        let list: ModelList = serde_json::from_value(body.clone()).unwrap();
+
+        assert_eq!(list.data[0].id, "gpt-4o");
+        assert_eq!(list.data[0].created, 1715367049);
+        assert_eq!(serde_json::to_value(&list).unwrap(), body);
+        assert_eq!(
+            serde_json::to_value(ModelList::new(vec![ModelObject::new("gpt-4o", "openai")]))
+                .unwrap()["data"][0],
+            json!({"id": "gpt-4o", "object": "model", "created": 0, "owned_by": "openai"})
+        );
+    }
+
+    #[test]
+    fn test_safety_identifier_and_prompt_cache_key() {
+        let request_json = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hello!"}],
+            "safety_identifier": "user-1234",
+            "prompt_cache_key": "support-bot"
+        });
+
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(request_json.clone())
+            .expect("Failed to parse ChatCompletionRequest");
+
+        assert_eq!(request.safety_identifier.as_deref(), Some("user-1234"));
+        assert_eq!(request.prompt_cache_key.as_deref(), Some("support-bot"));
+        assert_eq!(request.extra, Some(HashMap::new()));
+        assert_eq!(serde_json::to_value(&request).unwrap(), request_json);
+
+        // Unset fields are not sent upstream
+        let serialized = serde_json::to_value(OpenAIChatCompletionRequest::new("gpt-4o")).unwrap();
+        assert!(serialized.get("safety_identifier").is_none());
+        assert!(serialized.get("prompt_cache_key").is_none());
+    }
+
+    #[test]
+    fn test_is_refusal() {
+        let mut response = mock::completion("gpt-4o", "Hi");
+        assert!(!response.is_refusal());
+
+        response.choices[0].finish_reason = "content_filter".to_string();
+        assert!(response.is_refusal());
+
+        let mut refusal = mock::completion_json("gpt-4o", "Hi");
+        refusal["choices"][0]["message"]["refusal"] = json!("I can't help with that.");
+        let refusal: OpenAIChatCompletionResponse = serde_json::from_value(refusal).unwrap();
+        assert!(refusal.is_refusal());
+    }
+
+    #[test]
+    fn test_missing_total_tokens_is_computed() {
+        let mut response = mock::completion_json("gpt-4o", "Hi");
+        response["usage"] = json!({"prompt_tokens": 9, "completion_tokens": 12});
+        let mut response: OpenAIChatCompletionResponse = serde_json::from_value(response).unwrap();
+
+        response.usage.complete();
+
+        assert_eq!(response.usage.total_tokens, 21);
+        let usage = serde_json::to_value(&response.usage).unwrap();
+        assert_eq!(
+            usage,
+            json!({"prompt_tokens": 9, "completion_tokens": 12, "total_tokens": 21})
+        );
+    }
+
+    #[test]
+    fn test_missing_completion_tokens_is_computed() {
+        let mut usage: Usage =
+            serde_json::from_value(json!({"prompt_tokens": 9, "total_tokens": 21})).unwrap();
+
+        usage.complete();
+
+        assert_eq!(usage.completion_tokens, 12);
+    }
+
+    #[test]
+    fn test_assistant_audio_reference() {
+        let mut response = mock::completion_json("gpt-4o-audio-preview", "");
+        response["choices"][0]["message"] = json!({
+            "role": "assistant",
+            "content": null,
+            "audio": {
+                "id": "audio_abc123",
+                "expires_at": 1729018505,
+                "data": "UklGRg==",
+                "transcript": "Yes, golden retrievers are known to be friendly."
+            }
+        });
+        let response: OpenAIChatCompletionResponse = serde_json::from_value(response).unwrap();
+        let Message::Assistant {
+            audio: Some(audio), ..
+        } = &response.choices[0].message
+        else {
+            panic!("Expected assistant audio");
+        };
+        assert_eq!(audio.id, "audio_abc123");
+        assert_eq!(audio.extra["expires_at"], 1729018505);
+
+        // The next turn references the audio by id only
+        let request_json = json!({
+            "model": "gpt-4o-audio-preview",
+            "modalities": ["text", "audio"],
+            "messages": [
+                {"role": "user", "content": "Are golden retrievers friendly?"},
+                {"role": "assistant", "audio": {"id": audio.id}},
+                {"role": "user", "content": "Why is that?"}
+            ]
+        });
+        let request: OpenAIChatCompletionRequest =
+            serde_json::from_value(request_json.clone()).unwrap();
+        assert!(matches!(
+            &request.messages[1],
+            Message::Assistant { audio: Some(audio), content: None, .. } if audio.id == "audio_abc123"
+        ));
+        assert_eq!(serde_json::to_value(&request).unwrap(), request_json);
+    }
+
+    #[test]
+    fn test_tool_calls_round_trip() {
+        let response_json = json!({
+            "id": "chatcmpl-abc123",
+            "object": "chat.completion",
+            "created": 1699896916,
+            "model": "gpt-4o-mini-2024-07-18",
+            "system_fingerprint": "fp_0ba0d124f1",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_abc123",
+                        "type": "function",
+                        "function": {
+                            "name": "get_current_weather",
+                            "arguments": "{\n\"location\": \"Boston, MA\"\n}"
+                        }
+                    }],
+                    "refusal": null
+                },
+                "logprobs": null,
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 82, "completion_tokens": 17, "total_tokens": 99}
+        });
+
+        let response: OpenAIChatCompletionResponse =
+            serde_json::from_value(response_json.clone()).unwrap();
+        let Message::Assistant {
+            tool_calls: Some(tool_calls),
+            extra,
+            ..
+        } = &response.choices[0].message
+        else {
+            panic!("Expected assistant tool calls");
+        };
+        assert_eq!(
+            tool_calls[0],
+            ToolCall {
+                id: "call_abc123".to_string(),
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name: "get_current_weather".to_string(),
+                    arguments: "{\n\"location\": \"Boston, MA\"\n}".to_string(),
+                },
+            }
+        );
+        assert!(!extra.contains_key("tool_calls"));
+
+        // A null content is left out, which the API reads the same
+        let mut expected = response_json;
+        expected["choices"][0]["message"]
+            .as_object_mut()
+            .unwrap()
+            .remove("content");
+        assert_eq!(serde_json::to_value(&response).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_service_tier_round_trip() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "service_tier": "scale"
+        }))
+        .unwrap();
+        assert_eq!(request.service_tier, Some(ServiceTier::Scale));
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["service_tier"],
+            "scale"
+        );
+
+        let mut response = mock::completion_json("gpt-4o", "Hi");
+        response["service_tier"] = json!("turbo");
+        let response: OpenAIChatCompletionResponse = serde_json::from_value(response).unwrap();
+        assert_eq!(
+            response.service_tier,
+            Some(ServiceTier::Other("turbo".to_string()))
+        );
+        assert_eq!(
+            serde_json::to_value(&response).unwrap()["service_tier"],
+            "turbo"
+        );
+    }
+
+    fn params(errors: Vec<ValidationError>) -> Vec<String> {
+        errors.into_iter().map(|error| error.param).collect()
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_request() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        request.temperature = Some(2.0);
+        request.top_p = Some(0.0);
+        request.max_tokens = Some(0);
+
+        assert_eq!(request.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_messages() {
+        let request = OpenAIChatCompletionRequest::new("gpt-4o");
+
+        assert_eq!(params(request.validate().unwrap_err()), vec!["messages"]);
+    }
+
+    #[test]
+    fn test_validate_rejects_temperature_out_of_range() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        request.temperature = Some(2.5);
+
+        let errors = request.validate().unwrap_err();
+
+        assert_eq!(errors[0].param, "temperature");
+        assert_eq!(
+            errors[0].message,
+            "'temperature' must be between 0 and 2, got 2.5"
+        );
+        request.temperature = Some(-0.5);
+        assert_eq!(params(request.validate().unwrap_err()), vec!["temperature"]);
+    }
+
+    #[test]
+    fn test_validate_rejects_top_p_out_of_range() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        request.top_p = Some(1.5);
+
+        assert_eq!(params(request.validate().unwrap_err()), vec!["top_p"]);
+        request.top_p = Some(-0.1);
+        assert_eq!(params(request.validate().unwrap_err()), vec!["top_p"]);
+    }
+
+    #[test]
+    fn test_validate_rejects_negative_token_limits() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+        request.max_tokens = Some(-1);
+        request.max_completion_tokens = Some(-10);
+
+        // Every problem is reported
+        assert_eq!(
+            params(request.validate().unwrap_err()),
+            vec!["messages", "max_tokens", "max_completion_tokens"]
+        );
+    }
+
+    #[test]
+    fn test_sampling_fields_round_trip() {
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "top_p": 0.5,
+            "n": 2,
+            "stop": ["END", "\n\n"],
+            "presence_penalty": 0.25,
+            "frequency_penalty": -1.5
+        });
+
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(body.clone()).unwrap();
+
+        assert_eq!(request.top_p, Some(0.5));
+        assert_eq!(request.n, Some(2));
+        assert_eq!(
+            request.stop,
+            Some(StopSequence::Multiple(vec![
+                "END".to_string(),
+                "\n\n".to_string()
+            ]))
+        );
+        assert_eq!(request.presence_penalty, Some(0.25));
+        assert_eq!(request.frequency_penalty, Some(-1.5));
+        assert!(request.extra.as_ref().is_none_or(|extra| extra.is_empty()));
+        assert_eq!(serde_json::to_value(&request).unwrap(), body);
+
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "stop": "END"
+        }))
+        .unwrap();
+        assert_eq!(request.stop, Some(StopSequence::Single("END".to_string())));
+        assert_eq!(serde_json::to_value(&request).unwrap()["stop"], "END");
+    }
+
+    #[test]
+    fn test_embeddings_request_round_trip() {
+        let single = json!({"model": "text-embedding-3-small", "input": "Hello"});
+        let batch = json!({
+            "model": "text-embedding-3-small",
+            "input": ["Hello", "World"],
+            "encoding_format": "float",
+            "dimensions": 256
+        });
+
+        let request: EmbeddingsRequest = serde_json::from_value(single.clone()).unwrap();
+        assert_eq!(request.input, StringOrArray::String("Hello".to_string()));
+        assert_eq!(serde_json::to_value(&request).unwrap(), single);
+
+        let request: EmbeddingsRequest = serde_json::from_value(batch.clone()).unwrap();
+        assert_eq!(
+            request.input,
+            StringOrArray::Array(vec!["Hello".to_string(), "World".to_string()])
+        );
+        assert_eq!(request.dimensions, Some(256));
+        assert_eq!(serde_json::to_value(&request).unwrap(), batch);
+    }
+
+    #[test]
+    fn test_embeddings_response_round_trip() {
+        let response_json = json!({
+            "object": "list",
+            "data": [{"object": "embedding", "embedding": [0.1, -0.2], "index": 0}],
+            "model": "text-embedding-3-small",
+            "usage": {"prompt_tokens": 1, "total_tokens": 1}
+        });
+
+        let response: EmbeddingsResponse = serde_json::from_value(response_json.clone()).unwrap();
+
+        assert_eq!(response.data[0].embedding, json!([0.1, -0.2]));
+        assert_eq!(serde_json::to_value(&response).unwrap(), response_json);
+    }
+
+    #[test]
+    fn test_tools_round_trip() {
+        let request_json = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "What's the weather in Boston?"}],
+            "tools": [{
+                "type": "function",
+                "function": {
+                    "name": "get_current_weather",
+                    "description": "Get the current weather in a given location",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "location": {"type": "string", "description": "The city and state"},
+                            "unit": {"type": "string", "enum": ["celsius", "fahrenheit"]}
+                        },
+                        "required": ["location"]
+                    }
+                }
+            }],
+            "tool_choice": {"type": "function", "function": {"name": "get_current_weather"}}
+        });
+
+        let request: OpenAIChatCompletionRequest =
+            serde_json::from_value(request_json.clone()).unwrap();
+
+        let tools = request.tools.as_ref().unwrap();
+        assert_eq!(tools[0].kind, "function");
+        assert_eq!(tools[0].function.name, "get_current_weather");
+        assert_eq!(
+            tools[0].function.parameters["required"],
+            json!(["location"])
+        );
+        assert_eq!(
+            request.tool_choice,
+            Some(ToolChoice::function("get_current_weather"))
+        );
+        assert!(request.extra.as_ref().is_none_or(|extra| extra.is_empty()));
+        assert_eq!(serde_json::to_value(&request).unwrap(), request_json);
+
+        let request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        let body = serde_json::to_value(&request).unwrap();
+        assert!(body.get("tools").is_none());
+        assert!(body.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn test_content_text_joins_text_parts() {
+        let message: Message = serde_json::from_value(json!({
+            "role": "user",
+            "content": [
+                {"type": "text", "text": "What is in this image?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+                {"type": "text", "text": "Answer in one word."}
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            message.content_text(),
+            "What is in this image?\nAnswer in one word."
+        );
+    }
+
+    #[test]
+    fn test_content_text_without_content() {
+        let message: Message = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "get_weather", "arguments": "{}"}
+            }]
+        }))
+        .unwrap();
+
+        assert_eq!(message.content_text(), "");
+    }
+
+    #[test]
+    fn test_message_with_invalid_role() {
+        assert!(matches!(
+            Message::try_new("developer", "Be brief."),
+            Ok(Message::Developer { .. })
+        ));
+        assert_eq!(
+            Message::try_new("robot", "Beep").unwrap_err(),
+            InvalidRole("robot".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tool_message_round_trip() {
+        let message_json =
+            json!({"role": "tool", "content": "{\"temperature\": 22}", "tool_call_id": "call_abc"});
+
+        let message: Message = serde_json::from_value(message_json.clone()).unwrap();
+
+        assert!(matches!(
+            &message,
+            Message::Tool { tool_call_id, .. } if tool_call_id == "call_abc"
+        ));
+        assert_eq!(serde_json::to_value(&message).unwrap(), message_json);
+    }
+
+    #[test]
+    fn test_merge_choices() {
+        let mut first = mock::completion_json("claude-3-5-sonnet", "One");
+        first["usage"]["prompt_tokens_details"] = json!({"cached_tokens": 1});
+        let second = mock::completion_json("claude-3-5-sonnet", "Two");
+        let responses = [first, second]
+            .into_iter()
+            .map(|response| serde_json::from_value(response).unwrap())
+            .collect();
+
+        let merged = OpenAIChatCompletionResponse::merge_choices(responses).unwrap();
+
+        assert_eq!(merged.choices.len(), 2);
+        assert_eq!(merged.choices[1].index, 1);
+        assert_eq!(merged.choices[1].message.content_text(), "Two");
+        assert_eq!(merged.usage.total_tokens, 4);
+        assert_eq!(
+            merged.usage.prompt_tokens_details,
+            json!({"cached_tokens": 1})
+        );
+        assert!(OpenAIChatCompletionResponse::merge_choices(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_parse_chat_completion_response() {
+        let response_json = json!({
+            "id": "chatcmpl-123456",
+            "object": "chat.completion",
             "created": 1728933352,
             "model": "gpt-4o-2024-08-06",
             "choices": [
@@ -341,4 +2040,668 @@ mod tests {
             serde_json::to_value(&response).expect("Failed to serialize ChatCompletionResponse");
         assert_eq!(response_json, serialized);
     }
+
+    #[test]
+    fn test_parse_azure_chat_completion_response() {
+        let response_json = json!({
+            "id": "chatcmpl-AzureABC",
+            "object": "chat.completion",
+            "created": 1738944609,
+            "model": "gpt-4o-2024-08-06",
+            "prompt_filter_results": [
+                {
+                    "prompt_index": 0,
+                    "content_filter_results": {
+                        "hate": {"filtered": false, "severity": "safe"},
+                        "self_harm": {"filtered": false, "severity": "safe"},
+                        "sexual": {"filtered": false, "severity": "safe"},
+                        "violence": {"filtered": false, "severity": "safe"}
+                    }
+                }
+            ],
+            "choices": [
+                {
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": "Hello!"
+                    },
+                    "logprobs": null,
+                    "finish_reason": "stop"
+                }
+            ],
+            "usage": {
+                "prompt_tokens": 9,
+                "completion_tokens": 2,
+                "total_tokens": 11,
+                "prompt_tokens_details": {
+                    "cached_tokens": 0
+                },
+                "completion_tokens_details": {
+                    "reasoning_tokens": 0
+                }
+            },
+            "system_fingerprint": "fp_65792305e4"
+        });
+
+        let response: OpenAIChatCompletionResponse = serde_json::from_value(response_json.clone())
+            .expect("Failed to parse Azure ChatCompletionResponse");
+
+        let filter_results = response
+            .prompt_filter_results
+            .as_ref()
+            .expect("Expected prompt_filter_results");
+        assert_eq!(filter_results[0]["prompt_index"], 0);
+        assert_eq!(
+            filter_results[0]["content_filter_results"]["hate"]["filtered"],
+            false
+        );
+
+        // Serialize back to JSON and compare
+        let serialized =
+            serde_json::to_value(&response).expect("Failed to serialize ChatCompletionResponse");
+        assert_eq!(response_json, serialized);
+    }
+
+    #[tokio::test]
+    async fn test_read_body_capped() {
+        let response = reqwest::Response::from(axum::http::Response::new("x".repeat(16)));
+        let body = read_body_capped(response, 16)
+            .await
+            .expect("Body is within the limit");
+        assert_eq!(body.len(), 16);
+
+        let response = reqwest::Response::from(axum::http::Response::new("x".repeat(17)));
+        let err = read_body_capped(response, 16)
+            .await
+            .expect_err("Body exceeds the limit");
+        let too_large = err
+            .downcast_ref::<ResponseTooLarge>()
+            .expect("Expected ResponseTooLarge");
+        assert_eq!(too_large.limit, 16);
+    }
+
+    #[test]
+    fn test_is_event_stream() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_event_stream(&headers));
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(!is_event_stream(&headers));
+
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream; charset=utf-8"),
+        );
+        assert!(is_event_stream(&headers));
+    }
+
+    #[test]
+    fn test_response_into_chunks() {
+        let response: OpenAIChatCompletionResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-123456",
+            "object": "chat.completion",
+            "created": 1728933352,
+            "model": "gpt-4o-2024-08-06",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi there!", "refusal": null},
+                "logprobs": null,
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 19,
+                "completion_tokens": 10,
+                "total_tokens": 29,
+                "prompt_tokens_details": {},
+                "completion_tokens_details": {}
+            },
+            "system_fingerprint": "fp_6b68a8204b"
+        }))
+        .expect("Failed to parse ChatCompletionResponse");
+
+        let chunks = response.into_chunks();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            serde_json::to_value(&chunks[0]).unwrap(),
+            json!({
+                "id": "chatcmpl-123456",
+                "object": "chat.completion.chunk",
+                "created": 1728933352,
+                "model": "gpt-4o-2024-08-06",
+                "system_fingerprint": "fp_6b68a8204b",
+                "choices": [{
+                    "index": 0,
+                    "delta": {"role": "assistant", "content": "Hi there!", "refusal": null},
+                    "finish_reason": null
+                }]
+            })
+        );
+        assert_eq!(chunks[1].choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(chunks[1].choices[0].delta.content.is_none());
+    }
+
+    #[test]
+    fn test_chunk_logprobs_are_concatenated() {
+        let token = |token: &str, logprob: f64| {
+            json!({
+                "token": token,
+                "logprob": logprob,
+                "bytes": token.as_bytes(),
+                "top_logprobs": [{"token": token, "logprob": logprob, "bytes": token.as_bytes()}]
+            })
+        };
+        let chunk = |delta: Value, logprobs: Value, finish_reason: Value| {
+            serde_json::from_value::<ChatCompletionChunk>(json!({
+                "id": "chatcmpl-123",
+                "object": "chat.completion.chunk",
+                "created": 1728933352,
+                "model": "gpt-4o-mini",
+                "system_fingerprint": "fp_44709d6fcb",
+                "choices": [{
+                    "index": 0,
+                    "delta": delta,
+                    "logprobs": logprobs,
+                    "finish_reason": finish_reason
+                }]
+            }))
+            .unwrap()
+        };
+        let chunks = vec![
+            chunk(
+                json!({"role": "assistant", "content": ""}),
+                json!({"content": [], "refusal": null}),
+                Value::Null,
+            ),
+            chunk(
+                json!({"content": "Hello"}),
+                json!({"content": [token("Hello", -0.31)], "refusal": null}),
+                Value::Null,
+            ),
+            chunk(
+                json!({"content": " world"}),
+                json!({"content": [token(" world", -1.2)], "refusal": null}),
+                Value::Null,
+            ),
+            chunk(json!({}), Value::Null, json!("stop")),
+        ];
+        assert_eq!(
+            chunks[1].choices[0]
+                .logprobs
+                .as_ref()
+                .unwrap()
+                .content
+                .as_ref()
+                .unwrap()[0]
+                .top_logprobs[0]
+                .logprob,
+            -0.31
+        );
+
+        let response = OpenAIChatCompletionResponse::from_chunks(chunks).unwrap();
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.content_text(), "Hello world");
+        assert_eq!(choice.finish_reason, "stop");
+        assert_eq!(
+            serde_json::to_value(&choice.logprobs).unwrap(),
+            json!({"content": [token("Hello", -0.31), token(" world", -1.2)]})
+        );
+    }
+
+    // Fails with `code` on the first call and succeeds afterwards
+    async fn flaky_upstream(code: &'static str) -> (String, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        mock::upstream(move |request| {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                let error = json!({"error": {"message": "Try again", "type": "invalid_request_error", "code": code}});
+                (StatusCode::BAD_REQUEST, error)
+            } else {
+                let model = request["model"].as_str().unwrap();
+                (StatusCode::OK, mock::completion_json(model, "Hi"))
+            }
+        })
+        .await
+    }
+
+    fn retry_on(code: &str) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            retryable_codes: std::collections::HashSet::from([code.to_string()]),
+            base_delay: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_is_retried_after_retry_after() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(move || {
+                let attempt = counted.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt == 0 {
+                        let error = json!({"error": {"message": "Slow down", "type": "requests"}});
+                        let headers = [(reqwest::header::RETRY_AFTER, "1")];
+                        (StatusCode::TOO_MANY_REQUESTS, headers, axum::Json(error)).into_response()
+                    } else {
+                        axum::Json(mock::completion_json("gpt-4o", "Hi")).into_response()
+                    }
+                }
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        let started = Instant::now();
+
+        let response = OpenAIClient::new("sk-test".to_string())
+            .chat_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect("Retry succeeds");
+
+        assert_eq!(response.choices[0].message.content_text(), "Hi");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_fail_fast() {
+        for status in [StatusCode::BAD_REQUEST, StatusCode::UNAUTHORIZED] {
+            let (base_url, calls) = mock::upstream(move |_| {
+                let error = json!({"error": {"message": "Nope", "type": "invalid_request_error"}});
+                (status, error)
+            })
+            .await;
+
+            let err = OpenAIClient::new("sk-test".to_string())
+                .chat_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+                .await
+                .expect_err("Not retried");
+
+            assert!(err.to_string().contains("Nope"));
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configured_error_code_is_retried() {
+        let (base_url, calls) = flaky_upstream("context_busy").await;
+        let client =
+            OpenAIClient::new("sk-test".to_string()).with_retry_policy(retry_on("context_busy"));
+
+        let response = client
+            .chat_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect("Second attempt succeeds");
+
+        assert_eq!(response.model, "gpt-4o");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unconfigured_error_code_is_not_retried() {
+        let (base_url, calls) = flaky_upstream("invalid_value").await;
+        let client =
+            OpenAIClient::new("sk-test".to_string()).with_retry_policy(retry_on("context_busy"));
+
+        let err = client
+            .chat_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect_err("Error is not retryable");
+
+        assert!(err.to_string().contains("invalid_value"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_429_tightens_adaptive_limit() {
+        let (base_url, _) = mock::upstream(|_| {
+            let error = json!({"error": {"message": "Slow down", "type": "requests"}});
+            (StatusCode::TOO_MANY_REQUESTS, error)
+        })
+        .await;
+        let bounds = crate::rate_limit::AdaptiveBounds { min: 10, max: 100 };
+        let rate_limiter = Arc::new(
+            RateLimiter::new(HashMap::new())
+                .with_adaptive(HashMap::from([("gpt-4o".to_string(), bounds)])),
+        );
+        let client = OpenAIClient::new("sk-test".to_string())
+            .with_rate_limiter(rate_limiter.clone())
+            .with_retry_policy(RetryPolicy {
+                max_retries: 0,
+                ..Default::default()
+            });
+
+        client
+            .chat_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect_err("Throttled");
+
+        assert_eq!(rate_limiter.limit("gpt-4o"), Some(50));
+    }
+
+    #[tokio::test]
+    async fn test_pinned_api_version_is_sent() {
+        use crate::models::api_version::VersionLocation;
+        use axum::extract::RawQuery;
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(
+                move |RawQuery(query): RawQuery,
+                      headers: axum::http::HeaderMap,
+                      axum::Json(request): axum::Json<Value>| {
+                    let header = headers
+                        .get("openai-version")
+                        .map(|value| value.to_str().unwrap().to_string());
+                    recorded.lock().unwrap().push((query, header));
+                    let model = request["model"].as_str().unwrap().to_string();
+                    async move { axum::Json(mock::completion_json(&model, "Hi")) }
+                },
+            ),
+        );
+        let base_url = mock::spawn(app).await;
+        let pins = HashMap::from([("gpt-4o".to_string(), "2024-10-21".to_string())]);
+        let query = OpenAIClient::new("sk-test".to_string())
+            .with_api_versions(ApiVersions::new(pins.clone()));
+        let header = OpenAIClient::new("sk-test".to_string()).with_api_versions(
+            ApiVersions::new(pins)
+                .with_name("openai-version")
+                .with_location(VersionLocation::Header),
+        );
+
+        for (client, model) in [
+            (&query, "gpt-4o"),
+            (&query, "gpt-4o-mini"),
+            (&header, "gpt-4o"),
+        ] {
+            client
+                .chat_with_base_url(OpenAIChatCompletionRequest::new(model), &base_url)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (Some("api-version=2024-10-21".to_string()), None),
+                (None, None),
+                (None, Some("2024-10-21".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_parses_events_until_done() {
+        let chunks = vec![
+            mock::chunk_json("Hel", None),
+            mock::chunk_json("lo", Some("stop")),
+        ];
+        let (base_url, _) = mock::sse(chunks, std::time::Duration::ZERO).await;
+        let client = OpenAIClient::new("sk-test".to_string());
+
+        let chunks: Vec<ChatCompletionChunk> = client
+            .chat_stream_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect("Upstream streams")
+            .map(|chunk| chunk.expect("Chunk parses"))
+            .collect()
+            .await;
+
+        let content: Vec<_> = chunks
+            .iter()
+            .map(|chunk| chunk.choices[0].delta.content.clone().unwrap())
+            .collect();
+        assert_eq!(content, vec!["Hel", "lo"]);
+        assert_eq!(chunks[1].choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_rejects_json_response() {
+        let (base_url, _) = mock::openai("Hi").await;
+        let client = OpenAIClient::new("sk-test".to_string());
+
+        let err = client
+            .chat_stream_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .err()
+            .expect("A JSON response is not a stream");
+
+        assert!(err.is::<NotEventStream>());
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_is_capped() {
+        let chunks = vec![mock::chunk_json("Hi", None); 4];
+        let (base_url, _) = mock::sse(chunks, std::time::Duration::ZERO).await;
+        let client = OpenAIClient::new("sk-test".to_string()).with_max_response_bytes(300);
+
+        let mut chunks = client
+            .chat_stream_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect("Upstream streams");
+
+        let mut errors = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            if let Err(err) = chunk {
+                errors.push(err);
+            }
+        }
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].is::<ResponseTooLarge>());
+    }
+
+    // Answers with the bearer token it got, throttling `sk-throttled`
+    async fn key_echo() -> String {
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(|headers: reqwest::header::HeaderMap| async move {
+                let authorization = headers
+                    .get("authorization")
+                    .map_or("none", |value| value.to_str().unwrap())
+                    .to_string();
+                if authorization == "Bearer sk-throttled" {
+                    let error = json!({"error": {"message": "Rate limited", "type": "requests"}});
+                    return (StatusCode::TOO_MANY_REQUESTS, axum::Json(error));
+                }
+                (
+                    StatusCode::OK,
+                    axum::Json(mock::completion_json("gpt-4o", &authorization)),
+                )
+            }),
+        );
+        mock::spawn(app).await
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_rotate_across_calls() {
+        let base_url = key_echo().await;
+        let client = OpenAIClient::new("sk-unused".to_string())
+            .with_api_keys(vec!["sk-a".to_string(), "sk-b".to_string()])
+            .unwrap();
+
+        let mut keys = Vec::new();
+        for _ in 0..3 {
+            let request = OpenAIChatCompletionRequest::new("gpt-4o");
+            let response = client.chat_with_base_url(request, &base_url).await.unwrap();
+            keys.push(response.choices[0].message.content_text());
+        }
+
+        assert_eq!(keys, ["Bearer sk-a", "Bearer sk-b", "Bearer sk-a"]);
+    }
+
+    #[tokio::test]
+    async fn test_no_empty_bearer_token() {
+        assert!(OpenAIClient::new("sk-a".to_string())
+            .with_api_keys(Vec::new())
+            .is_err());
+        assert!(OpenAIClient::new("sk-a".to_string())
+            .with_api_keys(vec!["sk-b".to_string(), " ".to_string()])
+            .is_err());
+
+        let base_url = key_echo().await;
+        let client = OpenAIClient::new(String::new());
+        let request = OpenAIChatCompletionRequest::new("gpt-4o");
+
+        let response = client.chat_with_base_url(request, &base_url).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content_text(), "none");
+    }
+
+    #[tokio::test]
+    async fn test_throttled_key_is_retried_with_next_key() {
+        let base_url = key_echo().await;
+        let client = OpenAIClient::new("sk-unused".to_string())
+            .with_api_keys(vec!["sk-throttled".to_string(), "sk-b".to_string()])
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                max_retries: 1,
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            });
+        let request = OpenAIChatCompletionRequest::new("gpt-4o");
+
+        let response = client.chat_with_base_url(request, &base_url).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content_text(), "Bearer sk-b");
+    }
+
+    #[tokio::test]
+    async fn test_deadline_is_sent_as_timeout_field() {
+        let (base_url, _) = mock::upstream(|request| {
+            let content = request["timeout"].to_string();
+            (StatusCode::OK, mock::completion_json("gpt-4o", &content))
+        })
+        .await;
+        let client = OpenAIClient::new("sk-test".to_string())
+            .with_deadline_hint(DeadlineHint::Field("timeout".to_string()));
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+        request.deadline = Some(Instant::now() + std::time::Duration::from_secs(5));
+
+        let response = client.chat_with_base_url(request, &base_url).await.unwrap();
+
+        let timeout: u64 = response.choices[0].message.content_text().parse().unwrap();
+        assert!(timeout > 4000 && timeout <= 5000, "{}", timeout);
+    }
+
+    #[tokio::test]
+    async fn test_body_transform_rewrites_outbound_body() {
+        let sent = Arc::new(std::sync::Mutex::new(Value::Null));
+        let recorder = sent.clone();
+        let (base_url, _) = mock::upstream(move |request| {
+            *recorder.lock().unwrap() = request;
+            (StatusCode::OK, mock::completion_json("gpt-4o", "Hi"))
+        })
+        .await;
+        let transform = "drop:user;rename:max_tokens:max_completion_tokens"
+            .parse()
+            .unwrap();
+        let client = OpenAIClient::new("sk-test".to_string()).with_body_transform(transform);
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "max_tokens": 100,
+            "user": "user-1234"
+        }))
+        .unwrap();
+
+        client.chat_with_base_url(request, &base_url).await.unwrap();
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hi"}],
+                "max_completion_tokens": 100
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_upstream_exceeds_deadline() {
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(|| async {
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                axum::Json(mock::completion_json("gpt-4o", "Too late"))
+            }),
+        );
+        let base_url = mock::spawn(app).await;
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+        request.deadline = Some(Instant::now() + std::time::Duration::from_millis(100));
+
+        let err = OpenAIClient::new("sk-test".to_string())
+            .chat_with_base_url(request, &base_url)
+            .await
+            .expect_err("Upstream answers after the deadline");
+
+        assert!(err.is::<DeadlineExceeded>());
+    }
+
+    #[tokio::test]
+    async fn test_slow_upstream_times_out() {
+        let base_url = mock::slow("Too late", std::time::Duration::from_secs(5)).await;
+        let config = ClientConfig {
+            request_timeout: std::time::Duration::from_millis(100),
+            ..ClientConfig::default()
+        };
+
+        let err = OpenAIClient::new("sk-test".to_string())
+            .with_client_config(config)
+            .unwrap()
+            .chat_with_base_url(OpenAIChatCompletionRequest::new("gpt-4o"), &base_url)
+            .await
+            .expect_err("Upstream answers after the timeout");
+
+        assert!(err.is::<UpstreamTimeout>());
+    }
+
+    #[test]
+    fn test_endpoint_with_and_without_trailing_slash() {
+        assert_eq!(
+            endpoint("http://localhost:8000/v1", "chat/completions"),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        assert_eq!(
+            endpoint("http://localhost:8000/v1/", "chat/completions"),
+            "http://localhost:8000/v1/chat/completions"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_uses_configured_base_url() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let client = OpenAIClient::with_base_url("sk-test".to_string(), format!("{}/", base_url));
+
+        let response = client
+            .chat(OpenAIChatCompletionRequest::new("gpt-4o"))
+            .await
+            .expect("Configured upstream answers");
+
+        assert_eq!(response.choices[0].message.content_text(), "Hi");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_is_throttled() {
+        let mut headers = HeaderMap::new();
+        assert!(is_throttled(StatusCode::TOO_MANY_REQUESTS, &headers));
+        assert!(!is_throttled(StatusCode::OK, &headers));
+
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("0"),
+        );
+        assert!(is_throttled(StatusCode::OK, &headers));
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("12"),
+        );
+        assert!(!is_throttled(StatusCode::OK, &headers));
+    }
 }
@@ -0,0 +1,182 @@
+// Providers
+//
+// A provider answers OpenAI chat completion requests, translating them to its
+// own API where needed, so the handler can route a model to any backend.
+// Streaming, base URL overrides and stored completions are OpenAI only, the
+// `OpenAIClient` in `AppState` keeps serving those.
+use crate::models::anthropic::{AnthropicClient, ANTHROPIC_PROVIDER};
+use crate::models::bedrock::{BedrockClient, BEDROCK_SERVICE};
+use crate::models::echo::{EchoProvider, ECHO_PROVIDER};
+use crate::models::gemini::{GeminiClient, GEMINI_PROVIDER};
+use crate::models::openai::{
+    OpenAIChatCompletionRequest, OpenAIChatCompletionResponse, OpenAIClient, ServiceTier,
+};
+use anyhow::Result;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+
+pub const OPENAI_PROVIDER: &str = "openai";
+
+// Counts the call to a provider that answers in one attempt, the OpenAI
+// client counts its retries itself
+fn attempt(request: &OpenAIChatCompletionRequest) {
+    request.attempts.fetch_add(1, Ordering::Relaxed);
+}
+
+pub type ChatFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<OpenAIChatCompletionResponse>> + Send + 'a>>;
+
+pub type WarmupFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+pub trait Provider: Send + Sync {
+    // Reported in `x-kubellm-provider`, `/status` and metrics
+    fn name(&self) -> &str;
+
+    fn serves(&self, _model: &str) -> bool {
+        true
+    }
+
+    // Models it knows by name, listed by `GET /v1/models`
+    fn models(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Whether it takes `service_tier`, requests with a tier it doesn't take
+    // are sent without one
+    fn supports_service_tier(&self, _tier: &ServiceTier) -> bool {
+        false
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_>;
+
+    // Opens a pooled connection ahead of the first request, nothing to do for
+    // providers without an upstream
+    fn warmup(&self) -> WarmupFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Provider for OpenAIClient {
+    fn name(&self) -> &str {
+        OPENAI_PROVIDER
+    }
+
+    // Including tiers newer than `ServiceTier`, for OpenAI to judge
+    fn supports_service_tier(&self, _tier: &ServiceTier) -> bool {
+        true
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+        Box::pin(OpenAIClient::chat(self, request))
+    }
+
+    fn warmup(&self) -> WarmupFuture<'_> {
+        Box::pin(OpenAIClient::warmup(self, self.base_url()))
+    }
+}
+
+impl Provider for EchoProvider {
+    fn name(&self) -> &str {
+        ECHO_PROVIDER
+    }
+
+    fn serves(&self, model: &str) -> bool {
+        EchoProvider::serves(self, model)
+    }
+
+    fn models(&self) -> Vec<String> {
+        EchoProvider::models(self)
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+        attempt(&request);
+        let response = EchoProvider::chat(self, &request);
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+impl Provider for AnthropicClient {
+    fn name(&self) -> &str {
+        ANTHROPIC_PROVIDER
+    }
+
+    fn serves(&self, model: &str) -> bool {
+        model.starts_with("claude-")
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+        attempt(&request);
+        Box::pin(AnthropicClient::chat(self, request))
+    }
+
+    fn warmup(&self) -> WarmupFuture<'_> {
+        Box::pin(AnthropicClient::warmup(self))
+    }
+}
+
+impl Provider for BedrockClient {
+    fn name(&self) -> &str {
+        BEDROCK_SERVICE
+    }
+
+    fn serves(&self, model: &str) -> bool {
+        BedrockClient::serves(model)
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+        attempt(&request);
+        Box::pin(BedrockClient::chat(self, request))
+    }
+
+    fn warmup(&self) -> WarmupFuture<'_> {
+        Box::pin(BedrockClient::warmup(self))
+    }
+}
+
+impl Provider for GeminiClient {
+    fn name(&self) -> &str {
+        GEMINI_PROVIDER
+    }
+
+    fn serves(&self, model: &str) -> bool {
+        model.starts_with("gemini-")
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+        attempt(&request);
+        Box::pin(GeminiClient::chat(self, request))
+    }
+
+    fn warmup(&self) -> WarmupFuture<'_> {
+        Box::pin(GeminiClient::warmup(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[tokio::test]
+    async fn test_providers_behind_trait_objects() {
+        let (base_url, _) = mock::openai("Hello from OpenAI").await;
+        let providers: Vec<Box<dyn Provider>> = vec![
+            Box::new(EchoProvider::new(vec!["echo-model".to_string()])),
+            Box::new(OpenAIClient::with_base_url("sk-test".to_string(), base_url)),
+        ];
+        let request = |model: &str| {
+            OpenAIChatCompletionRequest::new(model).with_message("user", "Hello from echo")
+        };
+
+        for (model, name, content) in [
+            ("echo-model", ECHO_PROVIDER, "Hello from echo"),
+            ("gpt-4o", OPENAI_PROVIDER, "Hello from OpenAI"),
+        ] {
+            let provider = providers.iter().find(|p| p.serves(model)).unwrap();
+            assert_eq!(provider.name(), name);
+            let response = provider.chat(request(model)).await.unwrap();
+            assert_eq!(response.choices[0].message.content_text(), content);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Tool choice translation
+//
+// OpenAI's `tool_choice` is a mode string or a named function. Every provider
+// spells these choices differently, so translation layers render the parsed
+// OpenAI value in their provider's format from here, instead of passing the
+// OpenAI value through.
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// `"auto"`, `"none"`, `"required"` or
+// `{"type": "function", "function": {"name": ...}}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(FunctionChoice),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoiceMode {
+    // The model decides whether to call a tool
+    Auto,
+    None,
+    // The model must call at least one tool
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionChoice {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionName,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+impl ToolChoice {
+    pub fn function(name: impl Into<String>) -> Self {
+        ToolChoice::Function(FunctionChoice {
+            kind: "function".to_string(),
+            function: FunctionName { name: name.into() },
+        })
+    }
+
+    // Anthropic Messages `tool_choice`
+    pub fn to_anthropic(&self) -> Value {
+        match self {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => json!({"type": "auto"}),
+            ToolChoice::Mode(ToolChoiceMode::None) => json!({"type": "none"}),
+            ToolChoice::Mode(ToolChoiceMode::Required) => json!({"type": "any"}),
+            ToolChoice::Function(choice) => json!({"type": "tool", "name": choice.function.name}),
+        }
+    }
+
+    // Gemini `tool_config`
+    pub fn to_gemini(&self) -> Value {
+        let config = match self {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => json!({"mode": "AUTO"}),
+            ToolChoice::Mode(ToolChoiceMode::None) => json!({"mode": "NONE"}),
+            ToolChoice::Mode(ToolChoiceMode::Required) => json!({"mode": "ANY"}),
+            ToolChoice::Function(choice) => {
+                json!({"mode": "ANY", "allowed_function_names": [choice.function.name]})
+            }
+        };
+        json!({"function_calling_config": config})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anthropic(value: Value) -> Value {
+        serde_json::from_value::<ToolChoice>(value)
+            .unwrap()
+            .to_anthropic()
+    }
+
+    #[test]
+    fn test_auto_to_anthropic() {
+        assert_eq!(anthropic(json!("auto")), json!({"type": "auto"}));
+    }
+
+    #[test]
+    fn test_none_to_anthropic() {
+        assert_eq!(anthropic(json!("none")), json!({"type": "none"}));
+    }
+
+    #[test]
+    fn test_required_to_anthropic() {
+        assert_eq!(anthropic(json!("required")), json!({"type": "any"}));
+    }
+
+    #[test]
+    fn test_function_to_anthropic() {
+        let choice = json!({"type": "function", "function": {"name": "get_weather"}});
+        assert_eq!(
+            anthropic(choice),
+            json!({"type": "tool", "name": "get_weather"})
+        );
+    }
+
+    #[test]
+    fn test_to_gemini() {
+        assert_eq!(
+            ToolChoice::Mode(ToolChoiceMode::Required).to_gemini(),
+            json!({"function_calling_config": {"mode": "ANY"}})
+        );
+        assert_eq!(
+            ToolChoice::function("get_weather").to_gemini(),
+            json!({"function_calling_config": {"mode": "ANY", "allowed_function_names": ["get_weather"]}})
+        );
+    }
+
+    #[test]
+    fn test_invalid_tool_choice() {
+        let parse = |value: Value| serde_json::from_value::<ToolChoice>(value);
+
+        assert!(parse(json!("sometimes")).is_err());
+        assert!(parse(json!({"type": "function"})).is_err());
+        assert!(parse(json!(true)).is_err());
+    }
+
+    #[test]
+    fn test_tool_choice_round_trip() {
+        for value in [
+            json!("auto"),
+            json!("none"),
+            json!("required"),
+            json!({"type": "function", "function": {"name": "get_weather"}}),
+        ] {
+            let choice: ToolChoice = serde_json::from_value(value.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&choice).unwrap(), value);
+        }
+    }
+}
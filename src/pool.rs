@@ -0,0 +1,248 @@
+use crate::models::openai::{OpenAIChatCompletionRequest, ServiceTier};
+use crate::models::provider::{ChatFuture, Provider, WarmupFuture};
+use futures_util::future::join_all;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const POOL_PROVIDER: &str = "pool";
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(600);
+pub const DEFAULT_MAX_SESSIONS: usize = 100_000;
+pub const SESSION_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Load-balanced deployments
+//
+// Spreads requests round-robin over deployments serving the same models, such
+// as OpenAI compatible endpoints in several regions. Requests of a session,
+// named in `x-kubellm-session`, stay on the deployment that served the
+// session's first request, so a conversation doesn't switch deployments
+// halfway. A session idle for the TTL is rebalanced and later pruned, and past
+// the most sessions the least recently used one is forgotten.
+pub struct DeploymentPool {
+    deployments: Vec<Arc<dyn Provider>>,
+    next: AtomicUsize,
+    sessions: Mutex<Sessions>,
+    session_ttl: Duration,
+    max_sessions: usize,
+}
+
+#[derive(Default)]
+struct Sessions {
+    // Deployment, last use and recency of each session
+    by_name: HashMap<String, (usize, Instant, u64)>,
+    // Session names, least recently used first
+    recency: BTreeMap<u64, String>,
+    next: u64,
+}
+
+impl Sessions {
+    // Deployment of a session that's been used within the TTL
+    fn get(&self, name: &str, now: Instant, ttl: Duration) -> Option<usize> {
+        let (deployment, last_used, _) = self.by_name.get(name)?;
+        (now.duration_since(*last_used) < ttl).then_some(*deployment)
+    }
+
+    fn touch(&mut self, name: &str, deployment: usize, now: Instant) {
+        let recency = self.next;
+        self.next += 1;
+        if let Some((_, _, previous)) = self
+            .by_name
+            .insert(name.to_string(), (deployment, now, recency))
+        {
+            self.recency.remove(&previous);
+        }
+        self.recency.insert(recency, name.to_string());
+    }
+
+    // Forgets the least recently used sessions while `forget` holds for them
+    fn forget_while(&mut self, mut forget: impl FnMut(&(usize, Instant, u64), usize) -> bool) {
+        while let Some(entry) = self.recency.first_entry() {
+            let session = &self.by_name[entry.get()];
+            if !forget(session, self.by_name.len()) {
+                break;
+            }
+            let name = entry.remove();
+            self.by_name.remove(&name);
+        }
+    }
+}
+
+impl DeploymentPool {
+    pub fn new(deployments: Vec<Arc<dyn Provider>>) -> Self {
+        Self {
+            deployments,
+            next: AtomicUsize::new(0),
+            sessions: Mutex::new(Sessions::default()),
+            session_ttl: DEFAULT_SESSION_TTL,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+        }
+    }
+
+    pub fn with_session_ttl(mut self, session_ttl: Duration) -> Self {
+        self.session_ttl = session_ttl;
+        self
+    }
+
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    // Forgets sessions idle for the TTL
+    pub fn prune_sessions(&self) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.forget_while(|(_, last_used, _), _| {
+            now.duration_since(*last_used) >= self.session_ttl
+        });
+    }
+
+    // Prunes sessions every `period`, for as long as the pool is in use
+    pub async fn prune_sessions_every(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if Arc::strong_count(&self) == 1 {
+                return;
+            }
+            self.prune_sessions();
+        }
+    }
+
+    // Index of the deployment for the next request
+    fn pick(&self, session: Option<&str>) -> usize {
+        let Some(session) = session else {
+            return self.round_robin();
+        };
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let deployment = sessions
+            .get(session, now, self.session_ttl)
+            .unwrap_or_else(|| self.round_robin());
+        sessions.touch(session, deployment, now);
+        sessions.forget_while(|_, len| len > self.max_sessions);
+        deployment
+    }
+
+    fn round_robin(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.deployments.len()
+    }
+}
+
+impl Provider for DeploymentPool {
+    fn name(&self) -> &str {
+        POOL_PROVIDER
+    }
+
+    fn serves(&self, model: &str) -> bool {
+        self.deployments
+            .iter()
+            .any(|deployment| deployment.serves(model))
+    }
+
+    fn supports_service_tier(&self, tier: &ServiceTier) -> bool {
+        self.deployments
+            .iter()
+            .all(|deployment| deployment.supports_service_tier(tier))
+    }
+
+    fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+        let deployment = &self.deployments[self.pick(request.session.as_deref())];
+        deployment.chat(request)
+    }
+
+    // Every deployment, failing when any of them does
+    fn warmup(&self) -> WarmupFuture<'_> {
+        let warmups = self
+            .deployments
+            .iter()
+            .map(|deployment| deployment.warmup());
+        Box::pin(async move { join_all(warmups).await.into_iter().collect() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::echo::EchoProvider;
+
+    fn pool() -> DeploymentPool {
+        let deployment = |name: &str| -> Arc<dyn Provider> {
+            Arc::new(EchoProvider::new(vec!["*".to_string()]).with_reply(name))
+        };
+        DeploymentPool::new(vec![deployment("eu"), deployment("us")])
+    }
+
+    async fn served_by(pool: &DeploymentPool, session: Option<&str>) -> String {
+        let request = OpenAIChatCompletionRequest {
+            session: session.map(str::to_string),
+            ..OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi")
+        };
+        let response = pool.chat(request).await.unwrap();
+        response.choices[0].message.content_text()
+    }
+
+    fn session_count(pool: &DeploymentPool) -> usize {
+        pool.sessions.lock().unwrap().by_name.len()
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_balanced_round_robin() {
+        let pool = pool();
+
+        assert_eq!(served_by(&pool, None).await, "eu");
+        assert_eq!(served_by(&pool, None).await, "us");
+        assert_eq!(served_by(&pool, None).await, "eu");
+    }
+
+    #[tokio::test]
+    async fn test_session_sticks_to_deployment() {
+        let pool = pool();
+
+        let first = served_by(&pool, Some("conversation-1")).await;
+        assert_eq!(served_by(&pool, None).await, "us");
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, first);
+        assert_eq!(served_by(&pool, Some("conversation-2")).await, "eu");
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, first);
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_is_rebalanced() {
+        let pool = pool().with_session_ttl(Duration::ZERO);
+
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, "eu");
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, "us");
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_session_is_forgotten() {
+        let pool = pool().with_max_sessions(2);
+
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, "eu");
+        assert_eq!(served_by(&pool, Some("conversation-2")).await, "us");
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, "eu");
+        assert_eq!(served_by(&pool, Some("conversation-3")).await, "eu");
+        assert_eq!(session_count(&pool), 2);
+
+        // conversation-2 was forgotten, conversation-1 is kept
+        assert_eq!(served_by(&pool, Some("conversation-1")).await, "eu");
+        assert_eq!(served_by(&pool, Some("conversation-2")).await, "us");
+        assert_eq!(session_count(&pool), 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_are_pruned() {
+        let pool = pool().with_session_ttl(Duration::from_millis(20));
+        served_by(&pool, Some("conversation-1")).await;
+        served_by(&pool, Some("conversation-2")).await;
+
+        pool.prune_sessions();
+        assert_eq!(session_count(&pool), 2);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        pool.prune_sessions();
+        assert_eq!(session_count(&pool), 0);
+    }
+}
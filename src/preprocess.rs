@@ -0,0 +1,232 @@
+use crate::models::openai::{Content, Message, OpenAIChatCompletionRequest};
+use crate::validation::ValidationError;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+// Request preprocessing, applied before a request is routed upstream
+
+// Rewrites retired model names to their successor
+#[derive(Debug, Clone, Default)]
+pub struct DeprecatedModels {
+    successors: HashMap<String, String>,
+    warn: bool,
+}
+
+impl DeprecatedModels {
+    pub fn new(successors: HashMap<String, String>) -> Self {
+        Self {
+            successors,
+            warn: true,
+        }
+    }
+
+    pub fn with_warnings(mut self, warn: bool) -> Self {
+        self.warn = warn;
+        self
+    }
+
+    // Returns the original model name when the request was remapped
+    pub fn remap(&self, request: &mut OpenAIChatCompletionRequest) -> Option<String> {
+        let successor = self.successors.get(&request.model)?;
+        let deprecated = std::mem::replace(&mut request.model, successor.clone());
+        if self.warn {
+            tracing::warn!(
+                model = %deprecated,
+                successor = %request.model,
+                "Model is deprecated, using its successor"
+            );
+        }
+        Some(deprecated)
+    }
+}
+
+// Named prompt templates, expanded into messages ahead of the request's own.
+// A request picks one with `"template": {"name": ..., "variables": {...}}`,
+// and every `{{variable}}` in the template's messages is replaced.
+#[derive(Debug, Clone, Default)]
+pub struct PromptTemplates {
+    templates: HashMap<String, Vec<Message>>,
+}
+
+impl PromptTemplates {
+    pub fn new(templates: HashMap<String, Vec<Message>>) -> Self {
+        Self { templates }
+    }
+
+    // Returns the name of the expanded template, if the request used one
+    pub fn expand(
+        &self,
+        request: &mut OpenAIChatCompletionRequest,
+    ) -> Result<Option<String>, ValidationError> {
+        let Some(reference) = request
+            .extra
+            .as_mut()
+            .and_then(|extra| extra.remove("template"))
+        else {
+            return Ok(None);
+        };
+        if self.templates.is_empty() {
+            return Err(ValidationError::new(
+                "template",
+                "Prompt templates are not enabled",
+            ));
+        }
+        let name = reference
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| ValidationError::new("template.name", "Missing template name"))?;
+        let template = self.templates.get(name).ok_or_else(|| {
+            ValidationError::new("template.name", format!("Unknown template '{}'", name))
+        })?;
+        let no_variables = Map::new();
+        let variables = reference
+            .get("variables")
+            .and_then(Value::as_object)
+            .unwrap_or(&no_variables);
+
+        let mut messages = template.clone();
+        for message in &mut messages {
+            if let Some(Content::Text(text)) = content_mut(message) {
+                *text = render(text, variables).map_err(|variable| {
+                    ValidationError::new(
+                        "template.variables",
+                        format!("Missing variable '{}' for template '{}'", variable, name),
+                    )
+                })?;
+            }
+        }
+        messages.append(&mut request.messages);
+        request.messages = messages;
+        Ok(Some(name.to_string()))
+    }
+}
+
+fn content_mut(message: &mut Message) -> Option<&mut Content> {
+    match message {
+        Message::Developer { content, .. }
+        | Message::System { content, .. }
+        | Message::User { content, .. }
+        | Message::Tool { content, .. }
+        | Message::Function { content, .. } => Some(content),
+        Message::Assistant { content, .. } => content.as_mut(),
+    }
+}
+
+// Replaces `{{name}}` placeholders, returning the first missing variable
+fn render(template: &str, variables: &Map<String, Value>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        match variables.get(name) {
+            Some(Value::String(value)) => rendered.push_str(value),
+            Some(value) => rendered.push_str(&value.to_string()),
+            None => return Err(name.to_string()),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn deprecated() -> DeprecatedModels {
+        DeprecatedModels::new(HashMap::from([("gpt-4".to_string(), "gpt-4o".to_string())]))
+    }
+
+    #[test]
+    fn test_deprecated_model_is_remapped() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4").with_message("user", "Hi");
+
+        let original = deprecated().remap(&mut request);
+
+        assert_eq!(original.as_deref(), Some("gpt-4"));
+        assert_eq!(request.model, "gpt-4o");
+    }
+
+    #[test]
+    fn test_current_model_is_untouched() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o-mini");
+
+        assert_eq!(deprecated().with_warnings(false).remap(&mut request), None);
+        assert_eq!(request.model, "gpt-4o-mini");
+    }
+
+    fn templates() -> PromptTemplates {
+        PromptTemplates::new(HashMap::from([(
+            "support".to_string(),
+            vec![Message::try_new(
+                "system",
+                "You help customers of {{company}} with {{ product }}. Answer in {{language}}.",
+            )
+            .unwrap()],
+        )]))
+    }
+
+    fn templated_request(variables: Value) -> OpenAIChatCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "My order is late"}],
+            "template": {"name": "support", "variables": variables}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_template_expanded_into_messages() {
+        let mut request = templated_request(json!({
+            "company": "Acme",
+            "product": "rockets",
+            "language": "Dutch"
+        }));
+
+        let name = templates().expand(&mut request).unwrap();
+
+        assert_eq!(name.as_deref(), Some("support"));
+        let messages = serde_json::to_value(&request.messages).unwrap();
+        assert_eq!(
+            messages,
+            json!([
+                {"role": "system", "content": "You help customers of Acme with rockets. Answer in Dutch."},
+                {"role": "user", "content": "My order is late"}
+            ])
+        );
+        // The reference isn't sent upstream
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("template")
+            .is_none());
+    }
+
+    #[test]
+    fn test_template_missing_variable() {
+        let mut request = templated_request(json!({"company": "Acme"}));
+
+        let error = templates().expand(&mut request).unwrap_err();
+
+        assert_eq!(error.param, "template.variables");
+        assert_eq!(
+            error.message,
+            "Missing variable 'product' for template 'support'"
+        );
+    }
+
+    #[test]
+    fn test_templates_off_by_default() {
+        let mut request = templated_request(json!({}));
+
+        let error = PromptTemplates::default().expand(&mut request).unwrap_err();
+        assert_eq!(error.message, "Prompt templates are not enabled");
+
+        let mut plain = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hi");
+        assert_eq!(PromptTemplates::default().expand(&mut plain), Ok(None));
+    }
+}
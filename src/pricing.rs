@@ -0,0 +1,263 @@
+use crate::capabilities::{CapabilityTable, Requirements};
+use crate::models::openai::{OpenAIChatCompletionRequest, Usage};
+use crate::router::ModelRouter;
+use serde_json::Value;
+
+// Model name clients send to be routed to the cheapest capable candidate
+pub const CHEAPEST_MODEL: &str = "cheapest";
+
+// USD per million tokens. Audio tokens are billed at the text rate unless
+// the model has an audio rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+    pub audio_input: Option<f64>,
+    pub audio_output: Option<f64>,
+}
+
+impl Price {
+    pub fn new(input: f64, output: f64) -> Self {
+        Self {
+            input,
+            output,
+            audio_input: None,
+            audio_output: None,
+        }
+    }
+
+    pub fn with_audio(mut self, input: f64, output: f64) -> Self {
+        self.audio_input = Some(input);
+        self.audio_output = Some(output);
+        self
+    }
+
+    // USD for the tokens in `usage`, pricing the `audio_tokens` of the token
+    // details at the audio rate
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let audio_input = audio_tokens(&usage.prompt_tokens_details);
+        let audio_output = audio_tokens(&usage.completion_tokens_details);
+        let text_input = (usage.prompt_tokens as f64 - audio_input).max(0.0);
+        let text_output = (usage.completion_tokens as f64 - audio_output).max(0.0);
+        (text_input * self.input
+            + text_output * self.output
+            + audio_input * self.audio_input.unwrap_or(self.input)
+            + audio_output * self.audio_output.unwrap_or(self.output))
+            / 1_000_000.0
+    }
+}
+
+fn audio_tokens(details: &Value) -> f64 {
+    details
+        .get("audio_tokens")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0)
+}
+
+// Prices keyed by model prefix, the longest matching prefix wins
+#[derive(Debug, Clone)]
+pub struct PricingTable {
+    entries: Vec<(String, Price)>,
+}
+
+impl Default for PricingTable {
+    fn default() -> Self {
+        Self::new()
+            .with("gpt-3.5-turbo", Price::new(0.5, 1.5))
+            .with("gpt-4-turbo", Price::new(10.0, 30.0))
+            .with("gpt-4o", Price::new(2.5, 10.0))
+            .with("gpt-4o-mini", Price::new(0.15, 0.6))
+            .with(
+                "gpt-4o-audio-preview",
+                Price::new(2.5, 10.0).with_audio(40.0, 80.0),
+            )
+            .with(
+                "gpt-4o-mini-audio-preview",
+                Price::new(0.15, 0.6).with_audio(10.0, 20.0),
+            )
+            .with("gpt-4.1", Price::new(2.0, 8.0))
+            .with("gpt-4.1-mini", Price::new(0.4, 1.6))
+            .with("gpt-4.1-nano", Price::new(0.1, 0.4))
+            .with("o1", Price::new(15.0, 60.0))
+            .with("o1-mini", Price::new(1.1, 4.4))
+            .with("o3", Price::new(2.0, 8.0))
+            .with("o3-mini", Price::new(1.1, 4.4))
+            .with("o4-mini", Price::new(1.1, 4.4))
+            .with("claude-3-5-haiku", Price::new(0.8, 4.0))
+            .with("claude-3-5-sonnet", Price::new(3.0, 15.0))
+            .with("claude-3-7-sonnet", Price::new(3.0, 15.0))
+            .with("gemini-1.5-flash", Price::new(0.075, 0.3))
+            .with("gemini-1.5-pro", Price::new(1.25, 5.0))
+            .with("gemini-2.0-flash", Price::new(0.1, 0.4))
+    }
+}
+
+impl PricingTable {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, prefix: impl Into<String>, price: Price) -> Self {
+        self.entries.push((prefix.into(), price));
+        self
+    }
+
+    pub fn lookup(&self, model: &str) -> Option<Price> {
+        self.entries
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| *price)
+    }
+
+    // USD for a response from `model`, `None` when the model has no price
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        self.lookup(model).map(|price| price.cost(usage))
+    }
+}
+
+// Cost routing
+//
+// Sends requests for `CHEAPEST_MODEL` to the cheapest of the candidates that
+// is routed to a provider and has the capabilities the request needs: tools,
+// images and a context window that fits it. Candidates are compared by the sum
+// of their input and output price, ties go to the earlier candidate and
+// candidates without a price are never picked.
+#[derive(Debug, Clone)]
+pub struct CostRouting {
+    candidates: Vec<String>,
+    pricing: PricingTable,
+}
+
+impl CostRouting {
+    pub fn new(candidates: Vec<String>, pricing: PricingTable) -> Self {
+        Self {
+            candidates,
+            pricing,
+        }
+    }
+
+    pub fn pick(
+        &self,
+        request: &OpenAIChatCompletionRequest,
+        capabilities: &CapabilityTable,
+        router: &ModelRouter,
+    ) -> Option<&str> {
+        let needs = Requirements::of(request);
+        self.candidates
+            .iter()
+            .filter(|model| router.route(model).is_some())
+            .filter(|model| capabilities.lookup(model).satisfies(&needs))
+            .filter_map(|model| {
+                let price = self.pricing.lookup(model)?;
+                Some((model.as_str(), price.input + price.output))
+            })
+            .reduce(|cheapest, candidate| {
+                if candidate.1 < cheapest.1 {
+                    candidate
+                } else {
+                    cheapest
+                }
+            })
+            .map(|(model, _)| model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::openai::OpenAIClient;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn routing() -> CostRouting {
+        let candidates = ["gpt-4o", "o1-mini", "gpt-4o-mini", "claude-3-5-haiku"];
+        CostRouting::new(
+            candidates.map(str::to_string).to_vec(),
+            PricingTable::default().with("o1-mini", Price::new(0.01, 0.01)),
+        )
+    }
+
+    fn openai_router() -> ModelRouter {
+        let mut router = ModelRouter::default();
+        router.register("", Arc::new(OpenAIClient::new("sk-test".to_string())));
+        router
+    }
+
+    #[test]
+    fn test_lookup_longest_prefix() {
+        let pricing = PricingTable::default();
+
+        assert_eq!(
+            pricing.lookup("gpt-4o-mini-2024-07-18").unwrap().input,
+            0.15
+        );
+        assert_eq!(pricing.lookup("gpt-4o-2024-08-06").unwrap().input, 2.5);
+        assert_eq!(pricing.lookup("llama3"), None);
+    }
+
+    #[test]
+    fn test_audio_tokens_are_priced_at_audio_rate() {
+        let usage: Usage = serde_json::from_value(json!({
+            "prompt_tokens": 1_000,
+            "completion_tokens": 3_000,
+            "total_tokens": 4_000,
+            "prompt_tokens_details": {"audio_tokens": 400, "cached_tokens": 0},
+            "completion_tokens_details": {"audio_tokens": 2_000}
+        }))
+        .unwrap();
+        let pricing = PricingTable::default();
+
+        let cost = pricing
+            .cost("gpt-4o-audio-preview-2024-12-17", &usage)
+            .unwrap();
+
+        // 600 text and 400 audio tokens in, 1000 text and 2000 audio tokens out
+        let expected = (600.0 * 2.5 + 400.0 * 40.0 + 1_000.0 * 10.0 + 2_000.0 * 80.0) / 1e6;
+        assert!((cost - expected).abs() < 1e-12);
+
+        // Without an audio rate audio tokens cost as much as text tokens
+        let cost = pricing.cost("gpt-4o-2024-08-06", &usage).unwrap();
+        assert!((cost - (1_000.0 * 2.5 + 3_000.0 * 10.0) / 1e6).abs() < 1e-12);
+        assert_eq!(pricing.cost("llama3", &usage), None);
+    }
+
+    #[test]
+    fn test_text_request_routes_to_cheapest_capable_model() {
+        let request = OpenAIChatCompletionRequest::new(CHEAPEST_MODEL).with_message("user", "Hi");
+
+        let routing = routing();
+
+        let model = routing.pick(&request, &CapabilityTable::default(), &openai_router());
+
+        assert_eq!(model, Some("o1-mini"));
+    }
+
+    #[test]
+    fn test_candidates_without_needed_capability_are_skipped() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": CHEAPEST_MODEL,
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "What is in this image?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}}
+            ]}]
+        }))
+        .unwrap();
+        let routing = routing();
+
+        // o1-mini can't see images
+        let model = routing.pick(&request, &CapabilityTable::default(), &openai_router());
+        assert_eq!(model, Some("gpt-4o-mini"));
+
+        // Only candidates routed to a provider count
+        let mut router = ModelRouter::default();
+        router.register(
+            "claude-",
+            Arc::new(OpenAIClient::new("sk-test".to_string())),
+        );
+        let model = routing.pick(&request, &CapabilityTable::default(), &router);
+        assert_eq!(model, Some("claude-3-5-haiku"));
+    }
+}
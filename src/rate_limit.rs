@@ -0,0 +1,470 @@
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+pub const MINUTE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+// Counts requests per key in fixed windows
+#[derive(Debug)]
+struct Windows {
+    length: Duration,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl Windows {
+    fn new(length: Duration) -> Self {
+        Self {
+            length,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts a request, returning the count in the current window and the
+    // time until that window resets.
+    fn hit(&self, key: &str) -> (u32, Duration) {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(key.to_string()).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= self.length {
+            window.started = now;
+            window.count = 0;
+        }
+        window.count += 1;
+        let reset = self
+            .length
+            .saturating_sub(now.duration_since(window.started));
+        (window.count, reset)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoftLimitExceeded {
+    pub model: String,
+    pub count: u32,
+    pub threshold: u32,
+}
+
+// Soft per-model limit: crossing the threshold is reported so it can be logged
+// and counted, but the request is always served.
+#[derive(Debug)]
+pub struct SoftLimiter {
+    thresholds: HashMap<String, u32>,
+    windows: Windows,
+    exceeded: AtomicU64,
+}
+
+impl SoftLimiter {
+    pub fn new(thresholds: HashMap<String, u32>) -> Self {
+        Self::with_window(thresholds, MINUTE)
+    }
+
+    pub fn with_window(thresholds: HashMap<String, u32>, window: Duration) -> Self {
+        Self {
+            thresholds,
+            windows: Windows::new(window),
+            exceeded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, model: &str) -> Option<SoftLimitExceeded> {
+        let threshold = *self.thresholds.get(model)?;
+        let (count, _) = self.windows.hit(model);
+
+        if count > threshold {
+            self.exceeded.fetch_add(1, Ordering::Relaxed);
+            Some(SoftLimitExceeded {
+                model: model.to_string(),
+                count,
+                threshold,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Number of requests served above their soft threshold
+    pub fn exceeded_total(&self) -> u64 {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+}
+
+// Successful upstream responses in a row after which an adaptive limit is raised
+pub const LOOSEN_AFTER: u32 = 10;
+
+// Range an adaptive limit moves in, written as `min..max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AdaptiveBounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl FromStr for AdaptiveBounds {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (min, max) = value
+            .split_once("..")
+            .ok_or_else(|| anyhow!("expected min..max"))?;
+        let bounds = AdaptiveBounds {
+            min: min.trim().parse()?,
+            max: max.trim().parse()?,
+        };
+        if bounds.min == 0 || bounds.min > bounds.max {
+            return Err(anyhow!("expected 0 < min <= max"));
+        }
+        Ok(bounds)
+    }
+}
+
+// A limit that follows the upstream: halved when the upstream throttles and
+// raised by a tenth of its range after a run of successes, within its bounds.
+#[derive(Debug)]
+struct AdaptiveLimit {
+    bounds: AdaptiveBounds,
+    current: u32,
+    successes: u32,
+}
+
+impl AdaptiveLimit {
+    fn new(bounds: AdaptiveBounds) -> Self {
+        Self {
+            bounds,
+            current: bounds.max,
+            successes: 0,
+        }
+    }
+
+    fn observe(&mut self, throttled: bool) {
+        if throttled {
+            self.current = (self.current / 2).max(self.bounds.min);
+            self.successes = 0;
+            return;
+        }
+        self.successes += 1;
+        if self.successes >= LOOSEN_AFTER {
+            let step = ((self.bounds.max - self.bounds.min) / 10).max(1);
+            self.current = (self.current + step).min(self.bounds.max);
+            self.successes = 0;
+        }
+    }
+}
+
+// Hard per-model limit: requests over the limit are rejected until the
+// window resets. Adaptive models have a limit that tunes itself to what the
+// upstream accepts, see `observe`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: HashMap<String, u32>,
+    adaptive: Mutex<HashMap<String, AdaptiveLimit>>,
+    windows: Windows,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<String, u32>) -> Self {
+        Self::with_window(limits, MINUTE)
+    }
+
+    pub fn with_window(limits: HashMap<String, u32>, window: Duration) -> Self {
+        Self {
+            limits,
+            adaptive: Mutex::new(HashMap::new()),
+            windows: Windows::new(window),
+        }
+    }
+
+    // Adaptive limits start at their maximum and take precedence over static ones
+    pub fn with_adaptive(self, bounds: HashMap<String, AdaptiveBounds>) -> Self {
+        let adaptive = bounds
+            .into_iter()
+            .map(|(model, bounds)| (model, AdaptiveLimit::new(bounds)))
+            .collect();
+        Self {
+            adaptive: Mutex::new(adaptive),
+            ..self
+        }
+    }
+
+    // The limit currently applied to `model`
+    pub fn limit(&self, model: &str) -> Option<u32> {
+        let adaptive = self.adaptive.lock().unwrap();
+        match adaptive.get(model) {
+            Some(limit) => Some(limit.current),
+            None => self.limits.get(model).copied(),
+        }
+    }
+
+    // Feeds an upstream response for `model` to its adaptive limit. `throttled`
+    // is a 429 or a response reporting no remaining requests.
+    pub fn observe(&self, model: &str, throttled: bool) {
+        if let Some(limit) = self.adaptive.lock().unwrap().get_mut(model) {
+            limit.observe(throttled);
+        }
+    }
+
+    // `Ok(None)` means the model is not limited
+    pub fn check(&self, model: &str) -> Result<Option<RateLimitStatus>, RateLimitStatus> {
+        let Some(limit) = self.limit(model) else {
+            return Ok(None);
+        };
+        let (count, reset) = self.windows.hit(model);
+        let status = RateLimitStatus {
+            limit,
+            remaining: limit.saturating_sub(count),
+            reset,
+        };
+        if count > limit {
+            Err(status)
+        } else {
+            Ok(Some(status))
+        }
+    }
+}
+
+// Caps concurrent upstream requests of each provider separately, so a slow
+// provider only queues its own requests
+#[derive(Debug)]
+pub struct ProviderConcurrency {
+    permits: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ProviderConcurrency {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn semaphore(&self, provider: &str) -> Arc<Semaphore> {
+        self.semaphores
+            .lock()
+            .unwrap()
+            .entry(provider.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.permits)))
+            .clone()
+    }
+}
+
+// Caps the streams a key has open at once. A stream holds its slot until it
+// is dropped, whether it finished or the client went away.
+#[derive(Debug)]
+pub struct StreamLimiter {
+    max: usize,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl StreamLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // `None` when `key` already has the maximum number of streams open
+    pub fn acquire(&self, key: &str) -> Option<StreamSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(key.to_string()).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(StreamSlot {
+            key: key.to_string(),
+            open: self.open.clone(),
+        })
+    }
+
+    pub fn open(&self, key: &str) -> usize {
+        self.open.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct StreamSlot {
+    key: String,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(window: Duration) -> SoftLimiter {
+        SoftLimiter::with_window(HashMap::from([("gpt-4o".to_string(), 2)]), window)
+    }
+
+    #[test]
+    fn test_soft_limit_warns_after_threshold() {
+        let limiter = limiter(MINUTE);
+
+        assert_eq!(limiter.record("gpt-4o"), None);
+        assert_eq!(limiter.record("gpt-4o"), None);
+        assert_eq!(
+            limiter.record("gpt-4o"),
+            Some(SoftLimitExceeded {
+                model: "gpt-4o".to_string(),
+                count: 3,
+                threshold: 2,
+            })
+        );
+        assert_eq!(limiter.exceeded_total(), 1);
+    }
+
+    #[test]
+    fn test_soft_limit_ignores_unconfigured_models() {
+        let limiter = limiter(MINUTE);
+
+        for _ in 0..10 {
+            assert_eq!(limiter.record("gpt-4o-mini"), None);
+        }
+        assert_eq!(limiter.exceeded_total(), 0);
+    }
+
+    #[test]
+    fn test_soft_limit_window_resets() {
+        let limiter = limiter(Duration::from_millis(20));
+
+        limiter.record("gpt-4o");
+        limiter.record("gpt-4o");
+        assert!(limiter.record("gpt-4o").is_some());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(limiter.record("gpt-4o"), None);
+    }
+
+    #[test]
+    fn test_rate_limiter_rejects_over_limit() {
+        let limiter = RateLimiter::new(HashMap::from([("gpt-4o".to_string(), 2)]));
+
+        let first = limiter.check("gpt-4o").unwrap().unwrap();
+        assert_eq!((first.limit, first.remaining), (2, 1));
+        let second = limiter.check("gpt-4o").unwrap().unwrap();
+        assert_eq!(second.remaining, 0);
+
+        let rejected = limiter.check("gpt-4o").expect_err("Over the limit");
+        assert_eq!((rejected.limit, rejected.remaining), (2, 0));
+        assert!(rejected.reset <= MINUTE && rejected.reset > Duration::from_secs(55));
+
+        assert_eq!(limiter.check("gpt-4o-mini"), Ok(None));
+    }
+
+    #[test]
+    fn test_concurrency_is_limited_per_provider() {
+        let concurrency = ProviderConcurrency::new(1);
+
+        let _permit = concurrency.semaphore("openai").try_acquire_owned().unwrap();
+
+        assert!(concurrency.semaphore("openai").try_acquire().is_err());
+        assert!(concurrency.semaphore("anthropic").try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_stream_slots_are_freed_on_drop() {
+        let limiter = StreamLimiter::new(2);
+
+        let first = limiter.acquire("a").unwrap();
+        let _second = limiter.acquire("a").unwrap();
+        assert!(limiter.acquire("a").is_none());
+        assert!(limiter.acquire("b").is_some());
+
+        drop(first);
+        assert_eq!(limiter.open("a"), 1);
+        assert!(limiter.acquire("a").is_some());
+    }
+
+    #[test]
+    fn test_adaptive_limit_follows_upstream() {
+        let bounds = "10..100".parse().unwrap();
+        let limiter = RateLimiter::new(HashMap::from([("gpt-4o".to_string(), 500)]))
+            .with_adaptive(HashMap::from([("gpt-4o".to_string(), bounds)]));
+        assert_eq!(limiter.limit("gpt-4o"), Some(100));
+
+        limiter.observe("gpt-4o", true);
+        assert_eq!(limiter.limit("gpt-4o"), Some(50));
+        for _ in 0..5 {
+            limiter.observe("gpt-4o", true);
+        }
+        assert_eq!(limiter.limit("gpt-4o"), Some(10));
+
+        for _ in 0..LOOSEN_AFTER - 1 {
+            limiter.observe("gpt-4o", false);
+        }
+        assert_eq!(limiter.limit("gpt-4o"), Some(10));
+        limiter.observe("gpt-4o", false);
+        assert_eq!(limiter.limit("gpt-4o"), Some(19));
+        for _ in 0..100 * LOOSEN_AFTER {
+            limiter.observe("gpt-4o", false);
+        }
+        assert_eq!(limiter.limit("gpt-4o"), Some(100));
+
+        // Static limits don't adapt
+        limiter.observe("gpt-4o-mini", true);
+        assert_eq!(limiter.limit("gpt-4o-mini"), None);
+    }
+
+    #[test]
+    fn test_adaptive_limit_is_enforced() {
+        let limiter = RateLimiter::new(HashMap::new()).with_adaptive(HashMap::from([(
+            "gpt-4o".to_string(),
+            AdaptiveBounds { min: 1, max: 4 },
+        )]));
+        limiter.observe("gpt-4o", true);
+
+        assert!(limiter.check("gpt-4o").is_ok());
+        assert!(limiter.check("gpt-4o").is_ok());
+        let rejected = limiter
+            .check("gpt-4o")
+            .expect_err("Over the tightened limit");
+        assert_eq!(rejected.limit, 2);
+    }
+
+    #[test]
+    fn test_parse_adaptive_bounds() {
+        assert_eq!(
+            "60..600".parse::<AdaptiveBounds>().unwrap(),
+            AdaptiveBounds { min: 60, max: 600 }
+        );
+        assert!("600..60".parse::<AdaptiveBounds>().is_err());
+        assert!("0..60".parse::<AdaptiveBounds>().is_err());
+        assert!("60".parse::<AdaptiveBounds>().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+pub const DEFAULT_RETRYABLE_STATUSES: &[u16] = &[429, 500, 502, 503, 504];
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(500);
+// Longest wait between attempts, also for a `Retry-After` asking for more
+pub const MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Decides which upstream failures are worth another attempt
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub retryable_statuses: HashSet<u16>,
+    // OpenAI error `type` or `code` values, e.g. `server_error`
+    pub retryable_codes: HashSet<String>,
+    // Wait before the first retry, doubled for every further one
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            retryable_statuses: DEFAULT_RETRYABLE_STATUSES.iter().copied().collect(),
+            retryable_codes: HashSet::new(),
+            base_delay: DEFAULT_BASE_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, status: StatusCode, body: &[u8]) -> bool {
+        if self.retryable_statuses.contains(&status.as_u16()) {
+            return true;
+        }
+        let (error_type, code) = classify(body);
+        [error_type, code]
+            .into_iter()
+            .flatten()
+            .any(|value| self.retryable_codes.contains(&value))
+    }
+
+    // Wait before retry `retry`, counting from 1. The upstream's `Retry-After`
+    // wins when it gives one, otherwise the delay doubles per retry with a
+    // random half on top, so clients that failed together don't retry together.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(MAX_DELAY);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(MAX_DELAY);
+        let jitter = backoff.mul_f64(0.5 * random_fraction());
+        (backoff + jitter).min(MAX_DELAY)
+    }
+}
+
+// Seconds to wait from a `Retry-After` header. The HTTP date form isn't used
+// by LLM APIs and is ignored.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds: f64 = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    (seconds.is_finite() && seconds >= 0.0).then(|| Duration::from_secs_f64(seconds))
+}
+
+// In [0, 1), from the randomly keyed std hasher
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Extracts `error.type` and `error.code` from an OpenAI error body
+pub fn classify(body: &[u8]) -> (Option<String>, Option<String>) {
+    let Ok(value) = serde_json::from_slice::<Value>(body) else {
+        return (None, None);
+    };
+    let field = |name: &str| value["error"][name].as_str().map(String::from);
+    (field("type"), field("code"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUSY: &[u8] = br#"{"error": {"message": "Busy", "type": "invalid_request_error", "code": "context_busy"}}"#;
+
+    #[test]
+    fn test_classify_error_body() {
+        assert_eq!(
+            classify(BUSY),
+            (
+                Some("invalid_request_error".to_string()),
+                Some("context_busy".to_string())
+            )
+        );
+        assert_eq!(classify(b"upstream exploded"), (None, None));
+    }
+
+    #[test]
+    fn test_retryable_statuses() {
+        let policy = RetryPolicy {
+            retryable_statuses: HashSet::from([500]),
+            ..Default::default()
+        };
+
+        assert!(policy.is_retryable(StatusCode::INTERNAL_SERVER_ERROR, b""));
+        assert!(!policy.is_retryable(StatusCode::SERVICE_UNAVAILABLE, b""));
+    }
+
+    #[test]
+    fn test_retryable_error_codes() {
+        let policy = RetryPolicy {
+            retryable_codes: HashSet::from(["context_busy".to_string()]),
+            ..Default::default()
+        };
+
+        assert!(policy.is_retryable(StatusCode::BAD_REQUEST, BUSY));
+        assert!(!policy.is_retryable(StatusCode::BAD_REQUEST, b"{}"));
+        assert!(!RetryPolicy::default().is_retryable(StatusCode::BAD_REQUEST, BUSY));
+    }
+
+    #[test]
+    fn test_delay_backs_off_exponentially_with_jitter() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        for (retry, backoff) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = policy.delay(retry, None);
+            let backoff = Duration::from_millis(backoff);
+            assert!(delay >= backoff && delay <= backoff * 3 / 2, "{:?}", delay);
+        }
+        assert_eq!(policy.delay(20, None), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_retry_after_is_honored() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "2".parse().unwrap());
+        let retry_after = retry_after(&headers);
+
+        assert_eq!(retry_after, Some(Duration::from_secs(2)));
+        assert_eq!(
+            RetryPolicy::default().delay(1, retry_after),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            RetryPolicy::default().delay(1, Some(Duration::from_secs(3600))),
+            MAX_DELAY
+        );
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(super::retry_after(&headers), None);
+    }
+}
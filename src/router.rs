@@ -0,0 +1,154 @@
+use crate::models::provider::Provider;
+use std::sync::Arc;
+
+// Model routing
+//
+// Picks the provider of a model by the longest registered prefix of the model
+// name, e.g. `claude-` for Anthropic. The empty prefix matches every model and
+// routes what no other prefix does. Among providers registered under the same
+// prefix the first that serves the model wins, which lets the echo provider
+// take over single models from a catch-all.
+#[derive(Clone, Default)]
+pub struct ModelRouter {
+    routes: Vec<(String, Arc<dyn Provider>)>,
+}
+
+impl ModelRouter {
+    pub fn register(&mut self, prefix: impl Into<String>, provider: Arc<dyn Provider>) {
+        self.routes.push((prefix.into(), provider));
+    }
+
+    pub fn route(&self, model: &str) -> Option<&dyn Provider> {
+        self.routes
+            .iter()
+            .filter(|(prefix, provider)| {
+                model.starts_with(prefix.as_str()) && provider.serves(model)
+            })
+            .rev()
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, provider)| provider.as_ref())
+    }
+
+    // Whether a route names the model: a prefix other than the catch-all, or a
+    // provider that knows the model by name
+    pub fn routes_explicitly(&self, model: &str) -> bool {
+        self.routes.iter().any(|(prefix, provider)| {
+            model.starts_with(prefix.as_str())
+                && provider.serves(model)
+                && (!prefix.is_empty() || provider.models().iter().any(|known| known == model))
+        })
+    }
+
+    // Models the providers know by name and are routed to them, with the name
+    // of their provider
+    pub fn models(&self) -> Vec<(String, &str)> {
+        self.routes
+            .iter()
+            .flat_map(|(_, provider)| {
+                let name = provider.name();
+                provider
+                    .models()
+                    .into_iter()
+                    .filter(move |model| {
+                        self.route(model)
+                            .is_some_and(|routed| routed.name() == name)
+                    })
+                    .map(move |model| (model, name))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::echo::EchoProvider;
+    use crate::models::openai::OpenAIClient;
+
+    fn router() -> ModelRouter {
+        let mut router = ModelRouter::default();
+        router.register("gpt-", Arc::new(OpenAIClient::new("sk-test".to_string())));
+        router.register("o1-", Arc::new(OpenAIClient::new("sk-test".to_string())));
+        router.register(
+            "claude-",
+            Arc::new(EchoProvider::new(vec!["*".to_string()])),
+        );
+        router
+    }
+
+    #[test]
+    fn test_routes_explicitly() {
+        let mut router = router();
+        router.register("", Arc::new(EchoProvider::new(vec!["llama-3".to_string()])));
+        router.register("", Arc::new(OpenAIClient::new("sk-test".to_string())));
+
+        assert!(router.routes_explicitly("gpt-4o"));
+        assert!(router.routes_explicitly("claude-3-5-sonnet"));
+        assert!(router.routes_explicitly("llama-3"));
+        assert!(!router.routes_explicitly("mistral-large"));
+        assert!(!router.routes_explicitly(""));
+    }
+
+    fn provider(router: &ModelRouter, model: &str) -> Option<String> {
+        router
+            .route(model)
+            .map(|provider| provider.name().to_string())
+    }
+
+    #[test]
+    fn test_route_by_prefix() {
+        let router = router();
+
+        assert_eq!(provider(&router, "gpt-4o").as_deref(), Some("openai"));
+        assert_eq!(provider(&router, "o1-mini").as_deref(), Some("openai"));
+        assert_eq!(
+            provider(&router, "claude-3-5-haiku").as_deref(),
+            Some("echo")
+        );
+    }
+
+    #[test]
+    fn test_unmatched_model() {
+        assert_eq!(provider(&router(), "llama3"), None);
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let mut router = router();
+        router.register("", Arc::new(EchoProvider::new(vec!["*".to_string()])));
+        router.register(
+            "gpt-4o-mini",
+            Arc::new(EchoProvider::new(vec!["*".to_string()])),
+        );
+
+        assert_eq!(provider(&router, "llama3").as_deref(), Some("echo"));
+        assert_eq!(provider(&router, "gpt-4o").as_deref(), Some("openai"));
+        assert_eq!(provider(&router, "gpt-4o-mini").as_deref(), Some("echo"));
+    }
+
+    #[test]
+    fn test_first_serving_provider_wins_on_same_prefix() {
+        let mut router = ModelRouter::default();
+        router.register("", Arc::new(EchoProvider::new(vec!["gpt-4o".to_string()])));
+        router.register("", Arc::new(OpenAIClient::new("sk-test".to_string())));
+
+        assert_eq!(provider(&router, "gpt-4o").as_deref(), Some("echo"));
+        assert_eq!(provider(&router, "gpt-4o-mini").as_deref(), Some("openai"));
+    }
+
+    #[test]
+    fn test_models_routed_to_their_provider() {
+        let mut router = ModelRouter::default();
+        router.register(
+            "",
+            Arc::new(EchoProvider::new(vec![
+                "*".to_string(),
+                "echo-model".to_string(),
+                "gpt-4o".to_string(),
+            ])),
+        );
+        router.register("gpt-", Arc::new(OpenAIClient::new("sk-test".to_string())));
+
+        assert_eq!(router.models(), vec![("echo-model".to_string(), "echo")]);
+    }
+}
@@ -0,0 +1,257 @@
+// Streamed chat completions
+//
+// Chunks are forwarded to the client as the upstream sends them. The first
+// chunk is awaited before answering, so a failing upstream still gets a proper
+// error status instead of an event stream that ends in an error. The rest is
+// passed through a bounded buffer: when a client reads slowly, the gateway
+// stops reading from the upstream instead of queueing the whole completion.
+use super::{route_headers, with_latency, ApiError, AppState, OPENAI_PROVIDER};
+use crate::cache::{self, CacheStatus};
+use crate::metrics::StreamTimer;
+use crate::models::openai::{
+    ChatCompletionChunk, ChunkStream, NotEventStream, OpenAIChatCompletionRequest,
+};
+use crate::rate_limit::StreamSlot;
+use crate::streaming::{self, UsageAggregator};
+use anyhow::anyhow;
+use axum::response::{
+    sse::{Event, Sse},
+    IntoResponse, Response,
+};
+use futures_util::stream::{self, StreamExt};
+use serde_json::json;
+use std::time::Instant;
+
+pub(super) async fn respond(
+    state: AppState,
+    mut request: OpenAIChatCompletionRequest,
+    base_url: Option<String>,
+    started: Instant,
+    caller: Option<String>,
+    slot: Option<StreamSlot>,
+) -> Response {
+    let model = request.model.clone();
+    let upstream_started = Instant::now();
+    // Usage is always requested upstream, the client only gets it when asked
+    let client_wants_usage = streaming::include_usage(&request);
+    streaming::request_usage(&mut request);
+
+    let mut chunks = match dedupe_key(&state, &request, base_url.as_deref(), caller.as_deref()) {
+        Some(key) => {
+            let dedupe = state.stream_dedupe.clone().unwrap();
+            let upstream = upstream_chunks(state.clone(), request, base_url)
+                .map(|chunk| chunk.map_err(|err| err.to_string()));
+            let shared = dedupe
+                .subscribe(key, async move { upstream })
+                .map(|chunk| match chunk {
+                    Ok(Ok(chunk)) => Ok(chunk),
+                    Ok(Err(message)) => Err(anyhow!(message)),
+                    Err(lagged) => Err(lagged.into()),
+                });
+            Box::pin(shared)
+        }
+        None => upstream_chunks(state.clone(), request, base_url),
+    };
+    if let Some(max) = state.max_stream_duration {
+        chunks = Box::pin(streaming::limit_duration(chunks, max));
+    }
+
+    let first = match chunks.next().await {
+        Some(Ok(chunk)) => Some(chunk),
+        Some(Err(err)) => return ApiError::Upstream(err).into_response(),
+        None => None,
+    };
+    let time_to_first_token = upstream_started.elapsed();
+    let forwarder = Forwarder {
+        chunks: Box::pin(
+            stream::iter(first.map(Ok)).chain(streaming::bounded(chunks, streaming::STREAM_BUFFER)),
+        ),
+        usage: UsageAggregator::new(client_wants_usage),
+        timer: StreamTimer::new(started, model.clone(), state.metrics.clone()),
+        state: state.clone(),
+        model: model.clone(),
+        _slot: slot,
+    };
+    let mut response = Sse::new(forward(forwarder)).into_response();
+    route_headers(
+        &mut response,
+        OPENAI_PROVIDER,
+        &model,
+        false,
+        CacheStatus::Bypass,
+    );
+    with_latency(response, Some(time_to_first_token), started.elapsed())
+}
+
+// Identical deterministic streams can share one upstream stream. Streams from
+// an overridden upstream are kept apart, and so are those of different callers
+// and upstream keys, which `cache_key` covers: a shared stream is billed to
+// whoever opened it.
+fn dedupe_key(
+    state: &AppState,
+    request: &OpenAIChatCompletionRequest,
+    base_url: Option<&str>,
+    caller: Option<&str>,
+) -> Option<String> {
+    state.stream_dedupe.as_ref()?;
+    if request.temperature != Some(0.0) {
+        return None;
+    }
+    let mut key = format!(
+        "{}/{}",
+        cache::cache_key(request),
+        caller.unwrap_or_default()
+    );
+    if let Some(base_url) = base_url {
+        key = format!("{}@{}", key, base_url);
+    }
+    Some(key)
+}
+
+// Opens the upstream stream once the stream is first polled. Failing to open
+// it is the first and only item.
+fn upstream_chunks(
+    state: AppState,
+    request: OpenAIChatCompletionRequest,
+    base_url: Option<String>,
+) -> ChunkStream {
+    Box::pin(stream::once(open(state, request, base_url)).flatten())
+}
+
+async fn open(
+    state: AppState,
+    request: OpenAIChatCompletionRequest,
+    base_url: Option<String>,
+) -> ChunkStream {
+    // The permit is held until the stream is dropped, not just until it opens
+    let mut load = state.provider_stats.queue(OPENAI_PROVIDER);
+    let permit = match &state.concurrency {
+        Some(concurrency) => match concurrency.semaphore(OPENAI_PROVIDER).acquire_owned().await {
+            Ok(permit) => Some(permit),
+            Err(err) => return failed(err.into()),
+        },
+        None => None,
+    };
+    load.start();
+    let base_url = base_url.as_deref().unwrap_or(state.client.base_url());
+    let started = Instant::now();
+    let result = match state
+        .client
+        .chat_stream_with_base_url(request.clone(), base_url)
+        .await
+    {
+        Err(err) if err.is::<NotEventStream>() && state.stream_fallback => {
+            tracing::warn!(
+                model = %request.model,
+                "Upstream did not stream, retrying without streaming"
+            );
+            replayed(&state, request, base_url).await
+        }
+        result => result,
+    };
+    state
+        .provider_stats
+        .record(OPENAI_PROVIDER, result.is_ok(), started.elapsed());
+    match result {
+        Ok(chunks) => Box::pin(chunks.map(move |chunk| {
+            let _held = (&permit, &load);
+            chunk
+        })),
+        Err(err) => failed(err),
+    }
+}
+
+// Requests the complete response and replays it as chunks, ending with a
+// usage-only chunk like a stream with `include_usage`
+async fn replayed(
+    state: &AppState,
+    mut request: OpenAIChatCompletionRequest,
+    base_url: &str,
+) -> anyhow::Result<ChunkStream> {
+    request.stream = None;
+    if let Some(extra) = request.extra.as_mut() {
+        extra.remove("stream_options");
+    }
+    let response = state.client.chat_with_base_url(request, base_url).await?;
+    let usage = response.usage.clone();
+    let mut chunks = response.into_chunks();
+    if let Some(last) = chunks.last().cloned() {
+        chunks.push(ChatCompletionChunk {
+            choices: Vec::new(),
+            usage: Some(usage),
+            ..last
+        });
+    }
+    Ok(Box::pin(stream::iter(chunks.into_iter().map(Ok))))
+}
+
+fn failed(err: anyhow::Error) -> ChunkStream {
+    Box::pin(stream::once(async move { Err(err) }))
+}
+
+struct Forwarder {
+    chunks: ChunkStream,
+    usage: UsageAggregator,
+    timer: StreamTimer,
+    state: AppState,
+    model: String,
+    // Freed when the stream ends or the client disconnects
+    _slot: Option<StreamSlot>,
+}
+
+// Sends chunks as server-sent events, terminated like OpenAI streams. An
+// upstream failure mid-stream is sent as an error event before `[DONE]`.
+fn forward(forwarder: Forwarder) -> impl futures_util::Stream<Item = Result<Event, axum::Error>> {
+    stream::unfold(Some(forwarder), |forwarder| async move {
+        let mut forwarder = forwarder?;
+        loop {
+            match forwarder.chunks.next().await {
+                Some(Ok(mut chunk)) => {
+                    forwarder.state.chunk_normalizer.normalize(&mut chunk);
+                    let Some(chunk) = forwarder.usage.process(chunk) else {
+                        continue;
+                    };
+                    forwarder.timer.chunk(has_content(&chunk));
+                    return Some((Event::default().json_data(chunk), Some(forwarder)));
+                }
+                Some(Err(err)) => {
+                    tracing::error!(model = %forwarder.model, error = %err, "Stream failed");
+                    let error = json!({"error": {
+                        "message": err.to_string(),
+                        "type": "upstream_error",
+                    }});
+                    forwarder.chunks = Box::pin(stream::empty());
+                    return Some((
+                        Ok(Event::default().data(error.to_string())),
+                        Some(forwarder),
+                    ));
+                }
+                None => {
+                    let latency = forwarder.timer.finish();
+                    if let Some(usage) = forwarder.usage.usage() {
+                        tracing::info!(
+                            model = %forwarder.model,
+                            prompt_tokens = usage.prompt_tokens,
+                            completion_tokens = usage.completion_tokens,
+                            total_tokens = usage.total_tokens,
+                            latency_ms = latency.as_millis() as u64,
+                            "Stream completed"
+                        );
+                    }
+                    return Some((Ok(Event::default().data("[DONE]")), None));
+                }
+            }
+        }
+    })
+}
+
+fn has_content(chunk: &ChatCompletionChunk) -> bool {
+    chunk.choices.iter().any(|choice| {
+        choice
+            .delta
+            .content
+            .as_deref()
+            .is_some_and(|content| !content.is_empty())
+            || choice.delta.extra.contains_key("tool_calls")
+    })
+}
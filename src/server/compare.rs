@@ -0,0 +1,270 @@
+use super::{
+    base_url_override, body_rejected, dispatch, error_response, prepare, response_transforms,
+    ApiError, AppState, RouteTrace,
+};
+use crate::models::openai::{OpenAIChatCompletionRequest, OpenAIChatCompletionResponse};
+use crate::transform::{self, ResponseTransform};
+use crate::validation::ValidationError;
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::future::join_all;
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+
+// Sends one chat request to several models concurrently. The body is a chat
+// completion request with `models` instead of `model`; the response maps each
+// model to its response or error, plus the usage per model.
+pub(super) async fn compare_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<Map<String, Value>>, JsonRejection>,
+) -> Response {
+    let mut body = match payload {
+        Ok(Json(body)) => body,
+        Err(rejection) => return body_rejected(&state, rejection).into_response(),
+    };
+    let base_url = match base_url_override(&state, &headers) {
+        Ok(base_url) => base_url,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        }
+    };
+    let models = match body
+        .remove("models")
+        .map(serde_json::from_value::<Vec<String>>)
+    {
+        Some(Ok(models)) if !models.is_empty() => models,
+        _ => {
+            let error = ValidationError::new("models", "models must be a non-empty list of models");
+            return ApiError::from(error).into_response();
+        }
+    };
+    if body.get("stream") == Some(&Value::Bool(true)) {
+        let error = ValidationError::new("stream", "Streaming is not supported when comparing");
+        return ApiError::from(error).into_response();
+    }
+    if models.len() > state.max_fanout {
+        let message = format!(
+            "Comparing {} models exceeds the limit of {}",
+            models.len(),
+            state.max_fanout
+        );
+        return ApiError::from(ValidationError::new("models", message)).into_response();
+    }
+    tracing::info!(models = models.len(), "Comparing models");
+    let transforms = response_transforms(&state, &headers);
+
+    let calls = models.iter().map(|model| {
+        let mut body = body.clone();
+        body.insert("model".to_string(), Value::String(model.clone()));
+        compare_one(&state, Value::Object(body), base_url.as_deref(), transforms)
+    });
+    let outcomes = join_all(calls).await;
+
+    let mut results = Map::new();
+    let mut usage = Map::new();
+    for (model, outcome) in models.into_iter().zip(outcomes) {
+        match outcome {
+            Ok(response) => {
+                usage.insert(model.clone(), json!(response.usage));
+                results.insert(model, json!({"response": response}));
+            }
+            Err(err) => {
+                results.insert(model, err.body());
+            }
+        }
+    }
+    Json(json!({"object": "chat.compare", "results": results, "usage": usage})).into_response()
+}
+
+async fn compare_one(
+    state: &AppState,
+    body: Value,
+    base_url: Option<&str>,
+    transforms: &[Box<dyn ResponseTransform>],
+) -> Result<OpenAIChatCompletionResponse, ApiError> {
+    let mut request: OpenAIChatCompletionRequest =
+        serde_json::from_value(body).map_err(|err| ApiError::invalid_body(err.to_string()))?;
+    let annotations = transform::take_annotations(&mut request);
+    prepare(state, &mut request, &mut RouteTrace::default())?;
+    let mut response = dispatch(state, request, base_url)
+        .await
+        .map_err(ApiError::Upstream)?;
+    // Scrubbed like any other complete response
+    for transform in transforms {
+        transform.apply(&annotations, &mut response);
+    }
+    if let Some(filter) = &state.content_filter {
+        filter.apply(&mut response);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        router,
+        tests::{api_request, dev_state},
+        AppState, BASE_URL_HEADER,
+    };
+    use crate::mock;
+    use crate::transform::{ContentFilter, FilterRule};
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header::CONTENT_TYPE, Request};
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn compare_request(base_url: &str, body: Value) -> Request<Body> {
+        api_request("POST", "/v1/chat/compare")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compare_two_models() {
+        let (base_url, calls) = mock::upstream(|request| match request["model"].as_str() {
+            Some("broken-model") => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": {"message": "Model is down", "type": "server_error"}}),
+            ),
+            model => (
+                StatusCode::OK,
+                mock::completion_json(model.unwrap(), "Hi from gpt-4o"),
+            ),
+        })
+        .await;
+        let app = router(dev_state());
+
+        let response = app
+            .oneshot(compare_request(
+                &base_url,
+                json!({
+                    "models": ["gpt-4o", "broken-model"],
+                    "messages": [{"role": "user", "content": "Hi"}]
+                }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["results"]["gpt-4o"]["response"]["choices"][0]["message"]["content"],
+            "Hi from gpt-4o"
+        );
+        assert_eq!(
+            body["results"]["broken-model"],
+            json!({"error": {"message": "Model is down", "type": "server_error"}})
+        );
+        assert_eq!(body["usage"]["gpt-4o"]["total_tokens"], 2);
+        assert_eq!(body["usage"].get("broken-model"), None);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_compare_results_are_filtered() {
+        let (base_url, _) = mock::openai("The codename is Project Falcon.").await;
+        let rules = [FilterRule {
+            pattern: "(?i)project falcon".to_string(),
+            replacement: "[REDACTED]".to_string(),
+        }];
+        let state = AppState {
+            content_filter: Some(Arc::new(ContentFilter::new(&rules).unwrap())),
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(compare_request(
+                &base_url,
+                json!({
+                    "models": ["gpt-4o", "gpt-4o-mini"],
+                    "messages": [{"role": "user", "content": "Hi"}]
+                }),
+            ))
+            .await
+            .unwrap();
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        for model in ["gpt-4o", "gpt-4o-mini"] {
+            assert_eq!(
+                body["results"][model]["response"]["choices"][0]["message"]["content"],
+                "The codename is [REDACTED]."
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compare_validates_requests() {
+        let (base_url, calls) = mock::openai("Hi").await;
+
+        let response = router(dev_state())
+            .oneshot(compare_request(
+                &base_url,
+                json!({"models": ["gpt-4o"], "messages": [], "max_tokens": -1}),
+            ))
+            .await
+            .unwrap();
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let error = &body["results"]["gpt-4o"]["error"];
+        assert_eq!(error["type"], "invalid_request_error");
+        assert_eq!(error["errors"].as_array().unwrap().len(), 2);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compare_over_fanout_limit_is_rejected() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let state = AppState {
+            max_fanout: 2,
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(compare_request(
+                &base_url,
+                json!({
+                    "models": ["gpt-4o", "gpt-4o-mini", "gpt-4.1"],
+                    "messages": [{"role": "user", "content": "Hi"}]
+                }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["param"], "models");
+        assert_eq!(
+            body["error"]["message"],
+            "Comparing 3 models exceeds the limit of 2"
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compare_requires_models() {
+        let app = router(dev_state());
+
+        let response = app
+            .oneshot(compare_request(
+                "http://127.0.0.1:1/v1",
+                json!({"messages": [{"role": "user", "content": "Hi"}]}),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
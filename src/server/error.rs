@@ -0,0 +1,251 @@
+use crate::models::deadline::DeadlineExceeded;
+use crate::models::openai::{OpenAIError, UpstreamTimeout};
+use crate::rate_limit::RateLimitStatus;
+use crate::validation::ValidationError;
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{header::RETRY_AFTER, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+// Errors returned to clients in the OpenAI error shape
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidBody(String),
+    InvalidRequest(ValidationError),
+    // Every problem `OpenAIChatCompletionRequest::validate` found
+    InvalidParams(Vec<ValidationError>),
+    PayloadTooLarge {
+        limit: usize,
+    },
+    // No provider is routed the model, `available` lists models that are
+    ModelNotFound {
+        model: String,
+        available: Vec<String>,
+    },
+    RateLimited {
+        model: String,
+        status: RateLimitStatus,
+    },
+    // The caller's key has as many streams open as it may
+    TooManyStreams {
+        limit: usize,
+    },
+    Upstream(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidBody(_) | ApiError::InvalidRequest(_) | ApiError::InvalidParams(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::ModelNotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::RateLimited { .. } | ApiError::TooManyStreams { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            // Upstream error statuses are relayed so clients can back off or re-auth
+            ApiError::Upstream(err) => match err.downcast_ref::<OpenAIError>() {
+                Some(error) => error.status,
+                None if is_timeout(err) => StatusCode::GATEWAY_TIMEOUT,
+                None => StatusCode::BAD_GATEWAY,
+            },
+        }
+    }
+
+    pub fn body(&self) -> Value {
+        match self {
+            ApiError::InvalidBody(message) => json!({"error": {
+                "message": message,
+                "type": "invalid_request_error",
+            }}),
+            ApiError::InvalidRequest(error) => json!({"error": {
+                "message": error.message,
+                "type": "invalid_request_error",
+                "param": error.param,
+            }}),
+            // Shaped like a single error for clients that only read the first
+            ApiError::InvalidParams(errors) => json!({"error": {
+                "message": errors
+                    .iter()
+                    .map(|error| error.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                "type": "invalid_request_error",
+                "param": errors.first().map(|error| &error.param),
+                "errors": errors
+                    .iter()
+                    .map(|error| json!({"param": error.param, "message": error.message}))
+                    .collect::<Vec<_>>(),
+            }}),
+            ApiError::PayloadTooLarge { limit } => json!({"error": {
+                "message": format!("Request body is larger than the limit of {} bytes", limit),
+                "type": "invalid_request_error",
+            }}),
+            ApiError::ModelNotFound { model, available } => json!({"error": {
+                "message": format!("The model `{}` does not exist or is not served by this gateway", model),
+                "type": "invalid_request_error",
+                "param": "model",
+                "code": "model_not_found",
+                "available_models": available,
+            }}),
+            ApiError::RateLimited { model, status } => json!({"error": {
+                "message": format!(
+                    "Rate limit of {} requests per minute exceeded for model {}, retry in {}s",
+                    status.limit, model, reset_seconds(status)
+                ),
+                "type": "rate_limit_error",
+                "code": "rate_limit_exceeded",
+            }}),
+            ApiError::TooManyStreams { limit } => json!({"error": {
+                "message": format!(
+                    "Limit of {} concurrent streams per API key reached, close one or retry later",
+                    limit
+                ),
+                "type": "rate_limit_error",
+                "code": "too_many_streams",
+            }}),
+            ApiError::Upstream(err) => upstream_error_body(err).unwrap_or_else(|| {
+                json!({"error": {
+                    "message": err.to_string(),
+                    "type": "upstream_error",
+                    "code": if is_timeout(err) { Some("timeout") } else { None },
+                }})
+            }),
+        }
+    }
+}
+
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.is::<UpstreamTimeout>()
+        || err.is::<DeadlineExceeded>()
+        || err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout)
+}
+
+// The upstream's own error body, when it's in the OpenAI shape
+fn upstream_error_body(err: &anyhow::Error) -> Option<Value> {
+    let error = err.downcast_ref::<OpenAIError>()?;
+    serde_json::from_str::<Value>(&error.body)
+        .ok()
+        .filter(|body| body.get("error").is_some_and(Value::is_object))
+}
+
+impl ApiError {
+    // Turns a body that doesn't deserialize into the request type into an
+    // error, naming the parameter when a required one is missing.
+    pub fn invalid_body(message: String) -> Self {
+        match missing_field(&message) {
+            Some(param) => ApiError::InvalidRequest(ValidationError::new(
+                param,
+                format!("Missing required parameter: '{}'.", param),
+            )),
+            None => ApiError::InvalidBody(message),
+        }
+    }
+}
+
+// serde reports missing fields as "missing field `name`"
+fn missing_field(message: &str) -> Option<&str> {
+    let rest = message.split("missing field `").nth(1)?;
+    rest.split('`').next()
+}
+
+fn reset_seconds(status: &RateLimitStatus) -> u64 {
+    status.reset.as_secs_f64().ceil() as u64
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(error: ValidationError) -> Self {
+        ApiError::InvalidRequest(error)
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::invalid_body(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        if let ApiError::RateLimited { status, .. } = &self {
+            let reset = reset_seconds(status);
+            let headers = response.headers_mut();
+            headers.insert("x-ratelimit-limit", HeaderValue::from(status.limit));
+            headers.insert("x-ratelimit-remaining", HeaderValue::from(status.remaining));
+            headers.insert("x-ratelimit-reset", HeaderValue::from(reset));
+            headers.insert(RETRY_AFTER, HeaderValue::from(reset));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_field_names_param() {
+        let error = ApiError::invalid_body(
+            "Failed to deserialize the JSON body into the target type: missing field `model` at line 1 column 50".to_string(),
+        );
+
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.body()["error"]["param"], "model");
+        assert_eq!(
+            error.body()["error"]["message"],
+            "Missing required parameter: 'model'."
+        );
+    }
+
+    #[test]
+    fn test_upstream_status_and_body_are_relayed() {
+        let body = json!({"error": {"message": "Invalid API key", "type": "invalid_request_error", "code": "invalid_api_key"}});
+        let error = ApiError::Upstream(
+            OpenAIError {
+                status: StatusCode::UNAUTHORIZED,
+                body: body.to_string(),
+            }
+            .into(),
+        );
+
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.body(), body);
+    }
+
+    #[test]
+    fn test_upstream_body_without_error_is_wrapped() {
+        let error = ApiError::Upstream(
+            OpenAIError {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                body: "<html>Service Unavailable</html>".to_string(),
+            }
+            .into(),
+        );
+
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.body()["error"]["type"], "upstream_error");
+        assert_eq!(
+            error.body()["error"]["message"],
+            "OpenAI API error: <html>Service Unavailable</html>"
+        );
+    }
+
+    #[test]
+    fn test_other_body_errors_keep_message() {
+        let error = ApiError::invalid_body("expected value at line 1 column 1".to_string());
+
+        assert_eq!(error.body()["error"]["param"], Value::Null);
+        assert_eq!(
+            error.body()["error"]["message"],
+            "expected value at line 1 column 1"
+        );
+    }
+}
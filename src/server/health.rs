@@ -0,0 +1,115 @@
+use super::AppState;
+use crate::models::openai::OpenAIClient;
+use axum::extract::State;
+use reqwest::StatusCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a readiness check result is reused, so frequent probes don't turn
+// into a stream of upstream calls
+pub const READINESS_TTL: Duration = Duration::from_secs(10);
+
+// Whether the OpenAI upstream can be reached, checked with the same cheap
+// authenticated call as the warmup
+pub struct Readiness {
+    client: OpenAIClient,
+    ttl: Duration,
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Readiness {
+    pub fn new(client: OpenAIClient) -> Self {
+        Self {
+            client,
+            ttl: READINESS_TTL,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub async fn check(&self) -> bool {
+        if let Some((checked, ready)) = *self.last.lock().unwrap() {
+            if checked.elapsed() < self.ttl {
+                return ready;
+            }
+        }
+        let ready = match self.client.warmup(self.client.base_url()).await {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::warn!(error = %err, "Readiness check failed");
+                false
+            }
+        };
+        *self.last.lock().unwrap() = Some((Instant::now(), ready));
+        ready
+    }
+}
+
+// Liveness: the server answers
+pub(super) async fn healthz_handler() -> StatusCode {
+    StatusCode::OK
+}
+
+// Readiness: the upstream can be reached, when the check is enabled
+pub(super) async fn readyz_handler(State(state): State<AppState>) -> StatusCode {
+    match &state.readiness {
+        Some(readiness) if !readiness.check().await => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{router, tests::dev_state};
+    use super::*;
+    use crate::mock;
+    use axum::body::Body;
+    use axum::http::Request;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn probe(state: AppState, path: &str) -> StatusCode {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        router(state).oneshot(request).await.unwrap().status()
+    }
+
+    fn with_readiness(base_url: &str) -> AppState {
+        let client = OpenAIClient::with_base_url("sk-test".to_string(), base_url);
+        AppState {
+            readiness: Some(Arc::new(Readiness::new(client))),
+            ..dev_state()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_healthz_is_always_ok() {
+        let state = with_readiness("http://127.0.0.1:1/v1");
+
+        assert_eq!(probe(state, "/healthz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_without_check_is_ok() {
+        assert_eq!(probe(dev_state(), "/readyz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_checks_upstream_once_per_ttl() {
+        let (base_url, calls) = mock::models(StatusCode::OK).await;
+        let state = with_readiness(&base_url);
+
+        assert_eq!(probe(state.clone(), "/readyz").await, StatusCode::OK);
+        assert_eq!(probe(state, "/readyz").await, StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_unreachable_upstream() {
+        let state = with_readiness("http://127.0.0.1:1/v1");
+
+        assert_eq!(
+            probe(state, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
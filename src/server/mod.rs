@@ -0,0 +1,3795 @@
+use crate::cache::{self, CacheStatus, ResponseCache};
+use crate::capabilities::{CapabilityTable, UnsupportedStream};
+use crate::config;
+use crate::dedupe::StreamDedupe;
+use crate::logging::{LogLevel, LOG_LEVEL_FIELD};
+use crate::metrics::{self, Metrics};
+use crate::models::deadline::DeadlineExceeded;
+use crate::models::finish_reason;
+use crate::models::openai::{
+    completion, ChatCompletionChunk, EmbeddingsRequest, ModelList, ModelObject,
+    OpenAIChatCompletionRequest, OpenAIChatCompletionResponse, OpenAIClient, ProviderKey,
+};
+use crate::models::provider::{Provider, OPENAI_PROVIDER};
+use crate::preprocess::{DeprecatedModels, PromptTemplates};
+use crate::pricing::{CostRouting, CHEAPEST_MODEL};
+use crate::rate_limit::{ProviderConcurrency, RateLimiter, SoftLimiter, StreamLimiter};
+use crate::router::ModelRouter;
+use crate::shadow::Shadow;
+use crate::status::ProviderStats;
+use crate::streaming::ChunkNormalizer;
+use crate::transform::{self, ContentFilter, ResponseTransform};
+use crate::validation::{self, MaxTokensConflict, ToolLimits, ValidationError};
+use axum::{
+    extract::{rejection::JsonRejection, DefaultBodyLimit, Path, Query, Request, State},
+    http::{
+        header::{ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderValue,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::{future::join_all, stream};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod chat_stream;
+mod compare;
+mod error;
+mod health;
+mod overrides;
+mod stored;
+mod trace;
+
+pub use error::ApiError;
+pub use health::Readiness;
+pub use stored::StoredCompletions;
+pub use trace::RouteTrace;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub client: OpenAIClient,
+    pub soft_limiter: Arc<SoftLimiter>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub capabilities: Arc<CapabilityTable>,
+    pub unsupported_stream: UnsupportedStream,
+    // Streams still running after this are closed, see `streaming::limit_duration`
+    pub max_stream_duration: Option<Duration>,
+    // Streams a client may have open at once, by fingerprint of its API key
+    pub stream_limiter: Option<Arc<StreamLimiter>>,
+    // Time a request has to complete unless it sets `TIMEOUT_HEADER`
+    pub timeout: Option<Duration>,
+    pub deprecated_models: Arc<DeprecatedModels>,
+    // Replaces models without an explicit route, unless they are remapped or
+    // named by the configuration, see `keeps_model`
+    pub default_model: Option<String>,
+    // Model for requests without one, by fingerprint of the caller's API key
+    pub key_default_models: Arc<HashMap<String, String>>,
+    // Model of synthetic load balancer requests, answered without an upstream
+    pub healthcheck_model: Option<String>,
+    // Log level of requests, by fingerprint of the caller's API key, see
+    // `logging`
+    pub key_log_levels: Arc<HashMap<String, LogLevel>>,
+    // Picks the model of requests for `CHEAPEST_MODEL`
+    pub cost_routing: Option<Arc<CostRouting>>,
+    pub prompt_templates: Arc<PromptTemplates>,
+    // Model to retry with when the upstream fails for the requested model
+    pub fallback_models: Arc<HashMap<String, String>>,
+    // Model to retry with when the requested model refuses, opt-in
+    pub refusal_models: Arc<HashMap<String, String>>,
+    pub max_message_bytes: Option<usize>,
+    pub tool_limits: ToolLimits,
+    pub max_tokens_conflict: MaxTokensConflict,
+    // Largest accepted request body
+    pub max_body_bytes: usize,
+    // Most upstream calls a single request may fan out to, by comparing
+    // models or splitting `n`
+    pub max_fanout: usize,
+    // Sets `prompt_cache_key` on requests that don't have one
+    pub auto_prompt_cache_key: bool,
+    // Limits concurrent upstream requests to each provider
+    pub concurrency: Option<Arc<ProviderConcurrency>>,
+    // Sends `QUEUE_DEPTH_HEADER` as a backpressure signal
+    pub queue_depth_header: bool,
+    pub chunk_normalizer: Arc<ChunkNormalizer>,
+    // Rewrite JSON responses before they are returned, see `transform`
+    pub response_transforms: Arc<Vec<Box<dyn ResponseTransform>>>,
+    // Applied after the transforms, strict clients included
+    pub content_filter: Option<Arc<ContentFilter>>,
+    pub cache: Option<Arc<dyn ResponseCache>>,
+    // Provider of each model. Requests routed to OpenAI are sent by `client`,
+    // which also streams, splits choices and follows base URL overrides.
+    pub router: Arc<ModelRouter>,
+    // Fans out one upstream stream to identical deterministic requests
+    pub stream_dedupe: Option<Arc<StreamDedupe<Result<ChatCompletionChunk, String>>>>,
+    // Retries without streaming when the upstream answers a stream with JSON
+    pub stream_fallback: bool,
+    // Receives a copy of sampled requests, see `shadow`
+    pub shadow: Option<Arc<Shadow>>,
+    // Proxies `GET /v1/chat/completions/{id}` for stored completions, to the
+    // caller that created them
+    pub completion_retrieval: bool,
+    pub stored_completions: Arc<StoredCompletions>,
+    pub admin_token: Option<String>,
+    // Serves `/v1` requests without a bearer token, for deployments behind
+    // another auth layer. The upstream gets the server's key either way.
+    pub allow_anonymous: bool,
+    // Accepts upstream keys in `PROVIDER_KEY_HEADER`, for trusted internal
+    // callers
+    pub trusted_provider_keys: bool,
+    pub metrics: Arc<Metrics>,
+    pub provider_stats: Arc<ProviderStats>,
+    // Upstream check of `/readyz`, which always answers 200 without one
+    pub readiness: Option<Arc<Readiness>>,
+    pub dev_mode: bool,
+    pub base_url_allowlist: Arc<Vec<String>>,
+}
+
+impl AppState {
+    pub fn new(client: OpenAIClient) -> Self {
+        // Every model goes to OpenAI until other providers are routed
+        let mut router = ModelRouter::default();
+        router.register("", Arc::new(client.clone()));
+        Self {
+            client,
+            soft_limiter: Arc::new(SoftLimiter::new(HashMap::new())),
+            rate_limiter: Arc::new(RateLimiter::new(HashMap::new())),
+            capabilities: Arc::new(CapabilityTable::default()),
+            unsupported_stream: UnsupportedStream::default(),
+            max_stream_duration: None,
+            stream_limiter: None,
+            timeout: None,
+            deprecated_models: Arc::new(DeprecatedModels::default()),
+            default_model: None,
+            key_default_models: Arc::new(HashMap::new()),
+            healthcheck_model: None,
+            key_log_levels: Arc::new(HashMap::new()),
+            cost_routing: None,
+            prompt_templates: Arc::new(PromptTemplates::default()),
+            fallback_models: Arc::new(HashMap::new()),
+            refusal_models: Arc::new(HashMap::new()),
+            max_message_bytes: None,
+            tool_limits: ToolLimits::default(),
+            max_tokens_conflict: MaxTokensConflict::default(),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_fanout: DEFAULT_MAX_FANOUT,
+            auto_prompt_cache_key: false,
+            concurrency: None,
+            queue_depth_header: false,
+            chunk_normalizer: Arc::new(ChunkNormalizer::default()),
+            response_transforms: Arc::new(Vec::new()),
+            content_filter: None,
+            cache: None,
+            router: Arc::new(router),
+            stream_dedupe: None,
+            stream_fallback: true,
+            shadow: None,
+            completion_retrieval: false,
+            stored_completions: Arc::new(StoredCompletions::default()),
+            admin_token: None,
+            allow_anonymous: false,
+            trusted_provider_keys: false,
+            metrics: Arc::new(Metrics::default()),
+            readiness: None,
+            provider_stats: Arc::new(ProviderStats::default()),
+            dev_mode: false,
+            base_url_allowlist: Arc::new(Vec::new()),
+        }
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    let mut api = Router::new()
+        .route("/v1/chat/completions", post(chat_handler))
+        .route("/v1/chat/compare", post(compare::compare_handler))
+        .route("/v1/embeddings", post(embeddings_handler))
+        .route("/v1/models", get(models_handler));
+    if state.completion_retrieval {
+        api = api.route(
+            "/v1/chat/completions/{id}",
+            get(retrieve_completion_handler),
+        );
+    }
+    let api = api.route_layer(middleware::from_fn_with_state(state.clone(), require_key));
+    let mut router = Router::new()
+        .merge(api)
+        .route("/healthz", get(health::healthz_handler))
+        .route("/readyz", get(health::readyz_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/status", get(status_handler));
+    // Admin endpoints only exist when an admin token is configured
+    if state.admin_token.is_some() {
+        router = router.route("/admin/cache/invalidate", post(invalidate_cache_handler));
+    }
+    router
+        .layer(DefaultBodyLimit::max(state.max_body_bytes))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_content_length,
+        ))
+        .with_state(state)
+}
+
+// Bodies over the limit without a content length are only caught while reading
+fn body_rejected(state: &AppState, rejection: JsonRejection) -> ApiError {
+    if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return ApiError::PayloadTooLarge {
+            limit: state.max_body_bytes,
+        };
+    }
+    ApiError::from(rejection)
+}
+
+// Same as axum's default body limit
+pub const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+pub const DEFAULT_MAX_FANOUT: usize = 16;
+// Models listed in a model_not_found error, to keep its body small
+pub const MAX_LISTED_MODELS: usize = 50;
+
+// Rejects bodies that announce a size over the limit before any of it is
+// read. Chunked bodies without a length are capped by `DefaultBodyLimit`.
+async fn check_content_length(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > state.max_body_bytes as u64) {
+        return ApiError::PayloadTooLarge {
+            limit: state.max_body_bytes,
+        }
+        .into_response();
+    }
+    next.run(request).await
+}
+
+// Rejects `/v1` requests without a bearer token unless anonymous requests are
+// allowed. The token itself isn't checked, callers are told apart by it.
+// Health checks of load balancers, which have no key, are answered here.
+async fn require_key(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.allow_anonymous || bearer_token(request.headers()).is_some() {
+        return next.run(request).await;
+    }
+    if let Some(model) = &state.healthcheck_model {
+        if request.uri().path() == "/v1/chat/completions" {
+            let body = axum::body::to_bytes(request.into_body(), state.max_body_bytes).await;
+            let probe = body
+                .ok()
+                .and_then(|body| serde_json::from_slice::<ModelOnly>(&body).ok());
+            if probe.is_some_and(|probe| probe.model == *model) {
+                return healthcheck_response(model);
+            }
+        }
+    }
+    error_response(
+        StatusCode::UNAUTHORIZED,
+        "authentication_error",
+        "Missing API key, send it as `Authorization: Bearer <key>`",
+    )
+}
+
+// The model of a request body, whatever else it has
+#[derive(Deserialize)]
+struct ModelOnly {
+    model: String,
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .filter(|token| !token.is_empty())
+}
+
+pub const BASE_URL_HEADER: &str = "x-kubellm-base-url";
+pub const PROVIDER_HEADER: &str = "x-kubellm-provider";
+pub const MODEL_HEADER: &str = "x-kubellm-model";
+pub const FALLBACK_HEADER: &str = "x-kubellm-fallback";
+pub const CACHE_HEADER: &str = "x-kubellm-cache";
+pub const ROUTE_TRACE_HEADER: &str = "x-kubellm-route-trace";
+pub const TIMEOUT_HEADER: &str = "x-kubellm-timeout-ms";
+pub const QUEUE_DEPTH_HEADER: &str = "x-kubellm-queue-depth";
+// Time spent waiting for the upstream, until the first chunk for streams, and
+// the rest of the time the gateway took to answer
+pub const UPSTREAM_LATENCY_HEADER: &str = "x-kubellm-upstream-latency-ms";
+pub const OVERHEAD_HEADER: &str = "x-kubellm-overhead-ms";
+pub const ATTEMPTS_HEADER: &str = "x-kubellm-attempts";
+// Opts out of response transforms for clients with strict parsers
+pub const STRICT_HEADER: &str = "x-kubellm-strict";
+// Keeps the requests of a conversation on one deployment of a pool
+pub const SESSION_HEADER: &str = "x-kubellm-session";
+// The caller's own upstream API key, in trusted mode
+pub const PROVIDER_KEY_HEADER: &str = "x-kubellm-provider-key";
+
+// Model name clients send to get the default model of their API key
+pub const PLACEHOLDER_MODEL: &str = "default";
+
+pub fn error_response(
+    status: StatusCode,
+    error_type: &str,
+    message: impl Into<String>,
+) -> Response {
+    let body = json!({"error": {"message": message.into(), "type": error_type}});
+    (status, Json(body)).into_response()
+}
+
+// Decides whether the client wants a streamed response. An explicit `stream`
+// in the body always wins; the Accept header is only consulted when the body
+// leaves it out, so `Accept: text/event-stream` means `stream: true` and any
+// other Accept value means `stream: false`.
+pub fn negotiate_stream(body: Option<bool>, headers: &HeaderMap) -> Option<bool> {
+    if body.is_some() {
+        return body;
+    }
+    let accept = headers.get(ACCEPT)?.to_str().ok()?;
+    Some(
+        accept
+            .split(',')
+            .any(|media| media.trim().starts_with("text/event-stream")),
+    )
+}
+
+// Lets a developer point a single request at another upstream. The header is
+// ignored outside dev mode and only allowlisted hosts are accepted, so it can't
+// be used to make the gateway call arbitrary addresses.
+pub fn base_url_override(state: &AppState, headers: &HeaderMap) -> Result<Option<String>, String> {
+    if !state.dev_mode {
+        return Ok(None);
+    }
+    let Some(value) = headers.get(BASE_URL_HEADER) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| format!("Invalid {} header", BASE_URL_HEADER))?;
+    let url = reqwest::Url::parse(value).map_err(|_| format!("Invalid base URL: {}", value))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Unsupported base URL scheme: {}", url.scheme()));
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !state
+        .base_url_allowlist
+        .iter()
+        .any(|allowed| allowed == host)
+    {
+        return Err(format!("Host {} is not in the base URL allowlist", host));
+    }
+    Ok(Some(value.to_string()))
+}
+
+// Lets a trusted caller use its own key for the upstream. Outside trusted mode
+// the header is rejected, so the caller doesn't assume its key was used.
+fn provider_key(state: &AppState, headers: &HeaderMap) -> Result<Option<ProviderKey>, String> {
+    let Some(value) = headers.get(PROVIDER_KEY_HEADER) else {
+        return Ok(None);
+    };
+    if !state.trusted_provider_keys {
+        return Err(format!(
+            "The {} header is only accepted in trusted mode",
+            PROVIDER_KEY_HEADER
+        ));
+    }
+    let key = value
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| format!("Invalid {} header", PROVIDER_KEY_HEADER))?;
+    Ok(Some(ProviderKey::new(key)))
+}
+
+// How long the request has to complete, from `TIMEOUT_HEADER` or the
+// configured timeout
+fn request_timeout(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<Option<Duration>, ValidationError> {
+    let Some(value) = headers.get(TIMEOUT_HEADER) else {
+        return Ok(state.timeout);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|millis| *millis > 0)
+        .map(|millis| Some(Duration::from_millis(millis)))
+        .ok_or_else(|| {
+            let message = format!(
+                "{} must be a positive number of milliseconds",
+                TIMEOUT_HEADER
+            );
+            ValidationError::new(TIMEOUT_HEADER, message)
+        })
+}
+
+// The default model of the caller's API key, for requests that omit the model
+// or ask for `PLACEHOLDER_MODEL`
+fn key_default_model<'a>(
+    state: &'a AppState,
+    headers: &HeaderMap,
+    request: &OpenAIChatCompletionRequest,
+) -> Option<&'a String> {
+    if !request.model.is_empty() && request.model != PLACEHOLDER_MODEL {
+        return None;
+    }
+    let key = bearer_token(headers)?;
+    state.key_default_models.get(&config::fingerprint(key))
+}
+
+// Whether a model is served as asked instead of by the default model: the
+// cost router's, those with an explicit route and those the configuration
+// names as key default, fallback or alternate
+fn keeps_model(state: &AppState, model: &str) -> bool {
+    model == CHEAPEST_MODEL
+        || state.router.routes_explicitly(model)
+        || state
+            .key_default_models
+            .values()
+            .chain(state.fallback_models.values())
+            .chain(state.refusal_models.values())
+            .any(|configured| configured == model)
+}
+
+// Validates the request and adapts it to the target model before dispatch
+fn prepare(
+    state: &AppState,
+    request: &mut OpenAIChatCompletionRequest,
+    trace: &mut RouteTrace,
+) -> Result<(), ApiError> {
+    if let Some(name) = state.prompt_templates.expand(request)? {
+        trace.record("template", name);
+    }
+    if let Some(max_bytes) = state.max_message_bytes {
+        validation::check_message_length(request, max_bytes)?;
+    }
+    state.tool_limits.check(request)?;
+    if state.max_tokens_conflict.resolve(request)? {
+        trace.record("drop", "max_tokens");
+        tracing::info!(
+            max_completion_tokens = request.max_completion_tokens,
+            "Dropping max_tokens in favor of a different max_completion_tokens"
+        );
+    }
+    if let Some(original) = state.deprecated_models.remap(request) {
+        trace.record("alias", format!("{}>{}", original, request.model));
+    } else if let Some(model) = state
+        .default_model
+        .as_ref()
+        .filter(|model| **model != request.model && !keeps_model(state, &request.model))
+    {
+        let original = std::mem::replace(&mut request.model, model.clone());
+        trace.record("default", format!("{}>{}", original, request.model));
+    }
+    if let Some(routing) = state
+        .cost_routing
+        .as_ref()
+        .filter(|_| request.model == CHEAPEST_MODEL)
+    {
+        let Some(model) = routing.pick(request, &state.capabilities, &state.router) else {
+            let message = "No candidate model supports this request";
+            return Err(ValidationError::new("model", message).into());
+        };
+        request.model = model.to_string();
+        trace.record("cost", format!("{}>{}", CHEAPEST_MODEL, request.model));
+    }
+    if request.model.is_empty() {
+        let message = "Missing required parameter: 'model'.";
+        return Err(ValidationError::new("model", message).into());
+    }
+    if state.router.route(&request.model).is_none() {
+        let available = available_models(state)
+            .into_iter()
+            .map(|(model, _)| model)
+            .take(MAX_LISTED_MODELS)
+            .collect();
+        return Err(ApiError::ModelNotFound {
+            model: request.model.clone(),
+            available,
+        });
+    }
+    // Before any limit counts the request
+    request.validate().map_err(ApiError::InvalidParams)?;
+    if let Err(status) = state.rate_limiter.check(&request.model) {
+        return Err(ApiError::RateLimited {
+            model: request.model.clone(),
+            status,
+        });
+    }
+    if let Some(exceeded) = state.soft_limiter.record(&request.model) {
+        tracing::warn!(
+            model = %exceeded.model,
+            requests_per_minute = exceeded.count,
+            soft_limit = exceeded.threshold,
+            "Soft rate limit exceeded"
+        );
+    }
+    if state.capabilities.collapse_developer_role(request) > 0 {
+        trace.record("developer", "system");
+        tracing::info!(
+            model = %request.model,
+            "Sending developer messages as system messages"
+        );
+    }
+    if let Some(n) = state.capabilities.choice_split(request) {
+        if n > state.max_fanout as u64 {
+            let message = format!(
+                "n of {} exceeds the limit of {} for model {}, which is sent one request per choice",
+                n, state.max_fanout, request.model
+            );
+            return Err(ValidationError::new("n", message).into());
+        }
+    }
+    for param in state.capabilities.filter(request) {
+        trace.record("drop", param);
+        tracing::warn!(model = %request.model, param, "Dropping unsupported parameter");
+    }
+    if let Some(tier) = &request.service_tier {
+        let provider = state.router.route(&request.model);
+        if !provider.is_some_and(|provider| provider.supports_service_tier(tier)) {
+            trace.record("drop", "service_tier");
+            tracing::warn!(
+                model = %request.model,
+                service_tier = %tier,
+                "Dropping service tier the provider doesn't support"
+            );
+            request.service_tier = None;
+        }
+    }
+    if state.auto_prompt_cache_key && request.prompt_cache_key.is_none() {
+        request.prompt_cache_key = Some(cache::prompt_cache_key(request));
+    }
+    Ok(())
+}
+
+// One span per request, with the model once it's known
+#[tracing::instrument(
+    name = "chat_completion",
+    skip_all,
+    fields(model = tracing::field::Empty, log_level = tracing::field::Empty)
+)]
+async fn chat_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<OpenAIChatCompletionRequest>, JsonRejection>,
+) -> Response {
+    let log_level =
+        bearer_token(&headers).and_then(|key| state.key_log_levels.get(&config::fingerprint(key)));
+    if let Some(level) = log_level {
+        tracing::Span::current().record(LOG_LEVEL_FIELD, level.as_str());
+    }
+    if let Ok(Json(request)) = &payload {
+        if state.healthcheck_model.as_ref() == Some(&request.model) {
+            return healthcheck_response(&request.model);
+        }
+    }
+    let response = chat(state.clone(), headers, payload).await;
+    state.metrics.response(response.status().as_u16());
+    response
+}
+
+// Transforms to apply to responses, none for strict clients
+fn response_transforms<'a>(
+    state: &'a AppState,
+    headers: &HeaderMap,
+) -> &'a [Box<dyn ResponseTransform>] {
+    let strict = headers
+        .get(STRICT_HEADER)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
+    if strict {
+        &[]
+    } else {
+        &state.response_transforms
+    }
+}
+
+// Canned answer to health check requests, which reach neither the upstream
+// nor metrics and limits
+fn healthcheck_response(model: &str) -> Response {
+    let response = completion(
+        "chatcmpl-healthcheck".to_string(),
+        model,
+        "OK".to_string(),
+        finish_reason::STOP,
+        0,
+        0,
+    );
+    Json(response).into_response()
+}
+
+async fn chat(
+    state: AppState,
+    headers: HeaderMap,
+    payload: Result<Json<OpenAIChatCompletionRequest>, JsonRejection>,
+) -> Response {
+    tracing::debug!("Received request");
+    let started = Instant::now();
+    let mut request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return body_rejected(&state, rejection).into_response(),
+    };
+    let base_url = match base_url_override(&state, &headers) {
+        Ok(base_url) => base_url,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        }
+    };
+    request.provider_key = match provider_key(&state, &headers) {
+        Ok(key) => key,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        }
+    };
+    request.stream = negotiate_stream(request.stream, &headers);
+    request.session = headers
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let annotations = transform::take_annotations(&mut request);
+    let transforms = response_transforms(&state, &headers);
+    let mut trace = RouteTrace::default();
+    match overrides::apply(&mut request, &headers) {
+        Ok(applied) => {
+            for param in applied {
+                trace.record("override", param);
+            }
+        }
+        Err(err) => return ApiError::from(err).into_response(),
+    }
+    if let Some(model) = key_default_model(&state, &headers, &request) {
+        trace.record("key_default", model);
+        request.model = model.clone();
+    }
+    match request_timeout(&state, &headers) {
+        Ok(timeout) => request.deadline = timeout.map(|timeout| started + timeout),
+        Err(err) => return ApiError::from(err).into_response(),
+    }
+    // Kept to prepare the request again for a fallback or alternate model
+    let unprepared = (!state.fallback_models.is_empty() || !state.refusal_models.is_empty())
+        .then(|| request.clone());
+    if let Err(err) = prepare(&state, &mut request, &mut trace) {
+        return err.into_response();
+    }
+    tracing::Span::current().record("model", request.model.as_str());
+    state.metrics.model_requests.inc(&request.model);
+    // Models that can't stream get the complete response replayed as chunks
+    let mut pseudo_stream = false;
+    if request.stream == Some(true) && !state.capabilities.lookup(&request.model).streaming {
+        if state.unsupported_stream == UnsupportedStream::Reject {
+            let message = format!("Model {} does not support streaming", request.model);
+            return ApiError::from(ValidationError::new("stream", message)).into_response();
+        }
+        request.stream = None;
+        pseudo_stream = true;
+        trace.record("stream", "bridged");
+    }
+    // Split choices and other providers are answered in one piece as well
+    if request.stream == Some(true)
+        && (state.capabilities.choice_split(&request).is_some()
+            || provider_for(&state, &request.model) != OPENAI_PROVIDER)
+    {
+        request.stream = None;
+        pseudo_stream = true;
+        trace.record("stream", "bridged");
+    }
+    if request.stream == Some(true) {
+        let caller = bearer_token(&headers).map(cache::hex_digest);
+        let slot = match &state.stream_limiter {
+            Some(limiter) => {
+                let key = bearer_token(&headers)
+                    .map(config::fingerprint)
+                    .unwrap_or_default();
+                match limiter.acquire(&key) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        let limit = limiter.max();
+                        return ApiError::TooManyStreams { limit }.into_response();
+                    }
+                }
+            }
+            None => None,
+        };
+        trace.record("provider", OPENAI_PROVIDER);
+        if let Some(shadow) = &state.shadow {
+            shadow.mirror(&request);
+        }
+        let attempts = request.attempts.clone();
+        let upstream_started = Instant::now();
+        let response =
+            chat_stream::respond(state.clone(), request, base_url, started, caller, slot).await;
+        state
+            .metrics
+            .upstream_latency
+            .observe(upstream_started.elapsed());
+        let response = with_queue_depth(&state, response, OPENAI_PROVIDER);
+        let response = with_attempts(response, &attempts);
+        return traced(&state, response, &trace);
+    }
+
+    let cache_key = match &state.cache {
+        Some(cache) if cache::is_cacheable(&request) => {
+            // Responses from an overridden upstream are kept apart
+            let key = match &base_url {
+                Some(base_url) => format!("{}@{}", cache::cache_key(&request), base_url),
+                None => cache::cache_key(&request),
+            };
+            if let Some(mut response) = cache.get(&key) {
+                tracing::info!(
+                    latency_ms = started.elapsed().as_millis() as u64,
+                    "Cache hit"
+                );
+                trace.record("cache", "hit");
+                for transform in transforms {
+                    transform.apply(&annotations, &mut response);
+                }
+                if let Some(filter) = &state.content_filter {
+                    filter.apply(&mut response);
+                }
+                let provider = provider_for(&state, &request.model);
+                let response = served(response, pseudo_stream, provider, false, CacheStatus::Hit);
+                let response = with_queue_depth(&state, response, provider);
+                let response = with_latency(response, None, started.elapsed());
+                return traced(&state, response, &trace);
+            }
+            Some(key)
+        }
+        _ => None,
+    };
+    let cache_status = if cache_key.is_some() {
+        CacheStatus::Miss
+    } else {
+        CacheStatus::Bypass
+    };
+
+    let model = request.model.clone();
+    let stored = request.store == Some(true);
+    let attempts = request.attempts.clone();
+    trace.record("provider", provider_for(&state, &model));
+    if let Some(n) = state.capabilities.choice_split(&request) {
+        trace.record("split", n.to_string());
+    }
+    if let Some(shadow) = &state.shadow {
+        shadow.mirror(&request);
+    }
+    let upstream_started = Instant::now();
+    let dispatched = dispatch_with_fallback(
+        &state,
+        request,
+        unprepared.as_ref(),
+        base_url.as_deref(),
+        &mut trace,
+    )
+    .await;
+    state
+        .metrics
+        .upstream_latency
+        .observe(upstream_started.elapsed());
+    let (mut response, fallback_model) = match dispatched {
+        Ok(dispatched) => dispatched,
+        Err(err) => {
+            let response = upstream_failed(&state, &model, err, &trace);
+            return with_attempts(response, &attempts);
+        }
+    };
+    let mut upstream_latency = upstream_started.elapsed();
+    let mut fallback = fallback_model.is_some();
+    let mut served_model = model.clone();
+    if let Some(fallback_model) = fallback_model {
+        trace.record("fallback", format!("{}>{}", model, fallback_model));
+        served_model = fallback_model;
+    }
+    let alternate = state
+        .refusal_models
+        .get(&model)
+        .zip(unprepared.as_ref())
+        .filter(|_| response.is_refusal());
+    if let Some((alternate, unprepared)) = alternate {
+        tracing::warn!(
+            model = %model,
+            alternate = %alternate,
+            "Model refused, retrying with the alternate model"
+        );
+        let retried = Instant::now();
+        // The refusal is a valid answer, and stands when the alternate fails
+        match reroute(&state, unprepared, alternate, &mut trace) {
+            Ok(refusal_request) => {
+                let alternate = refusal_request.model.clone();
+                let dispatched = dispatch(&state, refusal_request, base_url.as_deref()).await;
+                state.metrics.upstream_latency.observe(retried.elapsed());
+                upstream_latency += retried.elapsed();
+                match dispatched {
+                    Ok(retried) => {
+                        trace.record("refusal", format!("{}>{}", model, alternate));
+                        served_model = alternate;
+                        response = retried;
+                        fallback = true;
+                    }
+                    Err(err) => tracing::warn!(
+                        alternate = %alternate,
+                        error = %err,
+                        "Alternate model failed, returning the refusal"
+                    ),
+                }
+            }
+            Err(err) => tracing::warn!(
+                alternate = %alternate,
+                error = ?err,
+                "Alternate model rejected the request, returning the refusal"
+            ),
+        }
+    }
+    if stored {
+        tracing::info!(id = %response.id, "Stored completion");
+        if state.completion_retrieval {
+            let owner = bearer_token(&headers).map(config::fingerprint);
+            state.stored_completions.record(&response.id, owner);
+        }
+    }
+    tracing::info!(
+        served_model = %served_model,
+        prompt_tokens = response.usage.prompt_tokens,
+        completion_tokens = response.usage.completion_tokens,
+        total_tokens = response.usage.total_tokens,
+        latency_ms = started.elapsed().as_millis() as u64,
+        "Chat completion"
+    );
+    // Only the requested model's own answers are cached, hits are served as its
+    if let (Some(cache), Some(key), false) = (&state.cache, cache_key, fallback) {
+        cache.put(key, &model, response.clone());
+    }
+    for transform in transforms {
+        transform.apply(&annotations, &mut response);
+    }
+    if let Some(filter) = &state.content_filter {
+        filter.apply(&mut response);
+    }
+    let provider = provider_for(&state, &served_model);
+    let response = served(response, pseudo_stream, provider, fallback, cache_status);
+    let response = with_queue_depth(&state, response, provider);
+    let response = with_latency(response, Some(upstream_latency), started.elapsed());
+    let response = with_attempts(response, &attempts);
+    traced(&state, response, &trace)
+}
+
+// Upstream calls it took to answer, counting retries, fallbacks and the retry
+// of a refusal. Left out when no upstream was called.
+fn with_attempts(mut response: Response, attempts: &AtomicU32) -> Response {
+    let attempts = attempts.load(Ordering::Relaxed);
+    if attempts > 0 {
+        response
+            .headers_mut()
+            .insert(ATTEMPTS_HEADER, HeaderValue::from(attempts));
+    }
+    response
+}
+
+// Splits the time to answer into upstream latency and gateway overhead, the
+// upstream latency is left out when no upstream was called
+fn with_latency(mut response: Response, upstream: Option<Duration>, total: Duration) -> Response {
+    let headers = response.headers_mut();
+    if let Some(upstream) = upstream {
+        headers.insert(
+            UPSTREAM_LATENCY_HEADER,
+            HeaderValue::from(upstream.as_millis() as u64),
+        );
+    }
+    let overhead = total.saturating_sub(upstream.unwrap_or_default());
+    headers.insert(
+        OVERHEAD_HEADER,
+        HeaderValue::from(overhead.as_millis() as u64),
+    );
+    response
+}
+
+// Tells clients how many requests are waiting for the provider, so they can
+// back off before the gateway is saturated
+fn with_queue_depth(state: &AppState, mut response: Response, provider: &str) -> Response {
+    if state.queue_depth_header {
+        let load = state.provider_stats.load();
+        let queued = load.get(provider).map_or(0, |load| load.queued);
+        response
+            .headers_mut()
+            .insert(QUEUE_DEPTH_HEADER, HeaderValue::from(queued));
+    }
+    response
+}
+
+fn upstream_failed(
+    state: &AppState,
+    model: &str,
+    err: anyhow::Error,
+    trace: &RouteTrace,
+) -> Response {
+    tracing::error!(model, error = %err, "Request failed");
+    traced(state, ApiError::Upstream(err).into_response(), trace)
+}
+
+// Adds the routing trace in dev mode
+fn traced(state: &AppState, mut response: Response, trace: &RouteTrace) -> Response {
+    if state.dev_mode {
+        if let Ok(value) = HeaderValue::from_str(&trace.render()) {
+            response.headers_mut().insert(ROUTE_TRACE_HEADER, value);
+        }
+    }
+    response
+}
+
+// Name of the provider `model` is sent to
+fn provider_for<'a>(state: &'a AppState, model: &str) -> &'a str {
+    state
+        .router
+        .route(model)
+        .map_or(OPENAI_PROVIDER, |provider| provider.name())
+}
+
+async fn dispatch(
+    state: &AppState,
+    request: OpenAIChatCompletionRequest,
+    base_url: Option<&str>,
+) -> anyhow::Result<OpenAIChatCompletionResponse> {
+    let Some(provider) = state.router.route(&request.model) else {
+        return Err(anyhow::anyhow!(
+            "No provider serves model {}",
+            request.model
+        ));
+    };
+    if provider.name() != OPENAI_PROVIDER {
+        let mut load = state.provider_stats.queue(provider.name());
+        let _permit = match &state.concurrency {
+            Some(concurrency) => Some(
+                concurrency
+                    .semaphore(provider.name())
+                    .acquire_owned()
+                    .await?,
+            ),
+            None => None,
+        };
+        load.start();
+        let started = Instant::now();
+        // The deadline applies here for providers that can't be told about it
+        let deadline = request.deadline;
+        let chat = provider.chat(request);
+        let result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), chat)
+                .await
+                .unwrap_or_else(|_| Err(DeadlineExceeded.into())),
+            None => chat.await,
+        };
+        state
+            .provider_stats
+            .record(provider.name(), result.is_ok(), started.elapsed());
+        return result;
+    }
+    match state.capabilities.choice_split(&request) {
+        Some(n) => dispatch_split(state, request, n, base_url).await,
+        None => dispatch_one(state, request, base_url).await,
+    }
+}
+
+// Sends `n` single choice requests concurrently for a model that doesn't
+// support `n`, and merges their answers
+async fn dispatch_split(
+    state: &AppState,
+    mut request: OpenAIChatCompletionRequest,
+    n: u64,
+    base_url: Option<&str>,
+) -> anyhow::Result<OpenAIChatCompletionResponse> {
+    request.n = None;
+    let calls = (0..n).map(|_| dispatch_one(state, request.clone(), base_url));
+    let responses = join_all(calls)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    OpenAIChatCompletionResponse::merge_choices(responses)
+        .ok_or_else(|| anyhow::anyhow!("No choices requested"))
+}
+
+async fn dispatch_one(
+    state: &AppState,
+    request: OpenAIChatCompletionRequest,
+    base_url: Option<&str>,
+) -> anyhow::Result<OpenAIChatCompletionResponse> {
+    let mut load = state.provider_stats.queue(OPENAI_PROVIDER);
+    let _permit = match &state.concurrency {
+        Some(concurrency) => Some(
+            concurrency
+                .semaphore(OPENAI_PROVIDER)
+                .acquire_owned()
+                .await?,
+        ),
+        None => None,
+    };
+    load.start();
+    let started = Instant::now();
+    let result = match base_url {
+        Some(base_url) => state.client.chat_with_base_url(request, base_url).await,
+        None => state.client.chat(request).await,
+    };
+    state
+        .provider_stats
+        .record(OPENAI_PROVIDER, result.is_ok(), started.elapsed());
+    result
+}
+
+// Retries once with the configured fallback model when the upstream fails,
+// with `unprepared` prepared for it. Returns the fallback model when it served
+// the request.
+async fn dispatch_with_fallback(
+    state: &AppState,
+    request: OpenAIChatCompletionRequest,
+    unprepared: Option<&OpenAIChatCompletionRequest>,
+    base_url: Option<&str>,
+    trace: &mut RouteTrace,
+) -> anyhow::Result<(OpenAIChatCompletionResponse, Option<String>)> {
+    let model = request.model.clone();
+    let err = match dispatch(state, request, base_url).await {
+        Ok(response) => return Ok((response, None)),
+        Err(err) => err,
+    };
+    let (Some(fallback), Some(unprepared)) = (state.fallback_models.get(&model), unprepared) else {
+        return Err(err);
+    };
+    let fallback_request = match reroute(state, unprepared, fallback, trace) {
+        Ok(fallback_request) => fallback_request,
+        Err(rejected) => {
+            tracing::warn!(
+                model = %model,
+                fallback = %fallback,
+                error = ?rejected,
+                "Fallback model rejected the request"
+            );
+            return Err(err);
+        }
+    };
+    tracing::warn!(
+        model = %model,
+        fallback = %fallback_request.model,
+        error = %err,
+        "Model failed, falling back"
+    );
+    let fallback = fallback_request.model.clone();
+    let response = dispatch(state, fallback_request, base_url).await?;
+    Ok((response, Some(fallback)))
+}
+
+// `unprepared` sent to `model` instead, prepared for it like the original
+// request was, limits included
+fn reroute(
+    state: &AppState,
+    unprepared: &OpenAIChatCompletionRequest,
+    model: &str,
+    trace: &mut RouteTrace,
+) -> Result<OpenAIChatCompletionRequest, ApiError> {
+    let mut request = OpenAIChatCompletionRequest {
+        model: model.to_string(),
+        ..unprepared.clone()
+    };
+    prepare(state, &mut request, trace)?;
+    // Only complete responses are rerouted
+    request.stream = None;
+    Ok(request)
+}
+
+// Tells the client which provider and model actually served the request
+fn served(
+    response: OpenAIChatCompletionResponse,
+    as_stream: bool,
+    provider: &str,
+    fallback: bool,
+    cache_status: CacheStatus,
+) -> Response {
+    let model = response.model.clone();
+    let mut response = if as_stream {
+        event_stream(response.into_chunks())
+    } else {
+        (StatusCode::OK, Json(response)).into_response()
+    };
+    route_headers(&mut response, provider, &model, fallback, cache_status);
+    response
+}
+
+fn route_headers(
+    response: &mut Response,
+    provider: &str,
+    model: &str,
+    fallback: bool,
+    cache_status: CacheStatus,
+) {
+    let headers = response.headers_mut();
+    headers.insert(PROVIDER_HEADER, HeaderValue::from_str(provider).unwrap());
+    if let Ok(model) = HeaderValue::from_str(model) {
+        headers.insert(MODEL_HEADER, model);
+    }
+    headers.insert(
+        FALLBACK_HEADER,
+        HeaderValue::from_static(if fallback { "true" } else { "false" }),
+    );
+    headers.insert(
+        CACHE_HEADER,
+        HeaderValue::from_static(cache_status.as_str()),
+    );
+}
+
+// Sends chunks as server-sent events, terminated like OpenAI streams
+fn event_stream(chunks: Vec<ChatCompletionChunk>) -> Response {
+    let events = chunks
+        .into_iter()
+        .map(|chunk| Event::default().json_data(chunk))
+        .chain(std::iter::once(Ok(Event::default().data("[DONE]"))));
+    Sse::new(stream::iter(events)).into_response()
+}
+
+// Pre-establishes upstream connections so the first real request doesn't pay
+// for the TLS handshake. Failures are logged, never fatal. Returns how many
+// providers were warmed up.
+pub async fn warmup(providers: Vec<(String, Arc<dyn Provider>)>) -> usize {
+    let mut warmed = 0;
+    for (name, provider) in providers {
+        match provider.warmup().await {
+            Ok(()) => {
+                tracing::info!(provider = %name, "Warmed up provider");
+                warmed += 1;
+            }
+            Err(err) => tracing::warn!(provider = %name, error = %err, "Warmup failed"),
+        }
+    }
+    warmed
+}
+
+async fn retrieve_completion_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let base_url = match base_url_override(&state, &headers) {
+        Ok(base_url) => base_url,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        }
+    };
+    // Other callers' completions look the same as missing ones
+    let owner = bearer_token(&headers).map(config::fingerprint);
+    if !state.stored_completions.owned_by(&id, owner.as_deref()) {
+        let message = format!("No completion found with id '{}'", id);
+        return error_response(StatusCode::NOT_FOUND, "invalid_request_error", message);
+    }
+    let base_url = base_url.as_deref().unwrap_or(state.client.base_url());
+    match state.client.retrieve_completion(&id, base_url).await {
+        Ok((status, body)) => (status, [(CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(err) => ApiError::Upstream(err).into_response(),
+    }
+}
+
+// Embeddings go to the OpenAI upstream, or the base URL override
+async fn embeddings_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<EmbeddingsRequest>, JsonRejection>,
+) -> Response {
+    let mut request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return body_rejected(&state, rejection).into_response(),
+    };
+    let base_url = match base_url_override(&state, &headers) {
+        Ok(base_url) => base_url,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        }
+    };
+    request.provider_key = match provider_key(&state, &headers) {
+        Ok(key) => key,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        }
+    };
+    let base_url = base_url.as_deref().unwrap_or(state.client.base_url());
+    match state.client.embeddings(&request, base_url).await {
+        Ok(response) => Json(response).into_response(),
+        Err(err) => ApiError::Upstream(err).into_response(),
+    }
+}
+
+// Rolling request counts, error rate and median latency per provider
+async fn status_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.provider_stats.snapshot())
+}
+
+// Models the providers know by name, plus those named in the configuration,
+// each owned by the provider it routes to. Catch-all providers such as OpenAI
+// serve any model and only show up through the configured names. Sorted by
+// model.
+fn available_models(state: &AppState) -> Vec<(String, &str)> {
+    let configured = state
+        .default_model
+        .iter()
+        .chain(state.key_default_models.values())
+        .chain(state.fallback_models.values())
+        .chain(state.refusal_models.values());
+    let mut models: Vec<(String, &str)> = state.router.models();
+    models.extend(configured.filter_map(|model| {
+        let provider = state.router.route(model)?.name();
+        Some((model.clone(), provider))
+    }));
+    models.sort();
+    models.dedup_by(|a, b| a.0 == b.0);
+    models
+}
+
+// The available models in the OpenAI model list shape
+async fn models_handler(State(state): State<AppState>) -> Json<ModelList> {
+    Json(ModelList::new(
+        available_models(&state)
+            .into_iter()
+            .map(|(model, provider)| ModelObject::new(model, provider))
+            .collect(),
+    ))
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+    if let Some(shadow) = &state.shadow {
+        shadow.render(&mut out);
+    }
+    let mut load: Vec<_> = state.provider_stats.load().into_iter().collect();
+    load.sort_by(|a, b| a.0.cmp(&b.0));
+    let queued: Vec<_> = load
+        .iter()
+        .map(|(provider, load)| (provider.as_str(), load.queued as u64))
+        .collect();
+    metrics::render_gauge(
+        &mut out,
+        "kubellm_queue_depth",
+        "Requests waiting for a concurrency slot per provider",
+        "provider",
+        &queued,
+    );
+    let in_flight: Vec<_> = load
+        .iter()
+        .map(|(provider, load)| (provider.as_str(), load.in_flight as u64))
+        .collect();
+    metrics::render_gauge(
+        &mut out,
+        "kubellm_in_flight_requests",
+        "Requests being answered by the upstream per provider",
+        "provider",
+        &in_flight,
+    );
+    metrics::render_counter(
+        &mut out,
+        "kubellm_soft_limit_exceeded_total",
+        "Requests served above their model's soft requests/minute threshold",
+        state.soft_limiter.exceeded_total(),
+    );
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        out,
+    )
+}
+
+// Compares digests of the tokens byte by byte without stopping early, so the
+// time taken doesn't tell how much of a guess was right
+fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(token) = &state.admin_token else {
+        return false;
+    };
+    bearer_token(headers).is_some_and(|provided| {
+        let (provided, token) = (Sha256::digest(provided), Sha256::digest(token));
+        provided
+            .iter()
+            .zip(token.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvalidateQuery {
+    model: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+async fn invalidate_cache_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<InvalidateQuery>,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return error_response(
+            StatusCode::UNAUTHORIZED,
+            "authentication_error",
+            "Invalid admin token",
+        );
+    }
+    let Some(cache) = &state.cache else {
+        return (StatusCode::OK, Json(json!({"evicted": 0}))).into_response();
+    };
+
+    let evicted = match (query.all, query.model) {
+        (true, _) => cache.invalidate_all(),
+        (false, Some(model)) => cache.invalidate_model(&model),
+        (false, None) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "Specify a model or all=true",
+            )
+        }
+    };
+    tracing::info!(evicted, "Evicted cached responses");
+    (StatusCode::OK, Json(json!({"evicted": evicted}))).into_response()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cache::InMemoryCache;
+    use crate::client::ClientConfig;
+    use crate::mock;
+    use crate::models::anthropic::AnthropicClient;
+    use crate::models::bedrock::BedrockClient;
+    use crate::models::deadline::DeadlineHint;
+    use crate::models::echo::EchoProvider;
+    use crate::models::gemini::GeminiClient;
+    use crate::models::provider::ChatFuture;
+    use crate::pool::{DeploymentPool, POOL_PROVIDER};
+    use crate::pricing::PricingTable;
+    use crate::retry::RetryPolicy;
+    use crate::sigv4::{AwsCredentials, SigV4Signer};
+    use crate::transform::{FilterRule, MetadataEnricher};
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header::RETRY_AFTER, Request};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    // Requests to `/v1` routes with a client key
+    pub(crate) fn api_request(method: &str, uri: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer sk-client")
+    }
+
+    async fn into_json(response: Response) -> Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_accept_event_stream_enables_streaming() {
+        assert_eq!(
+            negotiate_stream(None, &accept("text/event-stream")),
+            Some(true)
+        );
+        assert_eq!(
+            negotiate_stream(None, &accept("application/json, text/event-stream")),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_accept_json_disables_streaming() {
+        assert_eq!(
+            negotiate_stream(None, &accept("application/json")),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_body_stream_takes_precedence() {
+        assert_eq!(
+            negotiate_stream(Some(false), &accept("text/event-stream")),
+            Some(false)
+        );
+        assert_eq!(
+            negotiate_stream(Some(true), &accept("application/json")),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_no_preference_leaves_stream_unset() {
+        assert_eq!(negotiate_stream(None, &HeaderMap::new()), None);
+    }
+
+    fn admin_state(cache: Arc<InMemoryCache>) -> AppState {
+        AppState {
+            cache: Some(cache),
+            admin_token: Some("admin-secret".to_string()),
+            ..AppState::new(OpenAIClient::new("sk-test".to_string()))
+        }
+    }
+
+    fn invalidate(uri: &str, token: &str) -> Request<Body> {
+        Request::post(uri)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_cache_by_model() {
+        let cache = Arc::new(InMemoryCache::new());
+        cache.put("a".to_string(), "gpt-4o", mock::completion("gpt-4o", "Hi!"));
+        cache.put(
+            "b".to_string(),
+            "gpt-4o-mini",
+            mock::completion("gpt-4o-mini", "Hi!"),
+        );
+        let app = router(admin_state(cache.clone()));
+
+        let response = app
+            .oneshot(invalidate(
+                "/admin/cache/invalidate?model=gpt-4o",
+                "admin-secret",
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(into_json(response).await, json!({"evicted": 1}));
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_cache_all() {
+        let cache = Arc::new(InMemoryCache::new());
+        cache.put("a".to_string(), "gpt-4o", mock::completion("gpt-4o", "Hi!"));
+        cache.put(
+            "b".to_string(),
+            "gpt-4o-mini",
+            mock::completion("gpt-4o-mini", "Hi!"),
+        );
+        let app = router(admin_state(cache.clone()));
+
+        let response = app
+            .oneshot(invalidate(
+                "/admin/cache/invalidate?all=true",
+                "admin-secret",
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(into_json(response).await, json!({"evicted": 2}));
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_cache_requires_admin_token() {
+        let cache = Arc::new(InMemoryCache::new());
+        cache.put("a".to_string(), "gpt-4o", mock::completion("gpt-4o", "Hi!"));
+        let app = router(admin_state(cache.clone()));
+
+        let response = app
+            .oneshot(invalidate("/admin/cache/invalidate?all=true", "wrong"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_disabled_without_token() {
+        let app = router(AppState::new(OpenAIClient::new("sk-test".to_string())));
+
+        let response = app
+            .oneshot(invalidate("/admin/cache/invalidate?all=true", ""))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let state = AppState::new(OpenAIClient::new("sk-test".to_string()));
+        state
+            .metrics
+            .stream_first_token
+            .observe(std::time::Duration::from_millis(80));
+        let app = router(state);
+
+        let response = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("kubellm_stream_first_token_seconds_count 1"));
+        assert!(body.contains("kubellm_stream_duration_seconds_count 0"));
+        assert!(body.contains("kubellm_soft_limit_exceeded_total 0"));
+    }
+
+    fn chat_request(base_url: &str) -> Request<Body> {
+        let body = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}]});
+        api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_for_batch_input() {
+        let base_url = mock::embeddings().await;
+        let body = json!({"model": "text-embedding-3-small", "input": ["Hi", "Hello"]});
+        let request = api_request("POST", "/v1/embeddings")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = into_json(response).await;
+        assert_eq!(body["model"], "text-embedding-3-small");
+        assert_eq!(body["data"][1]["embedding"], json!([5.0]));
+        assert_eq!(body["usage"]["total_tokens"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_without_key_is_unauthorized() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let mut request = chat_request(&base_url);
+        request.headers_mut().remove(AUTHORIZATION);
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            into_json(response).await["error"]["type"],
+            "authentication_error"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    // Answers with the bearer token it got
+    async fn key_echo() -> String {
+        let app = axum::Router::new()
+            .route(
+                "/v1/chat/completions",
+                post(|headers: HeaderMap| async move {
+                    let authorization = headers[AUTHORIZATION].to_str().unwrap().to_string();
+                    Json(mock::completion_json("gpt-4o", &authorization))
+                }),
+            )
+            .route(
+                "/v1/embeddings",
+                post(|headers: HeaderMap| async move {
+                    let authorization = headers[AUTHORIZATION].to_str().unwrap().to_string();
+                    Json(json!({
+                        "object": "list",
+                        "data": [],
+                        "model": authorization,
+                        "usage": {"prompt_tokens": 0, "total_tokens": 0}
+                    }))
+                }),
+            );
+        mock::spawn(app).await
+    }
+
+    #[tokio::test]
+    async fn test_provider_key_reaches_upstream_in_trusted_mode() {
+        let base_url = key_echo().await;
+        let state = AppState {
+            trusted_provider_keys: true,
+            ..dev_state()
+        };
+        let mut request = chat_request(&base_url);
+        request
+            .headers_mut()
+            .insert(PROVIDER_KEY_HEADER, HeaderValue::from_static("sk-caller"));
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = into_json(response).await;
+        assert_eq!(body["choices"][0]["message"]["content"], "Bearer sk-caller");
+    }
+
+    #[tokio::test]
+    async fn test_provider_key_reaches_embeddings_upstream() {
+        let base_url = key_echo().await;
+        let state = AppState {
+            trusted_provider_keys: true,
+            ..dev_state()
+        };
+        let body = json!({"model": "text-embedding-3-small", "input": "Hi"});
+        let request = api_request("POST", "/v1/embeddings")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .header(PROVIDER_KEY_HEADER, "sk-caller")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(into_json(response).await["model"], "Bearer sk-caller");
+
+        // Outside trusted mode the header is refused rather than ignored
+        let request = api_request("POST", "/v1/embeddings")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .header(PROVIDER_KEY_HEADER, "sk-caller")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_provider_key_rejected_outside_trusted_mode() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let mut request = chat_request(&base_url);
+        request
+            .headers_mut()
+            .insert(PROVIDER_KEY_HEADER, HeaderValue::from_static("sk-caller"));
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_request_when_allowed() {
+        let (base_url, _) = mock::openai("Hi").await;
+        let state = AppState {
+            allow_anonymous: true,
+            ..dev_state()
+        };
+        let mut request = chat_request(&base_url);
+        request.headers_mut().remove(AUTHORIZATION);
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_temperature_override_header() {
+        let (base_url, _) = mock::upstream(|request| {
+            let content = request["temperature"].to_string();
+            (StatusCode::OK, mock::completion_json("gpt-4o", &content))
+        })
+        .await;
+        let mut request = chat_request(&base_url);
+        request
+            .headers_mut()
+            .insert("x-kubellm-temperature", HeaderValue::from_static("0.5"));
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ROUTE_TRACE_HEADER],
+            "override=temperature;provider=openai"
+        );
+        let body = into_json(response).await;
+        assert_eq!(body["choices"][0]["message"]["content"], "0.5");
+    }
+
+    #[tokio::test]
+    async fn test_service_tier_dropped_for_unsupporting_provider() {
+        let body = json!({
+            "model": "claude-3-5-haiku",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "service_tier": "scale"
+        });
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(prefix_routed_state())
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let trace = response.headers()[ROUTE_TRACE_HEADER].to_str().unwrap();
+        assert!(trace.contains("drop=service_tier"), "{}", trace);
+    }
+
+    #[tokio::test]
+    async fn test_n_is_split_for_single_choice_models() {
+        let (base_url, calls) = mock::upstream(|request| {
+            assert!(request.get("n").is_none());
+            (
+                StatusCode::OK,
+                mock::completion_json("claude-3-5-sonnet", "Hi"),
+            )
+        })
+        .await;
+        let body = json!({
+            "model": "claude-3-5-sonnet",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "n": 3
+        });
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ROUTE_TRACE_HEADER],
+            "provider=openai;split=3"
+        );
+        let body = into_json(response).await;
+        let indices: Vec<_> = body["choices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|choice| choice["index"].clone())
+            .collect();
+        assert_eq!(indices, vec![json!(0), json!(1), json!(2)]);
+        assert_eq!(body["usage"]["total_tokens"], 6);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_header_is_propagated() {
+        let (base_url, _) = mock::upstream(|request| {
+            let content = request["timeout_ms"].to_string();
+            (StatusCode::OK, mock::completion_json("gpt-4o", &content))
+        })
+        .await;
+        let state = AppState {
+            client: OpenAIClient::new("sk-test".to_string())
+                .with_deadline_hint(DeadlineHint::Field("timeout_ms".to_string())),
+            ..dev_state()
+        };
+        let mut request = chat_request(&base_url);
+        request
+            .headers_mut()
+            .insert(TIMEOUT_HEADER, HeaderValue::from_static("2000"));
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        let body = into_json(response).await;
+        let timeout: u64 = body["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(timeout > 1000 && timeout <= 2000, "{}", timeout);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout_is_gateway_timeout() {
+        let base_url = mock::slow("Too late", Duration::from_secs(5)).await;
+        let config = ClientConfig {
+            request_timeout: Duration::from_millis(100),
+            ..ClientConfig::default()
+        };
+        let state = AppState {
+            client: OpenAIClient::new("sk-test".to_string())
+                .with_client_config(config)
+                .unwrap(),
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(chat_request(&base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["code"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_timeout_header_applies_to_other_providers() {
+        let base_url = mock::slow("Too late", Duration::from_secs(5)).await;
+        let mut models = ModelRouter::default();
+        let anthropic = AnthropicClient::new("sk-ant-test".to_string()).with_base_url(base_url);
+        models.register("claude-", Arc::new(anthropic));
+        let state = AppState {
+            router: Arc::new(models),
+            ..dev_state()
+        };
+        let mut request = model_request("claude-3-5-haiku-latest", "http://127.0.0.1:1/v1");
+        request
+            .headers_mut()
+            .insert(TIMEOUT_HEADER, HeaderValue::from_static("100"));
+        let started = Instant::now();
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["code"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_timeout_header_is_rejected() {
+        let mut request = chat_request("http://127.0.0.1:1/v1");
+        request
+            .headers_mut()
+            .insert(TIMEOUT_HEADER, HeaderValue::from_static("soon"));
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(into_json(response).await["error"]["param"], TIMEOUT_HEADER);
+    }
+
+    fn annotated_request(base_url: &str) -> Request<Body> {
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "kubellm_annotations": {"documents": ["doc-1"]}
+        });
+        api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn enriching_state() -> AppState {
+        let enricher = MetadataEnricher::new(vec!["documents".to_string()]);
+        AppState {
+            response_transforms: Arc::new(vec![Box::new(enricher)]),
+            ..dev_state()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_annotations_are_returned_as_metadata() {
+        let (base_url, _) = mock::upstream(|request| {
+            // Annotations stay in the gateway
+            assert!(request.get(transform::ANNOTATIONS_FIELD).is_none());
+            (StatusCode::OK, mock::completion_json("gpt-4o", "Hi"))
+        })
+        .await;
+
+        let response = router(enriching_state())
+            .oneshot(annotated_request(&base_url))
+            .await
+            .unwrap();
+
+        let body = into_json(response).await;
+        assert_eq!(
+            body[transform::EXTRA_FIELD],
+            json!({"documents": ["doc-1"]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_filter_redacts_completion() {
+        let (base_url, _) = mock::openai("The codename is Project Falcon.").await;
+        let rules = [FilterRule {
+            pattern: "(?i)project falcon".to_string(),
+            replacement: "[REDACTED]".to_string(),
+        }];
+        let state = AppState {
+            content_filter: Some(Arc::new(ContentFilter::new(&rules).unwrap())),
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(chat_request(&base_url))
+            .await
+            .unwrap();
+
+        let body = into_json(response).await;
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            "The codename is [REDACTED]."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strict_client_gets_no_metadata() {
+        let (base_url, _) = mock::openai("Hi").await;
+        let mut request = annotated_request(&base_url);
+        request
+            .headers_mut()
+            .insert(STRICT_HEADER, HeaderValue::from_static("true"));
+
+        let response = router(enriching_state()).oneshot(request).await.unwrap();
+
+        let body = into_json(response).await;
+        assert!(body.get(transform::EXTRA_FIELD).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_error_status_is_relayed() {
+        let error = json!({"error": {"message": "Slow down", "type": "requests", "code": "rate_limit_exceeded"}});
+        let upstream_error = error.clone();
+        let (base_url, _) =
+            mock::upstream(move |_| (StatusCode::TOO_MANY_REQUESTS, upstream_error.clone())).await;
+
+        let response = router(dev_state())
+            .oneshot(chat_request(&base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[ROUTE_TRACE_HEADER], "provider=openai");
+        assert_eq!(into_json(response).await, error);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_upstream_is_bad_gateway() {
+        let response = router(dev_state())
+            .oneshot(chat_request("http://127.0.0.1:1/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["type"], "upstream_error");
+        assert!(body["error"]["code"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_upstream_response_is_bad_gateway() {
+        let (base_url, _) = mock::openai("A response far larger than the limit").await;
+        let state = AppState {
+            client: OpenAIClient::new("sk-test".to_string()).with_max_response_bytes(64),
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(chat_request(&base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_key_log_level_raises_request_verbosity() {
+        let (subscriber, logs) = crate::logging::tests::capture();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (base_url, _) = mock::openai("Hi").await;
+        let state = AppState {
+            key_log_levels: Arc::new(HashMap::from([(
+                config::fingerprint("sk-debugged"),
+                LogLevel::Debug,
+            )])),
+            ..dev_state()
+        };
+        let app = router(state);
+        let request = |token: &str| {
+            let mut request = chat_request(&base_url);
+            let value = HeaderValue::try_from(format!("Bearer {}", token)).unwrap();
+            request.headers_mut().insert(AUTHORIZATION, value);
+            request
+        };
+
+        app.clone().oneshot(request("sk-other")).await.unwrap();
+        assert!(!logs.contents().contains("Received request"));
+        assert!(logs.contents().contains("Chat completion"));
+
+        app.oneshot(request("sk-debugged")).await.unwrap();
+        assert_eq!(logs.contents().matches("Received request").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_without_model_routes_to_key_default() {
+        let (base_url, _) = mock::upstream(|request| {
+            let model = request["model"].as_str().unwrap();
+            (StatusCode::OK, mock::completion_json(model, "Hi"))
+        })
+        .await;
+        let state = AppState {
+            key_default_models: Arc::new(HashMap::from([(
+                config::fingerprint("sk-tenant"),
+                "gpt-4o-mini".to_string(),
+            )])),
+            ..dev_state()
+        };
+        let app = router(state);
+        let request = |token: &str| {
+            let body = json!({"messages": [{"role": "user", "content": "Hi"}]});
+            Request::post("/v1/chat/completions")
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .header(BASE_URL_HEADER, &base_url)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("sk-tenant")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o-mini");
+
+        let response = app.oneshot(request("sk-other")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["param"], "model");
+    }
+
+    #[tokio::test]
+    async fn test_any_model_routes_to_default_model() {
+        let (base_url, _) = mock::upstream(|request| {
+            let model = request["model"].as_str().unwrap();
+            (StatusCode::OK, mock::completion_json(model, "Hi"))
+        })
+        .await;
+        let state = AppState {
+            default_model: Some("gpt-4o-mini".to_string()),
+            ..dev_state()
+        };
+        let body =
+            json!({"model": "whatever-model", "messages": [{"role": "user", "content": "Hi"}]});
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o-mini");
+        assert_eq!(
+            response.headers()[ROUTE_TRACE_HEADER],
+            "default=whatever-model>gpt-4o-mini;provider=openai"
+        );
+    }
+
+    #[test]
+    fn test_default_model_leaves_named_models_alone() {
+        let mut models = ModelRouter::default();
+        models.register(
+            "claude-",
+            Arc::new(EchoProvider::new(vec!["*".to_string()])),
+        );
+        models.register("", Arc::new(OpenAIClient::new("sk-test".to_string())));
+        let state = AppState {
+            router: Arc::new(models),
+            default_model: Some("gpt-4o-mini".to_string()),
+            key_default_models: Arc::new(HashMap::from([(
+                "sha256:1a2b3c4d".to_string(),
+                "gpt-4o".to_string(),
+            )])),
+            ..dev_state()
+        };
+        let model = |name: &str| {
+            let mut request = OpenAIChatCompletionRequest::new(name).with_message("user", "Hi");
+            prepare(&state, &mut request, &mut RouteTrace::default()).unwrap();
+            request.model
+        };
+
+        assert_eq!(model("claude-3-5-sonnet"), "claude-3-5-sonnet");
+        assert_eq!(model("gpt-4o"), "gpt-4o");
+        assert_eq!(model("whatever-model"), "gpt-4o-mini");
+        assert_eq!(model(""), "gpt-4o-mini");
+    }
+
+    #[tokio::test]
+    async fn test_echo_provider_answers_without_upstream() {
+        let state = dev_state();
+        let mut models = ModelRouter::default();
+        models.register("", Arc::new(EchoProvider::new(vec!["gpt-4o".to_string()])));
+        models.register("", Arc::new(state.client.clone()));
+        let state = AppState {
+            router: Arc::new(models),
+            ..state
+        };
+        // Nothing listens here, any upstream call would fail
+        let request = chat_request("http://127.0.0.1:1/v1");
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[PROVIDER_HEADER], "echo");
+        let body = into_json(response).await;
+        assert_eq!(body["id"], "chatcmpl-echo");
+        assert_eq!(body["choices"][0]["message"]["content"], "Hi");
+        assert_eq!(body["usage"]["total_tokens"], 2);
+    }
+
+    // Answers every model with its own name
+    struct NamedProvider(&'static str);
+
+    impl Provider for NamedProvider {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn chat(&self, request: OpenAIChatCompletionRequest) -> ChatFuture<'_> {
+            let response = mock::completion(&request.model, self.0);
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    // Routes `gpt-` to OpenAI, `claude-` and `local-` to named providers and
+    // nothing else
+    fn prefix_routed_state() -> AppState {
+        let state = dev_state();
+        let mut models = ModelRouter::default();
+        models.register("gpt-", Arc::new(state.client.clone()));
+        models.register("claude-", Arc::new(NamedProvider("anthropic")));
+        models.register("local-", Arc::new(NamedProvider("local")));
+        AppState {
+            router: Arc::new(models),
+            ..state
+        }
+    }
+
+    #[tokio::test]
+    async fn test_models_lists_routed_models() {
+        let state = prefix_routed_state();
+        let mut models = (*state.router).clone();
+        models.register(
+            "",
+            Arc::new(EchoProvider::new(vec!["echo-model".to_string()])),
+        );
+        let state = AppState {
+            router: Arc::new(models),
+            default_model: Some("gpt-4o".to_string()),
+            fallback_models: Arc::new(HashMap::from([
+                ("gpt-4o".to_string(), "claude-3-5-haiku".to_string()),
+                ("claude-3-5-haiku".to_string(), "llama3".to_string()),
+            ])),
+            ..state
+        };
+
+        let response = router(state)
+            .oneshot(
+                api_request("GET", "/v1/models")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let list: ModelList = serde_json::from_value(into_json(response).await).unwrap();
+        assert_eq!(list.object, "list");
+        let models: Vec<_> = list
+            .data
+            .iter()
+            .map(|model| (model.id.as_str(), model.owned_by.as_str()))
+            .collect();
+        assert_eq!(
+            models,
+            vec![
+                ("claude-3-5-haiku", "anthropic"),
+                ("echo-model", "echo"),
+                ("gpt-4o", "openai"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_header_pins_pool_deployment() {
+        let deployment = |name: &str| -> Arc<dyn Provider> {
+            Arc::new(EchoProvider::new(vec!["*".to_string()]).with_reply(name))
+        };
+        let mut models = ModelRouter::default();
+        models.register(
+            "",
+            Arc::new(DeploymentPool::new(vec![
+                deployment("eu"),
+                deployment("us"),
+            ])),
+        );
+        let app = router(AppState {
+            router: Arc::new(models),
+            ..dev_state()
+        });
+        let send = |session: Option<&str>| {
+            let mut request = model_request("gpt-4o", "http://127.0.0.1:1/v1");
+            if let Some(session) = session {
+                let session = HeaderValue::from_str(session).unwrap();
+                request.headers_mut().insert(SESSION_HEADER, session);
+            }
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.headers()[PROVIDER_HEADER], POOL_PROVIDER);
+                into_json(response).await["choices"][0]["message"]["content"].clone()
+            }
+        };
+
+        let first = send(Some("conversation-1")).await;
+        assert_ne!(send(None).await, first);
+        assert_eq!(send(Some("conversation-1")).await, first);
+        assert_eq!(send(Some("conversation-1")).await, first);
+    }
+
+    fn model_request(model: &str, base_url: &str) -> Request<Body> {
+        let body = json!({"model": model, "messages": [{"role": "user", "content": "Hi"}]});
+        api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cheapest_model_is_cost_routed() {
+        let (base_url, _) = mock::upstream(|request| {
+            let model = request["model"].as_str().unwrap();
+            (StatusCode::OK, mock::completion_json(model, "Hi"))
+        })
+        .await;
+        let candidates = vec!["gpt-4o".to_string(), "gpt-4o-mini".to_string()];
+        let state = AppState {
+            cost_routing: Some(Arc::new(CostRouting::new(
+                candidates,
+                PricingTable::default(),
+            ))),
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(model_request(CHEAPEST_MODEL, &base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o-mini");
+        assert_eq!(
+            response.headers()[ROUTE_TRACE_HEADER],
+            "cost=cheapest>gpt-4o-mini;provider=openai"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_is_routed_through_provider() {
+        let state = prefix_routed_state();
+        let app = router(state.clone());
+
+        let response = app
+            .oneshot(model_request("local-llama", "http://127.0.0.1:1/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[PROVIDER_HEADER], "local");
+        let body = into_json(response).await;
+        assert_eq!(body["choices"][0]["message"]["content"], "local");
+        assert_eq!(state.provider_stats.snapshot()["local"].requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_models_are_routed_by_prefix() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let app = router(prefix_routed_state());
+
+        let response = app
+            .clone()
+            .oneshot(model_request("gpt-4o", &base_url))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[PROVIDER_HEADER], "openai");
+        let response = app
+            .oneshot(model_request("claude-3-5-haiku-latest", &base_url))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[PROVIDER_HEADER], "anthropic");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unrouted_model_is_not_found() {
+        let app = router(prefix_routed_state());
+
+        let response = app
+            .oneshot(model_request("mistral-large", "http://127.0.0.1:1/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            into_json(response).await,
+            json!({"error": {
+                "message": "The model `mistral-large` does not exist or is not served by this gateway",
+                "type": "invalid_request_error",
+                "param": "model",
+                "code": "model_not_found",
+                "available_models": []
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_model_error_lists_available_models() {
+        let state = prefix_routed_state();
+        let mut models = (*state.router).clone();
+        models.register(
+            "echo-",
+            Arc::new(EchoProvider::new(
+                (0..MAX_LISTED_MODELS + 5)
+                    .map(|i| format!("echo-{:03}", i))
+                    .collect(),
+            )),
+        );
+        let state = AppState {
+            router: Arc::new(models),
+            fallback_models: Arc::new(HashMap::from([(
+                "gpt-4o".to_string(),
+                "claude-3-5-haiku".to_string(),
+            )])),
+            ..state
+        };
+
+        let response = router(state)
+            .oneshot(model_request("mistral-large", "http://127.0.0.1:1/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = into_json(response).await;
+        let available = body["error"]["available_models"].as_array().unwrap();
+        assert_eq!(available.len(), MAX_LISTED_MODELS);
+        assert_eq!(
+            available[..2],
+            [json!("claude-3-5-haiku"), json!("echo-000")]
+        );
+        assert!(!available.contains(&json!("mistral-large")));
+    }
+
+    pub(crate) fn dev_state() -> AppState {
+        AppState {
+            dev_mode: true,
+            base_url_allowlist: Arc::new(vec!["127.0.0.1".to_string()]),
+            // Failing mock upstreams answer at once instead of after backoff
+            ..AppState::new(OpenAIClient::new("sk-test".to_string()).with_retry_policy(
+                RetryPolicy {
+                    max_retries: 0,
+                    ..Default::default()
+                },
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_base_url_override_in_dev_mode() {
+        let (base_url, calls) = mock::openai("Hello from the mock").await;
+        let app = router(dev_state());
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = into_json(response).await;
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            "Hello from the mock"
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_base_url_override_ignored_outside_dev_mode() {
+        let state = AppState {
+            dev_mode: false,
+            ..dev_state()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            BASE_URL_HEADER,
+            HeaderValue::from_static("http://127.0.0.1:8000/v1"),
+        );
+
+        assert_eq!(base_url_override(&state, &headers), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_base_url_override_rejects_unlisted_host() {
+        let app = router(dev_state());
+
+        let response = app
+            .oneshot(chat_request("http://169.254.169.254/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = into_json(response).await;
+        assert_eq!(
+            body["error"]["message"],
+            "Host 169.254.169.254 is not in the base URL allowlist"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback_model_headers() {
+        let (base_url, calls) = mock::upstream(|request| match request["model"].as_str() {
+            Some("gpt-4o") => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": {"message": "Overloaded", "type": "server_error"}}),
+            ),
+            model => (
+                StatusCode::OK,
+                mock::completion_json(model.unwrap(), "Hi from the fallback"),
+            ),
+        })
+        .await;
+        let state = AppState {
+            fallback_models: Arc::new(HashMap::from([(
+                "gpt-4o".to_string(),
+                "gpt-4o-mini".to_string(),
+            )])),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[PROVIDER_HEADER], "openai");
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o-mini");
+        assert_eq!(response.headers()[FALLBACK_HEADER], "true");
+        assert_eq!(response.headers()[ATTEMPTS_HEADER], "2");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    fn fallback_state() -> AppState {
+        AppState {
+            fallback_models: Arc::new(HashMap::from([(
+                "gpt-4o".to_string(),
+                "gpt-4o-mini".to_string(),
+            )])),
+            ..dev_state()
+        }
+    }
+
+    async fn failing_upstream() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        mock::upstream(|request| match request["model"].as_str() {
+            Some("gpt-4o") => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": {"message": "Overloaded", "type": "server_error"}}),
+            ),
+            model => (
+                StatusCode::OK,
+                mock::completion_json(model.unwrap(), "Hi from the fallback"),
+            ),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_fallback_answer_is_not_cached() {
+        let (base_url, calls) = failing_upstream().await;
+        let app = router(AppState {
+            cache: Some(Arc::new(InMemoryCache::new())),
+            ..fallback_state()
+        });
+
+        app.clone()
+            .oneshot(deterministic_request(&base_url))
+            .await
+            .unwrap();
+        let second = app.oneshot(deterministic_request(&base_url)).await.unwrap();
+
+        assert_eq!(second.headers()[CACHE_HEADER], "miss");
+        assert_eq!(second.headers()[MODEL_HEADER], "gpt-4o-mini");
+        assert_eq!(second.headers()[FALLBACK_HEADER], "true");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_model_is_rate_limited() {
+        let (base_url, calls) = failing_upstream().await;
+        let limits = HashMap::from([("gpt-4o-mini".to_string(), 1)]);
+        let app = router(AppState {
+            rate_limiter: Arc::new(RateLimiter::new(limits)),
+            ..fallback_state()
+        });
+
+        let first = app.clone().oneshot(chat_request(&base_url)).await.unwrap();
+        let second = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(first.headers()[MODEL_HEADER], "gpt-4o-mini");
+        assert_eq!(second.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_attempts_header_counts_retries() {
+        let failed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (base_url, _) = mock::upstream(move |request| {
+            if !failed.swap(true, Ordering::SeqCst) {
+                let error = json!({"error": {"message": "Overloaded", "type": "server_error"}});
+                return (StatusCode::SERVICE_UNAVAILABLE, error);
+            }
+            let model = request["model"].as_str().unwrap();
+            (StatusCode::OK, mock::completion_json(model, "Hi"))
+        })
+        .await;
+        let client = OpenAIClient::new("sk-test".to_string()).with_retry_policy(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let app = router(AppState {
+            client,
+            ..dev_state()
+        });
+
+        let response = app.clone().oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ATTEMPTS_HEADER], "2");
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+        assert_eq!(response.headers()[ATTEMPTS_HEADER], "1");
+    }
+
+    #[tokio::test]
+    async fn test_served_headers_without_fallback() {
+        let (base_url, _) = mock::openai("Hi").await;
+        let app = router(dev_state());
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.headers()[PROVIDER_HEADER], "openai");
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o");
+        assert_eq!(response.headers()[FALLBACK_HEADER], "false");
+    }
+
+    #[tokio::test]
+    async fn test_too_many_tools_are_rejected() {
+        let state = AppState {
+            tool_limits: ToolLimits {
+                max_tools: Some(1),
+                max_bytes: None,
+            },
+            ..dev_state()
+        };
+        let tool = json!({"type": "function", "function": {"name": "get_weather"}});
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "tools": [tool, tool]
+        });
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, "http://127.0.0.1:1/v1")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["param"], "tools");
+        assert_eq!(
+            body["error"]["message"],
+            "Request has 2 tools, the maximum is 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_over_max_length_is_rejected() {
+        let state = AppState {
+            max_message_bytes: Some(1),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let response = app
+            .oneshot(chat_request("http://127.0.0.1:1/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            into_json(response).await,
+            json!({"error": {
+                "message": "Message 0 has 2 bytes of content, the maximum is 1",
+                "type": "invalid_request_error",
+                "param": "messages[0].content"
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warmup_calls_each_provider_once() {
+        let (openai_url, openai_calls) = mock::models(StatusCode::OK).await;
+        let (anthropic_url, anthropic_calls) = mock::models(StatusCode::OK).await;
+        let (gemini_url, gemini_calls) = mock::models(StatusCode::OK).await;
+        let (bedrock_url, bedrock_calls) = mock::models(StatusCode::OK).await;
+        let (eu_url, eu_calls) = mock::models(StatusCode::OK).await;
+        let (us_url, us_calls) = mock::models(StatusCode::OK).await;
+        let (shadow_url, shadow_calls) = mock::models(StatusCode::UNAUTHORIZED).await;
+        let client = OpenAIClient::new("sk-test".to_string());
+        let credentials = AwsCredentials::new("AKIDEXAMPLE", "secret");
+        let signer = SigV4Signer::new(credentials, "us-east-1", "bedrock");
+        let pool = DeploymentPool::new(vec![
+            Arc::new(client.deployment(eu_url)),
+            Arc::new(client.deployment(us_url)),
+        ]);
+        let providers: Vec<(&str, Arc<dyn Provider>)> = vec![
+            ("openai", Arc::new(client.deployment(openai_url))),
+            (
+                "anthropic",
+                Arc::new(AnthropicClient::new("sk-ant".to_string()).with_base_url(anthropic_url)),
+            ),
+            (
+                "gemini",
+                Arc::new(GeminiClient::new("key".to_string()).with_base_url(gemini_url)),
+            ),
+            // Bedrock calls its endpoint itself
+            (
+                "bedrock",
+                Arc::new(
+                    BedrockClient::new("us-east-1", Arc::new(signer))
+                        .with_base_url(format!("{}/models", bedrock_url)),
+                ),
+            ),
+            ("pool", Arc::new(pool)),
+            ("shadow", Arc::new(client.deployment(shadow_url))),
+            (
+                "echo",
+                Arc::new(EchoProvider::new(vec!["echo".to_string()])),
+            ),
+        ];
+
+        let warmed = warmup(
+            providers
+                .into_iter()
+                .map(|(name, provider)| (name.to_string(), provider))
+                .collect(),
+        )
+        .await;
+
+        // All but the shadow upstream, which rejects the key
+        assert_eq!(warmed, 6);
+        for calls in [
+            openai_calls,
+            anthropic_calls,
+            gemini_calls,
+            bedrock_calls,
+            eu_calls,
+            us_calls,
+            shadow_calls,
+        ] {
+            assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_over_soft_limit_is_served() {
+        let (subscriber, logs) = crate::logging::tests::capture();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (base_url, calls) = mock::openai("Hi").await;
+        let state = AppState {
+            soft_limiter: Arc::new(SoftLimiter::new(HashMap::from([("gpt-4o".to_string(), 1)]))),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        for _ in 0..3 {
+            let response = app.clone().oneshot(chat_request(&base_url)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        let logs = logs.contents();
+        let warnings: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains("Soft rate limit exceeded"))
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("WARN"));
+        assert!(warnings[0].contains("model=gpt-4o"));
+        assert!(warnings[0].contains("soft_limit=1"));
+        let metrics = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(metrics.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("kubellm_soft_limit_exceeded_total 2"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_request_gets_429_with_headers() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let state = AppState {
+            rate_limiter: Arc::new(RateLimiter::new(HashMap::from([("gpt-4o".to_string(), 1)]))),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let first = app.clone().oneshot(chat_request(&base_url)).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-limit"], "1");
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+        let reset: u64 = response.headers()["x-ratelimit-reset"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(reset > 0 && reset <= 60);
+        assert_eq!(response.headers()[RETRY_AFTER], reset.to_string().as_str());
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Rate limit of 1 requests per minute exceeded for model gpt-4o"));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_prompt_cache_key_auto_populated_when_enabled() {
+        let state = AppState {
+            auto_prompt_cache_key: true,
+            ..dev_state()
+        };
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o")
+            .with_message("system", "You are a support bot.")
+            .with_message("user", "Hello!");
+        let mut keyed = request.clone();
+        keyed.prompt_cache_key = Some("support-bot".to_string());
+
+        prepare(&state, &mut request, &mut RouteTrace::default()).unwrap();
+        prepare(&state, &mut keyed, &mut RouteTrace::default()).unwrap();
+
+        assert_eq!(
+            request.prompt_cache_key,
+            Some(cache::prompt_cache_key(&request))
+        );
+        assert_eq!(keyed.prompt_cache_key.as_deref(), Some("support-bot"));
+    }
+
+    #[test]
+    fn test_prompt_cache_key_left_unset_by_default() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hello!");
+
+        prepare(&dev_state(), &mut request, &mut RouteTrace::default()).unwrap();
+
+        assert_eq!(request.prompt_cache_key, None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_model_is_rejected() {
+        let app = router(dev_state());
+        let body = json!({"messages": [{"role": "user", "content": "Hi"}]});
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            into_json(response).await,
+            json!({"error": {
+                "message": "Missing required parameter: 'model'.",
+                "type": "invalid_request_error",
+                "param": "model"
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_status_header() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let state = AppState {
+            cache: Some(Arc::new(InMemoryCache::new())),
+            ..dev_state()
+        };
+        let app = router(state);
+        let deterministic = || {
+            let body = json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hi"}],
+                "temperature": 0.0
+            });
+            api_request("POST", "/v1/chat/completions")
+                .header(CONTENT_TYPE, "application/json")
+                .header(BASE_URL_HEADER, &base_url)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let first = app.clone().oneshot(deterministic()).await.unwrap();
+        let second = app.clone().oneshot(deterministic()).await.unwrap();
+        let bypassed = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(first.headers()[CACHE_HEADER], "miss");
+        assert_eq!(second.headers()[CACHE_HEADER], "hit");
+        assert_eq!(bypassed.headers()[CACHE_HEADER], "bypass");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_identical_request_from_other_user_is_cached() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let state = AppState {
+            cache: Some(Arc::new(InMemoryCache::new())),
+            ..dev_state()
+        };
+        let app = router(state);
+        let from_user = |user: &str| {
+            let body = json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "Hi"}],
+                "temperature": 0.0,
+                "user": user
+            });
+            api_request("POST", "/v1/chat/completions")
+                .header(CONTENT_TYPE, "application/json")
+                .header(BASE_URL_HEADER, &base_url)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        app.clone().oneshot(from_user("user-1")).await.unwrap();
+        let second = app.oneshot(from_user("user-2")).await.unwrap();
+
+        assert_eq!(second.headers()[CACHE_HEADER], "hit");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_content_length_is_rejected_before_parsing() {
+        let state = AppState {
+            max_body_bytes: 16,
+            ..dev_state()
+        };
+        let app = router(state);
+        // Not JSON, a parsed body would be a 400
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, "1000000")
+            .body(Body::from("not json"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(
+            body["error"]["message"],
+            "Request body is larger than the limit of 16 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_is_rejected_before_upstream() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let body = json!({"model": "gpt-4o", "messages": [], "temperature": 3, "max_tokens": -1});
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["param"], "messages");
+        let params: Vec<_> = body["error"]["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["param"].as_str().unwrap())
+            .collect();
+        assert_eq!(params, vec!["messages", "temperature", "max_tokens"]);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_does_not_count_against_rate_limit() {
+        let (base_url, _) = mock::openai("Hi").await;
+        let state = AppState {
+            rate_limiter: Arc::new(RateLimiter::new(HashMap::from([("gpt-4o".to_string(), 1)]))),
+            soft_limiter: Arc::new(SoftLimiter::new(HashMap::from([("gpt-4o".to_string(), 0)]))),
+            ..dev_state()
+        };
+        let soft_limiter = state.soft_limiter.clone();
+        let app = router(state);
+        let body = json!({"model": "gpt-4o", "messages": []});
+        let invalid = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = app.clone().oneshot(invalid).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(soft_limiter.exceeded_total(), 0);
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_chunked_body_is_rejected() {
+        let state = AppState {
+            max_body_bytes: 16,
+            ..dev_state()
+        };
+        let app = router(state);
+        let chunks = vec![
+            Ok::<_, std::io::Error>(r#"{"model": "gpt-4o", "#),
+            Ok(r#""messages": []}"#),
+        ];
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from_stream(stream::iter(chunks)))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            into_json(response).await["error"]["message"],
+            "Request body is larger than the limit of 16 bytes"
+        );
+    }
+
+    fn o1_mini_stream_request(base_url: &str) -> Request<Body> {
+        let body = json!({
+            "model": "o1-mini",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "stream": true
+        });
+        api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stream_bridged_for_non_streaming_model() {
+        let (base_url, _) = mock::upstream(|request| {
+            // The upstream must not be asked to stream
+            assert_eq!(request.get("stream"), None);
+            (
+                StatusCode::OK,
+                mock::completion_json("o1-mini", "Hello there"),
+            )
+        })
+        .await;
+        let app = router(dev_state());
+
+        let response = app
+            .oneshot(o1_mini_stream_request(&base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(events.len(), 3);
+        let first: Value = serde_json::from_str(events[0]).unwrap();
+        assert_eq!(first["object"], "chat.completion.chunk");
+        assert_eq!(first["choices"][0]["delta"]["content"], "Hello there");
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    fn stream_request(base_url: &str, temperature: Option<f64>) -> Request<Body> {
+        let mut body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "stream": true
+        });
+        if let Some(temperature) = temperature {
+            body["temperature"] = json!(temperature);
+        }
+        api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn events(response: Response) -> Vec<String> {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn usage_chunk() -> Value {
+        let mut chunk = mock::chunk_json("", None);
+        chunk["choices"] = json!([]);
+        chunk["usage"] = json!({"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3});
+        chunk
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_forwarded() {
+        let chunks = vec![
+            mock::chunk_json("Hel", None),
+            mock::chunk_json("lo", Some("stop")),
+            usage_chunk(),
+        ];
+        let (base_url, _) = mock::sse(chunks, Duration::ZERO).await;
+
+        let response = router(dev_state())
+            .oneshot(stream_request(&base_url, None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        assert_eq!(response.headers()[ROUTE_TRACE_HEADER], "provider=openai");
+        let events = events(response).await;
+        // The usage-only chunk wasn't asked for
+        assert_eq!(events.len(), 3);
+        let first: Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(first["choices"][0]["delta"]["content"], "Hel");
+        let last: Value = serde_json::from_str(&events[1]).unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn test_accept_header_alone_streams() {
+        let (base_url, _) = mock::sse(vec![mock::chunk_json("Hi", None)], Duration::ZERO).await;
+        let mut request = chat_request(&base_url);
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static("text/event-stream"));
+
+        let response = router(dev_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        assert_eq!(events(response).await.last().unwrap(), "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn test_json_answer_to_stream_is_replayed() {
+        let (base_url, calls) = mock::openai("Hello there").await;
+
+        let response = router(dev_state())
+            .oneshot(stream_request(&base_url, None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = events(response).await;
+        let first: Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(first["choices"][0]["delta"]["content"], "Hello there");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_stream_gets_error_status() {
+        let (base_url, _) = mock::upstream(|_| {
+            let error = json!({"error": {"message": "Overloaded", "type": "server_error"}});
+            (StatusCode::INTERNAL_SERVER_ERROR, error)
+        })
+        .await;
+
+        let response = router(dev_state())
+            .oneshot(stream_request(&base_url, None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["message"], "Overloaded");
+    }
+
+    #[tokio::test]
+    async fn test_identical_streams_share_upstream() {
+        let chunks = vec![mock::chunk_json("Hi", Some("stop"))];
+        let (base_url, calls) = mock::sse(chunks, Duration::from_millis(200)).await;
+        let state = AppState {
+            stream_dedupe: Some(Arc::new(StreamDedupe::default())),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let (first, second) = tokio::join!(
+            app.clone().oneshot(stream_request(&base_url, Some(0.0))),
+            app.oneshot(stream_request(&base_url, Some(0.0))),
+        );
+
+        let first = events(first.unwrap()).await;
+        let second = events(second.unwrap()).await;
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 2);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streams_beyond_key_limit_are_rejected() {
+        let chunks = vec![mock::chunk_json("Hi", None); 3];
+        let (stream_url, _) = mock::sse(chunks, Duration::from_millis(50)).await;
+        let (base_url, _) = mock::openai("Hello").await;
+        let state = AppState {
+            stream_limiter: Some(Arc::new(StreamLimiter::new(1))),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let open = app
+            .clone()
+            .oneshot(stream_request(&stream_url, None))
+            .await
+            .unwrap();
+        assert_eq!(open.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(stream_request(&stream_url, None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = into_json(response).await;
+        assert_eq!(body["error"]["code"], "too_many_streams");
+
+        // Other requests are served while the stream is open
+        let response = app.clone().oneshot(chat_request(&base_url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A disconnected client frees its slot
+        drop(open);
+        let response = app
+            .clone()
+            .oneshot(stream_request(&stream_url, None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // And so does a stream that ran to completion
+        assert_eq!(events(response).await.last().unwrap(), "[DONE]");
+        let response = app
+            .oneshot(stream_request(&stream_url, None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_streams_of_different_keys_are_not_shared() {
+        let chunks = vec![mock::chunk_json("Hi", Some("stop"))];
+        let (base_url, calls) = mock::sse(chunks, Duration::from_millis(200)).await;
+        let state = AppState {
+            stream_dedupe: Some(Arc::new(StreamDedupe::default())),
+            trusted_provider_keys: true,
+            ..dev_state()
+        };
+        let app = router(state);
+        let with_key = |header: &'static str, key: &'static str| {
+            let mut request = stream_request(&base_url, Some(0.0));
+            request
+                .headers_mut()
+                .insert(header, HeaderValue::from_static(key));
+            request
+        };
+
+        let (first, second, third) = tokio::join!(
+            app.clone()
+                .oneshot(with_key(AUTHORIZATION.as_str(), "Bearer sk-tenant-a")),
+            app.clone()
+                .oneshot(with_key(AUTHORIZATION.as_str(), "Bearer sk-tenant-b")),
+            app.oneshot(with_key(PROVIDER_KEY_HEADER, "sk-own-upstream")),
+        );
+
+        for response in [first, second, third] {
+            assert_eq!(events(response.unwrap()).await.len(), 2);
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_is_cut_off() {
+        let chunks = vec![mock::chunk_json("Hi", None); 10];
+        let (base_url, _) = mock::sse(chunks, Duration::from_millis(300)).await;
+        let state = AppState {
+            max_stream_duration: Some(Duration::from_millis(800)),
+            ..dev_state()
+        };
+
+        let response = router(state)
+            .oneshot(stream_request(&base_url, None))
+            .await
+            .unwrap();
+
+        let events = events(response).await;
+        assert!(events.len() < 10);
+        let last: Value = serde_json::from_str(&events[events.len() - 2]).unwrap();
+        assert_eq!(last["truncated"], "max_stream_duration");
+        assert_eq!(last["choices"][0]["finish_reason"], "length");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn test_stream_rejected_for_non_streaming_model() {
+        let state = AppState {
+            unsupported_stream: UnsupportedStream::Reject,
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let response = app
+            .oneshot(o1_mini_stream_request("http://127.0.0.1:1/v1"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            into_json(response).await,
+            json!({"error": {
+                "message": "Model o1-mini does not support streaming",
+                "type": "invalid_request_error",
+                "param": "stream"
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_trace_header_in_dev_mode() {
+        let (base_url, _) = mock::openai("Hi").await;
+        let state = AppState {
+            deprecated_models: Arc::new(DeprecatedModels::new(HashMap::from([(
+                "gpt-4-0314".to_string(),
+                "gpt-4o".to_string(),
+            )]))),
+            ..dev_state()
+        };
+        let app = router(state);
+        let body = json!({"model": "gpt-4-0314", "messages": [{"role": "user", "content": "Hi"}]});
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(
+            response.headers()[ROUTE_TRACE_HEADER],
+            "alias=gpt-4-0314>gpt-4o;provider=openai"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_route_trace_outside_dev_mode() {
+        let state = AppState::new(OpenAIClient::new("sk-test".to_string()));
+        let mut trace = RouteTrace::default();
+        trace.record("provider", "openai");
+
+        let response = traced(&state, StatusCode::OK.into_response(), &trace);
+
+        assert_eq!(response.headers().get(ROUTE_TRACE_HEADER), None);
+    }
+
+    #[tokio::test]
+    async fn test_refusal_retried_with_alternate_model() {
+        let (base_url, calls) = mock::upstream(|request| {
+            let model = request["model"].as_str().unwrap();
+            let mut response = mock::completion_json(model, "Sure, here you go");
+            if model == "gpt-4o" {
+                response["choices"][0]["message"]["content"] = Value::Null;
+                response["choices"][0]["message"]["refusal"] = json!("I can't help with that.");
+                response["choices"][0]["finish_reason"] = json!("content_filter");
+            }
+            (StatusCode::OK, response)
+        })
+        .await;
+        let state = AppState {
+            refusal_models: Arc::new(HashMap::from([(
+                "gpt-4o".to_string(),
+                "permissive-model".to_string(),
+            )])),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.headers()[MODEL_HEADER], "permissive-model");
+        assert_eq!(response.headers()[FALLBACK_HEADER], "true");
+        let body = into_json(response).await;
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            "Sure, here you go"
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    // Refuses as gpt-4o, answers with `alternate` status as any other model
+    async fn refusing_upstream(
+        alternate: StatusCode,
+    ) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        mock::upstream(move |request| {
+            let model = request["model"].as_str().unwrap();
+            if model != "gpt-4o" && alternate != StatusCode::OK {
+                let error = json!({"error": {"message": "Overloaded", "type": "server_error"}});
+                return (alternate, error);
+            }
+            let mut response = mock::completion_json(model, "Sure, here you go");
+            if model == "gpt-4o" {
+                response["choices"][0]["message"]["content"] = Value::Null;
+                response["choices"][0]["message"]["refusal"] = json!("I can't help with that.");
+                response["choices"][0]["finish_reason"] = json!("content_filter");
+            }
+            (StatusCode::OK, response)
+        })
+        .await
+    }
+
+    fn refusal_state() -> AppState {
+        AppState {
+            refusal_models: Arc::new(HashMap::from([(
+                "gpt-4o".to_string(),
+                "permissive-model".to_string(),
+            )])),
+            ..dev_state()
+        }
+    }
+
+    fn deterministic_request(base_url: &str) -> Request<Body> {
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "temperature": 0.0
+        });
+        api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_alternate_answer_is_not_cached() {
+        let (base_url, calls) = refusing_upstream(StatusCode::OK).await;
+        let app = router(AppState {
+            cache: Some(Arc::new(InMemoryCache::new())),
+            ..refusal_state()
+        });
+
+        app.clone()
+            .oneshot(deterministic_request(&base_url))
+            .await
+            .unwrap();
+        let second = app.oneshot(deterministic_request(&base_url)).await.unwrap();
+
+        assert_eq!(second.headers()[CACHE_HEADER], "miss");
+        assert_eq!(second.headers()[MODEL_HEADER], "permissive-model");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_refusal_returned_when_alternate_fails() {
+        let (base_url, calls) = refusing_upstream(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let app = router(refusal_state());
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o");
+        assert_eq!(response.headers()[FALLBACK_HEADER], "false");
+        let body = into_json(response).await;
+        assert_eq!(
+            body["choices"][0]["message"]["refusal"],
+            "I can't help with that."
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_alternate_model_is_rate_limited() {
+        let (base_url, calls) = refusing_upstream(StatusCode::OK).await;
+        let limits = HashMap::from([("permissive-model".to_string(), 1)]);
+        let app = router(AppState {
+            rate_limiter: Arc::new(RateLimiter::new(limits)),
+            ..refusal_state()
+        });
+
+        let first = app.clone().oneshot(chat_request(&base_url)).await.unwrap();
+        let second = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(first.headers()[MODEL_HEADER], "permissive-model");
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers()[MODEL_HEADER], "gpt-4o");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_refusal_returned_without_alternate_model() {
+        let (base_url, calls) = mock::upstream(|request| {
+            let mut response = mock::completion_json(request["model"].as_str().unwrap(), "");
+            response["choices"][0]["finish_reason"] = json!("content_filter");
+            (StatusCode::OK, response)
+        })
+        .await;
+        let app = router(dev_state());
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(response.headers()[MODEL_HEADER], "gpt-4o");
+        assert_eq!(
+            into_json(response).await["choices"][0]["finish_reason"],
+            "content_filter"
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sampled_request_is_mirrored_to_shadow() {
+        let (base_url, calls) = mock::openai("From the real provider").await;
+        let (shadow_url, shadow_calls) = mock::openai("From the shadow provider").await;
+        let shadow = Arc::new(Shadow::new(
+            OpenAIClient::new("sk-shadow".to_string()),
+            shadow_url,
+            1.0,
+        ));
+        let state = AppState {
+            shadow: Some(shadow.clone()),
+            ..dev_state()
+        };
+        let app = router(state);
+
+        let response = app.oneshot(chat_request(&base_url)).await.unwrap();
+
+        assert_eq!(
+            into_json(response).await["choices"][0]["message"]["content"],
+            "From the real provider"
+        );
+        // The shadow call finishes in the background
+        for _ in 0..100 {
+            if shadow
+                .stats
+                .requests
+                .load(std::sync::atomic::Ordering::SeqCst)
+                > 0
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(shadow_calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(
+            shadow
+                .stats
+                .errors
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+        assert_eq!(
+            shadow
+                .stats
+                .completion_tokens
+                .load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_completion_can_be_retrieved() {
+        let base_url = mock::stored("Remember me").await;
+        let state = AppState {
+            completion_retrieval: true,
+            ..dev_state()
+        };
+        let app = router(state);
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "store": true
+        });
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let created = into_json(app.clone().oneshot(request).await.unwrap()).await;
+        let id = created["id"].as_str().unwrap();
+        assert_eq!(id, "chatcmpl-123");
+
+        let retrieve = |id: &str| {
+            api_request("GET", &format!("/v1/chat/completions/{}", id))
+                .header(BASE_URL_HEADER, &base_url)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(retrieve(id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let retrieved = into_json(response).await;
+        assert_eq!(retrieved["id"], "chatcmpl-123");
+        assert_eq!(retrieved["choices"][0]["message"]["content"], "Remember me");
+
+        let missing = app.oneshot(retrieve("chatcmpl-unknown")).await.unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            into_json(missing).await["error"]["message"],
+            "No completion found with id 'chatcmpl-unknown'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_completion_is_hidden_from_other_callers() {
+        let base_url = mock::stored("Remember me").await;
+        let state = AppState {
+            completion_retrieval: true,
+            ..dev_state()
+        };
+        let app = router(state);
+        let body = json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "store": true
+        });
+        let request = api_request("POST", "/v1/chat/completions")
+            .header(CONTENT_TYPE, "application/json")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap();
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/v1/chat/completions/chatcmpl-123")
+            .header(AUTHORIZATION, "Bearer sk-other-client")
+            .header(BASE_URL_HEADER, &base_url)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            into_json(response).await["error"]["message"],
+            "No completion found with id 'chatcmpl-123'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completion_retrieval_disabled_by_default() {
+        let app = router(dev_state());
+
+        let response = app
+            .oneshot(
+                api_request("GET", "/v1/chat/completions/chatcmpl-123")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn millis(response: &Response, header: &str) -> u64 {
+        response.headers()[header]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_latency_headers() {
+        let base_url = mock::slow("Hi", Duration::from_millis(200)).await;
+
+        let response = router(dev_state())
+            .oneshot(chat_request(&base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let upstream = millis(&response, UPSTREAM_LATENCY_HEADER);
+        assert!((200..1000).contains(&upstream), "{}", upstream);
+        assert!(millis(&response, OVERHEAD_HEADER) < 100);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reports_time_to_first_token() {
+        let chunks = vec![mock::chunk_json("Hi", Some("stop")), usage_chunk()];
+        let (base_url, _) = mock::sse(chunks, Duration::from_millis(200)).await;
+
+        let response = router(dev_state())
+            .oneshot(stream_request(&base_url, None))
+            .await
+            .unwrap();
+
+        // Sent before the rest of the stream, so the later chunks don't count
+        let upstream = millis(&response, UPSTREAM_LATENCY_HEADER);
+        assert!((200..400).contains(&upstream), "{}", upstream);
+        assert!(millis(&response, OVERHEAD_HEADER) < 100);
+    }
+
+    #[tokio::test]
+    async fn test_healthcheck_request_is_answered_without_upstream() {
+        let (base_url, calls) = mock::openai("Hi").await;
+        let state = AppState {
+            healthcheck_model: Some("__healthcheck__".to_string()),
+            ..dev_state()
+        };
+        let metrics = state.metrics.clone();
+
+        let response = router(state)
+            .oneshot(model_request("__healthcheck__", &base_url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = into_json(response).await;
+        assert_eq!(body["model"], "__healthcheck__");
+        assert_eq!(body["usage"]["total_tokens"], 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(metrics.requests.load(Ordering::SeqCst), 0);
+        assert_eq!(metrics.model_requests.get("__healthcheck__"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_healthcheck_request_needs_no_api_key() {
+        let state = AppState {
+            healthcheck_model: Some("__healthcheck__".to_string()),
+            ..dev_state()
+        };
+        let app = router(state);
+        let without_key = |model: &str| {
+            let body = json!({"model": model, "messages": [{"role": "user", "content": "Hi"}]});
+            Request::builder()
+                .method("POST")
+                .uri("/v1/chat/completions")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let probe = app
+            .clone()
+            .oneshot(without_key("__healthcheck__"))
+            .await
+            .unwrap();
+        let request = app.oneshot(without_key("gpt-4o")).await.unwrap();
+
+        assert_eq!(probe.status(), StatusCode::OK);
+        assert_eq!(into_json(probe).await["model"], "__healthcheck__");
+        assert_eq!(request.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_requests_and_errors() {
+        let (base_url, _) = mock::upstream(|request| match request["model"].as_str() {
+            Some("broken-model") => (
+                StatusCode::BAD_GATEWAY,
+                json!({"error": {"message": "Model is down", "type": "server_error"}}),
+            ),
+            model => (StatusCode::OK, mock::completion_json(model.unwrap(), "Hi")),
+        })
+        .await;
+        let app = router(dev_state());
+
+        for model in ["gpt-4o", "gpt-4o", "broken-model"] {
+            app.clone()
+                .oneshot(model_request(model, &base_url))
+                .await
+                .unwrap();
+        }
+        let metrics = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let body = to_bytes(metrics.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("kubellm_requests_total 3"));
+        assert!(body.contains("kubellm_model_requests_total{model=\"gpt-4o\"} 2"));
+        assert!(body.contains("kubellm_model_requests_total{model=\"broken-model\"} 1"));
+        assert!(body.contains("kubellm_errors_total{status=\"502\"} 1"));
+        assert!(body.contains("kubellm_upstream_latency_seconds_count 3"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_depth_of_saturated_provider() {
+        let base_url = mock::slow("Hi", Duration::from_millis(300)).await;
+        let state = AppState {
+            concurrency: Some(Arc::new(ProviderConcurrency::new(1))),
+            queue_depth_header: true,
+            ..dev_state()
+        };
+        let app = router(state.clone());
+        let requests: Vec<_> = (0..3)
+            .map(|_| tokio::spawn(app.clone().oneshot(chat_request(&base_url))))
+            .collect();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let status = into_json(
+            app.clone()
+                .oneshot(Request::get("/status").body(Body::empty()).unwrap())
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status["openai"]["queue_depth"], 2);
+        assert_eq!(status["openai"]["in_flight"], 1);
+        let metrics = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(metrics.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("kubellm_queue_depth{provider=\"openai\"} 2"));
+
+        let mut depths = Vec::new();
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            let depth = response.headers()[QUEUE_DEPTH_HEADER].to_str().unwrap();
+            depths.push(depth.parse::<usize>().unwrap());
+        }
+        // Nothing is left waiting behind the last response
+        assert!(depths.iter().all(|depth| *depth <= 2));
+        assert!(depths.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn test_status_reports_error_rate() {
+        let (base_url, _) = mock::upstream(|request| match request["model"].as_str() {
+            Some("broken-model") => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": {"message": "Model is down", "type": "server_error"}}),
+            ),
+            model => (StatusCode::OK, mock::completion_json(model.unwrap(), "Hi")),
+        })
+        .await;
+        let state = dev_state();
+        for model in ["gpt-4o", "broken-model", "gpt-4o", "gpt-4o"] {
+            let request = OpenAIChatCompletionRequest::new(model).with_message("user", "Hi");
+            let _ = dispatch(&state, request, Some(&base_url)).await;
+        }
+        let app = router(state);
+
+        let response = app
+            .oneshot(Request::get("/status").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let status = into_json(response).await;
+        assert_eq!(status["openai"]["requests"], 4);
+        assert_eq!(status["openai"]["errors"], 1);
+        assert_eq!(status["openai"]["error_rate"], 0.25);
+        assert!(status["openai"]["p50_latency"].as_f64().unwrap() > 0.0);
+    }
+}
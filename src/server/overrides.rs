@@ -0,0 +1,165 @@
+use crate::models::openai::OpenAIChatCompletionRequest;
+use crate::validation::ValidationError;
+use axum::http::HeaderMap;
+use serde_json::Value;
+
+// Sampling parameters that can be set with an `x-kubellm-<param>` header,
+// with underscores written as dashes, e.g. `x-kubellm-max-tokens: 100`.
+// Values in the body win unless `x-kubellm-override-force: true` is sent.
+pub const OVERRIDE_PARAMS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "max_tokens",
+    "max_completion_tokens",
+    "seed",
+    "presence_penalty",
+    "frequency_penalty",
+];
+
+pub const FORCE_HEADER: &str = "x-kubellm-override-force";
+
+// Returns the parameters that were overridden
+pub fn apply(
+    request: &mut OpenAIChatCompletionRequest,
+    headers: &HeaderMap,
+) -> Result<Vec<&'static str>, ValidationError> {
+    let force = headers
+        .get(FORCE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let mut applied = Vec::new();
+    for param in OVERRIDE_PARAMS {
+        let header = format!("x-kubellm-{}", param.replace('_', "-"));
+        let Some(value) = headers.get(&header) else {
+            continue;
+        };
+        let invalid =
+            || ValidationError::new(*param, format!("Invalid number in {} header", header));
+        let value: f64 = value
+            .to_str()
+            .map_err(|_| invalid())?
+            .trim()
+            .parse()
+            .map_err(|_| invalid())?;
+        if !force && is_set(request, param) {
+            continue;
+        }
+        set(request, param, value).ok_or_else(invalid)?;
+        applied.push(*param);
+    }
+    Ok(applied)
+}
+
+fn is_set(request: &OpenAIChatCompletionRequest, param: &str) -> bool {
+    match param {
+        "temperature" => request.temperature.is_some(),
+        "top_p" => request.top_p.is_some(),
+        "presence_penalty" => request.presence_penalty.is_some(),
+        "frequency_penalty" => request.frequency_penalty.is_some(),
+        "max_tokens" => request.max_tokens.is_some(),
+        "max_completion_tokens" => request.max_completion_tokens.is_some(),
+        _ => request
+            .extra
+            .as_ref()
+            .is_some_and(|extra| extra.contains_key(param)),
+    }
+}
+
+fn set(request: &mut OpenAIChatCompletionRequest, param: &str, value: f64) -> Option<()> {
+    let integer = || (value.fract() == 0.0).then_some(value as i64);
+    match param {
+        "temperature" => request.temperature = Some(value as f32),
+        "top_p" => request.top_p = Some(value as f32),
+        "presence_penalty" => request.presence_penalty = Some(value as f32),
+        "frequency_penalty" => request.frequency_penalty = Some(value as f32),
+        "max_tokens" => request.max_tokens = Some(i32::try_from(integer()?).ok()?),
+        "max_completion_tokens" => {
+            request.max_completion_tokens = Some(i32::try_from(integer()?).ok()?)
+        }
+        "seed" => {
+            let seed = integer()?;
+            request
+                .extra
+                .get_or_insert_with(Default::default)
+                .insert(param.to_string(), Value::from(seed));
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_body_value_wins_without_force() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+        request.temperature = Some(1.0);
+
+        let applied = apply(&mut request, &headers(&[("x-kubellm-temperature", "0.2")])).unwrap();
+
+        assert!(applied.is_empty());
+        assert_eq!(request.temperature, Some(1.0));
+    }
+
+    #[test]
+    fn test_force_overrides_body_value() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+        request.temperature = Some(1.0);
+
+        let applied = apply(
+            &mut request,
+            &headers(&[
+                ("x-kubellm-temperature", "0.2"),
+                ("x-kubellm-override-force", "true"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(applied, vec!["temperature"]);
+        assert_eq!(request.temperature, Some(0.2));
+    }
+
+    #[test]
+    fn test_extra_and_integer_params() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+
+        apply(
+            &mut request,
+            &headers(&[
+                ("x-kubellm-top-p", "0.9"),
+                ("x-kubellm-max-tokens", "100"),
+                ("x-kubellm-seed", "42"),
+            ]),
+        )
+        .unwrap();
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(request.top_p, Some(0.9));
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["seed"], 42);
+    }
+
+    #[test]
+    fn test_invalid_override_is_rejected() {
+        let mut request = OpenAIChatCompletionRequest::new("gpt-4o");
+
+        let error = apply(&mut request, &headers(&[("x-kubellm-max-tokens", "1.5")])).unwrap_err();
+
+        assert_eq!(error.param, "max_tokens");
+        assert_eq!(
+            error.message,
+            "Invalid number in x-kubellm-max-tokens header"
+        );
+    }
+}
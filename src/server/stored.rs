@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+pub const DEFAULT_STORED_COMPLETIONS: usize = 10_000;
+
+// Owners of stored completions
+//
+// The upstream serves a completion created with `store: true` to anyone
+// holding the server's key, so the proxy remembers which caller created each
+// id, by fingerprint of their API key, and only lets that caller retrieve it.
+// Ids the proxy didn't create are unknown to it. Past the capacity the oldest
+// ids are forgotten.
+pub struct StoredCompletions {
+    capacity: usize,
+    owners: Mutex<Owners>,
+}
+
+#[derive(Default)]
+struct Owners {
+    by_id: HashMap<String, Option<String>>,
+    order: VecDeque<String>,
+}
+
+impl StoredCompletions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            owners: Mutex::new(Owners::default()),
+        }
+    }
+
+    // Records the caller that created a completion, `None` for anonymous ones
+    pub fn record(&self, id: &str, owner: Option<String>) {
+        let mut owners = self.owners.lock().unwrap();
+        if owners.by_id.insert(id.to_string(), owner).is_none() {
+            owners.order.push_back(id.to_string());
+        }
+        while owners.order.len() > self.capacity {
+            if let Some(oldest) = owners.order.pop_front() {
+                owners.by_id.remove(&oldest);
+            }
+        }
+    }
+
+    // Whether the caller created the completion
+    pub fn owned_by(&self, id: &str, owner: Option<&str>) -> bool {
+        let owners = self.owners.lock().unwrap();
+        owners
+            .by_id
+            .get(id)
+            .is_some_and(|recorded| recorded.as_deref() == owner)
+    }
+}
+
+impl Default for StoredCompletions {
+    fn default() -> Self {
+        Self::new(DEFAULT_STORED_COMPLETIONS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_the_owner_sees_a_completion() {
+        let stored = StoredCompletions::default();
+        stored.record("chatcmpl-1", Some("sha256:aaaaaaaa".to_string()));
+        stored.record("chatcmpl-2", None);
+
+        assert!(stored.owned_by("chatcmpl-1", Some("sha256:aaaaaaaa")));
+        assert!(!stored.owned_by("chatcmpl-1", Some("sha256:bbbbbbbb")));
+        assert!(!stored.owned_by("chatcmpl-1", None));
+        assert!(stored.owned_by("chatcmpl-2", None));
+        assert!(!stored.owned_by("chatcmpl-3", None));
+    }
+
+    #[test]
+    fn test_oldest_completions_are_forgotten() {
+        let stored = StoredCompletions::new(2);
+        for id in ["chatcmpl-1", "chatcmpl-2", "chatcmpl-3"] {
+            stored.record(id, None);
+        }
+
+        assert!(!stored.owned_by("chatcmpl-1", None));
+        assert!(stored.owned_by("chatcmpl-2", None));
+        assert!(stored.owned_by("chatcmpl-3", None));
+    }
+}
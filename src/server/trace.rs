@@ -0,0 +1,43 @@
+// Routing decision trace
+//
+// Records the steps that decided where a request went, e.g. a deprecated
+// alias being remapped or a fallback model taking over. In dev mode the trace
+// is returned in the `x-kubellm-route-trace` header as `step=outcome` pairs
+// separated by `;`, in the order they happened.
+#[derive(Debug, Default)]
+pub struct RouteTrace {
+    steps: Vec<(&'static str, String)>,
+}
+
+impl RouteTrace {
+    pub fn record(&mut self, step: &'static str, outcome: impl Into<String>) {
+        self.steps.push((step, outcome.into()));
+    }
+
+    pub fn render(&self) -> String {
+        self.steps
+            .iter()
+            .map(|(step, outcome)| format!("{}={}", step, outcome))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_keeps_order() {
+        let mut trace = RouteTrace::default();
+        trace.record("alias", "gpt-4>gpt-4o");
+        trace.record("drop", "logit_bias");
+        trace.record("provider", "openai");
+
+        assert_eq!(
+            trace.render(),
+            "alias=gpt-4>gpt-4o;drop=logit_bias;provider=openai"
+        );
+        assert_eq!(RouteTrace::default().render(), "");
+    }
+}
@@ -0,0 +1,183 @@
+use crate::metrics::{self, Histogram, LATENCY_BUCKETS};
+use crate::models::openai::{OpenAIChatCompletionRequest, OpenAIClient};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Shadow traffic
+//
+// Mirrors a sampled fraction of requests to a provider under evaluation. The
+// copy is sent from a background task so it never holds up the real request,
+// and its response is thrown away; only its usage, latency and errors are
+// recorded, in metrics of their own.
+
+// Picks a steady fraction of requests without randomness: request `n` is
+// sampled when it carries the running total `n * rate` past a whole number.
+#[derive(Debug)]
+pub struct Sampler {
+    rate: f64,
+    seen: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate: rate.clamp(0.0, 1.0),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sample(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+#[derive(Debug)]
+pub struct ShadowStats {
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+    pub prompt_tokens: AtomicU64,
+    pub completion_tokens: AtomicU64,
+    pub latency: Histogram,
+}
+
+impl Default for ShadowStats {
+    fn default() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            prompt_tokens: AtomicU64::new(0),
+            completion_tokens: AtomicU64::new(0),
+            latency: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+pub struct Shadow {
+    client: OpenAIClient,
+    base_url: String,
+    // Replaces the requested model, for providers with their own model names
+    model: Option<String>,
+    sampler: Sampler,
+    pub stats: ShadowStats,
+}
+
+impl Shadow {
+    pub fn new(client: OpenAIClient, base_url: impl Into<String>, sample_rate: f64) -> Self {
+        Self {
+            client,
+            base_url: base_url.into(),
+            model: None,
+            sampler: Sampler::new(sample_rate),
+            stats: ShadowStats::default(),
+        }
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    // The client as sent to the shadow upstream, e.g. to warm it up
+    pub fn upstream(&self) -> OpenAIClient {
+        self.client.deployment(self.base_url.clone())
+    }
+
+    // Sends a copy of the request in the background when it is sampled
+    pub fn mirror(self: &Arc<Self>, request: &OpenAIChatCompletionRequest) {
+        if !self.sampler.sample() {
+            return;
+        }
+        let mut request = OpenAIChatCompletionRequest {
+            stream: None,
+            // Not counted as attempts of the original request
+            attempts: Arc::default(),
+            // The caller's key is for the original upstream only
+            provider_key: None,
+            ..request.clone()
+        };
+        if let Some(model) = &self.model {
+            request.model = model.clone();
+        }
+        let shadow = self.clone();
+        tokio::spawn(async move { shadow.send(request).await });
+    }
+
+    async fn send(&self, request: OpenAIChatCompletionRequest) {
+        let started = Instant::now();
+        let result = self
+            .client
+            .chat_with_base_url(request, &self.base_url)
+            .await;
+        self.stats.latency.observe(started.elapsed());
+        match result {
+            Ok(response) => {
+                let usage = &response.usage;
+                self.stats
+                    .prompt_tokens
+                    .fetch_add(usage.prompt_tokens.max(0) as u64, Ordering::Relaxed);
+                self.stats
+                    .completion_tokens
+                    .fetch_add(usage.completion_tokens.max(0) as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(error = %err, "Shadow request failed");
+            }
+        }
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, out: &mut String) {
+        let stats = &self.stats;
+        metrics::render_counter(
+            out,
+            "kubellm_shadow_requests_total",
+            "Requests mirrored to the shadow provider",
+            stats.requests.load(Ordering::Relaxed),
+        );
+        metrics::render_counter(
+            out,
+            "kubellm_shadow_errors_total",
+            "Mirrored requests the shadow provider failed",
+            stats.errors.load(Ordering::Relaxed),
+        );
+        metrics::render_counter(
+            out,
+            "kubellm_shadow_prompt_tokens_total",
+            "Prompt tokens used by the shadow provider",
+            stats.prompt_tokens.load(Ordering::Relaxed),
+        );
+        metrics::render_counter(
+            out,
+            "kubellm_shadow_completion_tokens_total",
+            "Completion tokens used by the shadow provider",
+            stats.completion_tokens.load(Ordering::Relaxed),
+        );
+        stats.latency.render(
+            out,
+            "kubellm_shadow_latency_seconds",
+            "Time until the shadow provider answered a mirrored request",
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled(rate: f64, requests: usize) -> usize {
+        let sampler = Sampler::new(rate);
+        (0..requests).filter(|_| sampler.sample()).count()
+    }
+
+    #[test]
+    fn test_sampler_fraction() {
+        assert_eq!(sampled(0.0, 100), 0);
+        assert_eq!(sampled(0.1, 100), 10);
+        assert_eq!(sampled(0.25, 100), 25);
+        assert_eq!(sampled(1.0, 100), 100);
+        assert_eq!(sampled(2.0, 100), 100);
+    }
+}
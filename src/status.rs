@@ -0,0 +1,214 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Rolling request outcomes per provider, a quick health view for `/status`
+// that doesn't need a metrics stack.
+
+pub const STATUS_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+struct Outcome {
+    at: Instant,
+    success: bool,
+    latency: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProviderStatus {
+    pub requests: usize,
+    pub errors: usize,
+    pub error_rate: f64,
+    // Median latency in seconds
+    pub p50_latency: f64,
+    // Requests waiting for a concurrency slot and requests being answered now
+    pub queue_depth: usize,
+    pub in_flight: usize,
+}
+
+// Current requests of a provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Load {
+    pub queued: usize,
+    pub in_flight: usize,
+}
+
+pub struct ProviderStats {
+    window: Duration,
+    outcomes: Mutex<HashMap<String, VecDeque<Outcome>>>,
+    load: Mutex<HashMap<String, Load>>,
+}
+
+// Counts a request as queued until `start`, then as in flight until dropped
+pub struct LoadGuard {
+    stats: Arc<ProviderStats>,
+    provider: String,
+    started: bool,
+}
+
+impl LoadGuard {
+    pub fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        let mut load = self.stats.load.lock().unwrap();
+        let load = load.entry(self.provider.clone()).or_default();
+        load.queued -= 1;
+        load.in_flight += 1;
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        let mut load = self.stats.load.lock().unwrap();
+        let load = load.entry(self.provider.clone()).or_default();
+        if self.started {
+            load.in_flight -= 1;
+        } else {
+            load.queued -= 1;
+        }
+    }
+}
+
+impl Default for ProviderStats {
+    fn default() -> Self {
+        Self::new(STATUS_WINDOW)
+    }
+}
+
+impl ProviderStats {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            outcomes: Mutex::new(HashMap::new()),
+            load: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts a request for `provider` as queued
+    pub fn queue(self: &Arc<Self>, provider: &str) -> LoadGuard {
+        self.load
+            .lock()
+            .unwrap()
+            .entry(provider.to_string())
+            .or_default()
+            .queued += 1;
+        LoadGuard {
+            stats: self.clone(),
+            provider: provider.to_string(),
+            started: false,
+        }
+    }
+
+    pub fn load(&self) -> HashMap<String, Load> {
+        self.load.lock().unwrap().clone()
+    }
+
+    pub fn record(&self, provider: &str, success: bool, latency: Duration) {
+        let now = Instant::now();
+        let mut outcomes = self.outcomes.lock().unwrap();
+        let outcomes = outcomes.entry(provider.to_string()).or_default();
+        outcomes.push_back(Outcome {
+            at: now,
+            success,
+            latency,
+        });
+        while outcomes
+            .front()
+            .is_some_and(|outcome| now.duration_since(outcome.at) > self.window)
+        {
+            outcomes.pop_front();
+        }
+    }
+
+    // Status of every provider with requests in the window or in progress
+    pub fn snapshot(&self) -> HashMap<String, ProviderStatus> {
+        let now = Instant::now();
+        let outcomes = self.outcomes.lock().unwrap();
+        let load = self.load();
+        let providers = outcomes.keys().chain(load.keys()).collect::<HashSet<_>>();
+        providers
+            .into_iter()
+            .filter_map(|provider| {
+                let recent: Vec<&Outcome> = outcomes
+                    .get(provider)
+                    .into_iter()
+                    .flatten()
+                    .filter(|outcome| now.duration_since(outcome.at) <= self.window)
+                    .collect();
+                let load = load.get(provider).copied().unwrap_or_default();
+                if recent.is_empty() && load == Load::default() {
+                    return None;
+                }
+                let errors = recent.iter().filter(|outcome| !outcome.success).count();
+                let mut latencies: Vec<Duration> =
+                    recent.iter().map(|outcome| outcome.latency).collect();
+                latencies.sort();
+                let status = ProviderStatus {
+                    requests: recent.len(),
+                    errors,
+                    error_rate: if recent.is_empty() {
+                        0.0
+                    } else {
+                        errors as f64 / recent.len() as f64
+                    },
+                    p50_latency: latencies
+                        .get(latencies.len() / 2)
+                        .map_or(0.0, Duration::as_secs_f64),
+                    queue_depth: load.queued,
+                    in_flight: load.in_flight,
+                };
+                Some((provider.clone(), status))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let stats = ProviderStats::default();
+        stats.record("openai", true, Duration::from_millis(100));
+        stats.record("openai", false, Duration::from_millis(300));
+        stats.record("openai", true, Duration::from_millis(200));
+        stats.record("openai", true, Duration::from_millis(400));
+
+        let status = &stats.snapshot()["openai"];
+        assert_eq!(status.requests, 4);
+        assert_eq!(status.errors, 1);
+        assert_eq!(status.error_rate, 0.25);
+        assert_eq!(status.p50_latency, 0.3);
+    }
+
+    #[test]
+    fn test_load_is_tracked_until_dropped() {
+        let stats = Arc::new(ProviderStats::default());
+        let mut first = stats.queue("openai");
+        let second = stats.queue("openai");
+        first.start();
+
+        let status = &stats.snapshot()["openai"];
+        assert_eq!((status.queue_depth, status.in_flight), (1, 1));
+        assert_eq!(status.requests, 0);
+
+        drop(first);
+        drop(second);
+        assert!(stats.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_old_outcomes_leave_the_window() {
+        let stats = ProviderStats::new(Duration::from_millis(20));
+        stats.record("openai", false, Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(stats.snapshot().is_empty());
+
+        stats.record("openai", true, Duration::from_millis(1));
+        assert_eq!(stats.snapshot()["openai"].errors, 0);
+    }
+}
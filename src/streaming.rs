@@ -0,0 +1,446 @@
+use crate::models::finish_reason;
+use crate::models::openai::{
+    ChatCompletionChunk, ChunkChoice, Delta, OpenAIChatCompletionRequest, Usage,
+};
+use anyhow::{anyhow, Result};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::time::Duration;
+
+// Streaming helpers for forwarding upstream chunks to clients
+
+// Removes noise fields, such as OpenAI's `obfuscation` padding, from chunks
+// before they are forwarded, for clients with strict parsers.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkNormalizer {
+    strip_fields: Vec<String>,
+}
+
+impl ChunkNormalizer {
+    pub fn new(strip_fields: Vec<String>) -> Self {
+        Self { strip_fields }
+    }
+
+    pub fn normalize(&self, chunk: &mut ChatCompletionChunk) {
+        for field in &self.strip_fields {
+            chunk.extra.remove(field);
+            for choice in &mut chunk.choices {
+                choice.delta.extra.remove(field);
+            }
+        }
+    }
+}
+
+// Whether the client asked for `stream_options.include_usage`
+pub fn include_usage(request: &OpenAIChatCompletionRequest) -> bool {
+    request
+        .extra
+        .as_ref()
+        .and_then(|extra| extra.get("stream_options"))
+        .and_then(|options| options.get("include_usage"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+// Asks the upstream for the usage-only final chunk so token usage can be
+// tracked even when the client didn't ask for it.
+pub fn request_usage(request: &mut OpenAIChatCompletionRequest) {
+    let extra = request.extra.get_or_insert_with(Default::default);
+    let options = extra
+        .entry("stream_options".to_string())
+        .or_insert_with(|| json!({}));
+    if let Some(options) = options.as_object_mut() {
+        options.insert("include_usage".to_string(), Value::Bool(true));
+    }
+}
+
+// Collects the usage of a stream. With `include_usage`, the final chunk has
+// empty `choices` and only `usage`; it is forwarded when the client asked for
+// it and swallowed otherwise, since some clients choke on empty choices.
+#[derive(Debug, Default)]
+pub struct UsageAggregator {
+    forward_usage_chunk: bool,
+    usage: Option<Usage>,
+}
+
+impl UsageAggregator {
+    pub fn new(forward_usage_chunk: bool) -> Self {
+        Self {
+            forward_usage_chunk,
+            usage: None,
+        }
+    }
+
+    // Returns the chunk to forward, if any
+    pub fn process(&mut self, mut chunk: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        if let Some(usage) = &mut chunk.usage {
+            usage.complete();
+            self.usage = Some(usage.clone());
+        }
+        let usage_only = chunk.choices.is_empty() && chunk.usage.is_some();
+        if usage_only && !self.forward_usage_chunk {
+            return None;
+        }
+        Some(chunk)
+    }
+
+    pub fn usage(&self) -> Option<&Usage> {
+        self.usage.as_ref()
+    }
+}
+
+// Splits server-sent events out of an upstream body as it arrives, in pieces
+// that may end anywhere, even inside a line. Returns the data of every
+// complete event; comments and other fields are ignored.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        // Events are separated by blank lines, which may end in CRLF
+        self.buffer
+            .extend(bytes.iter().filter(|byte| **byte != b'\r'));
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.windows(2).position(|pair| pair == b"\n\n") {
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+            let data: Vec<&str> = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect();
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+        events
+    }
+}
+
+// Marks the final chunk of a stream cut off by `limit_duration`
+pub const TRUNCATED_FIELD: &str = "truncated";
+pub const MAX_STREAM_DURATION: &str = "max_stream_duration";
+
+struct Limited<S> {
+    chunks: Pin<Box<S>>,
+    // Last chunk seen and the choices that haven't finished yet
+    last: Option<ChatCompletionChunk>,
+    open: BTreeSet<i32>,
+}
+
+// Ends a stream that is still running after `max`. The client gets a final
+// chunk finishing every open choice with `length` and `"truncated":
+// "max_stream_duration"`, and the upstream is dropped, which aborts it.
+pub fn limit_duration<S>(
+    chunks: S,
+    max: Duration,
+) -> impl Stream<Item = Result<ChatCompletionChunk>>
+where
+    S: Stream<Item = Result<ChatCompletionChunk>>,
+{
+    let deadline = tokio::time::Instant::now() + max;
+    let limited = Limited {
+        chunks: Box::pin(chunks),
+        last: None,
+        open: BTreeSet::new(),
+    };
+    stream::unfold(Some(limited), move |limited| async move {
+        let mut limited = limited?;
+        match tokio::time::timeout_at(deadline, limited.chunks.next()).await {
+            Ok(Some(Ok(chunk))) => {
+                for choice in &chunk.choices {
+                    match choice.finish_reason {
+                        Some(_) => limited.open.remove(&choice.index),
+                        None => limited.open.insert(choice.index),
+                    };
+                }
+                limited.last = Some(chunk.clone());
+                Some((Ok(chunk), Some(limited)))
+            }
+            Ok(Some(Err(err))) => Some((Err(err), Some(limited))),
+            Ok(None) => None,
+            Err(_) => {
+                tracing::warn!(
+                    max_secs = max.as_secs_f64(),
+                    "Stream exceeded its maximum duration, closing it"
+                );
+                let truncated = match limited.last {
+                    Some(last) => Ok(truncation_chunk(last, &limited.open)),
+                    None => Err(anyhow!(
+                        "Stream sent nothing within the maximum duration of {}s",
+                        max.as_secs_f64()
+                    )),
+                };
+                Some((truncated, None))
+            }
+        }
+    })
+}
+
+fn truncation_chunk(last: ChatCompletionChunk, open: &BTreeSet<i32>) -> ChatCompletionChunk {
+    let indices: Vec<i32> = if open.is_empty() {
+        vec![0]
+    } else {
+        open.iter().copied().collect()
+    };
+    let choices = indices
+        .into_iter()
+        .map(|index| ChunkChoice {
+            index,
+            delta: Delta::default(),
+            finish_reason: Some(finish_reason::LENGTH.to_string()),
+            logprobs: None,
+        })
+        .collect();
+    let mut extra = std::collections::HashMap::new();
+    extra.insert(TRUNCATED_FIELD.to_string(), json!(MAX_STREAM_DURATION));
+    ChatCompletionChunk {
+        choices,
+        usage: None,
+        extra,
+        ..last
+    }
+}
+
+// Chunks held between the upstream and a client that reads slower than the
+// upstream sends
+pub const STREAM_BUFFER: usize = 16;
+
+// Pulls `chunks` in a task of its own into a channel of `capacity` items. Once
+// the channel is full the task stops pulling until the client catches up, so
+// the upstream is only read as fast as the client and TCP backpressure reaches
+// it. When the client goes away the task ends and drops the upstream.
+pub fn bounded<S>(chunks: S, capacity: usize) -> impl Stream<Item = S::Item>
+where
+    S: Stream + Send + 'static,
+    S::Item: Send + 'static,
+{
+    let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+    tokio::spawn(async move {
+        let mut chunks = Box::pin(chunks);
+        while let Some(chunk) = chunks.next().await {
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((chunk, receiver))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn chunk() -> ChatCompletionChunk {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion.chunk",
+            "created": 1728933352,
+            "model": "gpt-4o",
+            "system_fingerprint": "fp_123",
+            "choices": [{"index": 0, "delta": {"content": "Hi"}, "finish_reason": null}],
+            "obfuscation": "aBcD3"
+        }))
+        .expect("Failed to parse chunk")
+    }
+
+    #[test]
+    fn test_obfuscation_is_forwarded_by_default() {
+        let mut chunk = chunk();
+        ChunkNormalizer::default().normalize(&mut chunk);
+
+        let value = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(value["obfuscation"], "aBcD3");
+    }
+
+    #[test]
+    fn test_obfuscation_is_stripped() {
+        let mut chunk = chunk();
+        ChunkNormalizer::new(vec!["obfuscation".to_string()]).normalize(&mut chunk);
+
+        let value = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(value.get("obfuscation"), None::<&Value>);
+        assert_eq!(value["choices"][0]["delta"]["content"], "Hi");
+    }
+
+    fn stream_with_usage() -> Vec<ChatCompletionChunk> {
+        let usage_chunk = serde_json::from_value(json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion.chunk",
+            "created": 1728933352,
+            "model": "gpt-4o",
+            "choices": [],
+            "usage": {
+                "prompt_tokens": 9,
+                "completion_tokens": 2,
+                "total_tokens": 11,
+                "prompt_tokens_details": {},
+                "completion_tokens_details": {}
+            }
+        }))
+        .expect("Failed to parse chunk");
+        vec![chunk(), usage_chunk]
+    }
+
+    #[test]
+    fn test_usage_only_chunk_is_tracked_and_stripped() {
+        let mut aggregator = UsageAggregator::new(false);
+
+        let forwarded: Vec<_> = stream_with_usage()
+            .into_iter()
+            .filter_map(|chunk| aggregator.process(chunk))
+            .collect();
+
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0].choices.len(), 1);
+        assert_eq!(aggregator.usage().unwrap().total_tokens, 11);
+    }
+
+    #[test]
+    fn test_usage_only_chunk_is_forwarded_when_requested() {
+        let mut aggregator = UsageAggregator::new(true);
+
+        let forwarded: Vec<_> = stream_with_usage()
+            .into_iter()
+            .filter_map(|chunk| aggregator.process(chunk))
+            .collect();
+
+        assert_eq!(forwarded.len(), 2);
+        assert!(forwarded[1].choices.is_empty());
+        assert_eq!(aggregator.usage().unwrap().prompt_tokens, 9);
+    }
+
+    // Sets its flag when dropped, standing in for the upstream connection
+    struct Upstream(Arc<AtomicBool>);
+
+    impl Drop for Upstream {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_cut_off_at_max_duration() {
+        let aborted = Arc::new(AtomicBool::new(false));
+        let upstream = Upstream(aborted.clone());
+        // Sends a chunk every 10ms, forever
+        let endless = stream::unfold(upstream, |upstream| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Some((Ok(chunk()), upstream))
+        });
+
+        let started = std::time::Instant::now();
+        let chunks: Vec<_> = limit_duration(endless, Duration::from_millis(55))
+            .collect()
+            .await;
+
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert!(aborted.load(Ordering::SeqCst));
+        assert!(
+            (2..=7).contains(&chunks.len()),
+            "got {} chunks",
+            chunks.len()
+        );
+        let last = chunks.last().unwrap().as_ref().unwrap();
+        assert_eq!(last.choices[0].finish_reason.as_deref(), Some("length"));
+        assert_eq!(last.extra[TRUNCATED_FIELD], MAX_STREAM_DURATION);
+        assert_eq!(last.id, "chatcmpl-123");
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_stops_upstream_reads() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let upstream = stream::iter(0..1000).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let mut chunks = Box::pin(bounded(upstream, 4));
+        assert_eq!(chunks.next().await, Some(0));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The channel is full and one more chunk waits to be sent
+        assert!(
+            pulled.load(Ordering::SeqCst) <= 1 + 4 + 1,
+            "pulled {} chunks",
+            pulled.load(Ordering::SeqCst)
+        );
+        let rest: Vec<_> = chunks.collect().await;
+        assert_eq!(rest, (1..1000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_gone_client_drops_upstream() {
+        let aborted = Arc::new(AtomicBool::new(false));
+        let upstream = Upstream(aborted.clone());
+        let endless = stream::unfold(upstream, |upstream| async move { Some((1, upstream)) });
+
+        let mut chunks = Box::pin(bounded(endless, 4));
+        assert_eq!(chunks.next().await, Some(1));
+        drop(chunks);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(aborted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_short_stream_is_untouched() {
+        let chunks = stream::iter(vec![Ok(chunk()), Ok(chunk())]);
+
+        let chunks: Vec<_> = limit_duration(chunks, Duration::from_secs(1))
+            .collect()
+            .await;
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| !chunk
+            .as_ref()
+            .unwrap()
+            .extra
+            .contains_key(TRUNCATED_FIELD)));
+    }
+
+    #[test]
+    fn test_sse_decoder_joins_split_events() {
+        let mut decoder = SseDecoder::default();
+
+        assert!(decoder.push(b": keep-alive\n\ndata: {\"a\"").is_empty());
+        assert_eq!(
+            decoder.push(b":1}\r\n\r\ndata: [DONE]\n\n"),
+            vec!["{\"a\":1}", "[DONE]"]
+        );
+    }
+
+    #[test]
+    fn test_sse_decoder_joins_data_lines() {
+        let mut decoder = SseDecoder::default();
+
+        assert_eq!(
+            decoder.push(b"event: message\ndata: one\ndata:two\n\n"),
+            vec!["one\ntwo"]
+        );
+    }
+
+    #[test]
+    fn test_request_usage_enables_include_usage() {
+        let mut request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "stream": true,
+            "stream_options": {"include_usage": false}
+        }))
+        .unwrap();
+
+        assert!(!include_usage(&request));
+        request_usage(&mut request);
+        assert!(include_usage(&request));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::models::openai::{
+    Content, Message, OpenAIChatCompletionRequest, OpenAIChatCompletionResponse,
+};
+use anyhow::{anyhow, Result};
+use regex_automata::meta::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// Response transforms
+//
+// Hook point for rewriting a complete response before it is returned. They run
+// after the response was cached, so each client gets its own transform of a
+// shared response. Clients can attach annotations for the transforms to the
+// request under `kubellm_annotations`, which are never sent upstream.
+
+pub const ANNOTATIONS_FIELD: &str = "kubellm_annotations";
+// Response field for data added by the gateway, which OpenAI doesn't use
+pub const EXTRA_FIELD: &str = "kubellm_extra";
+
+pub trait ResponseTransform: Send + Sync {
+    fn apply(&self, annotations: &Map<String, Value>, response: &mut OpenAIChatCompletionResponse);
+}
+
+// Removes the annotations from the request, empty when it has none
+pub fn take_annotations(request: &mut OpenAIChatCompletionRequest) -> Map<String, Value> {
+    match request
+        .extra
+        .as_mut()
+        .and_then(|extra| extra.remove(ANNOTATIONS_FIELD))
+    {
+        Some(Value::Object(annotations)) => annotations,
+        _ => Map::new(),
+    }
+}
+
+// Copies the configured annotations into `kubellm_extra`, e.g. the documents
+// a RAG app put in context, so they come back with the answer
+#[derive(Debug, Clone, Default)]
+pub struct MetadataEnricher {
+    keys: Vec<String>,
+}
+
+impl MetadataEnricher {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self { keys }
+    }
+}
+
+impl ResponseTransform for MetadataEnricher {
+    fn apply(&self, annotations: &Map<String, Value>, response: &mut OpenAIChatCompletionResponse) {
+        let metadata: Map<String, Value> = self
+            .keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), annotations.get(key)?.clone())))
+            .collect();
+        if metadata.is_empty() {
+            return;
+        }
+        match response.kubellm_extra.get_or_insert_with(Default::default) {
+            Value::Object(extra) => extra.extend(metadata),
+            other => *other = Value::Object(metadata),
+        }
+    }
+}
+
+// Content filter
+//
+// Replaces matches of the configured patterns in the assistant's text, for
+// operators that must keep some terms out of generated content. Unlike the
+// response transforms clients can't opt out of it. Streamed responses are
+// sent as they arrive and aren't filtered.
+pub const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterRule {
+    pub pattern: String,
+    #[serde(default = "redacted")]
+    pub replacement: String,
+}
+
+fn redacted() -> String {
+    REDACTED.to_string()
+}
+
+// Rules applied in order, each to the output of the one before
+#[derive(Debug, Clone)]
+pub struct ContentFilter {
+    rules: Vec<(Regex, String)>,
+}
+
+impl ContentFilter {
+    pub fn new(rules: &[FilterRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern)
+                    .map_err(|err| anyhow!("invalid pattern '{}': {}", rule.pattern, err))?;
+                Ok((regex, rule.replacement.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn apply(&self, response: &mut OpenAIChatCompletionResponse) {
+        for choice in &mut response.choices {
+            if let Message::Assistant {
+                content: Some(Content::Text(text)),
+                ..
+            } = &mut choice.message
+            {
+                *text = self.redact(text);
+            }
+        }
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (regex, replacement) in &self.rules {
+            let mut redacted = String::with_capacity(text.len());
+            let mut last = 0;
+            for found in regex.find_iter(&text) {
+                redacted.push_str(&text[last..found.start()]);
+                redacted.push_str(replacement);
+                last = found.end();
+            }
+            redacted.push_str(&text[last..]);
+            text = redacted;
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use serde_json::json;
+
+    #[test]
+    fn test_annotations_are_taken_from_request() {
+        let mut request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "kubellm_annotations": {"documents": ["doc-1"]}
+        }))
+        .unwrap();
+
+        let annotations = take_annotations(&mut request);
+
+        assert_eq!(annotations["documents"], json!(["doc-1"]));
+        let body = serde_json::to_value(&request).unwrap();
+        assert!(body.get(ANNOTATIONS_FIELD).is_none());
+    }
+
+    #[test]
+    fn test_content_filter_redacts_configured_terms() {
+        let rules: Vec<FilterRule> = serde_json::from_value(json!([
+            {"pattern": "(?i)project falcon"},
+            {"pattern": "\\b\\d{3}-\\d{2}-\\d{4}\\b", "replacement": "***-**-****"}
+        ]))
+        .unwrap();
+        let filter = ContentFilter::new(&rules).unwrap();
+        let mut response = mock::completion(
+            "gpt-4o",
+            "Project Falcon launches soon, SSN 123-45-6789 is on file.",
+        );
+
+        filter.apply(&mut response);
+
+        assert_eq!(
+            response.choices[0].message.content_text(),
+            "[REDACTED] launches soon, SSN ***-**-**** is on file."
+        );
+        let invalid = FilterRule {
+            pattern: "(".to_string(),
+            replacement: redacted(),
+        };
+        assert!(ContentFilter::new(&[invalid]).is_err());
+    }
+
+    #[test]
+    fn test_enricher_copies_configured_annotations() {
+        let annotations = json!({"documents": ["doc-1", "doc-2"], "internal": "secret"});
+        let annotations = annotations.as_object().unwrap();
+        let mut response = mock::completion("gpt-4o", "Hi");
+
+        MetadataEnricher::new(vec!["documents".to_string()]).apply(annotations, &mut response);
+
+        let body = serde_json::to_value(&response).unwrap();
+        assert_eq!(body[EXTRA_FIELD], json!({"documents": ["doc-1", "doc-2"]}));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::models::openai::OpenAIChatCompletionRequest;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::str::FromStr;
+
+// Request validation, run before anything is sent upstream
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub param: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(param: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            param: param.into(),
+            message: message.into(),
+        }
+    }
+}
+
+// Rejects the first message whose text content is longer than `max_bytes`
+pub fn check_message_length(
+    request: &OpenAIChatCompletionRequest,
+    max_bytes: usize,
+) -> Result<(), ValidationError> {
+    for (index, message) in request.messages.iter().enumerate() {
+        let length = message.content_bytes();
+        if length > max_bytes {
+            return Err(ValidationError::new(
+                format!("messages[{}].content", index),
+                format!(
+                    "Message {} has {} bytes of content, the maximum is {}",
+                    index, length, max_bytes
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Caps on `tools`, which are sent with every request and count as prompt
+// tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ToolLimits {
+    pub max_tools: Option<usize>,
+    // Serialized size of all tool definitions together
+    pub max_bytes: Option<usize>,
+}
+
+impl ToolLimits {
+    pub fn check(&self, request: &OpenAIChatCompletionRequest) -> Result<(), ValidationError> {
+        let Some(tools) = &request.tools else {
+            return Ok(());
+        };
+        if let Some(max_tools) = self.max_tools.filter(|max| tools.len() > *max) {
+            return Err(ValidationError::new(
+                "tools",
+                format!(
+                    "Request has {} tools, the maximum is {}",
+                    tools.len(),
+                    max_tools
+                ),
+            ));
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            let bytes: usize = tools
+                .iter()
+                .map(|tool| serde_json::to_string(tool).map_or(0, |tool| tool.len()))
+                .sum();
+            if bytes > max_bytes {
+                return Err(ValidationError::new(
+                    "tools",
+                    format!(
+                        "Tool definitions have {} bytes, the maximum is {}",
+                        bytes, max_bytes
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// What to do with a request that sets `max_tokens` and `max_completion_tokens`
+// to different values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxTokensConflict {
+    #[default]
+    Reject,
+    // Keep `max_completion_tokens`, which replaces `max_tokens`
+    MaxCompletionTokens,
+}
+
+impl FromStr for MaxTokensConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(MaxTokensConflict::Reject),
+            "max_completion_tokens" => Ok(MaxTokensConflict::MaxCompletionTokens),
+            _ => Err(anyhow!("expected reject or max_completion_tokens")),
+        }
+    }
+}
+
+impl MaxTokensConflict {
+    // Returns whether `max_tokens` was dropped in favor of `max_completion_tokens`
+    pub fn resolve(
+        &self,
+        request: &mut OpenAIChatCompletionRequest,
+    ) -> Result<bool, ValidationError> {
+        let (Some(max_tokens), Some(max_completion_tokens)) =
+            (request.max_tokens, request.max_completion_tokens)
+        else {
+            return Ok(false);
+        };
+        if max_tokens == max_completion_tokens {
+            return Ok(false);
+        }
+        match self {
+            MaxTokensConflict::Reject => Err(ValidationError::new(
+                "max_tokens",
+                format!(
+                    "max_tokens ({}) and max_completion_tokens ({}) conflict, set only max_completion_tokens",
+                    max_tokens, max_completion_tokens
+                ),
+            )),
+            MaxTokensConflict::MaxCompletionTokens => {
+                request.max_tokens = None;
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_message_within_limit() {
+        let request = OpenAIChatCompletionRequest::new("gpt-4o").with_message("user", "Hello");
+
+        assert_eq!(check_message_length(&request, 5), Ok(()));
+    }
+
+    #[test]
+    fn test_message_over_limit_names_index() {
+        let request = OpenAIChatCompletionRequest::new("gpt-4o")
+            .with_message("system", "Be brief")
+            .with_message("user", "Hello there");
+
+        let err = check_message_length(&request, 10).expect_err("Second message is too long");
+
+        assert_eq!(err.param, "messages[1].content");
+        assert_eq!(
+            err.message,
+            "Message 1 has 11 bytes of content, the maximum is 10"
+        );
+    }
+
+    #[test]
+    fn test_array_content_sums_text_parts() {
+        let request: OpenAIChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "Hello"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+                    {"type": "text", "text": " world"}
+                ]
+            }]
+        }))
+        .unwrap();
+
+        assert_eq!(request.messages[0].content_bytes(), 11);
+        assert!(check_message_length(&request, 11).is_ok());
+        assert!(check_message_length(&request, 10).is_err());
+    }
+
+    fn with_tools(count: usize) -> OpenAIChatCompletionRequest {
+        let tools: Vec<_> = (0..count)
+            .map(|i| json!({"type": "function", "function": {"name": format!("tool_{}", i)}}))
+            .collect();
+        serde_json::from_value(json!({"model": "gpt-4o", "messages": [], "tools": tools})).unwrap()
+    }
+
+    #[test]
+    fn test_tool_limits() {
+        let limits = ToolLimits {
+            max_tools: Some(2),
+            max_bytes: Some(200),
+        };
+
+        assert!(limits.check(&with_tools(2)).is_ok());
+        let err = limits.check(&with_tools(3)).unwrap_err();
+        assert_eq!(err.message, "Request has 3 tools, the maximum is 2");
+        let limits = ToolLimits {
+            max_tools: None,
+            max_bytes: Some(90),
+        };
+        let err = limits.check(&with_tools(2)).unwrap_err();
+        assert_eq!(err.param, "tools");
+        assert!(err.message.starts_with("Tool definitions have"));
+    }
+
+    fn with_limits(max_tokens: i32, max_completion_tokens: i32) -> OpenAIChatCompletionRequest {
+        OpenAIChatCompletionRequest {
+            max_tokens: Some(max_tokens),
+            max_completion_tokens: Some(max_completion_tokens),
+            ..OpenAIChatCompletionRequest::new("gpt-4o")
+        }
+    }
+
+    #[test]
+    fn test_conflicting_max_tokens_are_rejected() {
+        let err = MaxTokensConflict::Reject
+            .resolve(&mut with_limits(100, 200))
+            .unwrap_err();
+
+        assert_eq!(err.param, "max_tokens");
+        assert_eq!(
+            err.message,
+            "max_tokens (100) and max_completion_tokens (200) conflict, set only max_completion_tokens"
+        );
+        assert_eq!(
+            MaxTokensConflict::Reject.resolve(&mut with_limits(100, 100)),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn test_conflicting_max_tokens_prefer_max_completion_tokens() {
+        let mut request = with_limits(100, 200);
+
+        let resolved = MaxTokensConflict::MaxCompletionTokens.resolve(&mut request);
+
+        assert_eq!(resolved, Ok(true));
+        assert_eq!(request.max_tokens, None);
+        assert_eq!(request.max_completion_tokens, Some(200));
+    }
+}